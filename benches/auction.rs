@@ -0,0 +1,117 @@
+mod common;
+
+use bigdecimal::{BigDecimal, FromPrimitive};
+use common::Harness;
+use fba::auction::{
+    calculate_batch_with, insert_order, AuctionConfig, BatchWorkspace, Order, Qty, Side,
+};
+use fba::price::{FixedPrice, Price};
+use rand::Rng;
+
+// pretty heterogeneous data
+fn random_orders() -> Vec<Order> {
+    let mut rng = rand::thread_rng();
+    (0..125000)
+        .map(|id| {
+            let random_price: f32 = rng.gen_range(140.0..150.0);
+            let random_qty: u32 = rng.gen_range(1..200);
+            Order::new(
+                id,
+                BigDecimal::from_f32(random_price).unwrap().round(3),
+                Qty::from(random_qty),
+            )
+        })
+        .collect()
+}
+
+fn batch(harness: &Harness) {
+    let mut bids = random_orders();
+    let mut asks = bids.clone();
+
+    let config = AuctionConfig::default();
+    let mut workspace = BatchWorkspace::default();
+    harness.bench("batch", || {
+        calculate_batch_with(&mut bids, &mut asks, &config, &mut workspace)
+    });
+}
+
+// same as `batch`, with prices converted to fixed-point
+fn batch_fixed_price(harness: &Harness) {
+    let to_fixed = |order: &Order| -> Order<FixedPrice<3>> {
+        let price = FixedPrice::from_decimal(order.price().unwrap());
+        Order::new(order.id(), price, order.qty())
+    };
+    let mut bids: Vec<_> = random_orders().iter().map(to_fixed).collect();
+    let mut asks = bids.clone();
+
+    let config = AuctionConfig::default();
+    let mut workspace = BatchWorkspace::default();
+    harness.bench("batch_fixed_price", || {
+        calculate_batch_with(&mut bids, &mut asks, &config, &mut workspace)
+    });
+}
+
+// large resting book that doesn't cross, a few new orders every batch
+fn batch_with_resting_book(harness: &Harness) {
+    let mut rng = rand::thread_rng();
+    let mut random_order = |id, low: f32, high: f32| {
+        let random_price: f32 = rng.gen_range(low..high);
+        let random_qty: u32 = rng.gen_range(1..200);
+        Order::new(
+            id,
+            BigDecimal::from_f32(random_price).unwrap().round(3),
+            Qty::from(random_qty),
+        )
+    };
+
+    let mut bids: Vec<Order> = vec![];
+    let mut asks: Vec<Order> = vec![];
+    for id in 0..125000 {
+        bids.push(random_order(id, 140.0, 145.0));
+        asks.push(random_order(id, 145.0, 150.0));
+    }
+
+    // the first batch sorts the book once
+    let config = AuctionConfig::default();
+    let mut workspace = BatchWorkspace::default();
+    calculate_batch_with(&mut bids, &mut asks, &config, &mut workspace);
+    let mut id = 125000;
+    harness.bench("batch_with_resting_book", || {
+        for _ in 0..10 {
+            id += 1;
+            insert_order(&mut bids, Side::Bid, random_order(id, 140.0, 145.0));
+            insert_order(&mut asks, Side::Ask, random_order(id, 145.0, 150.0));
+        }
+        calculate_batch_with(&mut bids, &mut asks, &config, &mut workspace)
+    });
+}
+
+// quiet book, bids and asks a tick apart don't cross, batch after batch
+fn batch_no_cross(harness: &Harness) {
+    let mut rng = rand::thread_rng();
+    let mut random_order = |id, low: u32, high: u32| {
+        let random_price = BigDecimal::new(rng.gen_range(low..high).into(), 3);
+        Order::new(id, random_price, Qty::from(rng.gen_range(1..200)))
+    };
+
+    let mut bids: Vec<Order> = (0..125000)
+        .map(|id| random_order(id, 140_000, 145_000))
+        .collect();
+    let mut asks: Vec<Order> = (125000..250000)
+        .map(|id| random_order(id, 145_000, 150_000))
+        .collect();
+
+    let config = AuctionConfig::default();
+    let mut workspace = BatchWorkspace::default();
+    harness.bench("batch_no_cross", || {
+        calculate_batch_with(&mut bids, &mut asks, &config, &mut workspace)
+    });
+}
+
+fn main() {
+    let harness = Harness::from_args();
+    batch(&harness);
+    batch_fixed_price(&harness);
+    batch_with_resting_book(&harness);
+    batch_no_cross(&harness);
+}
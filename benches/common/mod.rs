@@ -0,0 +1,116 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// counts allocations, so that benchmarks can report them next to the time
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// time spent measuring every benchmark
+const MEASURE: Duration = Duration::from_secs(3);
+
+/**
+Minimal benchmark harness that works on stable Rust.
+`cargo bench [filter]` runs the benchmarks with a name containing the filter, after one warm-up
+iteration, and prints the mean time and allocations per iteration. Without `--bench`, e.g. under
+`cargo test --benches`, every benchmark runs once to check it still works.
+*/
+pub struct Harness {
+    bench: bool,
+    filter: Option<String>,
+}
+
+impl Harness {
+    pub fn from_args() -> Harness {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        Harness {
+            bench: args.iter().any(|arg| arg == "--bench"),
+            filter: args.into_iter().find(|arg| !arg.starts_with("--")),
+        }
+    }
+
+    fn skip(&self, name: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_some_and(|filter| !name.contains(filter.as_str()))
+    }
+
+    pub fn bench<T>(&self, name: &str, mut f: impl FnMut() -> T) {
+        if self.skip(name) {
+            return;
+        }
+        black_box(f());
+        if !self.bench {
+            println!("{} ... ok", name);
+            return;
+        }
+
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let mut iterations: u32 = 0;
+        while start.elapsed() < MEASURE {
+            black_box(f());
+            iterations += 1;
+        }
+        let per_iter = start.elapsed() / iterations;
+        let allocations =
+            (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / u64::from(iterations);
+        println!(
+            "{:<28} {:>14} ns/iter {:>10} allocs/iter ({} iterations)",
+            name,
+            per_iter.as_nanos(),
+            allocations,
+            iterations
+        );
+    }
+
+    // for what only `f` itself can time, it returns the duration of a single sample
+    #[allow(dead_code)]
+    pub fn bench_latency(&self, name: &str, mut f: impl FnMut() -> Duration) {
+        if self.skip(name) {
+            return;
+        }
+        f();
+        if !self.bench {
+            println!("{} ... ok", name);
+            return;
+        }
+
+        let start = Instant::now();
+        let mut samples = vec![];
+        while start.elapsed() < MEASURE {
+            samples.push(f());
+        }
+        samples.sort();
+        let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+        println!(
+            "{:<28} {:>14} ns mean {:>14} ns p99 {:>14} ns max ({} samples)",
+            name,
+            mean.as_nanos(),
+            samples[samples.len() * 99 / 100].as_nanos(),
+            samples[samples.len() - 1].as_nanos(),
+            samples.len()
+        );
+    }
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use bigdecimal::BigDecimal;
+use common::Harness;
+use fba::auction::{AuctionConfig, Qty, TimeInForce};
+use fba::{answer_query, apply_command, run_batches, Command, Exchange, OrderType, Query};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+
+fn add(order_type: OrderType, price: u32) -> Command {
+    Command::Add {
+        order_type,
+        symbol: "AAPL".to_string(),
+        time_in_force: TimeInForce::GoodTillCancel,
+        min_qty: None,
+        display_qty: None,
+        expires_at: None,
+        post_only: false,
+        price: Some(BigDecimal::from(price)),
+        qty: Qty::from(1),
+    }
+}
+
+fn concurrent_bbo_queries(harness: &Harness) {
+    let exchange = Exchange::new();
+    for id in 0..10000 {
+        let price = 140 + id % 10;
+        apply_command(&exchange, add(OrderType::Buy, price), 1);
+        apply_command(&exchange, add(OrderType::Sell, price + 10), 1);
+    }
+
+    // 8 clients asking for the BBO at the same time
+    harness.bench("concurrent_bbo_queries", || {
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        answer_query(
+                            &exchange,
+                            Query::Bbo {
+                                symbol: "AAPL".to_string(),
+                            },
+                        );
+                    }
+                });
+            }
+        })
+    });
+}
+
+// 125k orders that don't cross, every batch builds the curves of the whole book and keeps it
+fn heavy_book() -> Arc<Exchange> {
+    let exchange = Exchange::new();
+    for id in 0..62_500 {
+        apply_command(&exchange, add(OrderType::Buy, 100 + id % 40), 1);
+        apply_command(&exchange, add(OrderType::Sell, 150 + id % 40), 1);
+    }
+    Arc::new(exchange)
+}
+
+/**
+Time to accept a connection while batches of a heavy book run back to back, the way the engine
+runs them once they take longer than the interval. The connections are served by a single-threaded
+runtime, like a server with every worker busy. Batches run on that runtime, as without
+`FBA_ENGINE_THREAD`, or on a thread of their own.
+*/
+fn accept_latency(harness: &Harness, name: &str, engine_thread: bool) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let exchange = heavy_book();
+    let stop = Arc::new(AtomicBool::new(false));
+    let engine = {
+        let stop = stop.clone();
+        let config = AuctionConfig::default();
+        if engine_thread {
+            Some(std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    run_batches(&exchange, &config);
+                }
+            }))
+        } else {
+            runtime.spawn(async move {
+                while !stop.load(Ordering::Relaxed) {
+                    run_batches(&exchange, &config);
+                    tokio::task::yield_now().await;
+                }
+            });
+            None
+        }
+    };
+
+    harness.bench_latency(name, || {
+        runtime.block_on(async {
+            let started = Instant::now();
+            let (stream, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+            let elapsed = started.elapsed();
+            drop((stream.unwrap(), accepted.unwrap()));
+            elapsed
+        })
+    });
+    stop.store(true, Ordering::Relaxed);
+    if let Some(engine) = engine {
+        engine.join().unwrap();
+    }
+}
+
+fn main() {
+    let harness = Harness::from_args();
+    concurrent_bbo_queries(&harness);
+    accept_latency(&harness, "accept_latency_shared", false);
+    accept_latency(&harness, "accept_latency_engine_thread", true);
+}
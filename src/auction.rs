@@ -1,567 +1,3844 @@
-use bigdecimal::{BigDecimal, FromPrimitive};
-use std::cmp::{max, min, Ordering};
-use std::collections::HashMap;
+use crate::price::Price;
+use bigdecimal::{BigDecimal, Signed, Zero};
+use serde::{Deserialize, Serialize};
+use std::cmp::{min, Ordering, Reverse};
+use std::collections::{BTreeMap, HashMap};
+use std::time::SystemTime;
 
-type Qty = u32;
+pub use crate::qty::Qty;
+pub type OrderId = u64;
+pub type ClientId = u64;
 
-pub enum BatchReport {
-    NoTrade,
+// generic over the price type, BigDecimal unless an embedder picks another one
+// `batch_seq` is the number of the auction run, assigned by the caller with `BatchResult::with_batch_seq`
+// decimals are serialized as strings, so they stay exact
+#[derive(Serialize, Deserialize)]
+pub enum BatchReport<P = BigDecimal> {
+    NoTrade {
+        batch_seq: u64,
+        reason: NoTradeReason,
+    },
     Trade {
-        price: BigDecimal,
+        batch_seq: u64,
+        price: P,
+        qty: Qty,
+        // one per order that traded, including partially cleared ones
+        cleared_bids: Vec<Fill<P>>,
+        cleared_asks: Vec<Fill<P>>,
+        // bid qty willing to trade at the price, but left uncleared
+        bid_imbalance: Qty,
+        // ask qty willing to trade at the price, but left uncleared
+        ask_imbalance: Qty,
+    },
+    // the batch would clear more than the circuit breaker allows, nothing is traded
+    CircuitBreak {
+        batch_seq: u64,
+        price: P,
         qty: Qty,
-        cleared_bids: Vec<Order>,
-        cleared_asks: Vec<Order>,
     },
 }
 
-#[derive(Clone)]
-pub struct Order {
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NoTradeReason {
+    // no orders on either side
+    EmptyBook,
+    // orders on one side only
+    OneSided,
+    // the highest bid is lower than the lowest ask
+    NoCross,
+    // only market orders would trade, so there is no price
+    NoReferencePrice,
+    // the total qty of a side doesn't fit a `Qty`
+    QtyOverflow,
+    // the symbol sits out batches after a circuit break
+    Halted,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Fill<P = BigDecimal> {
+    pub order_id: OrderId,
+    pub client_id: ClientId,
+    pub price: P,
     pub qty: Qty,
-    pub price: BigDecimal,
+    // quantity left resting in the book. 0 for a fully cleared order
+    pub remaining: Qty,
+    // the order rested for at least one batch before trading
+    pub maker: bool,
+    // rounded to the price scale, negative for a rebate
+    pub fee: BigDecimal,
+}
+
+impl<P> BatchReport<P> {
+    // bids first, none unless the batch traded
+    pub fn fills(&self) -> impl Iterator<Item = &Fill<P>> {
+        let (bids, asks): (&[Fill<P>], &[Fill<P>]) = match self {
+            BatchReport::Trade {
+                cleared_bids,
+                cleared_asks,
+                ..
+            } => (cleared_bids, cleared_asks),
+            _ => (&[], &[]),
+        };
+        bids.iter().chain(asks)
+    }
+}
+
+pub struct BatchResult<P = BigDecimal> {
+    pub report: BatchReport<P>,
+    // orders removed from the book for sitting there too long
+    pub expired: Vec<Order<P>>,
+    // orders removed from the book because of their time in force
+    pub cancelled: Vec<Order<P>>,
+    // stale orders removed because the book crossed by more than `max_cross`
+    pub crossed: Vec<Order<P>>,
+    // post-only orders removed instead of taking liquidity
+    pub rejected: Vec<Order<P>>,
+    // what is left in the book after the batch
+    pub residual: Residual,
+}
+
+impl<P> BatchResult<P> {
+    // the auction itself doesn't keep state between batches, so it can't number them
+    pub fn with_batch_seq(mut self, batch_seq: u64) -> BatchResult<P> {
+        match &mut self.report {
+            BatchReport::NoTrade { batch_seq: seq, .. }
+            | BatchReport::Trade { batch_seq: seq, .. }
+            | BatchReport::CircuitBreak { batch_seq: seq, .. } => *seq = batch_seq,
+        }
+        self
+    }
+}
+
+// total resting quantity per side
+#[derive(Debug, Default, PartialEq)]
+pub struct Residual {
+    pub bid_qty: Qty,
+    pub ask_qty: Qty,
+}
+
+/**
+Volume circuit breaker. A batch that would clear more than `max_qty` doesn't run,
+the book is left as it is and the symbol is halted, e.g. a fat finger or a flood of orders.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CircuitBreaker {
+    pub max_qty: Qty,
+    // batches the symbol sits out after a break. None - until it is resumed
+    pub cooldown: Option<u16>,
+}
+
+#[derive(Clone)]
+pub struct AuctionConfig {
+    // orders resting for more batches than this are removed. None - no expiry
+    pub max_batches_out: Option<u16>,
+    pub allocation_policy: AllocationPolicy,
+    pub clearing_price_rule: ClearingPriceRule,
+    // None - a client may trade with itself
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    // decimal digits of prices, the clearing price is rounded to it as well
+    pub price_scale: i64,
+    // the clearing price is snapped to a multiple of it. None - any price of the scale
+    pub tick_size: Option<BigDecimal>,
+    // applies to the tick and the price scale of the clearing price
+    pub rounding_mode: RoundingMode,
+    pub fees: FeeSchedule,
+    // None - batches of any size trade
+    pub circuit_breaker: Option<CircuitBreaker>,
+    // a bid above an ask by more than this is stale, see `remove_crossed`. None - any cross trades
+    pub max_cross: Option<BigDecimal>,
+}
+
+impl Default for AuctionConfig {
+    fn default() -> AuctionConfig {
+        AuctionConfig {
+            max_batches_out: None,
+            allocation_policy: AllocationPolicy::default(),
+            clearing_price_rule: ClearingPriceRule::default(),
+            self_trade_prevention: None,
+            price_scale: 2,
+            tick_size: None,
+            rounding_mode: RoundingMode::default(),
+            fees: FeeSchedule::default(),
+            circuit_breaker: None,
+            max_cross: None,
+        }
+    }
+}
+
+/**
+Fees in basis points of the traded notional, negative for a rebate.
+Everyone trades at the same price in a batch, so makers are the orders that rested
+for at least one batch, and takers are the ones that arrived since the last batch.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FeeSchedule {
+    pub maker_bps: BigDecimal,
+    pub taker_bps: BigDecimal,
+}
+
+impl FeeSchedule {
+    fn fee(&self, maker: bool, price: &BigDecimal, qty: Qty, scale: i64) -> BigDecimal {
+        let bps = if maker {
+            &self.maker_bps
+        } else {
+            &self.taker_bps
+        };
+        // of the absolute notional, a negative price doesn't turn a fee into a rebate
+        let fee = price.abs() * qty.to_decimal() * bps * BigDecimal::new(1.into(), 4);
+        round_price(&fee, scale).with_scale(scale)
+    }
+}
+
+// which order is cancelled when a client's bid and ask would clear against each other.
+// Order ids grow over time, so the oldest order has the lowest id
+#[derive(Clone, Copy)]
+pub enum SelfTradePrevention {
+    CancelOldest,
+    CancelNewest,
+    CancelBoth,
+}
+
+// price picked between the marginal bid and the marginal ask.
+// Midpoint and WeightedMid are rounded to the nearest tick, or the price scale if there is no tick.
+// Halfway prices are rounded as the rounding mode says, up by default, e.g. 3 for bid 3 and ask 2 with tick 1
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ClearingPriceRule {
+    #[default]
+    Midpoint,
+    // marginal bid price, favors sellers
+    BidSide,
+    // marginal ask price, favors buyers
+    AskSide,
+    // midpoint shifted towards the side with less quantity on the curve
+    WeightedMid,
+    // the marginal price nearer to WeightedMid, the bid if both are as near. Always the price
+    // of an order in the book, never rounded, for venues that only clear at quoted prices
+    OnGrid,
+}
+
+// how a clearing price between two ticks, or finer than the price scale, is rounded
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RoundingMode {
+    // halfway prices away from zero
+    #[default]
+    HalfUp,
+    // halfway prices to the even digit, no bias towards either side over many batches
+    HalfEven,
+    // towards negative infinity, favors buyers
+    Floor,
+    // towards positive infinity, favors sellers
+    Ceil,
+}
+
+// how the marginal price level is filled when it can't be filled completely
+#[derive(Clone, Copy, Default)]
+pub enum AllocationPolicy {
+    // orders are filled one by one in priority order
+    #[default]
+    TimePriority,
+    // every order at the level gets a share proportional to its quantity
+    ProRata,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    // rests in the book until cleared or cancelled
+    #[default]
+    GoodTillCancel,
+    // fully cleared in the next batch or cancelled, never partially
+    FillOrKill,
+    // cleared as much as possible in the next batch, the rest is cancelled
+    ImmediateOrCancel,
+}
+
+/**
+Fields are read through accessors, only the auction and the order book change them,
+so that an order can't lose or keep its time priority behind their back.
+Fields added later need a default, so that older snapshots can still be read.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Order<P = BigDecimal> {
+    id: OrderId,
+    #[serde(default)]
+    client_id: ClientId,
+    qty: Qty,
+    // None for a market order, which trades at any price
+    price: Option<P>,
+    #[serde(default)]
+    time_in_force: TimeInForce,
+    // smallest fill accepted in a batch, the order doesn't trade at all otherwise
+    #[serde(default)]
+    min_qty: Option<Qty>,
+    // iceberg order, only this much of the qty is shown at a time. None - all of it
+    #[serde(default)]
+    display_qty: Option<Qty>,
+    // shown part of an iceberg, refreshed from the reserve once it is filled
+    #[serde(default)]
+    shown: Qty,
+    // good-till-time, removed before the first batch at or after it. None - no expiry
+    #[serde(default)]
+    expires_at: Option<SystemTime>,
+    #[serde(default)]
     batches_out: u16,
+    // arrival number, orders of the same age trade in the order they arrived
+    #[serde(default)]
+    submitted: u64,
+    // only trades as a maker, it is rejected rather than cleared in its first batch
+    #[serde(default)]
+    post_only: bool,
+    // resting orders are never cleared
+    #[serde(default)]
     cleared: bool,
 }
 
-impl Order {
-    pub(crate) fn new(price: BigDecimal, qty: Qty) -> Order {
+impl<P: Price> Order<P> {
+    pub fn new(id: OrderId, price: P, qty: Qty) -> Order<P> {
         Order {
+            id,
+            client_id: 0,
             qty,
-            price,
+            price: Some(price),
+            time_in_force: TimeInForce::GoodTillCancel,
+            min_qty: None,
+            display_qty: None,
+            expires_at: None,
+            shown: Qty::ZERO,
+            batches_out: 0,
+            submitted: 0,
+            post_only: false,
+            cleared: false,
+        }
+    }
+
+    pub fn market(id: OrderId, qty: Qty) -> Order<P> {
+        Order {
+            id,
+            client_id: 0,
+            qty,
+            price: None,
+            time_in_force: TimeInForce::GoodTillCancel,
+            min_qty: None,
+            display_qty: None,
+            expires_at: None,
+            shown: Qty::ZERO,
             batches_out: 0,
+            submitted: 0,
+            post_only: false,
             cleared: false,
         }
     }
+
+    pub fn with_client(mut self, client_id: ClientId) -> Order<P> {
+        self.client_id = client_id;
+        self
+    }
+
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Order<P> {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    pub fn with_min_qty(mut self, min_qty: Option<Qty>) -> Order<P> {
+        self.min_qty = min_qty;
+        self
+    }
+
+    pub fn with_display_qty(mut self, display_qty: Option<Qty>) -> Order<P> {
+        self.display_qty = display_qty;
+        self.shown = display_qty.map_or(Qty::ZERO, |display_qty| min(display_qty, self.qty));
+        self
+    }
+
+    pub fn with_expires_at(mut self, expires_at: Option<SystemTime>) -> Order<P> {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn with_post_only(mut self, post_only: bool) -> Order<P> {
+        self.post_only = post_only;
+        self
+    }
+
+    // numbered by the exchange as orders arrive or lose their priority
+    pub fn with_submitted(mut self, submitted: u64) -> Order<P> {
+        self.submitted = submitted;
+        self
+    }
+
+    pub fn id(&self) -> OrderId {
+        self.id
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    pub fn submitted(&self) -> u64 {
+        self.submitted
+    }
+
+    // left to trade
+    pub fn qty(&self) -> Qty {
+        self.qty
+    }
+
+    // None for a market order
+    pub fn price(&self) -> Option<&P> {
+        self.price.as_ref()
+    }
+
+    pub fn time_in_force(&self) -> TimeInForce {
+        self.time_in_force
+    }
+
+    pub fn min_qty(&self) -> Option<Qty> {
+        self.min_qty
+    }
+
+    pub fn display_qty(&self) -> Option<Qty> {
+        self.display_qty
+    }
+
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    pub fn post_only(&self) -> bool {
+        self.post_only
+    }
+
+    // the part of the qty shown on the curves and in the depth, the rest is hidden
+    pub fn visible_qty(&self) -> Qty {
+        match self.display_qty {
+            None => self.qty,
+            Some(_) => self.shown,
+        }
+    }
+
+    fn hidden_qty(&self) -> Qty {
+        self.qty - self.visible_qty()
+    }
+
+    // batches the order has been resting for since it lost its priority last
+    pub fn batches_out(&self) -> u16 {
+        self.batches_out
+    }
+
+    /**
+    A new price or a larger qty loses the time priority, a smaller qty keeps it.
+    Returns true if the priority is lost, the order has to be moved in its side then.
+    */
+    pub(crate) fn amend(&mut self, price: P, qty: Qty) -> bool {
+        let reset = self.price.as_ref() != Some(&price) || qty > self.qty;
+        if reset {
+            self.batches_out = 0;
+        }
+        self.price = Some(price);
+        self.qty = qty;
+        // a new tranche with the new priority, a smaller qty comes out of the reserve first
+        self.shown = match self.display_qty {
+            Some(display_qty) if reset => min(display_qty, qty),
+            _ => min(self.shown, qty),
+        };
+        reset
+    }
+
+    // a smaller qty keeps the time priority, returns the qty left
+    pub(crate) fn reduce(&mut self, qty: Qty) -> Qty {
+        self.qty = self.qty - min(self.qty, qty);
+        // the reserve goes first
+        self.shown = min(self.shown, self.qty);
+        self.qty
+    }
+
+    /**
+    Takes a partial fill out of the shown part of an iceberg first.
+    Once it is filled, the next tranche is shown from the reserve, behind the orders
+    resting at the same price. Returns true if the order lost its priority.
+    */
+    fn refresh(&mut self, fill: Qty) -> bool {
+        let display_qty = match self.display_qty {
+            None => return false,
+            Some(display_qty) => display_qty,
+        };
+        self.shown = self.shown - min(self.shown, fill);
+        if !self.shown.is_zero() {
+            return false;
+        }
+        self.shown = min(display_qty, self.qty);
+        self.batches_out = 0;
+        true
+    }
+
+    // a remainder smaller than min_qty may still be filled completely
+    fn below_min_qty(&self, fill: Qty) -> bool {
+        match self.min_qty {
+            Some(min_qty) => !fill.is_zero() && fill < min(min_qty, self.qty),
+            None => false,
+        }
+    }
+}
+
+impl<P: Price> Fill<P> {
+    // order is expected to be already updated by the clearing
+    fn new(order: &Order<P>, price: &P, qty: Qty, config: &AuctionConfig) -> Fill<P> {
+        Fill::with_role(order, price, qty, order.batches_out > 0, config)
+    }
+
+    fn with_role(
+        order: &Order<P>,
+        price: &P,
+        qty: Qty,
+        maker: bool,
+        config: &AuctionConfig,
+    ) -> Fill<P> {
+        Fill {
+            order_id: order.id,
+            client_id: order.client_id,
+            price: price.clone(),
+            qty,
+            remaining: if order.cleared { Qty::ZERO } else { order.qty },
+            maker,
+            fee: config
+                .fees
+                .fee(maker, &price.to_decimal(), qty, config.price_scale),
+        }
+    }
+}
+
+// total quantity resting at a single price
+pub struct PriceLevel<P = BigDecimal> {
+    pub price: P,
+    pub qty: Qty,
 }
 
-struct Segment {
-    price: BigDecimal,
+#[derive(Clone, Copy, PartialEq)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+// borrows the price of the orders, the curves never outlive the book.
+// Serialized to look at the curves, there is no book to borrow from when deserializing
+#[derive(Serialize)]
+struct Segment<'a, P> {
+    // None for market orders, the most aggressive end of the curve
+    price: Option<&'a P>,
     q_max: Qty,
 }
 
-pub fn calculate_batch(bids: &mut Vec<Order>, asks: &mut Vec<Order>) -> BatchReport {
-    // sort bids. price high -> low, batches_out desc
-    bids.sort_unstable_by(|order, other| {
-        match order.price.cmp(&other.price) {
-            Ordering::Equal => order.batches_out.cmp(&other.batches_out).reverse(), // old orders have priority
-            x => x.reverse(), // high price has priority
+/**
+Buffers of a batch that are not part of its result. Cleared, but not freed between batches,
+so a book that reuses its workspace doesn't allocate them again in the steady state.
+The curves borrow the book and can't be kept, only their sizes are, so that a batch allocates
+them at once rather than growing them.
+*/
+pub struct BatchWorkspace<P: Price = BigDecimal> {
+    demand_capacity: usize,
+    supply_capacity: usize,
+    bid_allocations: Vec<Qty>,
+    ask_allocations: Vec<Qty>,
+    // orders sitting out the batch because of min_qty
+    withheld_bids: Vec<Order<P>>,
+    withheld_asks: Vec<Order<P>>,
+}
+
+impl<P: Price> Default for BatchWorkspace<P> {
+    fn default() -> BatchWorkspace<P> {
+        BatchWorkspace {
+            demand_capacity: 0,
+            supply_capacity: 0,
+            bid_allocations: vec![],
+            ask_allocations: vec![],
+            withheld_bids: vec![],
+            withheld_asks: vec![],
         }
-    });
-    // sort asks. price low -> high, batches_out desc
-    asks.sort_unstable_by(|order, other| {
-        match order.price.cmp(&other.price) {
-            Ordering::Equal => order.batches_out.cmp(&other.batches_out).reverse(), // old orders have priority
-            x => x, // low price has priority
+    }
+}
+
+// allocates the buffers of a single batch, see `calculate_batch_with` to reuse them
+pub fn calculate_batch<P: Price>(
+    bids: &mut Vec<Order<P>>,
+    asks: &mut Vec<Order<P>>,
+    config: &AuctionConfig,
+) -> BatchResult<P> {
+    calculate_batch_with(bids, asks, config, &mut BatchWorkspace::default())
+}
+
+// same as `calculate_batch`, with the buffers of the previous batches
+pub fn calculate_batch_with<P: Price>(
+    bids: &mut Vec<Order<P>>,
+    asks: &mut Vec<Order<P>>,
+    config: &AuctionConfig,
+    workspace: &mut BatchWorkspace<P>,
+) -> BatchResult<P> {
+    // books kept in order by `insert_order` are only checked, not re-sorted
+    sort_unless_sorted(bids, Side::Bid);
+    sort_unless_sorted(asks, Side::Ask);
+
+    let crossed = match &config.max_cross {
+        Some(max_cross) => remove_crossed(bids, asks, max_cross),
+        None => vec![],
+    };
+    let mut cancelled = vec![];
+    let mut rejected = vec![];
+    let BatchWorkspace {
+        demand_capacity,
+        supply_capacity,
+        bid_allocations,
+        ask_allocations,
+        withheld_bids,
+        withheld_asks,
+    } = workspace;
+
+    let report = loop {
+        if !may_cross(bids, asks) {
+            break BatchReport::NoTrade {
+                batch_seq: 0,
+                reason: NoTradeReason::NoCross,
+            };
+        }
+
+        // demand curve
+        let mut demand = Vec::with_capacity(*demand_capacity);
+        let demand_built = orders_to_curve_segments(bids, Side::Bid, &mut demand);
+        // supply curve
+        let mut supply = Vec::with_capacity(*supply_capacity);
+        let supply_built = orders_to_curve_segments(asks, Side::Ask, &mut supply);
+
+        let intersection = demand_built
+            .and(supply_built)
+            .and_then(|_| intersect_demand_supply(&demand, &supply, config));
+        *demand_capacity = demand.capacity();
+        *supply_capacity = supply.capacity();
+        let (p_star, q_shown) = match intersection {
+            Err(reason) => {
+                break BatchReport::NoTrade {
+                    batch_seq: 0,
+                    reason,
+                }
+            }
+            Ok(intersection) => intersection,
+        };
+        let reserves = match_reserves(bids, asks, &p_star, q_shown);
+        let q_star = reserves.total();
+        // removing orders never clears more, so a batch that passes once passes for good
+        if config
+            .circuit_breaker
+            .is_some_and(|breaker| q_star > breaker.max_qty)
+        {
+            let report = BatchReport::CircuitBreak {
+                batch_seq: 0,
+                price: p_star,
+                qty: q_star,
+            };
+            let mut result = untouched_batch(report, bids, asks);
+            result.crossed = crossed;
+            result.rejected = rejected;
+            return result;
         }
-    });
 
-    // demand curve
-    let mut demand = orders_to_curve_segments(&bids);
-    // supply curve
-    let mut supply = orders_to_curve_segments(&asks);
+        let policy = config.allocation_policy;
+        let (bid_shown, ask_shown) = (reserves.bid_shown, reserves.ask_shown);
+        allocate_suitable(
+            bids,
+            &p_star,
+            bid_shown,
+            policy,
+            bid_suitable,
+            bid_allocations,
+        );
+        allocate_suitable(
+            asks,
+            &p_star,
+            ask_shown,
+            policy,
+            ask_suitable,
+            ask_allocations,
+        );
+        allocate_hidden(bids, reserves.bid_hidden, bid_allocations);
+        allocate_hidden(asks, reserves.ask_hidden, ask_allocations);
 
-    match intersect_demand_supply(&demand, &supply) {
-        None => BatchReport::NoTrade,
-        Some((p_star, q_star)) => {
-            let cleared_bids =
-                clear_orders(bids, &p_star, q_star, |bid_price, price| bid_price >= price);
-            let cleared_asks =
-                clear_orders(asks, &p_star, q_star, |ask_price, price| ask_price <= price);
+        // self trades are removed the same way as partial fill-or-kill orders
+        if let Some(stp) = config.self_trade_prevention {
+            let prevented = prevent_self_trade(
+                bids,
+                bid_allocations,
+                asks,
+                ask_allocations,
+                stp,
+                &mut cancelled,
+            );
+            if prevented {
+                continue;
+            }
+        }
 
-            //remove cleared orders
-            bids.retain(|order| !order.cleared);
-            asks.retain(|order| !order.cleared);
+        // partial fill of a fill-or-kill order is rolled back by
+        // recalculating the batch without it
+        let bids_killed = kill_partial_fok(bids, bid_allocations, &mut cancelled);
+        let asks_killed = kill_partial_fok(asks, ask_allocations, &mut cancelled);
+        if bids_killed || asks_killed {
+            continue;
+        }
 
-            BatchReport::Trade {
-                price: p_star.clone(),
-                qty: q_star.clone(),
-                cleared_bids,
-                cleared_asks,
+        // a post-only order clearing in its first batch would be the taker, so it goes
+        // and the batch is recalculated without it, like a partial fill-or-kill
+        let bids_rejected = reject_post_only(bids, bid_allocations, &mut rejected);
+        let asks_rejected = reject_post_only(asks, ask_allocations, &mut rejected);
+        if bids_rejected || asks_rejected {
+            continue;
+        }
+
+        // orders which can't get their minimum fill don't trade this batch,
+        // but keep resting unlike fill-or-kill
+        let bids_withheld = withhold_below_min_qty(bids, bid_allocations, withheld_bids);
+        let asks_withheld = withhold_below_min_qty(asks, ask_allocations, withheld_asks);
+        if bids_withheld || asks_withheld {
+            continue;
+        }
+
+        // shown qty only, the reserves stay hidden
+        let bid_imbalance = suitable_qty(bids, &p_star, bid_suitable) - bid_shown;
+        let ask_imbalance = suitable_qty(asks, &p_star, ask_suitable) - ask_shown;
+
+        let mut refreshed = false;
+        let cleared_bids = clear_orders(bids, bid_allocations, &p_star, config, &mut refreshed);
+        let cleared_asks = clear_orders(asks, ask_allocations, &p_star, config, &mut refreshed);
+
+        //remove cleared orders
+        bids.retain(|order| !order.cleared);
+        asks.retain(|order| !order.cleared);
+        // icebergs with a new tranche lost their priority
+        if refreshed {
+            bids.sort_by(|order, other| priority(Side::Bid, order, other));
+            asks.sort_by(|order, other| priority(Side::Ask, order, other));
+        }
+
+        break BatchReport::Trade {
+            batch_seq: 0,
+            price: p_star,
+            qty: q_star,
+            cleared_bids,
+            cleared_asks,
+            bid_imbalance,
+            ask_imbalance,
+        };
+    };
+
+    for order in withheld_bids.drain(..) {
+        insert_order(bids, Side::Bid, order);
+    }
+    for order in withheld_asks.drain(..) {
+        insert_order(asks, Side::Ask, order);
+    }
+
+    // fill-or-kill and immediate-or-cancel orders never rest
+    cancelled.extend(remove_orders(bids, |order| {
+        order.time_in_force != TimeInForce::GoodTillCancel
+    }));
+    cancelled.extend(remove_orders(asks, |order| {
+        order.time_in_force != TimeInForce::GoodTillCancel
+    }));
+
+    // orders left in the book survived one more batch
+    age_orders(bids);
+    age_orders(asks);
+
+    let mut expired = vec![];
+    if let Some(max_batches_out) = config.max_batches_out {
+        expired.extend(remove_orders(bids, |order| {
+            order.batches_out > max_batches_out
+        }));
+        expired.extend(remove_orders(asks, |order| {
+            order.batches_out > max_batches_out
+        }));
+    }
+
+    BatchResult {
+        report,
+        expired,
+        cancelled,
+        crossed,
+        rejected,
+        residual: residual(bids, asks),
+    }
+}
+
+/**
+A book crosses between batches, but a bid above an ask by more than `max_cross` is most likely
+a stale order nobody updated, so the older order of the best pair is removed until the best bid
+and ask are close enough. Market orders have no price and are not checked.
+Expects sorted sides, which stay sorted.
+*/
+fn remove_crossed<P: Price>(
+    bids: &mut Vec<Order<P>>,
+    asks: &mut Vec<Order<P>>,
+    max_cross: &BigDecimal,
+) -> Vec<Order<P>> {
+    let mut removed = vec![];
+    loop {
+        let best = |orders: &[Order<P>]| {
+            orders.iter().enumerate().find_map(|(idx, order)| {
+                order.price.as_ref().map(|price| (idx, price.to_decimal()))
+            })
+        };
+        let (bid, ask) = match (best(bids), best(asks)) {
+            (Some((bid, bid_price)), Some((ask, ask_price)))
+                if &bid_price - &ask_price > *max_cross =>
+            {
+                (bid, ask)
             }
+            _ => break,
+        };
+        // older: rested for more batches, then arrived first
+        let age = |order: &Order<P>| (Reverse(order.batches_out), order.submitted, order.id);
+        if age(&bids[bid]) <= age(&asks[ask]) {
+            removed.push(bids.remove(bid));
+        } else {
+            removed.push(asks.remove(ask));
         }
     }
+    removed
 }
 
-fn clear_orders(
-    orders: &mut Vec<Order>,
-    p_star: &BigDecimal,
-    mut q_star: Qty,
-    price_predicate: fn(&BigDecimal, &BigDecimal) -> bool,
-) -> Vec<Order> {
-    let mut cleared: Vec<Order> = vec![];
+/**
+False if the sorted sides surely don't cross: the best bid and ask are priced and apart.
+Most batches of a quiet book end here without building the curves. A side too large for a curve
+still goes on to report `QtyOverflow`, like any batch.
+*/
+fn may_cross<P: Price>(bids: &[Order<P>], asks: &[Order<P>]) -> bool {
+    let (best_bid, best_ask) = match (bids.first(), asks.first()) {
+        (Some(bid), Some(ask)) => (bid.price.as_ref(), ask.price.as_ref()),
+        // empty or one-sided
+        _ => return true,
+    };
+    let fits = |orders: &[Order<P>]| {
+        orders
+            .iter()
+            .try_fold(Qty::ZERO, |total, order| {
+                total.checked_add(order.visible_qty())
+            })
+            .is_some()
+    };
+    crosses(best_bid, best_ask) || !fits(bids) || !fits(asks)
+}
+
+// saturated, a side may be too large for a curve
+fn residual<P>(bids: &[Order<P>], asks: &[Order<P>]) -> Residual {
+    let total = |orders: &[Order<P>]| {
+        orders
+            .iter()
+            .fold(Qty::ZERO, |qty, order| qty.saturating_add(order.qty))
+    };
+    Residual {
+        bid_qty: total(bids),
+        ask_qty: total(asks),
+    }
+}
+
+// nothing is cancelled, aged or expired, as if the batch never ran
+fn untouched_batch<P>(
+    report: BatchReport<P>,
+    bids: &[Order<P>],
+    asks: &[Order<P>],
+) -> BatchResult<P> {
+    BatchResult {
+        report,
+        expired: vec![],
+        cancelled: vec![],
+        crossed: vec![],
+        rejected: vec![],
+        residual: residual(bids, asks),
+    }
+}
+
+// batch of a halted symbol, the book is left as it is
+pub fn halted_batch<P>(bids: &[Order<P>], asks: &[Order<P>]) -> BatchResult<P> {
+    let report = BatchReport::NoTrade {
+        batch_seq: 0,
+        reason: NoTradeReason::Halted,
+    };
+    untouched_batch(report, bids, asks)
+}
+
+// outcome of a batch that is not applied to the book
+pub struct BatchSimulation<P = BigDecimal> {
+    pub result: BatchResult<P>,
+    // books as they would be after the batch
+    pub bids: Vec<Order<P>>,
+    pub asks: Vec<Order<P>>,
+}
+
+// "what-if" batch, the books passed in are left untouched
+pub fn simulate_batch<P: Price>(
+    bids: &[Order<P>],
+    asks: &[Order<P>],
+    config: &AuctionConfig,
+) -> BatchSimulation<P> {
+    let mut bids = bids.to_vec();
+    let mut asks = asks.to_vec();
+
+    let result = calculate_batch(&mut bids, &mut asks, config);
+
+    BatchSimulation { result, bids, asks }
+}
+
+/**
+Price and qty the books would clear at in the next batch, read-only, nothing is allocated.
+Orders that sit out a batch, e.g. below their MINQTY, are still on the curves,
+so the batch may clear differently than indicated.
+*/
+pub fn indicative_price<P: Price>(
+    bids: &[Order<P>],
+    asks: &[Order<P>],
+    config: &AuctionConfig,
+) -> Result<(P, Qty), NoTradeReason> {
+    let mut demand = vec![];
+    orders_to_curve_segments(bids, Side::Bid, &mut demand)?;
+    let mut supply = vec![];
+    orders_to_curve_segments(asks, Side::Ask, &mut supply)?;
+    intersect_demand_supply(&demand, &supply, config)
+}
+
+/**
+Removes the good-till-time orders expired at `now`. The auction keeps no clock of its own,
+so the caller does it before the batch.
+*/
+pub fn expire_orders<P>(
+    bids: &mut Vec<Order<P>>,
+    asks: &mut Vec<Order<P>>,
+    now: SystemTime,
+) -> Vec<Order<P>> {
+    let expired = |order: &Order<P>| order.expires_at.is_some_and(|expires_at| expires_at <= now);
+    let mut removed = remove_orders(bids, expired);
+    removed.extend(remove_orders(asks, expired));
+    removed
+}
 
+fn age_orders<P>(orders: &mut [Order<P>]) {
     for order in orders {
-        if price_predicate(&order.price, &p_star) {
-            if order.qty <= q_star {
-                // fully clear the order
-                order.cleared = true;
-                q_star -= order.qty;
-                cleared.push(order.clone())
-            } else {
-                // partially clear the order
-                let qty_diff = order.qty - q_star;
-                order.qty = qty_diff;
-                break;
+        order.batches_out = order.batches_out.saturating_add(1);
+    }
+}
+
+// removes matching orders, keeps the rest in the same order
+fn remove_orders<P>(
+    orders: &mut Vec<Order<P>>,
+    predicate: impl Fn(&Order<P>) -> bool,
+) -> Vec<Order<P>> {
+    // nothing to remove in most batches, don't rebuild the book
+    if !orders.iter().any(&predicate) {
+        return vec![];
+    }
+    let (removed, resting) = std::mem::take(orders).into_iter().partition(predicate);
+    *orders = resting;
+    removed
+}
+
+// returns true if any order was killed
+fn kill_partial_fok<P: Price>(
+    orders: &mut Vec<Order<P>>,
+    allocations: &[Qty],
+    cancelled: &mut Vec<Order<P>>,
+) -> bool {
+    let before = cancelled.len();
+    let mut allocations = allocations.iter();
+
+    // retain visits orders in their sorted order
+    orders.retain(|order| match allocations.next() {
+        Some(&qty) if order.time_in_force == TimeInForce::FillOrKill && qty < order.qty => {
+            cancelled.push(order.clone());
+            false
+        }
+        _ => true,
+    });
+
+    cancelled.len() > before
+}
+
+// returns true if any post-only order was about to take liquidity
+fn reject_post_only<P: Price>(
+    orders: &mut Vec<Order<P>>,
+    allocations: &[Qty],
+    rejected: &mut Vec<Order<P>>,
+) -> bool {
+    let before = rejected.len();
+    let mut allocations = allocations.iter();
+
+    orders.retain(|order| match allocations.next() {
+        Some(qty) if order.post_only && order.batches_out == 0 && !qty.is_zero() => {
+            rejected.push(order.clone());
+            false
+        }
+        _ => true,
+    });
+
+    rejected.len() > before
+}
+
+/**
+Trades an incoming order right away against the opposite side, best first, at the prices of the
+resting orders, as long as they cross. What is left of the order is up to the caller to rest.
+FOK, MINQTY and post-only orders wait for the batch, which rejects a post-only order that crosses.
+So do resting orders the batch treats specially: market orders have no price, MINQTY orders may
+not take a small fill and orders of the same client are left to self-trade prevention, if it is on.
+Icebergs only trade their shown part.
+Returns the fills of the resting orders and of the incoming one, which is the taker.
+*/
+pub fn try_immediate_cross<P: Price>(
+    order: &mut Order<P>,
+    side: Side,
+    opposite: &mut Vec<Order<P>>,
+    config: &AuctionConfig,
+) -> Vec<Fill<P>> {
+    let mut fills = vec![];
+    if order.time_in_force == TimeInForce::FillOrKill || order.min_qty.is_some() || order.post_only
+    {
+        return fills;
+    }
+
+    let mut idx = 0;
+    let mut refreshed = false;
+    while !order.qty.is_zero() && idx < opposite.len() {
+        let resting = &mut opposite[idx];
+        let price = match &resting.price {
+            Some(price) => price.clone(),
+            None => {
+                idx += 1;
+                continue;
             }
+        };
+        let crosses = match side {
+            Side::Bid => bid_suitable(&order.price, &price),
+            Side::Ask => ask_suitable(&order.price, &price),
+        };
+        // the side is sorted by price, nothing further crosses either
+        if !crosses {
+            break;
+        }
+        let self_trade =
+            config.self_trade_prevention.is_some() && resting.client_id == order.client_id;
+        if resting.min_qty.is_some() || self_trade {
+            idx += 1;
+            continue;
+        }
+
+        let qty = min(order.qty, resting.visible_qty());
+        resting.qty -= qty;
+        order.qty -= qty;
+        fills.push(Fill::with_role(resting, &price, qty, true, config));
+        fills.push(Fill::with_role(order, &price, qty, false, config));
+        if resting.qty.is_zero() {
+            opposite.remove(idx);
+        } else {
+            refreshed |= resting.refresh(qty);
+            idx += 1;
+        }
+    }
+    // the next tranche of an iceberg goes behind the orders at its price
+    if refreshed {
+        let opposite_side = match side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        opposite.sort_by(|order, other| priority(opposite_side, order, other));
+    }
+    order.shown = min(order.shown, order.qty);
+    fills
+}
+
+/**
+Inserts the order after all orders with the same or higher priority.
+A side built this way stays sorted, so the next batch doesn't have to sort it,
+and orders that arrived in the same batch keep their arrival order.
+*/
+pub fn insert_order<P: Price>(orders: &mut Vec<Order<P>>, side: Side, order: Order<P>) {
+    let idx = orders.partition_point(|other| priority(side, other, &order) != Ordering::Greater);
+    orders.insert(idx, order);
+}
+
+/**
+Adds many orders to a side at once and sorts it once, instead of an `insert_order` per order.
+The side stays sorted like one built by `insert_order`.
+*/
+pub fn extend_orders<P: Price>(
+    orders: &mut Vec<Order<P>>,
+    side: Side,
+    new: impl IntoIterator<Item = Order<P>>,
+) {
+    orders.extend(new);
+    // stable, a sorted side followed by the new orders is merged rather than sorted from scratch
+    orders.sort_by(|order, other| priority(side, order, other));
+}
+
+// a linear check, sorting a side that is in order already would be n log n for nothing
+fn sort_unless_sorted<P: Price>(orders: &mut [Order<P>], side: Side) {
+    if !orders.is_sorted_by(|order, other| priority(side, order, other) != Ordering::Greater) {
+        orders.sort_unstable_by(|order, other| priority(side, order, other));
+    }
+}
+
+// bids: market, then price high -> low, batches_out desc, submitted asc, id asc
+// asks: market, then price low -> high, batches_out desc, submitted asc, id asc
+fn priority<P: Price>(side: Side, order: &Order<P>, other: &Order<P>) -> Ordering {
+    price_priority(side, order.price.as_ref(), other.price.as_ref())
+        .then_with(|| order.batches_out.cmp(&other.batches_out).reverse()) // old orders have priority
+        // within a batch first come first served. batches_out goes first, a refreshed iceberg
+        // keeps its number but is as old as a new order
+        .then_with(|| order.submitted.cmp(&other.submitted))
+        .then_with(|| order.id.cmp(&other.id)) // so that the order never depends on the sort
+}
+
+// Less if the first price is more aggressive, market orders go first
+fn price_priority<P: Price>(side: Side, price: Option<&P>, other: Option<&P>) -> Ordering {
+    match (price, other) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (Some(price), Some(other)) if side == Side::Bid => price.cmp(other).reverse(),
+        (Some(price), Some(other)) => price.cmp(other),
+    }
+}
+
+fn bid_suitable<P: Price>(bid_price: &Option<P>, price: &P) -> bool {
+    bid_price
+        .as_ref()
+        .is_none_or(|bid_price| bid_price >= price)
+}
+
+fn ask_suitable<P: Price>(ask_price: &Option<P>, price: &P) -> bool {
+    ask_price
+        .as_ref()
+        .is_none_or(|ask_price| ask_price <= price)
+}
+
+// market orders cross anything
+fn crosses<P: Price>(bid_price: Option<&P>, ask_price: Option<&P>) -> bool {
+    match (bid_price, ask_price) {
+        (Some(bid_price), Some(ask_price)) => bid_price >= ask_price,
+        _ => true,
+    }
+}
+
+// sorted orders willing to trade at p_star
+fn suitable<'a, P: Price>(
+    orders: &'a [Order<P>],
+    p_star: &P,
+    price_predicate: fn(&Option<P>, &P) -> bool,
+) -> &'a [Order<P>] {
+    let count = orders
+        .iter()
+        .take_while(|order| price_predicate(&order.price, p_star))
+        .count();
+    &orders[..count]
+}
+
+fn suitable_qty<P: Price>(
+    orders: &[Order<P>],
+    p_star: &P,
+    price_predicate: fn(&Option<P>, &P) -> bool,
+) -> Qty {
+    suitable(orders, p_star, price_predicate)
+        .iter()
+        .map(Order::visible_qty)
+        .sum()
+}
+
+/**
+Split of the batch qty between the shown and the hidden qty of either side, both sides add up to the same.
+The price is found by the shown qty alone. Hidden reserves then trade at it with the shown qty
+left over on the other side and with each other, after the shown qty, so nothing shown loses to them.
+*/
+struct Reserves {
+    bid_shown: Qty,
+    bid_hidden: Qty,
+    ask_shown: Qty,
+    ask_hidden: Qty,
+}
+
+impl Reserves {
+    fn total(&self) -> Qty {
+        self.bid_shown + self.bid_hidden
+    }
+}
+
+fn match_reserves<P: Price>(
+    bids: &[Order<P>],
+    asks: &[Order<P>],
+    p_star: &P,
+    q_shown: Qty,
+) -> Reserves {
+    let bids = suitable(bids, p_star, bid_suitable);
+    let asks = suitable(asks, p_star, ask_suitable);
+    let shown_left = |orders: &[Order<P>]| -> Qty {
+        orders.iter().map(Order::visible_qty).sum::<Qty>() - q_shown
+    };
+    let hidden = |orders: &[Order<P>]| -> Qty { orders.iter().map(Order::hidden_qty).sum() };
+    let (bid_hidden, ask_hidden) = (hidden(bids), hidden(asks));
+
+    let bids_on_shown = min(bid_hidden, shown_left(asks));
+    let asks_on_shown = min(ask_hidden, shown_left(bids));
+    let hidden_on_hidden = min(bid_hidden - bids_on_shown, ask_hidden - asks_on_shown);
+    Reserves {
+        bid_shown: q_shown + asks_on_shown,
+        bid_hidden: bids_on_shown + hidden_on_hidden,
+        ask_shown: q_shown + bids_on_shown,
+        ask_hidden: asks_on_shown + hidden_on_hidden,
+    }
+}
+
+// hidden reserves of the suitable orders on top of their shown allocation, in priority order
+fn allocate_hidden<P: Price>(orders: &[Order<P>], mut qty: Qty, allocations: &mut [Qty]) {
+    for (order, allocation) in orders.iter().zip(allocations.iter_mut()) {
+        if qty.is_zero() {
+            break;
+        }
+        let hidden = min(qty, order.hidden_qty());
+        *allocation += hidden;
+        qty -= hidden;
+    }
+}
+
+// returns true if any order was withheld
+fn withhold_below_min_qty<P: Price>(
+    orders: &mut Vec<Order<P>>,
+    allocations: &[Qty],
+    withheld: &mut Vec<Order<P>>,
+) -> bool {
+    let before = withheld.len();
+    let mut allocations = allocations.iter();
+
+    orders.retain(|order| match allocations.next() {
+        Some(&qty) if order.below_min_qty(qty) => {
+            withheld.push(order.clone());
+            false
+        }
+        _ => true,
+    });
+
+    withheld.len() > before
+}
+
+/**
+* Finds a client with both a bid and an ask among the cleared orders
+* and cancels one or both of them according to the policy.
+* Orders without a client (client_id 0) are never considered self trades.
+* returns: true if any order was cancelled
+*/
+fn prevent_self_trade<P>(
+    bids: &mut Vec<Order<P>>,
+    bid_allocations: &[Qty],
+    asks: &mut Vec<Order<P>>,
+    ask_allocations: &[Qty],
+    stp: SelfTradePrevention,
+    cancelled: &mut Vec<Order<P>>,
+) -> bool {
+    // first cleared bid of every client
+    let mut clearing_bids: HashMap<ClientId, OrderId> = HashMap::new();
+    for (bid, &qty) in bids.iter().zip(bid_allocations) {
+        if !qty.is_zero() && bid.client_id != 0 {
+            clearing_bids.entry(bid.client_id).or_insert(bid.id);
+        }
+    }
+    if clearing_bids.is_empty() {
+        return false;
+    }
+
+    let self_trade = asks
+        .iter()
+        .zip(ask_allocations)
+        .filter(|(_, qty)| !qty.is_zero())
+        .find_map(|(ask, _)| clearing_bids.get(&ask.client_id).map(|&bid| (bid, ask.id)));
+    let (bid_id, ask_id) = match self_trade {
+        None => return false,
+        Some(ids) => ids,
+    };
+
+    let (cancel_bid, cancel_ask) = match stp {
+        SelfTradePrevention::CancelOldest => (bid_id < ask_id, ask_id < bid_id),
+        SelfTradePrevention::CancelNewest => (bid_id > ask_id, ask_id > bid_id),
+        SelfTradePrevention::CancelBoth => (true, true),
+    };
+    if cancel_bid {
+        cancelled.extend(remove_orders(bids, |order| order.id == bid_id));
+    }
+    if cancel_ask {
+        cancelled.extend(remove_orders(asks, |order| order.id == ask_id));
+    }
+    true
+}
+
+/**
+* params: orders sorted by priority
+* allocations: set to the quantity cleared for every order with suitable price, these go first
+*/
+fn allocate_suitable<P: Price>(
+    orders: &[Order<P>],
+    p_star: &P,
+    q_star: Qty,
+    policy: AllocationPolicy,
+    price_predicate: fn(&Option<P>, &P) -> bool,
+    allocations: &mut Vec<Qty>,
+) {
+    allocate(
+        suitable(orders, p_star, price_predicate),
+        q_star,
+        policy,
+        allocations,
+    )
+}
+
+fn clear_orders<P: Price>(
+    orders: &mut [Order<P>],
+    allocations: &[Qty],
+    p_star: &P,
+    config: &AuctionConfig,
+    refreshed: &mut bool,
+) -> Vec<Fill<P>> {
+    let mut cleared = vec![];
+
+    for (order, &qty) in orders.iter_mut().zip(allocations) {
+        if qty.is_zero() {
+            continue;
+        }
+        if qty == order.qty {
+            // fully clear the order
+            order.cleared = true;
+            cleared.push(Fill::new(order, p_star, qty, config));
+        } else {
+            // partially clear the order
+            order.qty -= qty;
+            cleared.push(Fill::new(order, p_star, qty, config));
+            // after the fill, which is still the old tranche's
+            *refreshed |= order.refresh(qty);
+        }
+    }
+    cleared
+}
+
+/**
+* params: orders with suitable price sorted by priority
+* allocations: set to the quantity cleared for every order
+*/
+fn allocate<P: Price>(
+    orders: &[Order<P>],
+    mut q_star: Qty,
+    policy: AllocationPolicy,
+    allocations: &mut Vec<Qty>,
+) {
+    allocations.clear();
+    allocations.resize(orders.len(), Qty::ZERO);
+
+    let mut level_start = 0;
+    while level_start < orders.len() && !q_star.is_zero() {
+        let price = &orders[level_start].price;
+        let level_end = level_start
+            + orders[level_start..]
+                .iter()
+                .take_while(|order| &order.price == price)
+                .count();
+        let level = &orders[level_start..level_end];
+        let level_qty: Qty = level.iter().map(Order::visible_qty).sum();
+
+        if level_qty <= q_star {
+            // the whole level is cleared
+            for (idx, order) in level.iter().enumerate() {
+                allocations[level_start + idx] = order.visible_qty();
+            }
+            q_star -= level_qty;
+        } else {
+            // marginal level
+            match policy {
+                AllocationPolicy::TimePriority => {
+                    for (idx, order) in level.iter().enumerate() {
+                        let qty = min(order.visible_qty(), q_star);
+                        allocations[level_start + idx] = qty;
+                        q_star -= qty;
+                    }
+                }
+                AllocationPolicy::ProRata => {
+                    let mut allocated = Qty::ZERO;
+                    for (idx, order) in level.iter().enumerate() {
+                        let qty = pro_rata_share(order.visible_qty(), q_star, level_qty);
+                        allocations[level_start + idx] = qty;
+                        allocated += qty;
+                    }
+                    // rounding leftovers go up to a lot each in priority order, as long as
+                    // the order has room. The level is larger than q*, so they always fit
+                    let mut leftover = q_star - allocated;
+                    while !leftover.is_zero() {
+                        for (idx, order) in level.iter().enumerate() {
+                            let allocation = &mut allocations[level_start + idx];
+                            let qty =
+                                min(min(leftover, Qty::LOT), order.visible_qty() - *allocation);
+                            *allocation += qty;
+                            leftover -= qty;
+                        }
+                    }
+                }
+            }
+            q_star = Qty::ZERO;
+        }
+
+        level_start = level_end;
+    }
+}
+
+// share of q* in whole lots, rounded down from less than the order qty
+fn pro_rata_share(qty: Qty, q_star: Qty, level_qty: Qty) -> Qty {
+    let lot = u128::from(Qty::LOT.units());
+    let units =
+        u128::from(qty.units()) * u128::from(q_star.units()) / u128::from(level_qty.units());
+    Qty::from_units((units / lot * lot) as u64)
+}
+
+/**
+* params: orders in any order
+* segments: set to the curve's segments, cumulative quantity grows from the best price
+* returns: QtyOverflow if the cumulative quantity doesn't fit a `Qty`
+*/
+fn orders_to_curve_segments<'a, P: Price>(
+    orders: &'a [Order<P>],
+    side: Side,
+    segments: &mut Vec<Segment<'a, P>>,
+) -> Result<(), NoTradeReason> {
+    segments.clear();
+
+    // orders sorted by priority are already in the curve's order, one level after another
+    let mut max_q = Qty::ZERO;
+    for order in orders {
+        max_q = max_q
+            .checked_add(order.visible_qty())
+            .ok_or(NoTradeReason::QtyOverflow)?;
+        let price = order.price.as_ref();
+        match segments.last().map(|last| last.price) {
+            Some(level) if level == price => {
+                if let Some(last) = segments.last_mut() {
+                    last.q_max = max_q;
+                }
+            }
+            Some(level) if price_priority(side, level, price) == Ordering::Greater => {
+                return sorted_curve_segments(orders, side, segments);
+            }
+            _ => segments.push(Segment {
+                price,
+                q_max: max_q,
+            }),
+        }
+    }
+    Ok(())
+}
+
+// same as `orders_to_curve_segments`, for orders out of priority order
+fn sorted_curve_segments<'a, P: Price>(
+    orders: &'a [Order<P>],
+    side: Side,
+    segments: &mut Vec<Segment<'a, P>>,
+) -> Result<(), NoTradeReason> {
+    segments.clear();
+
+    // every sum below is part of the total
+    orders
+        .iter()
+        .try_fold(Qty::ZERO, |total, order| {
+            total.checked_add(order.visible_qty())
+        })
+        .ok_or(NoTradeReason::QtyOverflow)?;
+
+    // price low -> high
+    let mut levels: BTreeMap<&P, Qty> = BTreeMap::new();
+    let mut market_qty = Qty::ZERO;
+
+    // orders are usually sorted, so equal prices are summed up before touching the map
+    let mut run: Option<(&P, Qty)> = None;
+    for order in orders {
+        let order_price = match &order.price {
+            Some(price) => price,
+            None => {
+                market_qty += order.visible_qty();
+                continue;
+            }
+        };
+        match &mut run {
+            Some((price, qty)) if *price == order_price => *qty += order.visible_qty(),
+            _ => {
+                if let Some((price, qty)) = run.replace((order_price, order.visible_qty())) {
+                    *levels.entry(price).or_insert(Qty::ZERO) += qty;
+                }
+            }
+        }
+    }
+    if let Some((price, qty)) = run {
+        *levels.entry(price).or_insert(Qty::ZERO) += qty;
+    }
+
+    let mut max_q = Qty::ZERO;
+    let mut to_segment = |(price, qty): (Option<&'a P>, Qty)| {
+        max_q += qty;
+        Segment {
+            price,
+            q_max: max_q,
+        }
+    };
+
+    // market orders are the most aggressive on both sides
+    let market = (!market_qty.is_zero()).then_some((None, market_qty));
+    let limits = levels.into_iter().map(|(price, qty)| (Some(price), qty));
+    match side {
+        // high price goes first
+        Side::Bid => segments.extend(market.into_iter().chain(limits.rev()).map(&mut to_segment)),
+        // low price goes first
+        Side::Ask => segments.extend(market.into_iter().chain(limits).map(&mut to_segment)),
+    }
+    Ok(())
+}
+
+/**
+* returns: not cumulative quantity per price, price low -> high.
+* Market orders have no price, so they are not included
+*/
+pub fn price_levels<P: Price>(orders: &[Order<P>]) -> Vec<PriceLevel<P>> {
+    let mut levels: BTreeMap<&P, Qty> = BTreeMap::new();
+
+    for order in orders {
+        if let Some(price) = &order.price {
+            // saturates, two huge orders at one price would overflow
+            let level = levels.entry(price).or_insert(Qty::ZERO);
+            *level = level.saturating_add(order.visible_qty());
+        }
+    }
+
+    levels
+        .into_iter()
+        .map(|(price, qty)| PriceLevel {
+            price: price.clone(),
+            qty,
+        })
+        .collect()
+}
+
+/**
+* params: sorted curve's segments
+* returns: p*, q*
+*
+* Walks both curves from their most aggressive end while their segments cross.
+* q* is the largest qty both curves reach at crossing prices. Where they touch exactly,
+* both stepping at the same qty, q* is that qty, not the step before, and both curves step.
+* p* is picked by the `ClearingPriceRule` between the prices of the last pair of segments that
+* fill something, every price in between clears q*. That is also the tie-break of an exact touch,
+* where demand equals supply anywhere between the two prices. `OnGrid` takes one of the two
+* prices as it is.
+* A segment without qty of its own, e.g. a level listed twice, fills nothing and is stepped over.
+*/
+fn intersect_demand_supply<P: Price>(
+    demand: &[Segment<'_, P>],
+    supply: &[Segment<'_, P>],
+    config: &AuctionConfig,
+) -> Result<(P, Qty), NoTradeReason> {
+    // no trades: no orders for side
+    if demand.is_empty() && supply.is_empty() {
+        return Err(NoTradeReason::EmptyBook);
+    }
+    if demand.is_empty() || supply.is_empty() {
+        return Err(NoTradeReason::OneSided);
+    }
+
+    // no trades: highest bid is lower than the lowes ask
+    if !crosses(demand[0].price, supply[0].price) {
+        return Err(NoTradeReason::NoCross);
+    }
+
+    // the last pair that crossed, their prices bound p*
+    let (mut marginal_demand, mut marginal_supply) = (0, 0);
+    let (mut next_demand, mut next_supply) = (0, 0);
+    let mut q_star = Qty::ZERO;
+
+    while next_demand < demand.len() && next_supply < supply.len() {
+        let seg_demand = &demand[next_demand];
+        let seg_supply = &supply[next_supply];
+        if !crosses(seg_demand.price, seg_supply.price) {
+            break;
+        }
+        marginal_demand = next_demand;
+        marginal_supply = next_supply;
+        // the right edge of the shorter segment, the touching qty if they end together
+        q_star = min(seg_demand.q_max, seg_supply.q_max);
+
+        // move along the shorter curve. Curves of the same length both step:
+        // more volume needs the next segments of both sides to cross, and moving
+        // just one of them would make it the marginal price with nothing to fill
+        match seg_demand.q_max.cmp(&seg_supply.q_max) {
+            Ordering::Less => next_demand = next_step(demand, next_demand),
+            Ordering::Greater => next_supply = next_step(supply, next_supply),
+            Ordering::Equal => {
+                next_demand = next_step(demand, next_demand);
+                next_supply = next_step(supply, next_supply);
+            }
+        }
+    }
+
+    match clearing_price(&demand[marginal_demand], &supply[marginal_supply], config) {
+        Some(p_star) => Ok((p_star, q_star)),
+        None => Err(NoTradeReason::NoReferencePrice),
+    }
+}
+
+// the next segment that adds qty to the curve
+fn next_step<P>(curve: &[Segment<'_, P>], idx: usize) -> usize {
+    let q_max = curve[idx].q_max;
+    idx + 1
+        + curve[idx + 1..]
+            .iter()
+            .take_while(|segment| segment.q_max == q_max)
+            .count()
+}
+
+// rounded to the tick and the price scale, unless `OnGrid`, but never outside of the marginal prices,
+// which may be off the grid if the orders were not rounded.
+// A market order takes the price of the other side, None if both are market orders.
+// Calculated in decimals whatever the price type is
+fn clearing_price<P: Price>(
+    demand: &Segment<'_, P>,
+    supply: &Segment<'_, P>,
+    config: &AuctionConfig,
+) -> Option<P> {
+    let (demand_price, supply_price) = match (demand.price, supply.price) {
+        (None, None) => return None,
+        (None, Some(supply_price)) => return Some(supply_price.clone()),
+        (Some(demand_price), None) => return Some(demand_price.clone()),
+        (Some(demand_price), Some(supply_price)) => (demand_price, supply_price),
+    };
+    let (demand_decimal, supply_decimal) = (demand_price.to_decimal(), supply_price.to_decimal());
+
+    let price = match config.clearing_price_rule {
+        // the side with less qty on the curve, as it is, not snapped to the tick
+        ClearingPriceRule::OnGrid if demand.q_max <= supply.q_max => {
+            return Some(demand_price.clone())
+        }
+        ClearingPriceRule::OnGrid => return Some(supply_price.clone()),
+        ClearingPriceRule::Midpoint => (&demand_decimal + &supply_decimal) / 2,
+        ClearingPriceRule::BidSide => demand_decimal,
+        ClearingPriceRule::AskSide => supply_decimal,
+        ClearingPriceRule::WeightedMid => {
+            let q_demand = demand.q_max.to_decimal();
+            let q_supply = supply.q_max.to_decimal();
+            (demand_decimal * &q_supply + supply_decimal * &q_demand) / (q_demand + q_supply)
+        }
+    };
+    let price = match &config.tick_size {
+        None => price,
+        Some(tick) => round_with(&(price / tick), 0, config.rounding_mode) * tick,
+    };
+    Some(
+        P::from_decimal(&round_with(
+            &price,
+            config.price_scale,
+            config.rounding_mode,
+        ))
+        .max(supply_price.clone())
+        .min(demand_price.clone()),
+    )
+}
+
+/**
+* Rounds half up to `scale` decimal digits.
+* Unlike BigDecimal::round, works for any number of digits, e.g. after a division
+*/
+pub fn round_price(price: &BigDecimal, scale: i64) -> BigDecimal {
+    round_with(price, scale, RoundingMode::HalfUp)
+}
+
+// Rounds to `scale` decimal digits the way `mode` says
+pub fn round_with(price: &BigDecimal, scale: i64, mode: RoundingMode) -> BigDecimal {
+    if price.as_bigint_and_exponent().1 <= scale {
+        return price.clone();
+    }
+    // with_scale cuts the digits off, towards zero
+    let truncated = price.with_scale(scale);
+    let remainder = price - &truncated;
+    if remainder.is_zero() {
+        return truncated;
+    }
+    let unit = BigDecimal::new(1.into(), scale);
+    let away_from_zero = if price.is_negative() {
+        &truncated - &unit
+    } else {
+        &truncated + &unit
+    };
+    let half = BigDecimal::new(5.into(), scale + 1);
+    let distance = remainder.abs();
+    match mode {
+        RoundingMode::HalfUp if distance >= half => away_from_zero,
+        RoundingMode::HalfEven if distance > half => away_from_zero,
+        RoundingMode::HalfEven if distance == half => {
+            let (digits, _) = truncated.as_bigint_and_exponent();
+            if (digits % 2u8).is_zero() {
+                truncated
+            } else {
+                away_from_zero
+            }
+        }
+        RoundingMode::Floor if remainder.is_negative() => away_from_zero,
+        RoundingMode::Ceil if remainder.is_positive() => away_from_zero,
+        _ => truncated,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auction::{
+        age_orders, allocate, calculate_batch, calculate_batch_with, intersect_demand_supply,
+        may_cross, orders_to_curve_segments, price_levels, round_with, simulate_batch,
+        try_immediate_cross, AllocationPolicy, AuctionConfig, BatchReport, BatchResult,
+        BatchWorkspace, CircuitBreaker, ClearingPriceRule, FeeSchedule, Fill, NoTradeReason, Order,
+        OrderId, Qty, Residual, RoundingMode, Segment, SelfTradePrevention, Side, TimeInForce,
+    };
+    use bigdecimal::{BigDecimal, FromPrimitive};
+    use std::str::FromStr;
+
+    #[test]
+    fn supply_demand_intersect_horizontally() {
+        let bids = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(7.0).unwrap()),
+                q_max: Qty::from(2),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(6.0).unwrap()),
+                q_max: Qty::from(3),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(5.0).unwrap()),
+                q_max: Qty::from(6),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(3.0).unwrap()),
+                q_max: Qty::from(8),
+            },
+        ];
+
+        let asks = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(2.0).unwrap()),
+                q_max: Qty::from(1),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(3.0).unwrap()),
+                q_max: Qty::from(2),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(5.0).unwrap()),
+                q_max: Qty::from(5),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(7.0).unwrap()),
+                q_max: Qty::from(8),
+            },
+        ];
+
+        if let Ok((p_star, q_star)) =
+            intersect_demand_supply(&bids, &asks, &AuctionConfig::default())
+        {
+            assert_eq!(q_star, Qty::from(5));
+            assert_eq!(p_star, BigDecimal::from(5));
+        }
+    }
+
+    #[test]
+    fn supply_demand_intersect_vertically() {
+        let bids = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(8.0).unwrap()),
+                q_max: Qty::from(2),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(6.0).unwrap()),
+                q_max: Qty::from(3),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(5.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(6),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(1.0).unwrap()),
+                q_max: Qty::from(8),
+            },
+        ];
+
+        let asks = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(2.0).unwrap()),
+                q_max: Qty::from(1),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(3.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(6.0).unwrap()),
+                q_max: Qty::from(5),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(7.0).unwrap()),
+                q_max: Qty::from(8),
+            },
+        ];
+
+        if let Ok((p_star, q_star)) =
+            intersect_demand_supply(&bids, &asks, &AuctionConfig::default())
+        {
+            assert_eq!(q_star, Qty::from(4));
+            assert_eq!(p_star, BigDecimal::from(4));
+        }
+    }
+
+    #[test]
+    fn supply_demand_intersect_in_one_dot() {
+        let bids = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(7.0).unwrap()),
+                q_max: Qty::from(2),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(5.0).unwrap()),
+                q_max: Qty::from(3),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(3.0).unwrap()),
+                q_max: Qty::from(6),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(1.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+        ];
+
+        let asks = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(1.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(2.0).unwrap()),
+                q_max: Qty::from(7),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+        ];
+
+        // the curves touch at 4 where both step, then the bid of 3 and the ask of 2 trade up to 6.
+        // The ask of 4 is listed twice, the second one adds nothing and is never reached
+        assert_eq!(
+            intersect_demand_supply(&bids, &asks, &AuctionConfig::default()),
+            Ok((BigDecimal::from_f32(2.5).unwrap(), Qty::from(6)))
+        );
+    }
+
+    #[test]
+    fn supply_demand_touch_exactly() {
+        let price = |price: &str| BigDecimal::from_str(price).unwrap();
+        let prices = [price("10"), price("9"), price("9.5"), price("11")];
+        let bids = [
+            Segment {
+                price: Some(&prices[0]),
+                q_max: Qty::from(5),
+            },
+            Segment {
+                price: Some(&prices[1]),
+                q_max: Qty::from(8),
+            },
+        ];
+        let asks = [
+            Segment {
+                price: Some(&prices[2]),
+                q_max: Qty::from(5),
+            },
+            Segment {
+                price: Some(&prices[3]),
+                q_max: Qty::from(8),
+            },
+        ];
+        let intersect = |clearing_price_rule| {
+            let config = AuctionConfig {
+                clearing_price_rule,
+                ..AuctionConfig::default()
+            };
+            intersect_demand_supply(&bids, &asks, &config).unwrap()
+        };
+
+        // demand equals supply at 5 anywhere from 9.5 to 10, the touching qty trades
+        // and the rule picks the price
+        assert_eq!(
+            intersect(ClearingPriceRule::Midpoint),
+            (price("9.75"), Qty::from(5))
+        );
+        assert_eq!(
+            intersect(ClearingPriceRule::BidSide),
+            (price("10"), Qty::from(5))
+        );
+        assert_eq!(
+            intersect(ClearingPriceRule::AskSide),
+            (price("9.5"), Qty::from(5))
+        );
+        // as much qty on both sides, the midpoint is as far from either
+        assert_eq!(
+            intersect(ClearingPriceRule::OnGrid),
+            (price("10"), Qty::from(5))
+        );
+
+        // touching at the very end of both curves
+        assert_eq!(
+            intersect_demand_supply(&bids[..1], &asks[..1], &AuctionConfig::default()),
+            Ok((price("9.75"), Qty::from(5)))
+        );
+    }
+
+    #[test]
+    fn supply_demand_duplicated_segment_is_stepped_over() {
+        let price = |price: &str| BigDecimal::from_str(price).unwrap();
+        let prices = [price("5"), price("4.5"), price("1"), price("2"), price("4")];
+        let bids = [
+            Segment {
+                price: Some(&prices[0]),
+                q_max: Qty::from(9),
+            },
+            Segment {
+                price: Some(&prices[1]),
+                q_max: Qty::from(12),
+            },
+        ];
+        let ask = |idx: usize, q_max| Segment {
+            price: Some(&prices[idx]),
+            q_max: Qty::from(q_max),
+        };
+        let asks = [ask(2, 4), ask(3, 7), ask(4, 9)];
+        let duplicated = [ask(2, 4), ask(3, 7), ask(4, 9), ask(4, 9)];
+
+        // the bid of 4.5 crosses the second ask of 4, which has nothing to fill,
+        // so it doesn't become the marginal bid
+        let expected = Ok((price("4.5"), Qty::from(9)));
+        assert_eq!(
+            intersect_demand_supply(&bids, &asks, &AuctionConfig::default()),
+            expected
+        );
+        assert_eq!(
+            intersect_demand_supply(&bids, &duplicated, &AuctionConfig::default()),
+            expected
+        );
+    }
+
+    #[test]
+    fn supply_demand_intersect_in_one_dot_with_price_rules() {
+        let bids = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(7.0).unwrap()),
+                q_max: Qty::from(2),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(5.0).unwrap()),
+                q_max: Qty::from(3),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(3.0).unwrap()),
+                q_max: Qty::from(6),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(1.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+        ];
+
+        let asks = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(1.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(2.0).unwrap()),
+                q_max: Qty::from(7),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+        ];
+
+        let intersect = |clearing_price_rule| {
+            let config = AuctionConfig {
+                clearing_price_rule,
+                ..AuctionConfig::default()
+            };
+            intersect_demand_supply(&bids, &asks, &config).unwrap()
+        };
+
+        assert_eq!(
+            intersect(ClearingPriceRule::Midpoint),
+            (BigDecimal::from_f32(2.5).unwrap(), Qty::from(6))
+        );
+        assert_eq!(
+            intersect(ClearingPriceRule::BidSide),
+            (BigDecimal::from(3), Qty::from(6))
+        );
+        assert_eq!(
+            intersect(ClearingPriceRule::AskSide),
+            (BigDecimal::from(2), Qty::from(6))
+        );
+        // (3 * 7 + 2 * 6) / (6 + 7) = 2.538..
+        assert_eq!(
+            intersect(ClearingPriceRule::WeightedMid),
+            (BigDecimal::from_str("2.54").unwrap(), Qty::from(6))
+        );
+        // the bid is nearer, unlike the midpoint it is a price in the book
+        assert_eq!(
+            intersect(ClearingPriceRule::OnGrid),
+            (BigDecimal::from(3), Qty::from(6))
+        );
+    }
+
+    #[test]
+    fn supply_demand_intersect_coincident_steps() {
+        let price = |price: &str| BigDecimal::from_str(price).unwrap();
+        let prices = [
+            price("12"),
+            price("11"),
+            price("9"),
+            price("8"),
+            price("10"),
+            price("10.5"),
+        ];
+        let bids = [
+            Segment {
+                price: Some(&prices[0]),
+                q_max: Qty::from(5),
+            },
+            Segment {
+                price: Some(&prices[1]),
+                q_max: Qty::from(8),
+            },
+            Segment {
+                price: Some(&prices[2]),
+                q_max: Qty::from(10),
+            },
+        ];
+        let asks = [
+            Segment {
+                price: Some(&prices[3]),
+                q_max: Qty::from(5),
+            },
+            Segment {
+                price: Some(&prices[4]),
+                q_max: Qty::from(8),
+            },
+            Segment {
+                price: Some(&prices[5]),
+                q_max: Qty::from(10),
+            },
+        ];
+
+        // 8 lots trade from 10 to 11. The ask of 10.5 crosses the bid of 11,
+        // but has nothing to fill, so it isn't the marginal ask
+        assert_eq!(
+            intersect_demand_supply(&bids, &asks, &AuctionConfig::default()),
+            Ok((price("10.5"), Qty::from(8)))
+        );
+    }
+
+    #[test]
+    fn supply_demand_intersect_not_enough_bids() {
+        let bids = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(7.0).unwrap()),
+                q_max: Qty::from(2),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(5.0).unwrap()),
+                q_max: Qty::from(3),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(3.0).unwrap()),
+                q_max: Qty::from(5),
+            },
+        ];
+
+        let asks = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(1.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(2.0).unwrap()),
+                q_max: Qty::from(7),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+        ];
+
+        if let Ok((p_star, q_star)) =
+            intersect_demand_supply(&bids, &asks, &AuctionConfig::default())
+        {
+            assert_eq!(q_star, Qty::from(5));
+            assert_eq!(p_star, BigDecimal::from_f32(2.5).unwrap());
+        }
+    }
+
+    #[test]
+    fn supply_demand_intersect_no_bids() {
+        let bids = [];
+
+        let asks = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(1.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(2.0).unwrap()),
+                q_max: Qty::from(7),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(9),
+            },
+        ];
+
+        assert_eq!(
+            intersect_demand_supply(&bids, &asks, &AuctionConfig::default()),
+            Err(NoTradeReason::OneSided)
+        );
+    }
+
+    #[test]
+    fn supply_demand_intersect_no_asks() {
+        let bids = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(7.0).unwrap()),
+                q_max: Qty::from(2),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(5.0).unwrap()),
+                q_max: Qty::from(3),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(3.0).unwrap()),
+                q_max: Qty::from(5),
+            },
+        ];
+
+        let asks = [];
+
+        assert_eq!(
+            intersect_demand_supply(&bids, &asks, &AuctionConfig::default()),
+            Err(NoTradeReason::OneSided)
+        );
+    }
+
+    #[test]
+    fn supply_demand_intersect_no_intersect_possible() {
+        let asks = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(3.0).unwrap()),
+                q_max: Qty::from(2),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(4.0).unwrap()),
+                q_max: Qty::from(4),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(5.0).unwrap()),
+                q_max: Qty::from(5),
+            },
+        ];
+
+        let bids = [
+            Segment {
+                price: Some(&BigDecimal::from_f32(2.0).unwrap()),
+                q_max: Qty::from(1),
+            },
+            Segment {
+                price: Some(&BigDecimal::from_f32(1.0).unwrap()),
+                q_max: Qty::from(5),
+            },
+        ];
+
+        assert_eq!(
+            intersect_demand_supply(&bids, &asks, &AuctionConfig::default()),
+            Err(NoTradeReason::NoCross)
+        );
+    }
+
+    #[test]
+    fn orders_to_curve_segments_converts_correctly() {
+        let orders = vec![
+            Order::new(1, BigDecimal::from_str("111.69").unwrap(), Qty::from(3)),
+            Order::new(2, BigDecimal::from_str("111.69").unwrap(), Qty::from(3)),
+            Order::new(3, BigDecimal::from_str("111.69").unwrap(), Qty::from(4)),
+            Order::new(4, BigDecimal::from_str("111.00").unwrap(), Qty::from(1)),
+            Order::new(5, BigDecimal::from_str("110.97").unwrap(), Qty::from(2)),
+            Order::new(6, BigDecimal::from_str("110.97").unwrap(), Qty::from(1)),
+        ];
+
+        let mut segments = vec![];
+        orders_to_curve_segments(&orders, Side::Bid, &mut segments).unwrap();
+        assert_eq!(segments.len(), 3);
+
+        assert_eq!(
+            segments.first().unwrap().price,
+            Some(&BigDecimal::from_str("111.69").unwrap())
+        );
+        assert_eq!(segments.first().unwrap().q_max, Qty::from(10));
+
+        assert_eq!(
+            segments.get(1).unwrap().price,
+            Some(&BigDecimal::from_str("111.00").unwrap())
+        );
+        assert_eq!(segments.get(1).unwrap().q_max, Qty::from(11));
+
+        assert_eq!(
+            segments.get(2).unwrap().price,
+            Some(&BigDecimal::from_str("110.97").unwrap())
+        );
+        assert_eq!(segments.get(2).unwrap().q_max, Qty::from(14));
+    }
+
+    #[test]
+    fn orders_to_curve_segments_handles_interleaved_prices() {
+        let orders = vec![
+            Order::new(1, BigDecimal::from_str("10.00").unwrap(), Qty::from(2)),
+            Order::new(2, BigDecimal::from_str("11.00").unwrap(), Qty::from(3)),
+            Order::new(3, BigDecimal::from_str("10.00").unwrap(), Qty::from(4)),
+            Order::new(4, BigDecimal::from_str("9.00").unwrap(), Qty::from(1)),
+        ];
+
+        let mut demand = vec![];
+        orders_to_curve_segments(&orders, Side::Bid, &mut demand).unwrap();
+        let demand: Vec<(String, Qty)> = demand
+            .iter()
+            .map(|s| (s.price.as_ref().unwrap().to_string(), s.q_max))
+            .collect();
+        assert_eq!(
+            demand,
+            vec![
+                ("11.00".to_string(), Qty::from(3)),
+                ("10.00".to_string(), Qty::from(9)),
+                ("9.00".to_string(), Qty::from(10))
+            ]
+        );
+
+        let mut supply = vec![];
+        orders_to_curve_segments(&orders, Side::Ask, &mut supply).unwrap();
+        let supply: Vec<(String, Qty)> = supply
+            .iter()
+            .map(|s| (s.price.as_ref().unwrap().to_string(), s.q_max))
+            .collect();
+        assert_eq!(
+            supply,
+            vec![
+                ("9.00".to_string(), Qty::from(1)),
+                ("10.00".to_string(), Qty::from(7)),
+                ("11.00".to_string(), Qty::from(10))
+            ]
+        );
+    }
+
+    #[test]
+    fn curve_quantity_past_u32_does_not_wrap() {
+        // 3 orders of 2 billion lots, more than a u32 could sum up
+        let lots = Qty::from(2_000_000_000);
+        let orders: Vec<Order> = (1..=3)
+            .map(|id| Order::new(id, BigDecimal::from(10 + id), lots))
+            .collect();
+        let mut demand = vec![];
+        orders_to_curve_segments(&orders, Side::Bid, &mut demand).unwrap();
+        let q_max: Vec<Qty> = demand.iter().map(|s| s.q_max).collect();
+        assert_eq!(q_max, vec![lots, lots + lots, lots + lots + lots]);
+        assert_eq!(q_max[2].to_string(), "6000000000");
+
+        // past the range of Qty itself the batch doesn't trade
+        let half = Qty::from_units(u64::MAX / 2 + 1);
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(10), half),
+            Order::new(2, BigDecimal::from(11), half),
+        ];
+        assert_eq!(
+            orders_to_curve_segments(&bids, Side::Bid, &mut demand),
+            Err(NoTradeReason::QtyOverflow)
+        );
+        let mut asks = vec![Order::new(3, BigDecimal::from(9), Qty::from(1))];
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+        assert!(matches!(
+            result.report,
+            BatchReport::NoTrade {
+                reason: NoTradeReason::QtyOverflow,
+                ..
+            }
+        ));
+        assert_eq!(result.residual.bid_qty, Qty::from_units(u64::MAX));
+        assert_eq!(bids.len(), 2);
+    }
+
+    #[test]
+    fn apart_book_reports_the_same_no_cross_as_the_curves() {
+        let orders = |side: Side| -> Vec<Order> {
+            let (base, first_id) = match side {
+                Side::Bid => (9, 1),
+                Side::Ask => (11, 4),
+            };
+            (0..3)
+                .map(|i| Order::new(first_id + i, BigDecimal::from(base), Qty::from(2)))
+                .collect()
+        };
+        let (mut bids, mut asks) = (orders(Side::Bid), orders(Side::Ask));
+        let (mut demand, mut supply) = (vec![], vec![]);
+        orders_to_curve_segments(&bids, Side::Bid, &mut demand).unwrap();
+        orders_to_curve_segments(&asks, Side::Ask, &mut supply).unwrap();
+        let config = AuctionConfig::default();
+        let from_curves = intersect_demand_supply(&demand, &supply, &config).unwrap_err();
+
+        let result = calculate_batch(&mut bids, &mut asks, &config);
+        assert!(!may_cross(&bids, &asks));
+        match result.report {
+            BatchReport::NoTrade { reason, .. } => assert_eq!(reason, from_curves),
+            _ => panic!(),
+        }
+        assert_eq!(from_curves, NoTradeReason::NoCross);
+        // the rest of the batch still runs
+        assert_eq!(
+            result.residual,
+            Residual {
+                bid_qty: Qty::from(6),
+                ask_qty: Qty::from(6)
+            }
+        );
+        assert!(bids.iter().chain(&asks).all(|o| o.batches_out == 1));
+
+        // a market order or a side too large for a curve goes through the curves
+        let huge = Qty::from_units(u64::MAX / 2 + 1);
+        assert!(may_cross(&[Order::market(7, Qty::from(1))], &asks));
+        assert!(may_cross(
+            &[
+                Order::new(8, BigDecimal::from(9), huge),
+                Order::new(9, BigDecimal::from(9), huge)
+            ],
+            &asks
+        ));
+    }
+
+    #[test]
+    fn price_levels_aggregates_quantity_per_price() {
+        let orders = vec![
+            Order::new(1, BigDecimal::from_str("111.00").unwrap(), Qty::from(1)),
+            Order::new(2, BigDecimal::from_str("111.69").unwrap(), Qty::from(3)),
+            Order::new(3, BigDecimal::from_str("110.97").unwrap(), Qty::from(2)),
+            Order::new(4, BigDecimal::from_str("111.69").unwrap(), Qty::from(4)),
+        ];
+
+        let levels = price_levels(&orders);
+        assert_eq!(levels.len(), 3);
+
+        assert_eq!(levels[0].price, BigDecimal::from_str("110.97").unwrap());
+        assert_eq!(levels[0].qty, Qty::from(2));
+        assert_eq!(levels[1].price, BigDecimal::from_str("111.00").unwrap());
+        assert_eq!(levels[1].qty, Qty::from(1));
+        assert_eq!(levels[2].price, BigDecimal::from_str("111.69").unwrap());
+        assert_eq!(levels[2].qty, Qty::from(7));
+
+        let huge = Qty::from_units(u64::MAX - 1);
+        let orders = vec![
+            Order::new(1, BigDecimal::from(10), huge),
+            Order::new(2, BigDecimal::from(10), huge),
+        ];
+        assert_eq!(price_levels(&orders)[0].qty, Qty::from_units(u64::MAX));
+    }
+
+    #[test]
+    fn calculate_batch_with_trades_correctly() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from_str("112").unwrap(), Qty::from(2)),
+            Order::new(2, BigDecimal::from_str("111.76").unwrap(), Qty::from(21)),
+            Order::new(3, BigDecimal::from_str("111.45").unwrap(), Qty::from(200)),
+            Order::new(4, BigDecimal::from_str("111.35").unwrap(), Qty::from(100)),
+        ];
+
+        let mut asks = vec![
+            Order::new(5, BigDecimal::from_str("110").unwrap(), Qty::from(2)),
+            Order::new(6, BigDecimal::from_str("111.32").unwrap(), Qty::from(21)),
+            Order::new(7, BigDecimal::from_str("111.45").unwrap(), Qty::from(100)),
+            Order::new(8, BigDecimal::from_str("112.35").unwrap(), Qty::from(100)),
+        ];
+
+        if let BatchReport::Trade {
+            price,
+            qty,
+            cleared_bids,
+            cleared_asks,
+            ..
+        } = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default()).report
+        {
+            assert_eq!(price, BigDecimal::from_str("111.45").unwrap());
+            assert_eq!(qty, Qty::from(123));
+            let filled = |fills: &[Fill]| {
+                fills
+                    .iter()
+                    .map(|fill| (fill.order_id, fill.qty, fill.remaining))
+                    .collect::<Vec<_>>()
+            };
+            // the marginal bid is filled partially, 100 of it keeps resting
+            assert_eq!(
+                filled(&cleared_bids),
+                [
+                    (1, Qty::from(2), Qty::ZERO),
+                    (2, Qty::from(21), Qty::ZERO),
+                    (3, Qty::from(100), Qty::from(100))
+                ]
+            );
+            assert_eq!(
+                filled(&cleared_asks),
+                [
+                    (5, Qty::from(2), Qty::ZERO),
+                    (6, Qty::from(21), Qty::ZERO),
+                    (7, Qty::from(100), Qty::ZERO)
+                ]
+            );
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    fn simulate_batch_leaves_books_untouched() {
+        let bids = vec![
+            Order::new(1, BigDecimal::from(11), Qty::from(4)),
+            Order::new(2, BigDecimal::from(10), Qty::from(5)),
+        ];
+        let asks = vec![Order::new(3, BigDecimal::from(10), Qty::from(6))];
+
+        let simulation = simulate_batch(&bids, &asks, &AuctionConfig::default());
+
+        assert!(matches!(
+            simulation.result.report,
+            BatchReport::Trade { qty, .. } if qty == Qty::from(6)
+        ));
+        assert_eq!(simulation.bids.len(), 1);
+        assert_eq!(
+            (simulation.bids[0].id, simulation.bids[0].qty),
+            (2, Qty::from(3))
+        );
+        assert!(simulation.asks.is_empty());
+
+        assert_eq!(bids.len(), 2);
+        assert_eq!(
+            (bids[0].id, bids[0].qty, bids[0].batches_out),
+            (1, Qty::from(4), 0)
+        );
+        assert_eq!(
+            (bids[1].id, bids[1].qty, bids[1].batches_out),
+            (2, Qty::from(5), 0)
+        );
+        assert_eq!(asks.len(), 1);
+        assert_eq!(asks[0].qty, Qty::from(6));
+    }
+
+    #[test]
+    fn calculate_batch_reports_partially_cleared_orders() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(11), Qty::from(4)),
+            Order::new(2, BigDecimal::from(10), Qty::from(5)),
+        ];
+        let mut asks = vec![Order::new(3, BigDecimal::from(10), Qty::from(6))];
+
+        if let BatchReport::Trade {
+            qty,
+            cleared_bids,
+            cleared_asks,
+            ..
+        } = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default()).report
+        {
+            assert_eq!(qty, Qty::from(6));
+            assert_eq!(cleared_bids.iter().map(|o| o.qty).sum::<Qty>(), qty);
+            assert_eq!(cleared_asks.iter().map(|o| o.qty).sum::<Qty>(), qty);
+            assert_eq!(cleared_bids[1].order_id, 2);
+            assert_eq!(cleared_bids[1].qty, Qty::from(2));
+        } else {
+            panic!();
+        }
+        // the rest of the marginal order keeps resting
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].qty, Qty::from(3));
+    }
+
+    #[test]
+    fn calculate_batch_reports_fills() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(11), Qty::from(4)).with_client(7),
+            Order::new(2, BigDecimal::from(10), Qty::from(5)).with_client(8),
+        ];
+        let mut asks = vec![Order::new(3, BigDecimal::from(10), Qty::from(6)).with_client(9)];
+
+        let report = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default()).report;
+        if let BatchReport::Trade { .. } = report {
+            let fills: Vec<_> = report.fills().collect();
+            assert_eq!(fills.len(), 3);
+
+            assert_eq!((fills[0].order_id, fills[0].client_id), (1, 7));
+            assert_eq!(
+                (fills[0].qty, fills[0].remaining),
+                (Qty::from(4), Qty::ZERO)
+            );
+            // partially cleared
+            assert_eq!((fills[1].order_id, fills[1].client_id), (2, 8));
+            assert_eq!(
+                (fills[1].qty, fills[1].remaining),
+                (Qty::from(2), Qty::from(3))
+            );
+
+            assert_eq!((fills[2].order_id, fills[2].client_id), (3, 9));
+            assert_eq!(
+                (fills[2].qty, fills[2].remaining),
+                (Qty::from(6), Qty::ZERO)
+            );
+            assert!(fills.iter().all(|f| f.price == BigDecimal::from(10)));
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    fn half_lots_clear() {
+        let qty = |qty: &str| qty.parse::<Qty>().unwrap();
+        let bids = || {
+            vec![
+                Order::new(1, BigDecimal::from(10), qty("0.5")),
+                Order::new(2, BigDecimal::from(10), qty("1.5")),
+            ]
+        };
+        let mut asks = vec![Order::new(3, BigDecimal::from(9), qty("1.25"))];
+
+        let mut time_priority = bids();
+        let result = calculate_batch(&mut time_priority, &mut asks, &AuctionConfig::default());
+        match &result.report {
+            BatchReport::Trade { qty: q_star, .. } => {
+                assert_eq!(*q_star, qty("1.25"));
+                let fills: Vec<_> = result
+                    .report
+                    .fills()
+                    .map(|f| (f.order_id, f.qty, f.remaining))
+                    .collect();
+                assert_eq!(
+                    fills,
+                    vec![
+                        (1, qty("0.5"), Qty::ZERO),
+                        (2, qty("0.75"), qty("0.75")),
+                        (3, qty("1.25"), Qty::ZERO)
+                    ]
+                );
+            }
+            _ => panic!(),
+        }
+        assert_eq!(result.residual.bid_qty, qty("0.75"));
+
+        // no whole lot to share, fractions go in priority order
+        let mut allocations = vec![];
+        allocate(
+            &bids(),
+            qty("1.25"),
+            AllocationPolicy::ProRata,
+            &mut allocations,
+        );
+        assert_eq!(allocations, vec![qty("0.5"), qty("0.75")]);
+    }
+
+    fn cleared_with_policy(policy: AllocationPolicy) -> Vec<(u64, Qty)> {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(10), Qty::from(3)),
+            Order::new(2, BigDecimal::from(10), Qty::from(3)),
+        ];
+        // older order has priority
+        bids[0].batches_out = 1;
+        let mut asks = vec![Order::new(3, BigDecimal::from(10), Qty::from(4))];
+
+        let config = AuctionConfig {
+            allocation_policy: policy,
+            ..Default::default()
+        };
+        match calculate_batch(&mut bids, &mut asks, &config).report {
+            BatchReport::Trade { cleared_bids, .. } => {
+                cleared_bids.iter().map(|o| (o.order_id, o.qty)).collect()
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn allocation_policies_split_marginal_level_differently() {
+        assert_eq!(
+            cleared_with_policy(AllocationPolicy::TimePriority),
+            vec![(1, Qty::from(3)), (2, Qty::from(1))]
+        );
+        assert_eq!(
+            cleared_with_policy(AllocationPolicy::ProRata),
+            vec![(1, Qty::from(2)), (2, Qty::from(2))]
+        );
+    }
+
+    #[test]
+    fn pro_rata_fills_better_prices_first_and_spreads_remainder() {
+        let orders = vec![
+            Order::new(1, BigDecimal::from(11), Qty::from(2)),
+            Order::new(2, BigDecimal::from(10), Qty::from(3)),
+            Order::new(3, BigDecimal::from(10), Qty::from(4)),
+            Order::new(4, BigDecimal::from(10), Qty::from(1)),
+        ];
+
+        // 5 left for the level of 8: 1.875, 2.5, 0.625 rounded down, 2 lots left over
+        let mut allocations = vec![];
+        allocate(
+            &orders,
+            Qty::from(7),
+            AllocationPolicy::ProRata,
+            &mut allocations,
+        );
+        assert_eq!(allocations, [2, 2, 3, 0].map(Qty::from));
+        allocate(
+            &orders,
+            Qty::from(7),
+            AllocationPolicy::TimePriority,
+            &mut allocations,
+        );
+        assert_eq!(allocations, [2, 3, 2, 0].map(Qty::from));
+    }
+
+    #[test]
+    fn fill_or_kill_order_is_not_partially_filled() {
+        let mut bids = vec![Order::new(1, BigDecimal::from(10), Qty::from(5))
+            .with_time_in_force(TimeInForce::FillOrKill)];
+        let mut asks = vec![Order::new(2, BigDecimal::from(10), Qty::from(3))];
+
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+
+        assert!(matches!(result.report, BatchReport::NoTrade { .. }));
+        assert_eq!(result.cancelled.len(), 1);
+        assert_eq!(result.cancelled[0].id, 1);
+        assert_eq!(result.cancelled[0].qty, Qty::from(5));
+        assert!(bids.is_empty());
+        // the ask isn't touched
+        assert_eq!(asks.len(), 1);
+        assert_eq!(asks[0].qty, Qty::from(3));
+    }
+
+    #[test]
+    fn fill_or_kill_rollback_lets_other_orders_trade() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(11), Qty::from(5))
+                .with_time_in_force(TimeInForce::FillOrKill),
+            Order::new(2, BigDecimal::from(10), Qty::from(2)),
+        ];
+        let mut asks = vec![Order::new(3, BigDecimal::from(10), Qty::from(3))];
+
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+
+        if let BatchReport::Trade {
+            qty, cleared_bids, ..
+        } = result.report
+        {
+            assert_eq!(qty, Qty::from(2));
+            assert_eq!(cleared_bids.len(), 1);
+            assert_eq!(cleared_bids[0].order_id, 2);
+        } else {
+            panic!();
+        }
+        assert_eq!(result.cancelled.len(), 1);
+        assert_eq!(result.cancelled[0].id, 1);
+        assert_eq!(asks[0].qty, Qty::from(1));
+    }
+
+    #[test]
+    fn fill_or_kill_order_fully_filled_or_cancelled() {
+        let fok = |id| {
+            Order::new(id, BigDecimal::from(10), Qty::from(3))
+                .with_time_in_force(TimeInForce::FillOrKill)
+        };
+        let mut bids = vec![fok(1)];
+        let mut asks = vec![Order::new(2, BigDecimal::from(10), Qty::from(3))];
+
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+        assert!(matches!(result.report, BatchReport::Trade { qty, .. } if qty == Qty::from(3)));
+        assert!(result.cancelled.is_empty());
+
+        // nothing to trade against, doesn't rest
+        let mut bids = vec![fok(3)];
+        let result = calculate_batch(&mut bids, &mut vec![], &AuctionConfig::default());
+        assert!(matches!(result.report, BatchReport::NoTrade { .. }));
+        assert_eq!(result.cancelled.len(), 1);
+        assert!(bids.is_empty());
+    }
+
+    #[test]
+    fn immediate_or_cancel_remainder_is_cancelled() {
+        let mut bids = vec![Order::new(1, BigDecimal::from(10), Qty::from(5))
+            .with_time_in_force(TimeInForce::ImmediateOrCancel)];
+        let mut asks = vec![Order::new(2, BigDecimal::from(10), Qty::from(3))];
+
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+
+        if let BatchReport::Trade {
+            qty, cleared_bids, ..
+        } = result.report
+        {
+            assert_eq!(qty, Qty::from(3));
+            assert_eq!(cleared_bids.len(), 1);
+            assert_eq!(
+                (cleared_bids[0].order_id, cleared_bids[0].qty),
+                (1, Qty::from(3))
+            );
+            // what's left of the IOC order is cancelled after the fill
+            assert_eq!(cleared_bids[0].remaining, Qty::from(2));
+        } else {
+            panic!();
+        }
+        assert_eq!(result.cancelled.len(), 1);
+        assert_eq!(
+            (result.cancelled[0].id, result.cancelled[0].qty),
+            (1, Qty::from(2))
+        );
+        assert!(bids.is_empty());
+        assert!(asks.is_empty());
+    }
+
+    #[test]
+    fn calculate_batch_ages_resting_orders() {
+        let mut bids = vec![Order::new(1, BigDecimal::from(10), Qty::from(5))];
+        let mut asks = vec![];
+
+        assert!(matches!(
+            calculate_batch(&mut bids, &mut asks, &AuctionConfig::default()).report,
+            BatchReport::NoTrade {
+                reason: NoTradeReason::OneSided,
+                ..
+            }
+        ));
+        assert_eq!(bids[0].batches_out, 1);
+
+        // newer order at the same price is placed first
+        bids.insert(0, Order::new(2, BigDecimal::from(10), Qty::from(5)));
+        asks.push(Order::new(3, BigDecimal::from(10), Qty::from(5)));
+
+        if let BatchReport::Trade { cleared_bids, .. } =
+            calculate_batch(&mut bids, &mut asks, &AuctionConfig::default()).report
+        {
+            assert_eq!(cleared_bids.len(), 1);
+            assert_eq!(cleared_bids[0].order_id, 1);
+        } else {
+            panic!();
+        }
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].id, 2);
+        assert_eq!(bids[0].batches_out, 1);
+    }
+
+    #[test]
+    fn age_orders_saturates() {
+        let mut orders = vec![Order::new(1, BigDecimal::from(10), Qty::from(5))];
+        orders[0].batches_out = u16::MAX;
+
+        age_orders(&mut orders);
+
+        assert_eq!(orders[0].batches_out, u16::MAX);
+    }
+
+    #[test]
+    fn calculate_batch_expires_orders_past_the_limit() {
+        let config = AuctionConfig {
+            max_batches_out: Some(2),
+            ..Default::default()
+        };
+        let mut bids = vec![Order::new(1, BigDecimal::from(10), Qty::from(5))];
+        let mut asks = vec![Order::new(2, BigDecimal::from(11), Qty::from(5))];
+
+        // order sits exactly at the limit and is kept
+        for _ in 0..2 {
+            assert!(calculate_batch(&mut bids, &mut asks, &config)
+                .expired
+                .is_empty());
+        }
+        assert_eq!(bids[0].batches_out, 2);
+
+        let expired = calculate_batch(&mut bids, &mut asks, &config).expired;
+        assert_eq!(expired.len(), 2);
+        assert!(bids.is_empty());
+        assert!(asks.is_empty());
+    }
+
+    #[test]
+    fn calculate_batch_clears_order_before_expiry() {
+        let config = AuctionConfig {
+            max_batches_out: Some(1),
+            ..Default::default()
+        };
+        let mut bids = vec![Order::new(1, BigDecimal::from(10), Qty::from(5))];
+        let mut asks = vec![];
+
+        assert!(calculate_batch(&mut bids, &mut asks, &config)
+            .expired
+            .is_empty());
+
+        asks.push(Order::new(2, BigDecimal::from(10), Qty::from(5)));
+        let result = calculate_batch(&mut bids, &mut asks, &config);
+
+        assert!(result.expired.is_empty());
+        assert!(matches!(result.report, BatchReport::Trade { qty, .. } if qty == Qty::from(5)));
+        assert!(bids.is_empty());
+        assert!(asks.is_empty());
+    }
+
+    #[test]
+    fn imbalance_at_clearing_price() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(11), Qty::from(4)),
+            Order::new(2, BigDecimal::from(10), Qty::from(5)),
+            Order::new(3, BigDecimal::from(9), Qty::from(7)),
+        ];
+        let mut asks = vec![
+            Order::new(4, BigDecimal::from(9), Qty::from(3)),
+            Order::new(5, BigDecimal::from(10), Qty::from(3)),
+        ];
+
+        if let BatchReport::Trade {
+            price,
+            qty,
+            bid_imbalance,
+            ask_imbalance,
+            ..
+        } = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default()).report
+        {
+            assert_eq!(price, BigDecimal::from(10));
+            assert_eq!(qty, Qty::from(6));
+            // 9 lots bid at 10 or higher, only 6 offered
+            assert_eq!(bid_imbalance, Qty::from(3));
+            assert_eq!(ask_imbalance, Qty::from(0));
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    fn residual_is_initial_minus_cleared() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(11), Qty::from(4)),
+            Order::new(2, BigDecimal::from(10), Qty::from(5)),
+            Order::new(3, BigDecimal::from(8), Qty::from(2)),
+        ];
+        let mut asks = vec![
+            Order::new(4, BigDecimal::from(9), Qty::from(6)),
+            Order::new(5, BigDecimal::from(12), Qty::from(3)),
+        ];
+
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+        let qty = match result.report {
+            BatchReport::Trade { qty, .. } => qty,
+            _ => panic!(),
+        };
+
+        assert_eq!(qty, Qty::from(6));
+        assert_eq!(
+            result.residual,
+            Residual {
+                bid_qty: Qty::from(11) - qty,
+                ask_qty: Qty::from(9) - qty,
+            }
+        );
+        assert_eq!(
+            result.residual.bid_qty,
+            bids.iter().map(|o| o.qty).sum::<Qty>()
+        );
+    }
+
+    fn self_trade_batch(stp: Option<SelfTradePrevention>) -> (BatchResult, Vec<Order>, Vec<Order>) {
+        // client 1 is on both sides, client 2 sells at a worse price
+        let mut bids = vec![Order::new(1, BigDecimal::from(10), Qty::from(5)).with_client(1)];
+        let mut asks = vec![
+            Order::new(2, BigDecimal::from(9), Qty::from(5)).with_client(1),
+            Order::new(3, BigDecimal::from(10), Qty::from(5)).with_client(2),
+        ];
+        let config = AuctionConfig {
+            self_trade_prevention: stp,
+            ..AuctionConfig::default()
+        };
+        let result = calculate_batch(&mut bids, &mut asks, &config);
+        (result, bids, asks)
+    }
+
+    #[test]
+    fn self_trade_is_allowed_by_default() {
+        let (result, _, _) = self_trade_batch(None);
+
+        assert!(matches!(result.report, BatchReport::Trade { .. }));
+        assert!(result.cancelled.is_empty());
+    }
+
+    #[test]
+    fn self_trade_prevention_cancels_oldest() {
+        let (result, bids, asks) = self_trade_batch(Some(SelfTradePrevention::CancelOldest));
+
+        // the bid is cancelled, nobody else buys
+        assert!(matches!(result.report, BatchReport::NoTrade { .. }));
+        assert_eq!(
+            result.cancelled.iter().map(|o| o.id).collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert!(bids.is_empty());
+        assert_eq!(asks.len(), 2);
+    }
+
+    #[test]
+    fn self_trade_prevention_cancels_newest() {
+        let (result, bids, asks) = self_trade_batch(Some(SelfTradePrevention::CancelNewest));
+
+        // the own ask is cancelled, the bid trades with client 2
+        if let BatchReport::Trade {
+            cleared_asks, qty, ..
+        } = &result.report
+        {
+            assert_eq!(*qty, Qty::from(5));
+            assert_eq!(cleared_asks[0].order_id, 3);
+        } else {
+            panic!();
+        }
+        assert_eq!(
+            result.cancelled.iter().map(|o| o.id).collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert!(bids.is_empty());
+        assert!(asks.is_empty());
+    }
+
+    #[test]
+    fn self_trade_prevention_cancels_both() {
+        let (result, bids, asks) = self_trade_batch(Some(SelfTradePrevention::CancelBoth));
+
+        assert!(matches!(result.report, BatchReport::NoTrade { .. }));
+        assert_eq!(result.cancelled.len(), 2);
+        assert!(bids.is_empty());
+        assert_eq!(asks.iter().map(|o| o.id).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn clearing_price_is_rounded_to_scale() {
+        let batch = |price_scale| {
+            let mut bids = vec![Order::new(
+                1,
+                BigDecimal::from_str("111.4567").unwrap(),
+                Qty::from(5),
+            )];
+            let mut asks = vec![Order::new(
+                2,
+                BigDecimal::from_str("111.4500").unwrap(),
+                Qty::from(5),
+            )];
+            let config = AuctionConfig {
+                price_scale,
+                ..AuctionConfig::default()
+            };
+            match calculate_batch(&mut bids, &mut asks, &config).report {
+                BatchReport::Trade { price, .. } => price,
+                _ => panic!(),
+            }
+        };
+
+        // midpoint is 111.45335
+        assert_eq!(batch(4), BigDecimal::from_str("111.4534").unwrap());
+        assert_eq!(batch(2), BigDecimal::from_str("111.45").unwrap());
+    }
+
+    #[test]
+    fn midpoint_is_snapped_to_tick() {
+        let batch = |bid: &str, ask: &str, tick_size: Option<&str>| {
+            let mut bids = vec![Order::new(
+                1,
+                BigDecimal::from_str(bid).unwrap(),
+                Qty::from(5),
+            )];
+            let mut asks = vec![Order::new(
+                2,
+                BigDecimal::from_str(ask).unwrap(),
+                Qty::from(5),
+            )];
+            let config = AuctionConfig {
+                tick_size: tick_size.map(|tick| BigDecimal::from_str(tick).unwrap()),
+                ..AuctionConfig::default()
+            };
+            match calculate_batch(&mut bids, &mut asks, &config).report {
+                BatchReport::Trade { price, .. } => price,
+                _ => panic!(),
+            }
+        };
+
+        assert_eq!(batch("4", "1", None), BigDecimal::from_str("2.5").unwrap());
+        // halfway between ticks 2 and 3, rounded up
+        assert_eq!(batch("4", "1", Some("1.0")), BigDecimal::from(3));
+        assert_eq!(
+            batch("10.50", "10.00", Some("0.25")),
+            BigDecimal::from_str("10.25").unwrap()
+        );
+        assert_eq!(
+            batch("10.60", "10.00", Some("0.25")),
+            BigDecimal::from_str("10.25").unwrap()
+        );
+    }
+
+    #[test]
+    fn on_grid_price_is_an_order_price() {
+        let batch = |bid: &str, qty: u32, clearing_price_rule| {
+            let mut bids = vec![Order::new(
+                1,
+                BigDecimal::from_str(bid).unwrap(),
+                Qty::from(qty),
+            )];
+            let mut asks = vec![Order::new(
+                2,
+                BigDecimal::from_str("10.00").unwrap(),
+                Qty::from(5),
+            )];
+            let config = AuctionConfig {
+                tick_size: Some(BigDecimal::from_str("0.25").unwrap()),
+                clearing_price_rule,
+                ..AuctionConfig::default()
+            };
+            match calculate_batch(&mut bids, &mut asks, &config).report {
+                BatchReport::Trade { price, .. } => price.to_string(),
+                _ => panic!(),
+            }
+        };
+
+        // the midpoint of 10.60 and 10.00 is snapped to the tick, a price nobody quoted
+        assert_eq!(batch("10.60", 5, ClearingPriceRule::Midpoint), "10.25");
+        assert_eq!(batch("10.60", 5, ClearingPriceRule::OnGrid), "10.60");
+        // more bid qty, the ask is nearer to the weighted midpoint
+        assert_eq!(batch("10.60", 7, ClearingPriceRule::OnGrid), "10.00");
+        // even off the tick, the order price is kept
+        assert_eq!(batch("10.13", 3, ClearingPriceRule::OnGrid), "10.13");
+    }
+
+    #[test]
+    fn clearing_price_follows_rounding_mode() {
+        let batch = |bid: &str, ask: &str, rounding_mode| {
+            let mut bids = vec![Order::new(
+                1,
+                BigDecimal::from_str(bid).unwrap(),
+                Qty::from(5),
+            )];
+            let mut asks = vec![Order::new(
+                2,
+                BigDecimal::from_str(ask).unwrap(),
+                Qty::from(5),
+            )];
+            let config = AuctionConfig {
+                rounding_mode,
+                ..AuctionConfig::default()
+            };
+            match calculate_batch(&mut bids, &mut asks, &config).report {
+                BatchReport::Trade { price, .. } => price.to_string(),
+                _ => panic!(),
+            }
+        };
+
+        // midpoint is 10.005, halfway at scale 2
+        assert_eq!(batch("10.01", "10.00", RoundingMode::HalfUp), "10.01");
+        assert_eq!(batch("10.01", "10.00", RoundingMode::HalfEven), "10.00");
+        assert_eq!(batch("10.01", "10.00", RoundingMode::Floor), "10.00");
+        assert_eq!(batch("10.01", "10.00", RoundingMode::Ceil), "10.01");
+        // midpoint is 10.015, the even digit is up this time
+        assert_eq!(batch("10.03", "10.00", RoundingMode::HalfEven), "10.02");
+        // midpoint is -2.005
+        assert_eq!(batch("-2.00", "-2.01", RoundingMode::HalfUp), "-2.01");
+        assert_eq!(batch("-2.00", "-2.01", RoundingMode::HalfEven), "-2.00");
+        assert_eq!(batch("-2.00", "-2.01", RoundingMode::Floor), "-2.01");
+        assert_eq!(batch("-2.00", "-2.01", RoundingMode::Ceil), "-2.00");
+    }
+
+    #[test]
+    fn round_with_every_mode() {
+        let round = |price: &str, mode| {
+            round_with(&BigDecimal::from_str(price).unwrap(), 1, mode).to_string()
+        };
+        assert_eq!(round("1.26", RoundingMode::HalfUp), "1.3");
+        assert_eq!(round("1.24", RoundingMode::HalfUp), "1.2");
+        assert_eq!(round("1.26", RoundingMode::HalfEven), "1.3");
+        assert_eq!(round("1.25", RoundingMode::HalfEven), "1.2");
+        assert_eq!(round("1.29", RoundingMode::Floor), "1.2");
+        assert_eq!(round("-1.21", RoundingMode::Floor), "-1.3");
+        assert_eq!(round("1.21", RoundingMode::Ceil), "1.3");
+        assert_eq!(round("-1.29", RoundingMode::Ceil), "-1.2");
+        // already at the scale
+        assert_eq!(round("1.2", RoundingMode::Ceil), "1.2");
+        assert_eq!(round("1.20", RoundingMode::Floor), "1.2");
+    }
+
+    #[test]
+    fn batch_above_circuit_breaker_leaves_book_untouched() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(11), Qty::from(4)),
+            Order::new(2, BigDecimal::from(10), Qty::from(4))
+                .with_time_in_force(TimeInForce::ImmediateOrCancel),
+        ];
+        let mut asks = vec![Order::new(3, BigDecimal::from(9), Qty::from(10))];
+        let (bids_before, asks_before) = (bids.clone(), asks.clone());
+        let config = |max_qty| AuctionConfig {
+            circuit_breaker: Some(CircuitBreaker {
+                max_qty: Qty::from(max_qty),
+                cooldown: None,
+            }),
+            ..AuctionConfig::default()
+        };
+
+        let result = calculate_batch(&mut bids, &mut asks, &config(7));
+        match result.report {
+            BatchReport::CircuitBreak { price, qty, .. } => {
+                assert_eq!(price, BigDecimal::from_str("9.50").unwrap());
+                assert_eq!(qty, Qty::from(8));
+            }
+            _ => panic!(),
+        }
+        // not even the IOC order is cancelled, or the orders aged
+        assert!(result.cancelled.is_empty());
+        assert_eq!(bids, bids_before);
+        assert_eq!(asks, asks_before);
+        assert_eq!(result.residual.bid_qty, Qty::from(8));
+
+        // at the max qty the batch trades
+        let result = calculate_batch(&mut bids, &mut asks, &config(8));
+        assert!(matches!(result.report, BatchReport::Trade { .. }));
+    }
+
+    #[test]
+    fn iceberg_shows_only_display_qty() {
+        let bids = vec![
+            Order::new(1, BigDecimal::from(10), Qty::from(10)).with_display_qty(Some(Qty::from(2))),
+            Order::new(2, BigDecimal::from(10), Qty::from(3)),
+            // display qty larger than the order shows all of it
+            Order::new(3, BigDecimal::from(9), Qty::from(1)).with_display_qty(Some(Qty::from(5))),
+        ];
+        let levels: Vec<(String, Qty)> = price_levels(&bids)
+            .into_iter()
+            .map(|level| (level.price.to_string(), level.qty))
+            .collect();
+        assert_eq!(
+            levels,
+            [
+                ("9".to_string(), Qty::from(1)),
+                ("10".to_string(), Qty::from(5))
+            ]
+        );
+
+        let mut demand = vec![];
+        orders_to_curve_segments(&bids, Side::Bid, &mut demand).unwrap();
+        assert_eq!(demand.last().unwrap().q_max, Qty::from(6));
+    }
+
+    #[test]
+    fn hidden_reserve_trades_after_shown_qty() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(10), Qty::from(10)).with_display_qty(Some(Qty::from(2))),
+            Order::new(2, BigDecimal::from(10), Qty::from(3)),
+        ];
+        let mut asks = vec![Order::new(3, BigDecimal::from(9), Qty::from(8))];
+
+        // the price is found by the 5 shown, the reserve takes what is left of the ask
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+        let filled: Vec<(OrderId, Qty)> = result
+            .report
+            .fills()
+            .map(|fill| (fill.order_id, fill.qty))
+            .collect();
+        match result.report {
+            BatchReport::Trade {
+                price,
+                qty,
+                bid_imbalance,
+                ask_imbalance,
+                ..
+            } => {
+                assert_eq!(price, BigDecimal::from_str("9.5").unwrap());
+                assert_eq!(qty, Qty::from(8));
+                assert_eq!(
+                    filled,
+                    [(1, Qty::from(5)), (2, Qty::from(3)), (3, Qty::from(8))]
+                );
+                assert_eq!(bid_imbalance, Qty::ZERO);
+                assert_eq!(ask_imbalance, Qty::ZERO);
+            }
+            _ => panic!(),
+        }
+        assert!(asks.is_empty());
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].qty(), Qty::from(5));
+        assert_eq!(bids[0].visible_qty(), Qty::from(2));
+        assert_eq!(result.residual.bid_qty, Qty::from(5));
+    }
+
+    #[test]
+    fn hidden_reserves_trade_with_each_other() {
+        let mut bids =
+            vec![Order::new(1, BigDecimal::from(10), Qty::from(6))
+                .with_display_qty(Some(Qty::from(1)))];
+        let mut asks =
+            vec![Order::new(2, BigDecimal::from(10), Qty::from(4))
+                .with_display_qty(Some(Qty::from(1)))];
+
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+        match result.report {
+            BatchReport::Trade { qty, .. } => assert_eq!(qty, Qty::from(4)),
+            _ => panic!(),
+        }
+        assert!(asks.is_empty());
+        assert_eq!(bids[0].qty(), Qty::from(2));
+        assert_eq!(bids[0].visible_qty(), Qty::from(1));
+    }
+
+    #[test]
+    fn incoming_order_crosses_right_away() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(10), Qty::from(6))
+                .with_client(1)
+                .with_display_qty(Some(Qty::from(2))),
+            Order::new(2, BigDecimal::from(10), Qty::from(2)).with_client(2),
+            Order::new(3, BigDecimal::from(9), Qty::from(5)).with_client(3),
+        ];
+        let config = AuctionConfig {
+            self_trade_prevention: Some(SelfTradePrevention::CancelOldest),
+            ..AuctionConfig::default()
+        };
+        calculate_batch(&mut bids, &mut vec![], &config);
+
+        let mut fok = Order::new(4, BigDecimal::from(9), Qty::from(5))
+            .with_time_in_force(TimeInForce::FillOrKill);
+        assert!(try_immediate_cross(&mut fok, Side::Ask, &mut bids, &config).is_empty());
+
+        // the shown part of the iceberg, the order of the same client is skipped
+        let mut ask = Order::new(4, BigDecimal::from(9), Qty::from(5)).with_client(2);
+        let fills = try_immediate_cross(&mut ask, Side::Ask, &mut bids, &config)
+            .into_iter()
+            .map(|fill| {
+                (
+                    fill.order_id,
+                    fill.price,
+                    fill.qty,
+                    fill.remaining,
+                    fill.maker,
+                )
+            })
+            .collect::<Vec<_>>();
+        let trade = |id, price: u32, qty: u32, remaining: u32, maker| {
+            let (price, qty) = (BigDecimal::from(price), Qty::from(qty));
+            (id, price, qty, Qty::from(remaining), maker)
+        };
+        assert_eq!(
+            fills,
+            [
+                trade(1, 10, 2, 4, true),
+                trade(4, 10, 2, 3, false),
+                trade(3, 9, 3, 2, true),
+                trade(4, 9, 3, 0, false),
+            ]
+        );
+        assert_eq!(ask.qty, Qty::ZERO);
+        // the next tranche of the iceberg is behind order 2
+        let ids = bids.iter().map(|order| order.id()).collect::<Vec<_>>();
+        assert_eq!(ids, [2, 1, 3]);
+    }
+
+    #[test]
+    fn refreshed_iceberg_loses_priority() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(10), Qty::from(6)).with_display_qty(Some(Qty::from(2))),
+            Order::new(2, BigDecimal::from(10), Qty::from(2)),
+        ];
+        let config = AuctionConfig::default();
+        // both rested for a batch
+        calculate_batch(&mut bids, &mut vec![], &config);
+
+        // the shown part of the iceberg goes first, the new one is as old as a new order
+        let mut asks = vec![Order::new(3, BigDecimal::from(10), Qty::from(2))];
+        let result = calculate_batch(&mut bids, &mut asks, &config);
+        assert_eq!(result.residual.bid_qty, Qty::from(6));
+        assert_eq!(bids[0].id(), 2);
+        assert_eq!(bids[1].id(), 1);
+        assert_eq!(bids[1].visible_qty(), Qty::from(2));
+
+        // then the order that was behind it
+        let mut asks = vec![Order::new(4, BigDecimal::from(10), Qty::from(2))];
+        calculate_batch(&mut bids, &mut asks, &config);
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].id(), 1);
+        assert_eq!(bids[0].qty(), Qty::from(4));
+    }
+
+    #[test]
+    fn order_below_min_qty_is_left_resting() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(11), Qty::from(4)),
+            Order::new(2, BigDecimal::from(10), Qty::from(5)).with_min_qty(Some(Qty::from(3))),
+        ];
+        let mut asks = vec![Order::new(3, BigDecimal::from(10), Qty::from(6))];
+
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+
+        // order 2 would get 2 lots only, so order 1 trades alone
+        if let BatchReport::Trade {
+            qty, cleared_bids, ..
+        } = result.report
+        {
+            assert_eq!(qty, Qty::from(4));
+            assert_eq!(
+                cleared_bids.iter().map(|o| o.order_id).collect::<Vec<_>>(),
+                vec![1]
+            );
         } else {
-            // no more orders with suitable price
-            break;
+            panic!();
         }
+        assert!(result.cancelled.is_empty());
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].id, 2);
+        assert_eq!(bids[0].qty, Qty::from(5));
+        assert_eq!(asks[0].qty, Qty::from(2));
     }
-    cleared
-}
 
-fn orders_to_curve_segments(orders: &[Order]) -> Vec<Segment> {
-    let mut segments: HashMap<BigDecimal, Qty> = HashMap::new();
+    #[test]
+    fn min_qty_is_met_by_full_fill_of_remainder() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(10), Qty::from(2)).with_min_qty(Some(Qty::from(3)))
+        ];
+        let mut asks = vec![Order::new(2, BigDecimal::from(10), Qty::from(6))];
 
-    let mut max_q = 0;
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
 
-    for order in orders {
-        max_q += order.qty;
-        segments.insert(order.price.clone(), max_q);
+        assert!(matches!(result.report, BatchReport::Trade { qty, .. } if qty == Qty::from(2)));
+        assert!(bids.is_empty());
     }
 
-    let mut segments: Vec<Segment> = segments
-        .into_iter()
-        .map(|(p, q)| Segment { price: p, q_max: q })
-        .collect();
-
-    segments.sort_unstable_by(|s1, s2| s1.q_max.cmp(&s2.q_max));
+    #[test]
+    fn no_trade_reasons() {
+        let reason = |bids: &[(u32, Qty)], asks: &[(u32, Qty)]| {
+            let orders = |side: &[(u32, Qty)]| {
+                side.iter()
+                    .enumerate()
+                    .map(|(id, &(price, qty))| Order::new(id as u64, BigDecimal::from(price), qty))
+                    .collect::<Vec<_>>()
+            };
+            match calculate_batch(
+                &mut orders(bids),
+                &mut orders(asks),
+                &AuctionConfig::default(),
+            )
+            .report
+            {
+                BatchReport::NoTrade { reason, .. } => reason,
+                _ => panic!(),
+            }
+        };
 
-    segments
-}
+        assert_eq!(reason(&[], &[]), NoTradeReason::EmptyBook);
+        assert_eq!(reason(&[(10, Qty::from(5))], &[]), NoTradeReason::OneSided);
+        assert_eq!(reason(&[], &[(10, Qty::from(5))]), NoTradeReason::OneSided);
+        assert_eq!(
+            reason(&[(9, Qty::from(5))], &[(10, Qty::from(5))]),
+            NoTradeReason::NoCross
+        );
+    }
 
-/**
-* params: sorted curve's segments
-* returns: p*, q*
-*/
-fn intersect_demand_supply(demand: &[Segment], supply: &[Segment]) -> Option<(BigDecimal, Qty)> {
-    let mut idx_demand: usize = 0;
-    let mut idx_supply: usize = 0;
+    #[test]
+    fn market_buy_clears_at_best_ask() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(9), Qty::from(5)),
+            Order::market(2, Qty::from(3)),
+        ];
+        let mut asks = vec![
+            Order::new(3, BigDecimal::from(10), Qty::from(2)),
+            Order::new(4, BigDecimal::from(11), Qty::from(4)),
+        ];
 
-    let mut idx_next_demand: usize = 0;
-    let mut idx_next_supply: usize = 0;
+        if let BatchReport::Trade {
+            price,
+            qty,
+            cleared_bids,
+            ..
+        } = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default()).report
+        {
+            // the market bid takes the marginal ask price
+            assert_eq!(price, BigDecimal::from(11));
+            assert_eq!(qty, Qty::from(3));
+            assert_eq!(
+                cleared_bids.iter().map(|o| o.order_id).collect::<Vec<_>>(),
+                vec![2]
+            );
+        } else {
+            panic!();
+        }
+        assert_eq!(bids.len(), 1);
+        assert_eq!(
+            asks.iter().map(|o| o.qty).collect::<Vec<_>>(),
+            vec![Qty::from(3)]
+        );
+    }
 
-    let size_demand: usize = demand.len();
-    let size_supply: usize = supply.len();
+    #[test]
+    fn market_orders_are_the_aggressive_end_of_the_curve() {
+        let orders = vec![
+            Order::new(1, BigDecimal::from(10), Qty::from(2)),
+            Order::market(2, Qty::from(3)),
+        ];
 
-    // no trades: no orders for side
-    if demand.is_empty() || supply.is_empty() {
-        return None;
-    }
+        let mut demand = vec![];
+        orders_to_curve_segments(&orders, Side::Bid, &mut demand).unwrap();
+        assert_eq!(demand[0].price, None);
+        assert_eq!(demand[0].q_max, Qty::from(3));
+        assert_eq!(demand[1].q_max, Qty::from(5));
 
-    // no trades: highest bid is lower than the lowes ask
-    if demand[idx_demand].price < supply[idx_supply].price {
-        return None;
+        let mut supply = vec![];
+        orders_to_curve_segments(&orders, Side::Ask, &mut supply).unwrap();
+        assert_eq!(supply[0].price, None);
+        assert_eq!(supply[1].price, Some(&BigDecimal::from(10)));
     }
 
-    let mut q_star: Qty = 0;
-
-    while idx_next_demand < size_demand && idx_next_supply < size_supply {
-        let seg_demand = &demand[idx_next_demand];
-        let seg_supply = &supply[idx_next_supply];
+    #[test]
+    fn market_orders_alone_have_no_price() {
+        let mut bids: Vec<Order> = vec![Order::market(1, Qty::from(5))];
+        let mut asks = vec![Order::market(2, Qty::from(5))];
 
-        //check price for this segments
-        if seg_supply.price > seg_demand.price {
-            break;
-        } else {
-            // before intersection
-            idx_demand = idx_next_demand;
-            idx_supply = idx_next_supply;
-            // move along demand if it's strictly shorter
-            if seg_demand.q_max < seg_supply.q_max {
-                idx_next_demand += 1;
-            } else {
-                // move along supply
-                idx_next_supply += 1;
+        assert!(matches!(
+            calculate_batch(&mut bids, &mut asks, &AuctionConfig::default()).report,
+            BatchReport::NoTrade {
+                reason: NoTradeReason::NoReferencePrice,
+                ..
             }
-        }
-
-        // q_star keeps the right edge
-        q_star = min(seg_demand.q_max, seg_supply.q_max);
+        ));
     }
 
-    Some((
-        (&demand[idx_demand].price + &supply[idx_supply].price) / 2,
-        q_star,
-    ))
-}
+    #[test]
+    fn amend_down_keeps_priority() {
+        let mut orders = vec![Order::new(1, BigDecimal::from(10), Qty::from(5))];
+        age_orders(&mut orders);
 
-#[cfg(test)]
-mod tests {
-    use crate::auction::{
-        calculate_batch, intersect_demand_supply, orders_to_curve_segments, BatchReport, Order,
-        Segment,
-    };
-    use bigdecimal::{BigDecimal, FromPrimitive};
-    use rand::Rng;
-    use std::str::FromStr;
+        assert!(!orders[0].amend(BigDecimal::from(10), Qty::from(3)));
+
+        assert_eq!(orders[0].qty, Qty::from(3));
+        assert_eq!(orders[0].batches_out, 1);
+    }
 
     #[test]
-    fn supply_demand_intersect_horizontally() {
-        let bids = [
-            Segment {
-                price: BigDecimal::from_f32(7.0).unwrap(),
-                q_max: 2,
-            },
-            Segment {
-                price: BigDecimal::from_f32(6.0).unwrap(),
-                q_max: 3,
-            },
-            Segment {
-                price: BigDecimal::from_f32(5.0).unwrap(),
-                q_max: 6,
-            },
-            Segment {
-                price: BigDecimal::from_f32(3.0).unwrap(),
-                q_max: 8,
-            },
+    fn amend_price_or_up_resets_priority() {
+        let mut orders = vec![
+            Order::new(1, BigDecimal::from(10), Qty::from(5)),
+            Order::new(2, BigDecimal::from(10), Qty::from(5)),
         ];
+        age_orders(&mut orders);
 
-        let asks = [
-            Segment {
-                price: BigDecimal::from_f32(2.0).unwrap(),
-                q_max: 1,
-            },
-            Segment {
-                price: BigDecimal::from_f32(3.0).unwrap(),
-                q_max: 2,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(5.0).unwrap(),
-                q_max: 5,
-            },
-            Segment {
-                price: BigDecimal::from_f32(7.0).unwrap(),
-                q_max: 8,
-            },
-        ];
+        assert!(orders[0].amend(BigDecimal::from(11), Qty::from(5)));
+        assert!(orders[1].amend(BigDecimal::from(10), Qty::from(6)));
 
-        if let Some((p_star, q_star)) = intersect_demand_supply(&bids, &asks) {
-            assert_eq!(q_star, 5);
-            assert_eq!(p_star, BigDecimal::from(5));
-        }
+        assert_eq!(orders[0].price, Some(BigDecimal::from(11)));
+        assert_eq!(orders[0].batches_out, 0);
+        assert_eq!(orders[1].qty, Qty::from(6));
+        assert_eq!(orders[1].batches_out, 0);
     }
 
     #[test]
-    fn supply_demand_intersect_vertically() {
-        let bids = [
-            Segment {
-                price: BigDecimal::from_f32(8.0).unwrap(),
-                q_max: 2,
-            },
-            Segment {
-                price: BigDecimal::from_f32(6.0).unwrap(),
-                q_max: 3,
-            },
-            Segment {
-                price: BigDecimal::from_f32(5.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 6,
-            },
-            Segment {
-                price: BigDecimal::from_f32(1.0).unwrap(),
-                q_max: 8,
-            },
+    fn equal_orders_clear_in_id_order() {
+        let mut bids = vec![
+            Order::new(3, BigDecimal::from(10), Qty::from(1)),
+            Order::new(1, BigDecimal::from(10), Qty::from(1)),
+            Order::new(2, BigDecimal::from(10), Qty::from(1)),
         ];
+        let mut asks = vec![Order::new(4, BigDecimal::from(10), Qty::from(2))];
 
-        let asks = [
-            Segment {
-                price: BigDecimal::from_f32(2.0).unwrap(),
-                q_max: 1,
-            },
-            Segment {
-                price: BigDecimal::from_f32(3.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(6.0).unwrap(),
-                q_max: 5,
-            },
-            Segment {
-                price: BigDecimal::from_f32(7.0).unwrap(),
-                q_max: 8,
-            },
-        ];
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
 
-        if let Some((p_star, q_star)) = intersect_demand_supply(&bids, &asks) {
-            assert_eq!(q_star, 4);
-            assert_eq!(p_star, BigDecimal::from(4));
+        match result.report {
+            BatchReport::Trade { cleared_bids, .. } => {
+                let ids: Vec<_> = cleared_bids.iter().map(|o| o.order_id).collect();
+                assert_eq!(ids, vec![1, 2]);
+            }
+            _ => panic!(),
         }
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].id, 3);
     }
 
     #[test]
-    fn supply_demand_intersect_in_one_dot() {
-        let bids = [
-            Segment {
-                price: BigDecimal::from_f32(7.0).unwrap(),
-                q_max: 2,
-            },
-            Segment {
-                price: BigDecimal::from_f32(5.0).unwrap(),
-                q_max: 3,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(3.0).unwrap(),
-                q_max: 6,
-            },
-            Segment {
-                price: BigDecimal::from_f32(1.0).unwrap(),
-                q_max: 9,
-            },
+    fn stale_orders_of_a_crossed_book_are_removed() {
+        let price = |p: &str| BigDecimal::from_str(p).unwrap();
+        let mut bids = vec![
+            Order::new(1, price("20"), Qty::from(1)),
+            Order::new(2, price("10.5"), Qty::from(2)),
+            Order::market(3, Qty::from(1)),
         ];
-
-        let asks = [
-            Segment {
-                price: BigDecimal::from_f32(1.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(2.0).unwrap(),
-                q_max: 7,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 9,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 9,
-            },
+        let mut asks = vec![
+            Order::new(4, price("10"), Qty::from(2)),
+            Order::new(5, price("8"), Qty::from(1)),
         ];
+        bids[0].batches_out = 3;
+        asks[1].batches_out = 5;
+        let config = AuctionConfig {
+            max_cross: Some(price("1")),
+            ..AuctionConfig::default()
+        };
+
+        let result = calculate_batch(&mut bids, &mut asks, &config);
 
-        if let Some((p_star, q_star)) = intersect_demand_supply(&bids, &asks) {
-            assert_eq!(q_star, 6);
-            assert_eq!(p_star, BigDecimal::from_f32(2.5).unwrap())
+        // the ask at 8 rested longer than the bid at 20, which rested longer than the ask at 10
+        let crossed: Vec<_> = result.crossed.iter().map(|o| o.id).collect();
+        assert_eq!(crossed, [5, 1]);
+        match result.report {
+            BatchReport::Trade { price: p, qty, .. } => {
+                assert_eq!(p, price("10.25"));
+                assert_eq!(qty, Qty::from(2));
+            }
+            _ => panic!(),
         }
+        assert_eq!(bids.len(), 1);
+        assert!(asks.is_empty());
     }
 
     #[test]
-    fn supply_demand_intersect_not_enough_bids() {
-        let bids = [
-            Segment {
-                price: BigDecimal::from_f32(7.0).unwrap(),
-                q_max: 2,
-            },
-            Segment {
-                price: BigDecimal::from_f32(5.0).unwrap(),
-                q_max: 3,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(3.0).unwrap(),
-                q_max: 5,
-            },
-        ];
-
-        let asks = [
-            Segment {
-                price: BigDecimal::from_f32(1.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(2.0).unwrap(),
-                q_max: 7,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 9,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 9,
-            },
+    fn batch_report_serde_round_trip() {
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from_str("10.10").unwrap(), Qty::from(3)).with_client(7),
+            Order::new(2, BigDecimal::from_str("9.90").unwrap(), Qty::from(1)),
         ];
+        let mut asks = vec![Order::new(
+            3,
+            BigDecimal::from_str("9.95").unwrap(),
+            Qty::from(2),
+        )];
+        let report = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default()).report;
 
-        if let Some((p_star, q_star)) = intersect_demand_supply(&bids, &asks) {
-            assert_eq!(q_star, 5);
-            assert_eq!(p_star, BigDecimal::from_f32(2.5).unwrap());
+        let json = serde_json::to_string(&report).unwrap();
+        // exact decimals
+        assert!(json.contains(r#""price":"10.03""#), "{}", json);
+        let parsed: BatchReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        match parsed {
+            BatchReport::Trade {
+                cleared_bids,
+                cleared_asks,
+                ..
+            } => {
+                assert_eq!(cleared_bids.len(), 1);
+                assert_eq!(cleared_bids[0].client_id, 7);
+                assert_eq!(cleared_bids[0].remaining, Qty::from(1));
+                assert_eq!(cleared_asks[0].order_id, 3);
+            }
+            _ => panic!(),
         }
+
+        // the priority of the resting orders survives
+        let json = serde_json::to_string(&bids).unwrap();
+        let parsed: Vec<Order> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, bids);
+        assert_eq!(parsed[0].batches_out, 1);
     }
 
     #[test]
-    fn supply_demand_intersect_no_bids() {
-        let bids = [];
-
-        let asks = [
-            Segment {
-                price: BigDecimal::from_f32(1.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(2.0).unwrap(),
-                q_max: 7,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 9,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 9,
-            },
+    fn equal_orders_clear_in_submission_order() {
+        // order 1 was amended after 2 and 3 arrived
+        let mut bids = vec![
+            Order::new(1, BigDecimal::from(10), Qty::from(1)).with_submitted(6),
+            Order::new(2, BigDecimal::from(10), Qty::from(1)).with_submitted(4),
+            Order::new(3, BigDecimal::from(10), Qty::from(1)).with_submitted(5),
         ];
+        let mut asks = vec![Order::new(4, BigDecimal::from(10), Qty::from(2))];
 
-        assert!(intersect_demand_supply(&bids, &asks).is_none())
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+
+        match result.report {
+            BatchReport::Trade { cleared_bids, .. } => {
+                let ids: Vec<_> = cleared_bids.iter().map(|o| o.order_id).collect();
+                assert_eq!(ids, vec![2, 3]);
+            }
+            _ => panic!(),
+        }
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].id, 1);
     }
 
     #[test]
-    fn supply_demand_intersect_no_asks() {
-        let bids = [
-            Segment {
-                price: BigDecimal::from_f32(7.0).unwrap(),
-                q_max: 2,
-            },
-            Segment {
-                price: BigDecimal::from_f32(5.0).unwrap(),
-                q_max: 3,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(3.0).unwrap(),
-                q_max: 5,
+    fn fees_for_makers_and_takers() {
+        let config = AuctionConfig {
+            fees: FeeSchedule {
+                maker_bps: BigDecimal::from(-2),
+                taker_bps: BigDecimal::from(5),
             },
-        ];
+            ..Default::default()
+        };
+        let mut bids = vec![Order::new(1, BigDecimal::from(100), Qty::from(10))];
+        let mut asks = vec![];
+        // the bid rests for a batch and becomes a maker
+        calculate_batch(&mut bids, &mut asks, &config);
+        asks.push(Order::new(2, BigDecimal::from(100), Qty::from(4)));
 
-        let asks = [];
+        let result = calculate_batch(&mut bids, &mut asks, &config);
 
-        assert!(intersect_demand_supply(&bids, &asks).is_none())
+        match result.report {
+            BatchReport::Trade {
+                cleared_bids,
+                cleared_asks,
+                ..
+            } => {
+                let fills: Vec<_> = cleared_bids.iter().chain(&cleared_asks).collect();
+                // 100 * 4 = 400 traded, -2 bps rebate for the bid, 5 bps fee for the ask
+                assert_eq!(fills.len(), 2);
+                assert!(fills[0].maker);
+                assert_eq!(fills[0].fee, BigDecimal::from_str("-0.08").unwrap());
+                assert!(!fills[1].maker);
+                assert_eq!(fills[1].fee, BigDecimal::from_str("0.20").unwrap());
+            }
+            _ => panic!(),
+        }
     }
 
     #[test]
-    fn supply_demand_intersect_no_intersect_possible() {
-        let asks = [
-            Segment {
-                price: BigDecimal::from_f32(3.0).unwrap(),
-                q_max: 2,
-            },
-            Segment {
-                price: BigDecimal::from_f32(4.0).unwrap(),
-                q_max: 4,
-            },
-            Segment {
-                price: BigDecimal::from_f32(5.0).unwrap(),
-                q_max: 5,
-            },
-        ];
+    fn fixed_price_clears_like_decimal() {
+        use crate::price::{FixedPrice, Price};
 
-        let bids = [
-            Segment {
-                price: BigDecimal::from_f32(2.0).unwrap(),
-                q_max: 1,
-            },
-            Segment {
-                price: BigDecimal::from_f32(1.0).unwrap(),
-                q_max: 5,
-            },
-        ];
+        let orders = |prices: &[&str]| -> Vec<Order> {
+            prices
+                .iter()
+                .enumerate()
+                .map(|(id, price)| {
+                    Order::new(
+                        id as u64,
+                        BigDecimal::from_str(price).unwrap(),
+                        Qty::from(3),
+                    )
+                })
+                .collect()
+        };
+        let fixed = |orders: &[Order]| -> Vec<Order<FixedPrice<2>>> {
+            orders
+                .iter()
+                .map(|order| {
+                    let price = FixedPrice::from_decimal(order.price.as_ref().unwrap());
+                    Order::new(order.id, price, order.qty)
+                })
+                .collect()
+        };
+        let mut bids = orders(&["10.05", "10.00", "9.95"]);
+        let mut asks = orders(&["9.90", "10.00", "10.10"]);
+        let mut fixed_bids = fixed(&bids);
+        let mut fixed_asks = fixed(&asks);
 
-        assert!(intersect_demand_supply(&bids, &asks).is_none())
+        let config = AuctionConfig {
+            clearing_price_rule: ClearingPriceRule::WeightedMid,
+            ..AuctionConfig::default()
+        };
+        let decimal = calculate_batch(&mut bids, &mut asks, &config).report;
+        let fixed = calculate_batch(&mut fixed_bids, &mut fixed_asks, &config).report;
+        match (decimal, fixed) {
+            (
+                BatchReport::Trade {
+                    price,
+                    qty,
+                    cleared_bids,
+                    ..
+                },
+                BatchReport::Trade {
+                    price: fixed_price,
+                    qty: fixed_qty,
+                    cleared_bids: fixed_bids,
+                    ..
+                },
+            ) => {
+                assert_eq!(fixed_price.to_decimal(), price);
+                assert_eq!(fixed_qty, qty);
+                assert_eq!(fixed_bids.len(), cleared_bids.len());
+            }
+            _ => panic!(),
+        }
+        assert_eq!(fixed_bids.len(), bids.len());
+        assert_eq!(fixed_asks.len(), asks.len());
     }
 
     #[test]
-    fn orders_to_curve_segments_converts_correctly() {
-        let orders = vec![
-            Order::new(BigDecimal::from_str("111.69").unwrap(), 3),
-            Order::new(BigDecimal::from_str("111.69").unwrap(), 3),
-            Order::new(BigDecimal::from_str("111.69").unwrap(), 4),
-            Order::new(BigDecimal::from_str("111.00").unwrap(), 1),
-            Order::new(BigDecimal::from_str("110.97").unwrap(), 2),
-            Order::new(BigDecimal::from_str("110.97").unwrap(), 1),
-        ];
-
-        let mut segments = orders_to_curve_segments(&orders);
-        assert_eq!(segments.len(), 3);
+    fn reused_workspace_gives_the_same_batches() {
+        let batches = || {
+            vec![
+                (
+                    vec![
+                        Order::new(1, BigDecimal::from(11), Qty::from(4)),
+                        Order::new(2, BigDecimal::from(10), Qty::from(5))
+                            .with_min_qty(Some(Qty::from(3))),
+                    ],
+                    vec![Order::new(3, BigDecimal::from(10), Qty::from(6))],
+                ),
+                (
+                    // unsorted, so the curves aren't built on the fast path
+                    vec![
+                        Order::new(4, BigDecimal::from(9), Qty::from(3)),
+                        Order::new(5, BigDecimal::from(12), Qty::from(2)),
+                    ],
+                    vec![
+                        Order::new(6, BigDecimal::from(11), Qty::from(1)),
+                        Order::new(7, BigDecimal::from(8), Qty::from(3)),
+                    ],
+                ),
+                (
+                    vec![],
+                    vec![Order::new(8, BigDecimal::from(10), Qty::from(1))],
+                ),
+            ]
+        };
 
-        assert_eq!(
-            segments.get(0).unwrap().price,
-            BigDecimal::from_str("111.69").unwrap()
-        );
-        assert_eq!(segments.get(0).unwrap().q_max, 10);
+        let config = AuctionConfig::default();
+        let mut workspace = BatchWorkspace::default();
+        for ((mut bids, mut asks), (mut fresh_bids, mut fresh_asks)) in
+            batches().into_iter().zip(batches())
+        {
+            let reused = calculate_batch_with(&mut bids, &mut asks, &config, &mut workspace);
+            let fresh = calculate_batch(&mut fresh_bids, &mut fresh_asks, &config);
+            let summary = |report: &BatchReport| {
+                report
+                    .fills()
+                    .map(|fill| (fill.order_id, fill.qty, fill.remaining))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(summary(&reused.report), summary(&fresh.report));
+            match (reused.report, fresh.report) {
+                (
+                    BatchReport::Trade { price, qty, .. },
+                    BatchReport::Trade {
+                        price: fresh_price,
+                        qty: fresh_qty,
+                        ..
+                    },
+                ) => {
+                    assert_eq!(price, fresh_price);
+                    assert_eq!(qty, fresh_qty);
+                }
+                (
+                    BatchReport::NoTrade { reason, .. },
+                    BatchReport::NoTrade { reason: fresh, .. },
+                ) => {
+                    assert_eq!(reason, fresh)
+                }
+                _ => panic!("different reports"),
+            }
+            assert_eq!(bids, fresh_bids);
+            assert_eq!(asks, fresh_asks);
+        }
+    }
 
-        assert_eq!(
-            segments.get(1).unwrap().price,
-            BigDecimal::from_str("111.00").unwrap()
-        );
-        assert_eq!(segments.get(1).unwrap().q_max, 11);
+    #[test]
+    fn coincident_steps_clear_at_the_whole_range() {
+        // both curves step at 5 lots, which trade at any price from 8 to 12
+        let price = |clearing_price_rule| {
+            let mut bids = vec![
+                Order::new(1, BigDecimal::from(12), Qty::from(5)),
+                Order::new(2, BigDecimal::from(9), Qty::from(3)),
+            ];
+            let mut asks = vec![
+                Order::new(3, BigDecimal::from(8), Qty::from(5)),
+                Order::new(4, BigDecimal::from(10), Qty::from(3)),
+            ];
+            let config = AuctionConfig {
+                clearing_price_rule,
+                ..AuctionConfig::default()
+            };
+            match calculate_batch(&mut bids, &mut asks, &config).report {
+                BatchReport::Trade { price, qty, .. } => {
+                    assert_eq!(qty, Qty::from(5));
+                    price
+                }
+                _ => panic!(),
+            }
+        };
 
-        assert_eq!(
-            segments.get(2).unwrap().price,
-            BigDecimal::from_str("110.97").unwrap()
-        );
-        assert_eq!(segments.get(2).unwrap().q_max, 14);
+        // the ask of 10 used to be taken as the marginal one, for a price of 11
+        assert_eq!(price(ClearingPriceRule::Midpoint), BigDecimal::from(10));
+        assert_eq!(price(ClearingPriceRule::BidSide), BigDecimal::from(12));
+        assert_eq!(price(ClearingPriceRule::AskSide), BigDecimal::from(8));
     }
 
     #[test]
-    fn calculate_batch_with_trades_correctly() {
+    fn post_only_bid_into_the_cross_is_rejected() {
         let mut bids = vec![
-            Order::new(BigDecimal::from_str("112").unwrap(), 2),
-            Order::new(BigDecimal::from_str("111.76").unwrap(), 21),
-            Order::new(BigDecimal::from_str("111.45").unwrap(), 200),
-            Order::new(BigDecimal::from_str("111.35").unwrap(), 100),
+            Order::new(1, BigDecimal::from(11), Qty::from(3)).with_post_only(true),
+            Order::new(2, BigDecimal::from(10), Qty::from(2)),
         ];
+        let mut asks = vec![Order::new(3, BigDecimal::from(10), Qty::from(3))];
 
-        let mut asks = vec![
-            Order::new(BigDecimal::from_str("110").unwrap(), 2),
-            Order::new(BigDecimal::from_str("111.32").unwrap(), 21),
-            Order::new(BigDecimal::from_str("111.45").unwrap(), 100),
-            Order::new(BigDecimal::from_str("112.35").unwrap(), 100),
-        ];
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
 
-        if let BatchReport::Trade {
-            price,
-            qty,
-            cleared_bids,
-            cleared_asks,
-        } = calculate_batch(&mut bids, &mut asks)
-        {
-            assert_eq!(price, BigDecimal::from_str("111.45").unwrap());
-            assert_eq!(qty, 123);
-        } else {
-            panic!();
+        // the batch clears without it, as if it was never sent
+        match result.report {
+            BatchReport::Trade {
+                qty, cleared_bids, ..
+            } => {
+                assert_eq!(qty, Qty::from(2));
+                assert_eq!(cleared_bids.len(), 1);
+                assert_eq!(cleared_bids[0].order_id, 2);
+            }
+            _ => panic!("expected a trade"),
         }
-    }
-}
-
-extern crate test;
-use rand::Rng;
-use std::str::FromStr;
-use test::Bencher;
+        assert_eq!(result.rejected.len(), 1);
+        assert_eq!(result.rejected[0].id, 1);
+        assert_eq!(result.rejected[0].qty, Qty::from(3));
+        assert!(result.cancelled.is_empty());
+        assert!(bids.is_empty());
 
-#[bench]
-fn batch(b: &mut Bencher) {
-    let mut rng = rand::thread_rng();
-
-    let mut bids: Vec<Order> = vec![];
-    let mut asks: Vec<Order> = vec![];
-
-    // pretty heterogeneous data
-    for _ in 0..125000 {
-        let random_price: f32 = rng.gen_range(140.0..150.0);
-        let random_qty: u32 = rng.gen_range(1..200);
-        let order = Order::new(
-            BigDecimal::from_f32(random_price).unwrap().round(3),
-            random_qty,
-        );
-        bids.push(order.clone());
-        asks.push(order.clone());
+        // below the asks it rests, and trades as a maker once it has
+        let mut bids = vec![Order::new(4, BigDecimal::from(9), Qty::from(1)).with_post_only(true)];
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+        assert!(result.rejected.is_empty());
+        assert_eq!(bids.len(), 1);
+        let mut asks = vec![Order::new(5, BigDecimal::from(9), Qty::from(1))];
+        let result = calculate_batch(&mut bids, &mut asks, &AuctionConfig::default());
+        assert!(result.rejected.is_empty());
+        assert!(result
+            .report
+            .fills()
+            .any(|fill| fill.order_id == 4 && fill.maker));
     }
-
-    b.iter(|| calculate_batch(&mut bids, &mut asks))
 }
@@ -0,0 +1,279 @@
+/*!
+Per-symbol settings file in TOML, a `[SYMBOL]` table per symbol. Decimals may be quoted
+to keep more digits than a TOML float has.
+
+```toml
+[AAPL]
+tick_size = 0.05
+max_qty = 1000
+
+[POWER]
+allow_negative_price = true
+price_scale = 1
+```
+
+Also the API keys file, see `parse_api_keys`.
+*/
+
+use crate::auction::Qty;
+use crate::{ClientId, OrderLimits, SymbolConfig};
+use bigdecimal::BigDecimal;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+// a table of the file, unset keys fall back to the global settings
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SymbolTable {
+    tick_size: Option<Decimal>,
+    price_scale: Option<i64>,
+    #[serde(default)]
+    allow_negative_price: bool,
+    max_qty: Option<Decimal>,
+    max_notional: Option<Decimal>,
+    price_band_pct: Option<Decimal>,
+}
+
+// a quoted decimal, an integer or a float, the latter as the shortest decimal that reads back as it
+struct Decimal(BigDecimal);
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        struct DecimalVisitor;
+
+        impl Visitor<'_> for DecimalVisitor {
+            type Value = Decimal;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a decimal")
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+                Ok(Decimal(BigDecimal::from(value)))
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+                self.visit_str(&value.to_string())
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+                BigDecimal::from_str(value)
+                    .map(Decimal)
+                    .map_err(|_| E::custom(format!("bad decimal {}", value)))
+            }
+        }
+
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+}
+
+// reads and parses the file, the errors name the path
+pub fn load_symbols(
+    path: &Path,
+    max_price_scale: i64,
+) -> Result<HashMap<String, SymbolConfig>, String> {
+    let text = read(path)?;
+    parse_symbols(&text, max_price_scale).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn load_api_keys(path: &Path) -> Result<HashMap<String, ClientId>, String> {
+    let text = read(path)?;
+    parse_api_keys(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))
+}
+
+/**
+A `<client id>,<api key>` line per key and `#` comments. The ids are in the file, so the orders
+of a client keep their owner across restarts. A client may have several keys, e.g. while one
+replaces another, but a key can't be listed twice.
+*/
+pub fn parse_api_keys(text: &str) -> Result<HashMap<String, ClientId>, String> {
+    let mut keys = HashMap::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |e: String| format!("line {}: {}", idx + 1, e);
+        let (id, key) = line
+            .split_once(',')
+            .ok_or_else(|| error(format!("expected <client id>,<api key>, got {}", line)))?;
+        let id = match id.trim().parse::<ClientId>() {
+            Ok(id) if id > 0 => id,
+            _ => return Err(error(format!("bad client id {}", id))),
+        };
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(error("empty api key".to_string()));
+        }
+        if keys.insert(key.to_string(), id).is_some() {
+            return Err(error(format!("the key of client {} is listed twice", id)));
+        }
+    }
+    Ok(keys)
+}
+
+/**
+Price scales are at most `max_price_scale`, the global one: prices are parsed at the global scale
+and rounded to the scale of their symbol. An unknown key or a symbol listed twice is an error.
+*/
+pub fn parse_symbols(
+    text: &str,
+    max_price_scale: i64,
+) -> Result<HashMap<String, SymbolConfig>, String> {
+    let tables: HashMap<String, SymbolTable> = toml::from_str(text).map_err(|e| e.to_string())?;
+    tables
+        .into_iter()
+        .map(|(symbol, table)| {
+            if symbol.is_empty() {
+                return Err("empty symbol".to_string());
+            }
+            let config = symbol_config(table, max_price_scale)
+                .map_err(|e| format!("[{}]: {}", symbol, e))?;
+            Ok((symbol, config))
+        })
+        .collect()
+}
+
+fn symbol_config(table: SymbolTable, max_price_scale: i64) -> Result<SymbolConfig, String> {
+    let positive = |key: &str, value: Option<Decimal>| match value {
+        Some(Decimal(decimal)) if decimal <= BigDecimal::from(0) => Err(format!(
+            "{} must be a positive decimal, got {}",
+            key, decimal
+        )),
+        value => Ok(value.map(|Decimal(decimal)| decimal)),
+    };
+    let price_scale = match table.price_scale {
+        Some(scale) if !(0..=max_price_scale).contains(&scale) => {
+            return Err(format!(
+                "price_scale must be between 0 and {}, got {}",
+                max_price_scale, scale
+            ))
+        }
+        scale => scale,
+    };
+    let max_qty = match table.max_qty {
+        Some(Decimal(decimal)) => Some(
+            Qty::from_decimal(&decimal)
+                .ok_or_else(|| format!("max_qty must be a quantity, got {}", decimal))?,
+        ),
+        None => None,
+    };
+    Ok(SymbolConfig {
+        allow_negative_price: table.allow_negative_price,
+        tick_size: positive("tick_size", table.tick_size)?,
+        price_scale,
+        limits: OrderLimits {
+            max_qty,
+            max_notional: positive("max_notional", table.max_notional)?,
+            price_band: positive("price_band_pct", table.price_band_pct)?,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auction::{AuctionConfig, BatchReport, Qty, TimeInForce};
+    use crate::config::{parse_api_keys, parse_symbols};
+    use crate::{apply_command, run_batches, Command, Exchange, OrderType};
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+
+    fn decimal(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn symbols_from_file() {
+        let text = r#"
+            # fat fingers
+            [AAPL]
+            tick_size = 0.05
+            max_qty = "1000"
+
+            ["POWER#2"]
+            allow_negative_price = true # spreads
+            price_scale = 1
+            price_band_pct = "2.5"
+        "#;
+        let symbols = parse_symbols(text, 2).unwrap();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols["AAPL"].tick_size, Some(decimal("0.05")));
+        assert_eq!(symbols["AAPL"].limits.max_qty, Some(Qty::from(1000)));
+        assert!(!symbols["AAPL"].allow_negative_price);
+        // a # in a quoted name is not a comment
+        assert!(symbols["POWER#2"].allow_negative_price);
+        assert_eq!(symbols["POWER#2"].price_scale, Some(1));
+        assert_eq!(symbols["POWER#2"].limits.price_band, Some(decimal("2.5")));
+
+        assert!(parse_symbols("tick_size = 1", 2).is_err());
+        assert!(parse_symbols("[A]\ntick = 1", 2).is_err());
+        assert!(parse_symbols("[A]\ntick_size = 0", 2).is_err());
+        assert!(parse_symbols("[A]\nprice_scale = 3", 2).is_err());
+        assert!(parse_symbols("[A]\n[A]", 2).is_err());
+        assert!(parse_symbols("[A]\nmax_qty", 2).is_err());
+        assert!(parse_symbols("[A]\nmax_qty = 0.000000001", 2).is_err());
+    }
+
+    #[test]
+    fn api_keys_from_file() {
+        let text = "# desk\n1,k3y-one\n2, k3y-two # rotated\n2,k3y-new\n";
+        let keys = parse_api_keys(text).unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys["k3y-one"], 1);
+        assert_eq!(keys["k3y-two"], 2);
+        assert_eq!(keys["k3y-new"], 2);
+
+        assert!(parse_api_keys("k3y").is_err());
+        assert!(parse_api_keys("0,k3y").is_err());
+        assert!(parse_api_keys("1,").is_err());
+        assert!(parse_api_keys("1,k3y\n2,k3y").is_err());
+    }
+
+    #[test]
+    fn every_book_has_its_own_tick() {
+        let text = "[AAPL]\ntick_size = 0.05\n[MSFT]\ntick_size = 0.5\n";
+        let exchange = parse_symbols(text, 2)
+            .unwrap()
+            .into_iter()
+            .fold(Exchange::new(), |exchange, (symbol, config)| {
+                exchange.with_symbol_config(&symbol, config)
+            });
+        for symbol in ["AAPL", "MSFT", "IBM"] {
+            for (order_type, price) in [(OrderType::Buy, "10.40"), (OrderType::Sell, "9.86")] {
+                let cmd = Command::Add {
+                    order_type,
+                    symbol: symbol.to_string(),
+                    time_in_force: TimeInForce::GoodTillCancel,
+                    min_qty: None,
+                    display_qty: None,
+                    expires_at: None,
+                    post_only: false,
+                    price: Some(decimal(price)),
+                    qty: Qty::from(1),
+                };
+                apply_command(&exchange, cmd, 1);
+            }
+        }
+
+        let results = run_batches(&exchange, &AuctionConfig::default());
+        let price = |symbol: &str| {
+            let (_, result) = results.iter().find(|(s, _)| s == symbol).unwrap();
+            match &result.report {
+                BatchReport::Trade { price, .. } => price.clone(),
+                _ => panic!("{} didn't trade", symbol),
+            }
+        };
+        assert_eq!(price("AAPL"), decimal("10.15"));
+        assert_eq!(price("MSFT"), decimal("10.0"));
+        // not configured, the global config has no tick
+        assert_eq!(price("IBM"), decimal("10.13"));
+    }
+}
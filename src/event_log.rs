@@ -0,0 +1,327 @@
+/*!
+Append-only log of everything that changes the books: every accepted command and every batch.
+Replaying it from empty rebuilds the books exactly, order ids and priorities included.
+
+```text
+CMD,<client id>,<command>
+BATCH,<batch number>,<unix millis>
+```
+Commands are in the message format, a disconnect that cancelled orders is `CANCEL_ALL`.
+*/
+
+use crate::auction::{AuctionConfig, TimeInForce};
+use crate::{
+    apply_command, run_batches, ClientId, Clock, Command, CommandHandler, Exchange, OrderType,
+};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+const CMD: &str = "CMD";
+const BATCH: &str = "BATCH";
+// not a message of the protocol, sent by the server when a client disconnects
+const CANCEL_ALL: &str = "CANCEL_ALL";
+
+pub struct EventLog {
+    out: Box<dyn Write + Send>,
+}
+
+impl EventLog {
+    pub fn new(out: impl Write + Send + 'static) -> EventLog {
+        EventLog { out: Box::new(out) }
+    }
+
+    // appends to the file, every event is written out as soon as it happens
+    pub fn open(path: &Path) -> io::Result<EventLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(EventLog::new(LineWriter::new(file)))
+    }
+
+    pub(crate) fn command(&mut self, client_id: ClientId, command: &str) {
+        self.write(format_args!("{},{},{}", CMD, client_id, command));
+    }
+
+    pub(crate) fn batch(&mut self, batch_seq: u64, at: SystemTime) {
+        let millis = at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+        self.write(format_args!("{},{},{}", BATCH, batch_seq, millis));
+    }
+
+    // the books don't wait for a failing disk, the gap is reported instead
+    fn write(&mut self, event: std::fmt::Arguments) {
+        if let Err(e) = writeln!(self.out, "{}", event) {
+            error!("failed to write the event log: {}", e);
+        }
+    }
+}
+
+/**
+The command as the log records it, None for queries, which don't change the books.
+Prices are as sent, replaying snaps them again like the first time.
+*/
+pub(crate) fn command_line(command: &Command) -> Option<String> {
+    let line = match command {
+        Command::Add {
+            order_type,
+            symbol,
+            time_in_force,
+            min_qty,
+            display_qty,
+            expires_at,
+            post_only,
+            price,
+            qty,
+        } => {
+            let side = match order_type {
+                OrderType::Buy => "BUY",
+                OrderType::Sell => "SELL",
+            };
+            let mut line = format!("ADD,{},{}", side, symbol);
+            match (time_in_force, price) {
+                (TimeInForce::FillOrKill, _) => line.push_str(",FOK"),
+                // market orders are IOC anyway
+                (TimeInForce::ImmediateOrCancel, Some(_)) => line.push_str(",IOC"),
+                _ => {}
+            }
+            if let Some(min_qty) = min_qty {
+                line.push_str(&format!(",MINQTY={}", min_qty));
+            }
+            if let Some(display_qty) = display_qty {
+                line.push_str(&format!(",DISPLAY={}", display_qty));
+            }
+            if let Some(expires_at) = expires_at {
+                let secs = expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                line.push_str(&format!(",GTT={}", secs));
+            }
+            if *post_only {
+                line.push_str(",POST_ONLY");
+            }
+            match price {
+                Some(price) => line.push_str(&format!(",{},{}", price, qty)),
+                None => line.push_str(&format!(",MKT,{}", qty)),
+            }
+            line
+        }
+        Command::Cancel { id } => format!("CANCEL,{}", id),
+        Command::Amend { id, price, qty } => format!("AMEND,{},{},{}", id, price, qty),
+        Command::Reduce { id, qty } => format!("REDUCE,{},{}", id, qty),
+        Command::CancelAll => CANCEL_ALL.to_string(),
+        // the client was authorized already, the token stays out of the log
+        Command::Halt { symbol, .. } => format!("HALT,{}", symbol),
+        Command::Resume { symbol, .. } => format!("RESUME,{}", symbol),
+        Command::Query(_) => return None,
+        // the sequence number only matters to the client
+        Command::Sequenced { command, .. } => return command_line(command),
+    };
+    Some(line)
+}
+
+// whether the command changed the books, rejected ones are not logged
+pub(crate) fn accepted(response: &str) -> bool {
+    // a disconnect without resting orders
+    !response.is_empty() && !response.starts_with("ERR,") && !response.starts_with("NACK,")
+}
+
+// the time of the batch being replayed, so good-till-time orders expire as they did
+struct ReplayClock(Mutex<SystemTime>);
+
+impl Clock for ReplayClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+/**
+Rebuilds the books of `exchange` from the log, which has to start with empty books.
+The exchange needs the symbol settings and `config` the auction config of the recorded session.
+A command that is rejected now was accepted then, so the books would differ and it is an error.
+New client ids continue after the ones in the log, so their orders keep their owners.
+*/
+pub fn replay(
+    log: impl BufRead,
+    mut exchange: Exchange,
+    config: &AuctionConfig,
+) -> io::Result<Exchange> {
+    let clock = Arc::new(ReplayClock(Mutex::new(UNIX_EPOCH)));
+    let live_clock = std::mem::replace(&mut exchange.clock, clock.clone());
+    // the replayed events are in the log already
+    let event_log = exchange.event_log.take();
+
+    let mut max_client_id = 0;
+    for (idx, line) in log.lines().enumerate() {
+        let line = line?;
+        let bad = |e: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", idx + 1, e),
+            )
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = line.trim().splitn(3, ',');
+        let (event, first, rest) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(event), Some(first), Some(rest)) => (event, first, rest),
+            _ => return Err(bad(format!("bad event {}", line))),
+        };
+        match event {
+            CMD => {
+                let client_id = first
+                    .parse::<ClientId>()
+                    .map_err(|_| bad(format!("bad client id {}", first)))?;
+                max_client_id = max_client_id.max(client_id);
+                let command = match rest {
+                    CANCEL_ALL => Command::CancelAll,
+                    msg => CommandHandler::parse_command(msg).map_err(|e| bad(e.to_string()))?,
+                };
+                if let Command::Query(_) = command {
+                    return Err(bad(format!("{} doesn't change the books", rest)));
+                }
+                let response = apply_command(&exchange, command, client_id);
+                if !accepted(&response) {
+                    return Err(bad(format!("{} was rejected: {}", rest, response)));
+                }
+            }
+            BATCH => {
+                let batch_seq = first
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|seq| *seq > 0)
+                    .ok_or_else(|| bad(format!("bad batch number {}", first)))?;
+                let millis = rest
+                    .parse::<u64>()
+                    .map_err(|_| bad(format!("bad time {}", rest)))?;
+                *clock.0.lock().unwrap() = UNIX_EPOCH + Duration::from_millis(millis);
+                // the batch runs with its recorded number
+                exchange
+                    .batch_seq
+                    .store(batch_seq - 1, atomic::Ordering::Relaxed);
+                run_batches(&exchange, config);
+            }
+            event => return Err(bad(format!("unknown event {}", event))),
+        }
+    }
+
+    exchange
+        .next_client_id
+        .fetch_max(max_client_id + 1, atomic::Ordering::Relaxed);
+    exchange.clock = live_clock;
+    exchange.event_log = event_log;
+    Ok(exchange)
+}
+
+// the recorded file, see `replay`
+pub fn replay_file(
+    path: &Path,
+    exchange: Exchange,
+    config: &AuctionConfig,
+) -> io::Result<Exchange> {
+    replay(io::BufReader::new(File::open(path)?), exchange, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auction::AuctionConfig;
+    use crate::event_log::{replay, EventLog, ReplayClock};
+    use crate::{apply_command, run_batches, Command, CommandHandler, Exchange};
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn submit(exchange: &Exchange, msg: &str, client_id: u64) -> String {
+        let cmd = CommandHandler::parse_command(msg).unwrap();
+        apply_command(exchange, cmd, client_id)
+    }
+
+    #[test]
+    fn replayed_log_rebuilds_the_books() {
+        let config = AuctionConfig::default();
+        let buf = SharedBuf::default();
+        let clock = Arc::new(ReplayClock(Mutex::new(
+            UNIX_EPOCH + Duration::from_secs(1_000),
+        )));
+        let live = Exchange::new()
+            .with_clock(clock.clone())
+            .with_event_log(EventLog::new(buf.clone()));
+
+        submit(&live, "ADD,BUY,AAPL,10.00,5", 1);
+        submit(&live, "ADD,BUY,AAPL,MINQTY=2,9.50,4", 2);
+        let add = CommandHandler::parse_command("ADD,SELL,AAPL,DISPLAY=1,9.00,3").unwrap();
+        let sequenced = Command::Sequenced {
+            seq: 7,
+            command: Box::new(add),
+        };
+        assert_eq!(apply_command(&live, sequenced, 2), "ACK,7,3");
+        submit(&live, "ADD,SELL,AAPL,GTT=1001,12.00,1", 1);
+        submit(&live, "ADD,SELL,MSFT,20.00,1", 3);
+        // rejected, not logged
+        assert_eq!(
+            submit(&live, "CANCEL,99", 1),
+            "ERR,UNKNOWN_ORDER,unknown order 99"
+        );
+        run_batches(&live, &config);
+
+        *clock.0.lock().unwrap() = UNIX_EPOCH + Duration::from_secs(1_001);
+        submit(&live, "AMEND,1,10.10,6", 1);
+        submit(&live, "REDUCE,2,1", 2);
+        submit(&live, "ADD,SELL,AAPL,FOK,MKT,2", 3);
+        apply_command(&live, Command::CancelAll, 3);
+        // nothing left to cancel, not logged either
+        apply_command(&live, Command::CancelAll, 3);
+        run_batches(&live, &config);
+        submit(&live, "ADD,SELL,AAPL,IOC,10.20,1", 2);
+
+        let log = buf.0.lock().unwrap().clone();
+        let text = String::from_utf8(log.clone()).unwrap();
+        assert_eq!(text.lines().count(), 12);
+        assert!(text.starts_with("CMD,1,ADD,BUY,AAPL,10.00,5\n"));
+        assert!(text.contains("CMD,2,ADD,SELL,AAPL,DISPLAY=1,9.00,3\n"));
+        assert!(text.contains("BATCH,2,1001000\n"));
+
+        let replayed = replay(log.as_slice(), Exchange::new(), &config).unwrap();
+        let books = |exchange: &Exchange| {
+            exchange
+                .books()
+                .into_iter()
+                .map(|(symbol, book)| {
+                    book.with_both_read(|bids, asks| (symbol, bids.to_vec(), asks.to_vec()))
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(books(&replayed), books(&live));
+        assert!(!books(&live)[0].1.is_empty());
+        // order and client ids go on from the same place
+        assert_eq!(
+            submit(&replayed, "ADD,BUY,AAPL,1.00,1", 9),
+            submit(&live, "ADD,BUY,AAPL,1.00,1", 9)
+        );
+        assert_eq!(
+            replayed
+                .next_client_id
+                .load(std::sync::atomic::Ordering::Relaxed),
+            4
+        );
+
+        let mut broken = log.clone();
+        broken.extend_from_slice(b"CMD,1,CANCEL,1234\n");
+        assert!(replay(broken.as_slice(), Exchange::new(), &config).is_err());
+    }
+}
@@ -0,0 +1,4928 @@
+/*!
+Frequent batch auction engine.
+
+Orders go into an [`Exchange`], one [`DoubleSidedBook`] per symbol, either as parsed [`Command`]s
+through [`apply_command`] or from TCP connections served by [`CommandHandler`].
+[`run_batches`] runs the auction for every book and returns the results.
+*/
+
+use crate::auction::{
+    calculate_batch_with, expire_orders, extend_orders, halted_batch, indicative_price,
+    insert_order, price_levels, round_price, try_immediate_cross, AuctionConfig, BatchReport,
+    BatchResult, BatchWorkspace, ClientId, Fill, NoTradeReason, Order, OrderId, Qty, Side,
+    TimeInForce,
+};
+use crate::event_log::EventLog;
+use crate::metrics::{BatchLatency, LatencyStats, Metrics};
+use crate::rate_limit::RateLimiter;
+use crate::tape::TapeEntry;
+use bigdecimal::BigDecimal;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, info_span, instrument, trace, warn};
+
+pub mod auction;
+pub mod config;
+pub mod event_log;
+pub mod metrics;
+pub mod price;
+pub mod qty;
+mod rate_limit;
+pub mod replay;
+pub mod snapshot;
+pub mod tape;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+// sent with the id of a post-only order that would have taken liquidity in the batch
+const POST_ONLY_REJECT: &str = "POST_ONLY_REJECT";
+const DEFAULT_DEPTH_LEVELS: usize = 10;
+const MAX_DEPTH_LEVELS: usize = 100;
+// batches of a symbol QUERY,STATS and the metrics are over
+const LATENCY_WINDOW: usize = 1000;
+// trades kept per symbol for QUERY,TRADES
+const TRADE_HISTORY_LEN: usize = 100;
+// orders of a client per QUERY,MYORDERS response, the rest is on the next page
+const CLIENT_ORDERS_PAGE: usize = 100;
+// filled and cancelled orders remembered for QUERY,ORDER
+const COMPLETED_ORDERS_LEN: usize = 10000;
+// bytes of a single message, far more than any valid command
+pub const DEFAULT_MAX_LINE: usize = 4096;
+
+pub struct DoubleSidedBook {
+    // queries only read the book, so they don't block each other
+    bids: RwLock<Vec<Order>>,
+    asks: RwLock<Vec<Order>>,
+    vwap: Mutex<Vwap>,
+    history: Mutex<TradeHistory>,
+    latency: Mutex<BatchLatency>,
+    // batch buffers, kept so that steady batches don't allocate them again
+    workspace: Mutex<BatchWorkspace>,
+    // None while the symbol trades
+    halt: Mutex<Option<Halt>>,
+}
+
+// how long a halted symbol sits out batches
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Halt {
+    Batches(u16),
+    UntilResumed,
+}
+
+impl DoubleSidedBook {
+    fn new() -> DoubleSidedBook {
+        DoubleSidedBook {
+            bids: RwLock::new(vec![]),
+            asks: RwLock::new(vec![]),
+            vwap: Mutex::new(Vwap::default()),
+            history: Mutex::new(TradeHistory::new(TRADE_HISTORY_LEN)),
+            latency: Mutex::new(BatchLatency::new(LATENCY_WINDOW)),
+            workspace: Mutex::new(BatchWorkspace::default()),
+            halt: Mutex::new(None),
+        }
+    }
+
+    // whether the next batch is skipped, counts the cooldown down
+    fn sit_out(&self) -> bool {
+        let mut halt = self.halt.lock().unwrap();
+        match *halt {
+            None => false,
+            Some(Halt::UntilResumed) => true,
+            Some(Halt::Batches(left)) => {
+                *halt = (left > 1).then(|| Halt::Batches(left - 1));
+                true
+            }
+        }
+    }
+
+    // true if the symbol was halted
+    fn resume(&self) -> bool {
+        self.halt.lock().unwrap().take().is_some()
+    }
+
+    /**
+    The only way to hold both sides at once, see also `with_both_read`.
+    Bids are always locked before asks, so two callers can't deadlock each other.
+    */
+    pub fn with_both_locked<T>(&self, f: impl FnOnce(&mut Vec<Order>, &mut Vec<Order>) -> T) -> T {
+        let mut bids = self.bids.write().unwrap();
+        let mut asks = self.asks.write().unwrap();
+        f(&mut bids, &mut asks)
+    }
+
+    // same locking order as `with_both_locked`, for read-only access
+    pub fn with_both_read<T>(&self, f: impl FnOnce(&[Order], &[Order]) -> T) -> T {
+        let bids = self.bids.read().unwrap();
+        let asks = self.asks.read().unwrap();
+        f(&bids, &asks)
+    }
+
+    /**
+    Seeds the bids with many orders under a single lock, so a batch sees either none or all of them.
+    Unlike ADD the orders skip the limits, the immediate cross and the event log. They keep their ids
+    and arrival numbers, which shouldn't clash with the ones the exchange hands out.
+    */
+    pub fn extend_bids(&self, orders: impl IntoIterator<Item = Order>) {
+        extend_orders(&mut self.bids.write().unwrap(), Side::Bid, orders);
+    }
+
+    // see `extend_bids`
+    pub fn extend_asks(&self, orders: impl IntoIterator<Item = Order>) {
+        extend_orders(&mut self.asks.write().unwrap(), Side::Ask, orders);
+    }
+}
+
+// how an order left the book
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OrderStatus {
+    Filled,
+    // by the client, its time in force or expiry
+    Cancelled,
+}
+
+impl fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderStatus::Filled => write!(f, "FILLED"),
+            OrderStatus::Cancelled => write!(f, "CANCELLED"),
+        }
+    }
+}
+
+// orders that left the book, the oldest one is forgotten when full
+struct CompletedOrders {
+    // with the client of the order, so that only it can look the order up
+    statuses: HashMap<OrderId, (ClientId, OrderStatus)>,
+    ids: VecDeque<OrderId>,
+    capacity: usize,
+}
+
+impl CompletedOrders {
+    fn new(capacity: usize) -> CompletedOrders {
+        CompletedOrders {
+            statuses: HashMap::with_capacity(capacity),
+            ids: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn insert(&mut self, id: OrderId, client_id: ClientId, status: OrderStatus) {
+        if self.statuses.insert(id, (client_id, status)).is_some() {
+            return;
+        }
+        if self.ids.len() == self.capacity {
+            if let Some(oldest) = self.ids.pop_front() {
+                self.statuses.remove(&oldest);
+            }
+        }
+        self.ids.push_back(id);
+    }
+
+    fn get(&self, id: OrderId) -> Option<OrderStatus> {
+        self.statuses.get(&id).map(|(_, status)| *status)
+    }
+
+    fn client_id(&self, id: OrderId) -> Option<ClientId> {
+        self.statuses.get(&id).map(|(client_id, _)| *client_id)
+    }
+}
+
+struct HistoricTrade {
+    batch_seq: u64,
+    price: BigDecimal,
+    qty: Qty,
+}
+
+// last trades of a symbol, the oldest one is evicted when full
+struct TradeHistory {
+    trades: VecDeque<HistoricTrade>,
+    capacity: usize,
+}
+
+impl TradeHistory {
+    fn new(capacity: usize) -> TradeHistory {
+        TradeHistory {
+            trades: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, trade: HistoricTrade) {
+        if self.trades.len() == self.capacity {
+            self.trades.pop_front();
+        }
+        self.trades.push_back(trade);
+    }
+
+    fn last_price(&self) -> Option<BigDecimal> {
+        self.trades.back().map(|trade| trade.price.clone())
+    }
+
+    // most recent `count` trades, oldest first
+    fn last(&self, count: usize) -> impl Iterator<Item = &HistoricTrade> {
+        self.trades
+            .iter()
+            .skip(self.trades.len().saturating_sub(count))
+    }
+}
+
+// volume weighted average price of the trades since the start
+#[derive(Default)]
+struct Vwap {
+    // sum of price * qty
+    notional: BigDecimal,
+    qty: BigDecimal,
+}
+
+impl Vwap {
+    fn add(&mut self, price: &BigDecimal, qty: Qty) {
+        let qty = qty.to_decimal();
+        self.notional += price * &qty;
+        self.qty += qty;
+    }
+
+    // None until the first trade
+    fn value(&self, price_scale: i64) -> Option<BigDecimal> {
+        (self.qty > BigDecimal::default())
+            .then(|| round_price(&(&self.notional / &self.qty), price_scale))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    // one JSON object per trade
+    Json,
+}
+
+/**
+One JSON line per symbol and batch, tagged with `event`.
+Batches without a trade are reported too, so consumers see every batch boundary.
+*/
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum BatchRecord<'a> {
+    Trade(TradeRecord<'a>),
+    NoTrade {
+        batch_seq: u64,
+        symbol: &'a str,
+        reason: NoTradeReason,
+    },
+    CircuitBreak {
+        batch_seq: u64,
+        symbol: &'a str,
+        price: &'a BigDecimal,
+        qty: Qty,
+    },
+}
+
+#[derive(Serialize)]
+struct TradeRecord<'a> {
+    batch_seq: u64,
+    symbol: &'a str,
+    price: &'a BigDecimal,
+    qty: Qty,
+    cleared_bids: usize,
+    cleared_asks: usize,
+    bid_imbalance: Qty,
+    ask_imbalance: Qty,
+}
+
+/**
+Trading phase of the exchange. With an opening auction orders only accumulate until the warm-up
+ends, then a single uncross batch clears everything that crosses and timed batches run as usual.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarketPhase {
+    // no batches before the instant
+    Accumulating { until: Instant },
+    // the first batch after the warm-up
+    Uncross,
+    Continuous,
+}
+
+/**
+Settings of a single symbol, symbols that aren't configured get the defaults.
+Whatever is left unset falls back to the global setting.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SymbolConfig {
+    // zero and negative prices are accepted, e.g. for power or spreads
+    pub allow_negative_price: bool,
+    // the clearing price of the symbol is snapped to it instead of the global tick
+    pub tick_size: Option<BigDecimal>,
+    // coarser than the global scale, incoming and clearing prices are rounded to it
+    pub price_scale: Option<i64>,
+    // each limit that is set replaces the global one
+    pub limits: OrderLimits,
+}
+
+// fat-finger protection, orders above any limit are rejected
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OrderLimits {
+    pub max_qty: Option<Qty>,
+    // price * qty
+    pub max_notional: Option<BigDecimal>,
+    // max distance from the last clearing price, in percent
+    pub price_band: Option<BigDecimal>,
+}
+
+impl OrderLimits {
+    /**
+    Notional of market orders is unknown, only their qty is checked.
+    The price band applies only once the symbol has traded, not after a trade at 0,
+    no price is within a percentage of it. Both are measured in absolute values, so they work for negative prices too.
+    */
+    fn check(
+        &self,
+        global: &OrderLimits,
+        price: Option<&BigDecimal>,
+        qty: Qty,
+        last_price: Option<&BigDecimal>,
+    ) -> Result<(), ClientError> {
+        let max_qty = self.max_qty.or(global.max_qty);
+        let max_notional = self.max_notional.as_ref().or(global.max_notional.as_ref());
+        let price_band = self.price_band.as_ref().or(global.price_band.as_ref());
+        if let Some(max_qty) = max_qty {
+            if qty > max_qty {
+                return Err(ClientError::MaxQty { qty, max_qty });
+            }
+        }
+        if let (Some(max_notional), Some(price)) = (max_notional, price) {
+            let notional = (price * qty.to_decimal()).abs();
+            if notional > *max_notional {
+                return Err(ClientError::MaxNotional {
+                    notional,
+                    max_notional: max_notional.clone(),
+                });
+            }
+        }
+        let last_price = last_price.filter(|last_price| **last_price != BigDecimal::from(0));
+        if let (Some(price_band), Some(price), Some(last_price)) = (price_band, price, last_price) {
+            let distance = (price - last_price).abs() * BigDecimal::from(100);
+            if distance > price_band * last_price.abs() {
+                return Err(ClientError::PriceBand {
+                    price: price.clone(),
+                    band: price_band.clone(),
+                    last_price: last_price.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+What becomes of a price with more decimal digits than the scale of its symbol. `Snap` rounds it
+half up and tells the client the price the order rests at, `Reject` answers `ERR,PRICE_PRECISION`.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PricePrecision {
+    #[default]
+    Snap,
+    Reject,
+}
+
+/**
+How incoming orders trade. `Hybrid` crosses an order that arrives marketable against the best
+opposite orders right away, like a continuous book, and leaves the rest for the next batch.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MatchMode {
+    // only in batches
+    #[default]
+    Batch,
+    Hybrid,
+}
+
+// wall clock of good-till-time orders, tests set the time themselves
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// order books of all traded symbols
+pub struct Exchange {
+    books: RwLock<HashMap<String, Arc<DoubleSidedBook>>>,
+    next_order_id: AtomicU64,
+    // numbers orders as they arrive or lose their priority, see `Order::with_submitted`
+    next_submission: AtomicU64,
+    // outbound channels of the connected clients
+    clients: Mutex<HashMap<ClientId, Responder>>,
+    next_client_id: AtomicU64,
+    // number of the last batch run
+    batch_seq: AtomicU64,
+    limits: OrderLimits,
+    symbols: HashMap<String, SymbolConfig>,
+    // max commands per second of a single connection
+    rate_limit: Option<u32>,
+    // client id of every API key. None - connections don't authenticate
+    api_keys: Option<HashMap<String, ClientId>>,
+    // max bytes of a message, longer ones close the connection
+    max_line: usize,
+    // resting orders of a client are cancelled once it disconnects
+    cancel_on_disconnect: bool,
+    // max orders on a side of a book. None - no limit
+    max_orders: Option<usize>,
+    match_mode: MatchMode,
+    price_precision: PricePrecision,
+    // HALT and RESUME have to carry it. None - HALT is disabled, RESUME open to all
+    admin_token: Option<String>,
+    phase: Mutex<MarketPhase>,
+    // when the engine runs the next batch. None - no engine runs them
+    next_batch: Mutex<Option<Instant>>,
+    // the price scale of incoming orders and indicative prices, batches are run with the config
+    // passed to them
+    auction_config: Arc<AuctionConfig>,
+    // expires good-till-time orders
+    clock: Arc<dyn Clock>,
+    completed: Mutex<CompletedOrders>,
+    metrics: Metrics,
+    // trades that cross on arrival, batches are recorded by their caller. Taken on shutdown to flush it
+    tape: Mutex<Tape>,
+    // accepted commands and batches, locked while they change the books so it has them in order
+    event_log: Option<Mutex<EventLog>>,
+}
+
+impl Default for Exchange {
+    fn default() -> Exchange {
+        Exchange::new()
+    }
+}
+
+impl Exchange {
+    pub fn new() -> Exchange {
+        Exchange {
+            books: RwLock::new(HashMap::new()),
+            next_order_id: AtomicU64::new(1),
+            next_submission: AtomicU64::new(1),
+            clients: Mutex::new(HashMap::new()),
+            next_client_id: AtomicU64::new(1),
+            batch_seq: AtomicU64::new(0),
+            limits: OrderLimits::default(),
+            symbols: HashMap::new(),
+            rate_limit: None,
+            api_keys: None,
+            max_line: DEFAULT_MAX_LINE,
+            cancel_on_disconnect: false,
+            max_orders: None,
+            match_mode: MatchMode::default(),
+            price_precision: PricePrecision::default(),
+            admin_token: None,
+            phase: Mutex::new(MarketPhase::Continuous),
+            next_batch: Mutex::new(None),
+            auction_config: Arc::new(AuctionConfig::default()),
+            clock: Arc::new(SystemClock),
+            completed: Mutex::new(CompletedOrders::new(COMPLETED_ORDERS_LEN)),
+            metrics: Metrics::default(),
+            tape: Mutex::new(None),
+            event_log: None,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: OrderLimits) -> Exchange {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_symbol_config(mut self, symbol: &str, config: SymbolConfig) -> Exchange {
+        self.symbols.insert(symbol.to_string(), config);
+        self
+    }
+
+    // degenerate orders never trade, but still slow down every batch
+    fn check_price(&self, symbol: &str, price: &BigDecimal) -> Result<(), ClientError> {
+        let allow_negative = self
+            .symbols
+            .get(symbol)
+            .is_some_and(|config| config.allow_negative_price);
+        if !allow_negative && *price <= BigDecimal::from(0) {
+            return Err(ClientError::NonPositivePrice(price.clone()));
+        }
+        Ok(())
+    }
+
+    // the limits of the symbol, the global ones where it has none
+    fn check_limits(
+        &self,
+        symbol: &str,
+        price: Option<&BigDecimal>,
+        qty: Qty,
+        last_price: Option<&BigDecimal>,
+    ) -> Result<(), ClientError> {
+        let own = self
+            .symbols
+            .get(symbol)
+            .map_or(&self.limits, |config| &config.limits);
+        own.check(&self.limits, price, qty, last_price)
+    }
+
+    // the incoming price at the scale of the symbol, see `PricePrecision`
+    fn snap_price(&self, symbol: &str, price: &BigDecimal) -> Result<BigDecimal, ClientError> {
+        let scale = self.symbol_price_scale(symbol);
+        let snapped = round_price(price, scale);
+        if snapped != *price && self.price_precision == PricePrecision::Reject {
+            return Err(ClientError::PricePrecision {
+                price: price.clone(),
+                scale,
+            });
+        }
+        Ok(snapped)
+    }
+
+    // the price scale of the symbol, or the global one if it has none of its own
+    fn symbol_price_scale(&self, symbol: &str) -> i64 {
+        self.symbols
+            .get(symbol)
+            .and_then(|config| config.price_scale)
+            .unwrap_or(self.auction_config.price_scale)
+    }
+
+    // the global auction config, with the tick and price scale of the symbol if it has its own
+    pub fn symbol_auction_config<'a>(
+        &self,
+        symbol: &str,
+        config: &'a AuctionConfig,
+    ) -> Cow<'a, AuctionConfig> {
+        match self.symbols.get(symbol) {
+            Some(own) if own.tick_size.is_some() || own.price_scale.is_some() => {
+                Cow::Owned(AuctionConfig {
+                    tick_size: own.tick_size.clone().or_else(|| config.tick_size.clone()),
+                    price_scale: own.price_scale.unwrap_or(config.price_scale),
+                    ..config.clone()
+                })
+            }
+            _ => Cow::Borrowed(config),
+        }
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: Option<u32>) -> Exchange {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /**
+    Connections have to send `AUTH,<api key>` first, then their orders and fills belong to the
+    client id of the key. Connections that haven't authenticated get ids after the highest one.
+    */
+    pub fn with_api_keys(mut self, api_keys: Option<HashMap<String, ClientId>>) -> Exchange {
+        if let Some(max_id) = api_keys.iter().flat_map(|keys| keys.values()).max() {
+            self.next_client_id
+                .fetch_max(max_id + 1, atomic::Ordering::Relaxed);
+        }
+        self.api_keys = api_keys;
+        self
+    }
+
+    pub fn with_max_line(mut self, max_line: usize) -> Exchange {
+        self.max_line = max_line;
+        self
+    }
+
+    pub fn max_line(&self) -> usize {
+        self.max_line
+    }
+
+    pub fn with_cancel_on_disconnect(mut self, cancel_on_disconnect: bool) -> Exchange {
+        self.cancel_on_disconnect = cancel_on_disconnect;
+        self
+    }
+
+    pub fn with_max_orders(mut self, max_orders: Option<usize>) -> Exchange {
+        self.max_orders = max_orders;
+        self
+    }
+
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Exchange {
+        self.admin_token = admin_token;
+        self
+    }
+
+    // whether a client may send the command, only admin commands need the token
+    fn authorize(&self, command: &Command) -> Result<(), ClientError> {
+        let (token, open) = match command {
+            Command::Halt { token, .. } => (token, false),
+            // it used to need no token
+            Command::Resume { token, .. } => (token, true),
+            _ => return Ok(()),
+        };
+        match &self.admin_token {
+            None if open => Ok(()),
+            None => Err(ClientError::AdminDisabled),
+            Some(admin_token) if token.as_ref() == Some(admin_token) => Ok(()),
+            Some(_) => Err(ClientError::Unauthorized),
+        }
+    }
+
+    // with API keys a client only lists and looks up its own orders
+    fn authorize_query(&self, query: &Query, client_id: ClientId) -> Result<(), ClientError> {
+        match query {
+            Query::ClientOrders {
+                client_id: owner, ..
+            } if self.api_keys.is_some() && *owner != client_id => Err(ClientError::Unauthorized),
+            // unknown orders are UNKNOWN to everyone
+            Query::Order { id }
+                if self
+                    .owner(client_id)
+                    .is_some_and(|owner| self.order_client(*id).is_some_and(|c| c != owner)) =>
+            {
+                Err(ClientError::Unauthorized)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn with_price_precision(mut self, price_precision: PricePrecision) -> Exchange {
+        self.price_precision = price_precision;
+        self
+    }
+
+    pub fn with_match_mode(mut self, match_mode: MatchMode) -> Exchange {
+        self.match_mode = match_mode;
+        self
+    }
+
+    // not while the book sits out batches or the opening auction collects orders
+    fn crosses_immediately(&self, book: &DoubleSidedBook) -> bool {
+        self.match_mode == MatchMode::Hybrid
+            && book.halt.lock().unwrap().is_none()
+            && !matches!(
+                *self.phase.lock().unwrap(),
+                MarketPhase::Accumulating { .. }
+            )
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Exchange {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_event_log(mut self, event_log: EventLog) -> Exchange {
+        self.event_log = Some(Mutex::new(event_log));
+        self
+    }
+
+    // the tape the batches are recorded on, so that it also has the trades of `MatchMode::Hybrid`
+    pub fn with_tape(mut self, tape: Tape) -> Exchange {
+        self.tape = Mutex::new(tape);
+        self
+    }
+
+    // drops the sender of the tape, which is closed once the callers drop theirs
+    pub fn close_tape(&self) {
+        self.tape.lock().unwrap().take();
+    }
+
+    // the config batches are run with, so QUERY,MID clears the same way
+    pub fn with_auction_config(mut self, config: Arc<AuctionConfig>) -> Exchange {
+        self.auction_config = config;
+        self
+    }
+
+    // no batches for `warm_up` from now, then a single uncross batch
+    pub fn with_opening_auction(mut self, warm_up: Duration) -> Exchange {
+        let until = Instant::now() + warm_up;
+        self.phase = Mutex::new(MarketPhase::Accumulating { until });
+        self
+    }
+
+    // phase of a batch due at `now`, moves on to the next phase once the batch is run
+    fn batch_phase(&self, now: Instant) -> MarketPhase {
+        let mut phase = self.phase.lock().unwrap();
+        let current = match *phase {
+            MarketPhase::Accumulating { until } if now < until => return *phase,
+            MarketPhase::Accumulating { .. } => MarketPhase::Uncross,
+            MarketPhase::Uncross | MarketPhase::Continuous => MarketPhase::Continuous,
+        };
+        *phase = current;
+        current
+    }
+
+    fn next_submission(&self) -> u64 {
+        self.next_submission.fetch_add(1, atomic::Ordering::Relaxed)
+    }
+
+    fn register_client(&self, responder: Responder) -> ClientId {
+        let id = self.next_client_id.fetch_add(1, atomic::Ordering::Relaxed);
+        self.clients.lock().unwrap().insert(id, responder);
+        id
+    }
+
+    // false if a connection with the id is registered already
+    fn register_client_as(&self, id: ClientId, responder: Responder) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.contains_key(&id) {
+            return false;
+        }
+        clients.insert(id, responder);
+        true
+    }
+
+    fn unregister_client(&self, id: ClientId) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    // message is dropped if the client is gone
+    fn notify(&self, client_id: ClientId, msg: String) {
+        if let Some(responder) = self.clients.lock().unwrap().get(&client_id) {
+            let _ = responder.send(msg);
+        }
+    }
+
+    fn complete(&self, id: OrderId, client_id: ClientId, status: OrderStatus) {
+        self.completed.lock().unwrap().insert(id, client_id, status);
+    }
+
+    // the client of a resting or completed order
+    fn order_client(&self, id: OrderId) -> Option<ClientId> {
+        let resting = self.books().iter().find_map(|(_, book)| {
+            book.with_both_read(|bids, asks| {
+                bids.iter()
+                    .chain(asks.iter())
+                    .find(|order| order.id() == id)
+                    .map(|order| order.client_id())
+            })
+        });
+        resting.or_else(|| self.completed.lock().unwrap().client_id(id))
+    }
+
+    /**
+    The only client whose orders `client_id` may cancel, amend or reduce, None for any client.
+    With API keys clients only touch their own orders. Without them a client is a connection,
+    and a client that reconnects still has to reach the orders it left resting.
+    */
+    fn owner(&self, client_id: ClientId) -> Option<ClientId> {
+        self.api_keys.is_some().then_some(client_id)
+    }
+
+    // book is created on the first order for the symbol
+    pub fn book(&self, symbol: &str) -> Arc<DoubleSidedBook> {
+        if let Some(book) = self.books.read().unwrap().get(symbol) {
+            return book.clone();
+        }
+        self.books
+            .write()
+            .unwrap()
+            .entry(symbol.to_string())
+            .or_insert_with(|| Arc::new(DoubleSidedBook::new()))
+            .clone()
+    }
+
+    // unlike `book`, doesn't create the book for unknown symbols
+    pub fn find_book(&self, symbol: &str) -> Option<Arc<DoubleSidedBook>> {
+        self.books.read().unwrap().get(symbol).cloned()
+    }
+
+    // snapshot of the books, so the map is not locked while they are used. sorted by symbol
+    pub fn books(&self) -> Vec<(String, Arc<DoubleSidedBook>)> {
+        let mut books: Vec<_> = self
+            .books
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(symbol, book)| (symbol.clone(), book.clone()))
+            .collect();
+        books.sort_unstable_by(|(symbol, _), (other, _)| symbol.cmp(other));
+        books
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum OrderType {
+    Buy,
+    Sell,
+}
+
+// also serialized, for embedders that pass commands around as JSON
+#[derive(Serialize, Deserialize)]
+pub enum Command {
+    Add {
+        order_type: OrderType,
+        symbol: String,
+        time_in_force: TimeInForce,
+        min_qty: Option<Qty>,
+        // iceberg order showing only this much at a time
+        display_qty: Option<Qty>,
+        // good-till-time
+        expires_at: Option<SystemTime>,
+        // rejected instead of taking liquidity in its first batch
+        post_only: bool,
+        // None for a market order
+        price: Option<BigDecimal>,
+        qty: Qty,
+    },
+    Cancel {
+        id: OrderId,
+    },
+    Amend {
+        id: OrderId,
+        price: BigDecimal,
+        qty: Qty,
+    },
+    // cancels a part of the resting qty
+    Reduce {
+        id: OrderId,
+        qty: Qty,
+    },
+    // cancels every resting order of the client, sent when it disconnects
+    CancelAll,
+    // stops the batches of a symbol until it is resumed, orders are still accepted
+    Halt {
+        symbol: String,
+        // of the admin, checked before the command is sent to the order book
+        token: Option<String>,
+    },
+    // lifts the halt of a circuit break or HALT
+    Resume {
+        symbol: String,
+        token: Option<String>,
+    },
+    Query(Query),
+    // answered with ACK or NACK echoing the sequence number of the client
+    Sequenced {
+        seq: u64,
+        command: Box<Command>,
+    },
+}
+
+// read-only requests, answered without going through the order book task
+#[derive(Serialize, Deserialize)]
+pub enum Query {
+    Bbo { symbol: String },
+    Spread { symbol: String },
+    // where the next batch would clear if the book stays as it is
+    Mid { symbol: String },
+    Depth { symbol: String, levels: usize },
+    Volume { symbol: String },
+    // rounded to the price scale of the symbol
+    Vwap { symbol: String },
+    Trades { symbol: String, count: usize },
+    Order { id: OrderId },
+    // wall time of the last batches of the symbol
+    Stats { symbol: String },
+    // resting orders of a client with ids above `after`, a page at a time
+    ClientOrders { client_id: ClientId, after: OrderId },
+    // liveness check, doesn't look at the books
+    Ping,
+    // the time of the server and until the next batch
+    Clock,
+}
+
+// outbound lines for a single client connection
+pub type Responder = UnboundedSender<String>;
+
+// connection the command came from
+#[derive(Clone)]
+pub struct Client {
+    id: ClientId,
+    responder: Responder,
+    // sent a known API key, or none are needed
+    authenticated: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseCommandError {
+    MissingField(&'static str),
+    UnknownCommand(String),
+    UnknownSide(String),
+    BadPrice(String),
+    BadQty(String),
+    ZeroQty,
+    BadId(String),
+    UnknownQuery(String),
+    BadLevels(String),
+    BadCount(String),
+    UnknownOption(String),
+    BadMinQty(String),
+    BadDisplayQty(String),
+    BadSeq(String),
+    BadExpiry(String),
+    PostOnlyMarket,
+}
+
+impl fmt::Display for ParseCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseCommandError::MissingField(field) => write!(f, "missing field {}", field),
+            ParseCommandError::UnknownCommand(cmd) => write!(f, "unknown command {}", cmd),
+            ParseCommandError::UnknownSide(side) => write!(f, "unknown side {}", side),
+            ParseCommandError::BadPrice(price) => write!(f, "bad price {}", price),
+            ParseCommandError::BadQty(qty) => write!(f, "bad qty {}", qty),
+            ParseCommandError::ZeroQty => write!(f, "qty must be positive"),
+            ParseCommandError::BadId(id) => write!(f, "bad id {}", id),
+            ParseCommandError::UnknownQuery(query) => write!(f, "unknown query {}", query),
+            ParseCommandError::BadLevels(levels) => write!(f, "bad levels {}", levels),
+            ParseCommandError::BadCount(count) => write!(f, "bad count {}", count),
+            ParseCommandError::UnknownOption(option) => write!(f, "unknown option {}", option),
+            ParseCommandError::BadMinQty(min_qty) => write!(f, "bad min qty {}", min_qty),
+            ParseCommandError::BadDisplayQty(display_qty) => {
+                write!(f, "bad display qty {}", display_qty)
+            }
+            ParseCommandError::BadSeq(seq) => write!(f, "bad sequence number {}", seq),
+            ParseCommandError::BadExpiry(expiry) => write!(f, "bad expiry {}", expiry),
+            ParseCommandError::PostOnlyMarket => write!(f, "a market order can't be post-only"),
+        }
+    }
+}
+
+/**
+Every rejection sent to a client, as `ERR,<code>,<message>`, or `NACK,<seq>,<code>,<message>`
+for a command with a sequence number. Clients switch on the code, the message is for people
+and may change.
+*/
+#[derive(Debug, PartialEq)]
+pub enum ClientError {
+    BadCommand(ParseCommandError),
+    // max bytes of a message, the connection is closed
+    LineTooLong(usize),
+    // the command is dropped
+    RateLimit,
+    AuthRequired,
+    AuthDisabled,
+    AlreadyAuthenticated,
+    // the connection is closed
+    UnknownApiKey,
+    ClientConnected(ClientId),
+    AdminDisabled,
+    Unauthorized,
+    NonPositivePrice(BigDecimal),
+    // more digits than the scale of the symbol, see `PricePrecision`
+    PricePrecision {
+        price: BigDecimal,
+        scale: i64,
+    },
+    MaxQty {
+        qty: Qty,
+        max_qty: Qty,
+    },
+    MaxNotional {
+        notional: BigDecimal,
+        max_notional: BigDecimal,
+    },
+    PriceBand {
+        price: BigDecimal,
+        band: BigDecimal,
+        last_price: BigDecimal,
+    },
+    ExpiryPassed,
+    // the side of the book has the max number of orders
+    BookFull,
+    UnknownOrder(OrderId),
+    NotHalted(String),
+}
+
+impl ClientError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ClientError::BadCommand(_) => "BAD_COMMAND",
+            ClientError::LineTooLong(_) => "LINE_TOO_LONG",
+            ClientError::RateLimit => "RATE_LIMIT",
+            ClientError::AuthRequired => "AUTH_REQUIRED",
+            ClientError::AuthDisabled => "AUTH_DISABLED",
+            ClientError::AlreadyAuthenticated => "ALREADY_AUTHENTICATED",
+            ClientError::UnknownApiKey => "UNKNOWN_API_KEY",
+            ClientError::ClientConnected(_) => "CLIENT_CONNECTED",
+            ClientError::AdminDisabled => "ADMIN_DISABLED",
+            ClientError::Unauthorized => "UNAUTHORIZED",
+            ClientError::NonPositivePrice(_) => "BAD_PRICE",
+            ClientError::PricePrecision { .. } => "PRICE_PRECISION",
+            ClientError::MaxQty { .. } => "MAX_QTY",
+            ClientError::MaxNotional { .. } => "MAX_NOTIONAL",
+            ClientError::PriceBand { .. } => "PRICE_BAND",
+            ClientError::ExpiryPassed => "EXPIRED",
+            ClientError::BookFull => "BOOK_FULL",
+            ClientError::UnknownOrder(_) => "UNKNOWN_ORDER",
+            ClientError::NotHalted(_) => "NOT_HALTED",
+        }
+    }
+
+    // the line sent to the client
+    pub fn response(&self) -> String {
+        format!("ERR,{},{}", self.code(), self)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::BadCommand(e) => write!(f, "{}", e),
+            ClientError::LineTooLong(max) => write!(f, "line longer than {} bytes", max),
+            ClientError::RateLimit => write!(f, "too many commands"),
+            ClientError::AuthRequired => write!(f, "AUTH required"),
+            ClientError::AuthDisabled => write!(f, "authentication is disabled"),
+            ClientError::AlreadyAuthenticated => write!(f, "already authenticated"),
+            ClientError::UnknownApiKey => write!(f, "unknown api key"),
+            ClientError::ClientConnected(id) => write!(f, "client {} is connected already", id),
+            ClientError::AdminDisabled => write!(f, "admin commands are disabled"),
+            ClientError::Unauthorized => write!(f, "unauthorized"),
+            ClientError::NonPositivePrice(price) => {
+                write!(f, "price must be positive, got {}", price)
+            }
+            ClientError::PricePrecision { price, scale } => {
+                write!(f, "price {} has more than {} decimal digits", price, scale)
+            }
+            ClientError::MaxQty { qty, max_qty } => {
+                write!(f, "qty {} exceeds max qty {}", qty, max_qty)
+            }
+            ClientError::MaxNotional {
+                notional,
+                max_notional,
+            } => write!(
+                f,
+                "notional {} exceeds max notional {}",
+                notional, max_notional
+            ),
+            ClientError::PriceBand {
+                price,
+                band,
+                last_price,
+            } => write!(
+                f,
+                "price {} is more than {}% away from the last price {}",
+                price, band, last_price
+            ),
+            ClientError::ExpiryPassed => write!(f, "expiry has passed"),
+            ClientError::BookFull => write!(f, "the side of the book is full"),
+            ClientError::UnknownOrder(id) => write!(f, "unknown order {}", id),
+            ClientError::NotHalted(symbol) => write!(f, "{} is not halted", symbol),
+        }
+    }
+}
+
+impl From<ParseCommandError> for ClientError {
+    fn from(e: ParseCommandError) -> ClientError {
+        ClientError::BadCommand(e)
+    }
+}
+
+// as sent, the order book checks the sign and the digits against the symbol
+fn parse_price(raw_price: &str) -> Result<BigDecimal, ParseCommandError> {
+    BigDecimal::from_str(raw_price).map_err(|_| ParseCommandError::BadPrice(raw_price.to_string()))
+}
+
+fn parse_qty(raw_qty: &str) -> Result<Qty, ParseCommandError> {
+    let qty = raw_qty
+        .parse::<Qty>()
+        .map_err(|_| ParseCommandError::BadQty(raw_qty.to_string()))?;
+    if qty.is_zero() {
+        return Err(ParseCommandError::ZeroQty);
+    }
+    Ok(qty)
+}
+
+pub struct CommandHandler;
+impl CommandHandler {
+    // `<seq>|<command>` sets the sequence number, echoed back in the ACK or NACK of the command
+    fn split_seq(msg: &str) -> Result<(Option<u64>, &str), ParseCommandError> {
+        match msg.split_once('|') {
+            None => Ok((None, msg)),
+            Some((seq, msg)) => match seq.trim().parse::<u64>() {
+                Ok(seq) => Ok((Some(seq), msg)),
+                Err(_) => Err(ParseCommandError::BadSeq(seq.to_string())),
+            },
+        }
+    }
+
+    pub fn parse_command(msg: &str) -> Result<Command, ParseCommandError> {
+        let mut split = msg.trim().split(',');
+        let mut field = |name| split.next().ok_or(ParseCommandError::MissingField(name));
+
+        match field("command")? {
+            "ADD" => {
+                let order_type = match field("side")? {
+                    "BUY" => OrderType::Buy,
+                    "SELL" => OrderType::Sell,
+                    side => return Err(ParseCommandError::UnknownSide(side.to_string())),
+                };
+
+                let symbol = field("symbol")?.to_string();
+
+                // optional flags go between the symbol and the price
+                let rest: Vec<&str> = split.collect();
+                let (options, price, qty) = match rest.as_slice() {
+                    [] => return Err(ParseCommandError::MissingField("price")),
+                    [_] => return Err(ParseCommandError::MissingField("qty")),
+                    [options @ .., price, qty] => (options, *price, *qty),
+                };
+
+                let mut time_in_force = TimeInForce::GoodTillCancel;
+                let mut min_qty = None;
+                let mut display_qty = None;
+                let mut expires_at = None;
+                let mut post_only = false;
+                for option in options {
+                    if let Some(value) = option.strip_prefix("MINQTY=") {
+                        let value = value
+                            .parse::<Qty>()
+                            .ok()
+                            .filter(|value| !value.is_zero())
+                            .ok_or_else(|| ParseCommandError::BadMinQty(value.to_string()))?;
+                        min_qty = Some(value);
+                        continue;
+                    }
+                    if let Some(value) = option.strip_prefix("DISPLAY=") {
+                        let value = value
+                            .parse::<Qty>()
+                            .ok()
+                            .filter(|value| !value.is_zero())
+                            .ok_or_else(|| ParseCommandError::BadDisplayQty(value.to_string()))?;
+                        display_qty = Some(value);
+                        continue;
+                    }
+                    // unix seconds
+                    if let Some(value) = option.strip_prefix("GTT=") {
+                        let value = value
+                            .parse::<u64>()
+                            .ok()
+                            .and_then(|secs| UNIX_EPOCH.checked_add(Duration::from_secs(secs)))
+                            .ok_or_else(|| ParseCommandError::BadExpiry(value.to_string()))?;
+                        expires_at = Some(value);
+                        continue;
+                    }
+                    match *option {
+                        "FOK" => time_in_force = TimeInForce::FillOrKill,
+                        "IOC" => time_in_force = TimeInForce::ImmediateOrCancel,
+                        "POST_ONLY" => post_only = true,
+                        option => return Err(ParseCommandError::UnknownOption(option.to_string())),
+                    }
+                }
+
+                let price = match price {
+                    // it takes whatever is there
+                    "MKT" if post_only => return Err(ParseCommandError::PostOnlyMarket),
+                    "MKT" => {
+                        // market orders never rest
+                        if time_in_force == TimeInForce::GoodTillCancel {
+                            time_in_force = TimeInForce::ImmediateOrCancel;
+                        }
+                        None
+                    }
+                    price => Some(parse_price(price)?),
+                };
+                let qty = parse_qty(qty)?;
+
+                Ok(Command::Add {
+                    order_type,
+                    symbol,
+                    time_in_force,
+                    min_qty,
+                    display_qty,
+                    expires_at,
+                    post_only,
+                    price,
+                    qty,
+                })
+            }
+            "CANCEL" => {
+                let id = field("id")?;
+                let id = id
+                    .parse::<OrderId>()
+                    .map_err(|_| ParseCommandError::BadId(id.to_string()))?;
+
+                Ok(Command::Cancel { id })
+            }
+            "AMEND" => {
+                let id = field("id")?;
+                let id = id
+                    .parse::<OrderId>()
+                    .map_err(|_| ParseCommandError::BadId(id.to_string()))?;
+                let price = parse_price(field("price")?)?;
+                let qty = parse_qty(field("qty")?)?;
+
+                Ok(Command::Amend { id, price, qty })
+            }
+            "REDUCE" => {
+                let id = field("id")?;
+                let id = id
+                    .parse::<OrderId>()
+                    .map_err(|_| ParseCommandError::BadId(id.to_string()))?;
+                let qty = parse_qty(field("qty")?)?;
+
+                Ok(Command::Reduce { id, qty })
+            }
+            "HALT" => {
+                let symbol = field("symbol")?.to_string();
+                let token = split.next().map(str::to_string);
+
+                Ok(Command::Halt { symbol, token })
+            }
+            "RESUME" => {
+                let symbol = field("symbol")?.to_string();
+                let token = split.next().map(str::to_string);
+
+                Ok(Command::Resume { symbol, token })
+            }
+            "PING" => Ok(Command::Query(Query::Ping)),
+            "QUERY" => match field("query")? {
+                "BBO" => {
+                    let symbol = field("symbol")?.to_string();
+
+                    Ok(Command::Query(Query::Bbo { symbol }))
+                }
+                "SPREAD" => {
+                    let symbol = field("symbol")?.to_string();
+
+                    Ok(Command::Query(Query::Spread { symbol }))
+                }
+                "MID" => {
+                    let symbol = field("symbol")?.to_string();
+
+                    Ok(Command::Query(Query::Mid { symbol }))
+                }
+                "DEPTH" => {
+                    let symbol = field("symbol")?.to_string();
+
+                    let levels = match split.next() {
+                        None => DEFAULT_DEPTH_LEVELS,
+                        Some(levels) => levels
+                            .parse::<usize>()
+                            .map_err(|_| ParseCommandError::BadLevels(levels.to_string()))?,
+                    };
+
+                    Ok(Command::Query(Query::Depth {
+                        symbol,
+                        levels: min(levels, MAX_DEPTH_LEVELS),
+                    }))
+                }
+                "VOLUME" => {
+                    let symbol = field("symbol")?.to_string();
+
+                    Ok(Command::Query(Query::Volume { symbol }))
+                }
+                "VWAP" => {
+                    let symbol = field("symbol")?.to_string();
+
+                    Ok(Command::Query(Query::Vwap { symbol }))
+                }
+                "TRADES" => {
+                    let symbol = field("symbol")?.to_string();
+                    let count = field("count")?;
+                    let count = count
+                        .parse::<usize>()
+                        .map_err(|_| ParseCommandError::BadCount(count.to_string()))?;
+
+                    Ok(Command::Query(Query::Trades {
+                        symbol,
+                        count: min(count, TRADE_HISTORY_LEN),
+                    }))
+                }
+                "ORDER" => {
+                    let id = field("id")?;
+                    let id = id
+                        .parse::<OrderId>()
+                        .map_err(|_| ParseCommandError::BadId(id.to_string()))?;
+
+                    Ok(Command::Query(Query::Order { id }))
+                }
+                "STATS" => {
+                    let symbol = field("symbol")?.to_string();
+
+                    Ok(Command::Query(Query::Stats { symbol }))
+                }
+                "MYORDERS" => {
+                    let client_id = field("client id")?;
+                    let client_id = client_id
+                        .parse::<ClientId>()
+                        .map_err(|_| ParseCommandError::BadId(client_id.to_string()))?;
+                    // the last id of the previous page
+                    let after = match split.next() {
+                        None => 0,
+                        Some(after) => after
+                            .parse::<OrderId>()
+                            .map_err(|_| ParseCommandError::BadId(after.to_string()))?,
+                    };
+
+                    Ok(Command::Query(Query::ClientOrders { client_id, after }))
+                }
+                "CLOCK" => Ok(Command::Query(Query::Clock)),
+                query => Err(ParseCommandError::UnknownQuery(query.to_string())),
+            },
+            cmd => Err(ParseCommandError::UnknownCommand(cmd.to_string())),
+        }
+    }
+
+    pub async fn handle_socket(
+        tcp_stream: TcpStream,
+        tx: Sender<(Command, Client)>,
+        exchange: Arc<Exchange>,
+    ) {
+        let (read_half, mut write_half) = tcp_stream.into_split();
+
+        // separate task writes responses back to the client
+        let (responder, mut responses) = tokio::sync::mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(line) = responses.recv().await {
+                if write_half.write_all(line.as_bytes()).await.is_err()
+                    || write_half.write_all(b"\n").await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        let mut client = CommandHandler::connect(&exchange, responder);
+        let mut limiter = RateLimiter::new(exchange.rate_limit);
+        if let Ok(peer) = read_half.peer_addr() {
+            debug!("client {} connected from {}", client.id, peer);
+        }
+
+        let mut buff_reader = BufReader::new(read_half);
+        let mut buf = vec![];
+        loop {
+            let read = CommandHandler::read_message(&mut buff_reader, &mut buf, exchange.max_line);
+            let msg = match read.await {
+                Ok(Some(msg)) => msg,
+                // socket closed by client
+                Ok(None) => break,
+                // the rest of the line is never read, so the connection can't be used any more
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    warn!("client {}: {}, closing connection", client.id, e);
+                    let e = ClientError::LineTooLong(exchange.max_line);
+                    let _ = client.responder.send(e.response());
+                    break;
+                }
+                Err(e) => {
+                    warn!("client {}: failed to read: {}", client.id, e);
+                    break;
+                }
+            };
+
+            if !CommandHandler::handle_message(&msg, &mut client, &mut limiter, &tx, &exchange)
+                .await
+            {
+                break;
+            }
+        }
+
+        CommandHandler::disconnect(client, &tx, &exchange).await;
+    }
+
+    /**
+    Next message of the stream, without its `\n` or `\r\n`. A message split across reads is buffered
+    until its end arrives, messages that arrived in one read are returned one at a time.
+    The last message is returned even without `\n`, `None` once the stream is closed.
+    A message over `max_len` bytes is an `InvalidData` error, without buffering more of it.
+    */
+    async fn read_message<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        buf: &mut Vec<u8>,
+        max_len: usize,
+    ) -> io::Result<Option<String>> {
+        buf.clear();
+        // room for `\r\n` after a message of max_len
+        let limit = max_len as u64 + 2;
+        if (&mut *reader).take(limit).read_until(b'\n', buf).await? == 0 {
+            return Ok(None);
+        }
+        let len = buf.len() - buf.ends_with(b"\n") as usize - buf.ends_with(b"\r\n") as usize;
+        if len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line longer than {} bytes", max_len),
+            ));
+        }
+        if buf.ends_with(b"\n") {
+            buf.pop();
+            if buf.ends_with(b"\r") {
+                buf.pop();
+            }
+        }
+        // invalid UTF-8 fails to parse and is answered with ERR like any other bad command
+        Ok(Some(String::from_utf8_lossy(buf).into_owned()))
+    }
+
+    /**
+    Unregisters a closed connection. Its orders are cancelled through the order book task,
+    so commands it sent before disconnecting are applied first.
+    */
+    pub(crate) async fn disconnect(
+        client: Client,
+        tx: &Sender<(Command, Client)>,
+        exchange: &Exchange,
+    ) {
+        exchange.unregister_client(client.id);
+        debug!("client {} disconnected", client.id);
+        if exchange.cancel_on_disconnect {
+            // the order book task is gone only on shutdown, the orders are kept then
+            let _ = tx.send((Command::CancelAll, client)).await;
+        }
+    }
+
+    // registers a new connection, responses and notifications go to the responder
+    fn connect(exchange: &Exchange, responder: Responder) -> Client {
+        Client {
+            id: exchange.register_client(responder.clone()),
+            responder,
+            authenticated: exchange.api_keys.is_none(),
+        }
+    }
+
+    /**
+    `AUTH,<api key>` makes the client the owner of the key, answered with `AUTHED,<client id>`.
+    A connection that sends an unknown key is closed, so keys can't be guessed one after another.
+    */
+    fn authenticate(exchange: &Exchange, client: &mut Client, msg: &str) -> bool {
+        let key = msg.trim().strip_prefix("AUTH,");
+        let response = match (&exchange.api_keys, key) {
+            (_, None) => ClientError::AuthRequired.response(),
+            (None, Some(_)) => ClientError::AuthDisabled.response(),
+            (Some(_), Some(_)) if client.authenticated => {
+                ClientError::AlreadyAuthenticated.response()
+            }
+            (Some(api_keys), Some(key)) => match api_keys.get(key) {
+                None => {
+                    warn!("client {}: unknown api key, closing connection", client.id);
+                    let _ = client.responder.send(ClientError::UnknownApiKey.response());
+                    return false;
+                }
+                Some(&id) if exchange.register_client_as(id, client.responder.clone()) => {
+                    exchange.unregister_client(client.id);
+                    debug!("client {} authenticated as {}", client.id, id);
+                    client.id = id;
+                    client.authenticated = true;
+                    format!("AUTHED,{}", id)
+                }
+                Some(&id) => ClientError::ClientConnected(id).response(),
+            },
+        };
+        let _ = client.responder.send(response);
+        true
+    }
+
+    /**
+    Returns false if the connection has to be closed.
+    Waits while the command queue is full, so a slow order book slows down its clients
+    instead of queueing commands without a limit.
+    */
+    #[instrument(name = "message", skip_all, fields(client = client.id))]
+    async fn handle_message(
+        msg: &str,
+        client: &mut Client,
+        limiter: &mut RateLimiter,
+        tx: &Sender<(Command, Client)>,
+        exchange: &Exchange,
+    ) -> bool {
+        trace!("client {}: {}", client.id, msg);
+        // dropped before parsing, so a flood costs as little as possible
+        if !limiter.allow(Instant::now()) {
+            debug!("client {}: over the rate limit, dropped", client.id);
+            let _ = client.responder.send(ClientError::RateLimit.response());
+            return true;
+        }
+        if !client.authenticated || msg.starts_with("AUTH,") {
+            return CommandHandler::authenticate(exchange, client, msg);
+        }
+        let (seq, msg) = match CommandHandler::split_seq(msg) {
+            Ok(split) => split,
+            Err(e) => {
+                debug!("client {}: {}", client.id, e);
+                let _ = client.responder.send(ClientError::from(e).response());
+                return true;
+            }
+        };
+        let reject = |e: ClientError| match seq {
+            Some(seq) => acknowledge(seq, &e.response()),
+            None => e.response(),
+        };
+        match CommandHandler::parse_command(msg) {
+            // answered right away, a sequence number adds nothing
+            Ok(Command::Query(query)) => {
+                let response = match exchange.authorize_query(&query, client.id) {
+                    Ok(()) => answer_query(exchange, query),
+                    Err(e) => {
+                        warn!("client {}: {}", client.id, e);
+                        e.response()
+                    }
+                };
+                let _ = client.responder.send(response);
+            }
+            Ok(command) => {
+                if let Err(e) = exchange.authorize(&command) {
+                    warn!("client {}: {}", client.id, e);
+                    let _ = client.responder.send(reject(e));
+                    return true;
+                }
+                let command = match seq {
+                    Some(seq) => Command::Sequenced {
+                        seq,
+                        command: Box::new(command),
+                    },
+                    None => command,
+                };
+                // order book task is gone, nothing will process further commands
+                if tx.send((command, client.clone())).await.is_err() {
+                    error!("order book is not accepting commands, closing connection");
+                    return false;
+                }
+            }
+            Err(e) => {
+                debug!("client {}: {}", client.id, e);
+                let _ = client.responder.send(reject(e.into()));
+            }
+        }
+        true
+    }
+}
+
+pub type Tape = Option<UnboundedSender<TapeEntry>>;
+
+/**
+Time between batches, uniform within `jitter` either side of `interval`, so nobody can time
+an order to arrive just before a batch. Without jitter it is always `interval`.
+*/
+pub struct BatchTiming {
+    interval: Duration,
+    jitter: Duration,
+    rng: StdRng,
+}
+
+impl BatchTiming {
+    pub fn new(interval: Duration, jitter: Duration) -> BatchTiming {
+        BatchTiming::with_rng(interval, jitter, StdRng::from_entropy())
+    }
+
+    // the same seed gives the same delays
+    pub fn seeded(interval: Duration, jitter: Duration, seed: u64) -> BatchTiming {
+        BatchTiming::with_rng(interval, jitter, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(interval: Duration, jitter: Duration, rng: StdRng) -> BatchTiming {
+        BatchTiming {
+            interval,
+            jitter,
+            rng,
+        }
+    }
+
+    // delay until the next batch, drawn anew every time
+    pub fn next_delay(&mut self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.interval;
+        }
+        let earliest = self.interval.saturating_sub(self.jitter);
+        self.rng.gen_range(earliest..=self.interval + self.jitter)
+    }
+}
+
+/**
+The matching engine: applies the commands of the channel and runs a batch every time `timing`
+says until `shutdown`, then applies the commands already sent. The final batch is up to the caller.
+*/
+pub async fn run_engine(
+    rx: Receiver<(Command, Client)>,
+    exchange: Arc<Exchange>,
+    config: Arc<AuctionConfig>,
+    output: OutputFormat,
+    tape: Tape,
+    mut timing: BatchTiming,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let (stop_updates, updates_stopped) = oneshot::channel();
+    let updates = tokio::spawn(update_order_book(rx, exchange.clone(), updates_stopped));
+    // the first batch runs right away. a late batch doesn't move the ones after it
+    let mut next_batch = tokio::time::Instant::now();
+    loop {
+        *exchange.next_batch.lock().unwrap() = Some(next_batch.into_std());
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_batch) => {
+                run_auction(exchange.clone(), &config, output, &tape).await;
+                next_batch += timing.next_delay();
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+    *exchange.next_batch.lock().unwrap() = None;
+    let _ = stop_updates.send(());
+    let _ = updates.await;
+}
+
+/**
+[`run_engine`] on a thread of its own, with a single-threaded runtime. A long batch then doesn't
+hold up the runtime serving the connections, they only share the command channel and the books.
+*/
+pub fn spawn_engine_thread(
+    rx: Receiver<(Command, Client)>,
+    exchange: Arc<Exchange>,
+    config: Arc<AuctionConfig>,
+    output: OutputFormat,
+    tape: Tape,
+    timing: BatchTiming,
+    shutdown: oneshot::Receiver<()>,
+) -> io::Result<std::thread::JoinHandle<()>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new()
+        .name("engine".to_string())
+        .spawn(move || {
+            runtime.block_on(run_engine(
+                rx, exchange, config, output, tape, timing, shutdown,
+            ))
+        })
+}
+
+pub async fn run_auction(
+    exchange: Arc<Exchange>,
+    config: &AuctionConfig,
+    output: OutputFormat,
+    tape: &Tape,
+) {
+    let results = run_scheduled_batches(&exchange, config, Instant::now());
+    record_trades(tape, &results);
+
+    // run separate task, that will print to stdio
+    tokio::spawn(async move { print_results(results, output) });
+}
+
+// runs the batches due at `now`, none while the opening auction accumulates orders
+pub fn run_scheduled_batches(
+    exchange: &Exchange,
+    config: &AuctionConfig,
+    now: Instant,
+) -> Vec<(String, BatchResult)> {
+    match exchange.batch_phase(now) {
+        MarketPhase::Accumulating { .. } => vec![],
+        MarketPhase::Uncross => {
+            info!("opening auction uncrosses, batches run from now on");
+            run_batches(exchange, config)
+        }
+        MarketPhase::Continuous => run_batches(exchange, config),
+    }
+}
+
+// runs a batch for every symbol and notifies the clients
+pub fn run_batches(exchange: &Exchange, config: &AuctionConfig) -> Vec<(String, BatchResult)> {
+    // no command runs in the middle of a logged batch
+    let mut event_log = exchange
+        .event_log
+        .as_ref()
+        .map(|event_log| event_log.lock().unwrap());
+    let batch_seq = exchange.batch_seq.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+    let _span = info_span!("batch", seq = batch_seq).entered();
+    let started = Instant::now();
+    let now = exchange.clock.now();
+    if let Some(event_log) = &mut event_log {
+        event_log.batch(batch_seq, now);
+    }
+    let results: Vec<(String, BatchResult)> = exchange
+        .books()
+        .into_iter()
+        .map(|(symbol, book)| {
+            let _span = info_span!("book", %symbol).entered();
+            let config = exchange.symbol_auction_config(&symbol, config);
+            // good-till-time orders go first, even while the symbol is halted
+            let expired = book.with_both_locked(|bids, asks| expire_orders(bids, asks, now));
+            let mut result = run_batch(&book, &config, batch_seq);
+            result.expired.extend(expired);
+            (symbol, result)
+        })
+        .collect();
+    debug!(
+        "batch {}: {} books in {:?}",
+        batch_seq,
+        results.len(),
+        started.elapsed()
+    );
+
+    let metrics = &exchange.metrics;
+    metrics.batches.fetch_add(1, atomic::Ordering::Relaxed);
+    for (symbol, result) in &results {
+        if let BatchReport::Trade { qty, .. } = &result.report {
+            metrics.trades.fetch_add(1, atomic::Ordering::Relaxed);
+            metrics
+                .traded_volume
+                .fetch_add(qty.units(), atomic::Ordering::Relaxed);
+            notify_fills(exchange, result.report.fills());
+        }
+        for fill in result
+            .report
+            .fills()
+            .filter(|fill| fill.remaining.is_zero())
+        {
+            exchange.complete(fill.order_id, fill.client_id, OrderStatus::Filled);
+        }
+        // after the fills, a partially filled IOC order ends up cancelled
+        for order in &result.cancelled {
+            exchange.notify(order.client_id(), format!("CANCELLED,{}", order.id()));
+            exchange.complete(order.id(), order.client_id(), OrderStatus::Cancelled);
+        }
+        for order in &result.expired {
+            exchange.complete(order.id(), order.client_id(), OrderStatus::Cancelled);
+        }
+        for order in &result.crossed {
+            warn!(
+                "{}: order {} crossed the book too far, cancelled",
+                symbol,
+                order.id()
+            );
+            exchange.notify(order.client_id(), format!("CANCELLED,{}", order.id()));
+            exchange.complete(order.id(), order.client_id(), OrderStatus::Cancelled);
+        }
+        for order in &result.rejected {
+            exchange.notify(
+                order.client_id(),
+                format!("{},{}", POST_ONLY_REJECT, order.id()),
+            );
+            exchange.complete(order.id(), order.client_id(), OrderStatus::Cancelled);
+        }
+    }
+
+    results
+}
+
+pub fn record_trades(tape: &Tape, results: &[(String, BatchResult)]) {
+    let tape = match tape {
+        Some(tape) => tape,
+        None => return,
+    };
+    for (symbol, result) in results {
+        if let BatchReport::Trade { price, qty, .. } = &result.report {
+            // the writer is gone only if the file failed, which is already reported
+            let _ = tape.send(TapeEntry::new(symbol.clone(), price.clone(), *qty));
+        }
+    }
+}
+
+pub fn print_results(results: Vec<(String, BatchResult)>, output: OutputFormat) {
+    // stdout is gone, nothing to report to
+    let _ = write_results(&mut io::stdout().lock(), &results, output);
+}
+
+pub fn write_results(
+    out: &mut impl Write,
+    results: &[(String, BatchResult)],
+    output: OutputFormat,
+) -> io::Result<()> {
+    if output == OutputFormat::Json {
+        for (symbol, result) in results {
+            let record = batch_record(symbol, &result.report);
+            writeln!(out, "{}", serde_json::to_string(&record).unwrap())?;
+        }
+        return Ok(());
+    }
+
+    for (symbol, result) in results {
+        let BatchResult {
+            report,
+            expired,
+            cancelled,
+            crossed,
+            rejected,
+            ..
+        } = result;
+        if !expired.is_empty() {
+            writeln!(out, "{}: Expired {} orders", symbol, expired.len())?;
+        }
+        if !cancelled.is_empty() {
+            writeln!(out, "{}: Cancelled {} orders", symbol, cancelled.len())?;
+        }
+        if !crossed.is_empty() {
+            writeln!(
+                out,
+                "{}: Cancelled {} stale crossed orders",
+                symbol,
+                crossed.len()
+            )?;
+        }
+        if !rejected.is_empty() {
+            writeln!(
+                out,
+                "{}: Rejected {} post-only orders",
+                symbol,
+                rejected.len()
+            )?;
+        }
+        match report {
+            BatchReport::NoTrade { reason, .. } => {
+                writeln!(out, "{}: No Trade: {:?}", symbol, reason)?
+            }
+            BatchReport::Trade {
+                batch_seq,
+                price,
+                qty,
+                cleared_bids,
+                cleared_asks,
+                bid_imbalance,
+                ask_imbalance,
+                ..
+            } => {
+                writeln!(
+                    out,
+                    "{}: Batch {}: cleared BID={}, cleared ASK={}, price={:?}, qty={}, imbalance BID={}, ASK={}",
+                    symbol,
+                    batch_seq,
+                    cleared_bids.len(),
+                    cleared_asks.len(),
+                    price,
+                    qty,
+                    bid_imbalance,
+                    ask_imbalance
+                )?;
+            }
+            BatchReport::CircuitBreak {
+                batch_seq,
+                price,
+                qty,
+            } => writeln!(
+                out,
+                "{}: Batch {}: CIRCUIT_BREAK, price={:?}, qty={}, halted",
+                symbol, batch_seq, price, qty
+            )?,
+        }
+    }
+    Ok(())
+}
+
+fn batch_record<'a>(symbol: &'a str, report: &'a BatchReport) -> BatchRecord<'a> {
+    match report {
+        BatchReport::NoTrade { batch_seq, reason } => BatchRecord::NoTrade {
+            batch_seq: *batch_seq,
+            symbol,
+            reason: *reason,
+        },
+        BatchReport::CircuitBreak {
+            batch_seq,
+            price,
+            qty,
+        } => BatchRecord::CircuitBreak {
+            batch_seq: *batch_seq,
+            symbol,
+            price,
+            qty: *qty,
+        },
+        BatchReport::Trade {
+            batch_seq,
+            price,
+            qty,
+            cleared_bids,
+            cleared_asks,
+            bid_imbalance,
+            ask_imbalance,
+            ..
+        } => BatchRecord::Trade(TradeRecord {
+            batch_seq: *batch_seq,
+            symbol,
+            price,
+            qty: *qty,
+            cleared_bids: cleared_bids.len(),
+            cleared_asks: cleared_asks.len(),
+            bid_imbalance: *bid_imbalance,
+            ask_imbalance: *ask_imbalance,
+        }),
+    }
+}
+
+fn notify_fills<'a>(exchange: &Exchange, fills: impl Iterator<Item = &'a Fill>) {
+    for fill in fills {
+        exchange.notify(fill.client_id, format_fill(fill));
+    }
+}
+
+fn format_fill(fill: &Fill) -> String {
+    format!(
+        "FILL,{},{},{},{},{}",
+        fill.order_id, fill.price, fill.qty, fill.remaining, fill.fee
+    )
+}
+
+/**
+Books the trades of an order that crossed on arrival, see `MatchMode::Hybrid`. They are numbered
+like the last batch and go on the tape of the exchange. Returns the fills of the client that added it, to be sent after ADDED,
+the other clients are notified right away.
+*/
+fn settle_immediate_fills(
+    exchange: &Exchange,
+    symbol: &str,
+    book: &DoubleSidedBook,
+    client_id: ClientId,
+    fills: Vec<Fill>,
+) -> Vec<String> {
+    let batch_seq = exchange.batch_seq.load(atomic::Ordering::Relaxed);
+    let mut own = vec![];
+    for fill in fills {
+        // every trade has a single taker fill
+        if !fill.maker {
+            book.vwap.lock().unwrap().add(&fill.price, fill.qty);
+            book.history.lock().unwrap().push(HistoricTrade {
+                batch_seq,
+                price: fill.price.clone(),
+                qty: fill.qty,
+            });
+            exchange
+                .metrics
+                .trades
+                .fetch_add(1, atomic::Ordering::Relaxed);
+            exchange
+                .metrics
+                .traded_volume
+                .fetch_add(fill.qty.units(), atomic::Ordering::Relaxed);
+            if let Some(tape) = &*exchange.tape.lock().unwrap() {
+                let entry = TapeEntry::new(symbol.to_string(), fill.price.clone(), fill.qty);
+                // the writer is gone only if the file failed, which is already reported
+                let _ = tape.send(entry);
+            }
+        }
+        if fill.remaining.is_zero() {
+            exchange.complete(fill.order_id, fill.client_id, OrderStatus::Filled);
+        }
+        if fill.client_id == client_id {
+            own.push(format_fill(&fill));
+        } else {
+            exchange.notify(fill.client_id, format_fill(&fill));
+        }
+    }
+    own
+}
+
+pub fn run_batch(book: &DoubleSidedBook, config: &AuctionConfig, batch_seq: u64) -> BatchResult {
+    if book.sit_out() {
+        return book.with_both_read(halted_batch).with_batch_seq(batch_seq);
+    }
+    let mut workspace = book.workspace.lock().unwrap();
+    let result = book
+        .with_both_locked(|bids, asks| {
+            // the auction only, not the wait for the locks
+            let started = Instant::now();
+            let result = calculate_batch_with(bids, asks, config, &mut workspace);
+            book.latency.lock().unwrap().record(started.elapsed());
+            result
+        })
+        .with_batch_seq(batch_seq);
+    drop(workspace);
+    if let (BatchReport::CircuitBreak { .. }, Some(breaker)) =
+        (&result.report, config.circuit_breaker)
+    {
+        *book.halt.lock().unwrap() = match breaker.cooldown {
+            Some(0) => None,
+            Some(batches) => Some(Halt::Batches(batches)),
+            None => Some(Halt::UntilResumed),
+        };
+    }
+    if let BatchReport::Trade {
+        batch_seq,
+        price,
+        qty,
+        ..
+    } = &result.report
+    {
+        book.vwap.lock().unwrap().add(price, *qty);
+        book.history.lock().unwrap().push(HistoricTrade {
+            batch_seq: *batch_seq,
+            price: price.clone(),
+            qty: *qty,
+        });
+    }
+    result
+}
+
+pub async fn update_order_book(
+    mut rx: Receiver<(Command, Client)>,
+    exchange: Arc<Exchange>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    loop {
+        let (cmd, client) = tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = &mut shutdown => {
+                // no new commands, but the ones already sent are applied
+                rx.close();
+                while let Some((cmd, client)) = rx.recv().await {
+                    apply_and_respond(&exchange, cmd, &client);
+                }
+                break;
+            }
+        };
+
+        /*
+        Come up with better non-blocking solution.
+        This implementation will lock current thread for the time auction is running
+         */
+        apply_and_respond(&exchange, cmd, &client);
+    }
+}
+
+fn apply_and_respond(exchange: &Exchange, cmd: Command, client: &Client) {
+    let response = apply_command(exchange, cmd, client.id);
+    // nothing to answer, e.g. a client without orders disconnected
+    if !response.is_empty() {
+        let _ = client.responder.send(response);
+    }
+}
+
+// ERR becomes NACK, any other response ACK with the same fields, e.g. the order id
+fn acknowledge(seq: u64, response: &str) -> String {
+    match response.split_once(',') {
+        Some(("ERR", reason)) => format!("NACK,{},{}", seq, reason),
+        Some((_, fields)) => format!("ACK,{},{}", seq, fields),
+        None => format!("ACK,{}", seq),
+    }
+}
+
+// applies the command to the books and returns the response line for the client
+pub fn apply_command(exchange: &Exchange, cmd: Command, client_id: ClientId) -> String {
+    let _span = info_span!("command", client = client_id).entered();
+    let event_log = match &exchange.event_log {
+        Some(event_log) => event_log,
+        None => return execute_command(exchange, cmd, client_id),
+    };
+    let mut event_log = event_log.lock().unwrap();
+    let line = event_log::command_line(&cmd);
+    let response = execute_command(exchange, cmd, client_id);
+    if let Some(line) = line.filter(|_| event_log::accepted(&response)) {
+        event_log.command(client_id, &line);
+    }
+    response
+}
+
+fn execute_command(exchange: &Exchange, cmd: Command, client_id: ClientId) -> String {
+    match cmd {
+        Command::Add {
+            order_type,
+            symbol,
+            time_in_force,
+            min_qty,
+            display_qty,
+            expires_at,
+            post_only,
+            price,
+            qty,
+        } => {
+            exchange
+                .metrics
+                .orders_received
+                .fetch_add(1, atomic::Ordering::Relaxed);
+            let last_price = exchange
+                .find_book(&symbol)
+                .and_then(|book| book.history.lock().unwrap().last_price());
+            let sent = price;
+            let price = match sent
+                .as_ref()
+                .map(|price| exchange.snap_price(&symbol, price))
+            {
+                Some(Ok(price)) => Some(price),
+                None => None,
+                Some(Err(e)) => {
+                    exchange
+                        .metrics
+                        .orders_rejected
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+                    return e.response();
+                }
+            };
+            let checked = match &price {
+                Some(price) => exchange.check_price(&symbol, price),
+                None => Ok(()),
+            };
+            if let Err(e) = checked.and_then(|_| {
+                exchange.check_limits(&symbol, price.as_ref(), qty, last_price.as_ref())
+            }) {
+                exchange
+                    .metrics
+                    .orders_rejected
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+                return e.response();
+            }
+            // it would be removed before it could trade
+            if expires_at.is_some_and(|expires_at| expires_at <= exchange.clock.now()) {
+                return ClientError::ExpiryPassed.response();
+            }
+            let book = exchange.book(&symbol);
+            let side = match order_type {
+                OrderType::Buy => Side::Bid,
+                OrderType::Sell => Side::Ask,
+            };
+            let cross_config = exchange
+                .crosses_immediately(&book)
+                .then(|| exchange.symbol_auction_config(&symbol, &exchange.auction_config));
+            let added = book.with_both_locked(|bids, asks| {
+                let (orders, opposite) = match side {
+                    Side::Bid => (bids, asks),
+                    Side::Ask => (asks, bids),
+                };
+                // checked under the lock, so the side never grows past the limit
+                if exchange.max_orders.is_some_and(|max| orders.len() >= max) {
+                    return None;
+                }
+                let id = exchange
+                    .next_order_id
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+                let order = match &price {
+                    Some(price) => Order::new(id, price.clone(), qty),
+                    None => Order::market(id, qty),
+                };
+                let mut order = order
+                    .with_client(client_id)
+                    .with_time_in_force(time_in_force)
+                    .with_min_qty(min_qty)
+                    .with_display_qty(display_qty)
+                    .with_expires_at(expires_at)
+                    .with_post_only(post_only)
+                    .with_submitted(exchange.next_submission());
+                let fills = match &cross_config {
+                    Some(config) => try_immediate_cross(&mut order, side, opposite, config),
+                    None => vec![],
+                };
+                if !order.qty().is_zero() {
+                    insert_order(orders, side, order);
+                }
+                Some((id, fills))
+            });
+            let (id, fills) = match added {
+                Some(added) => added,
+                None => {
+                    exchange
+                        .metrics
+                        .orders_rejected
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+                    return ClientError::BookFull.response();
+                }
+            };
+            let mut lines = match (price, sent) {
+                // the client learns the price it wasn't expecting
+                (Some(price), Some(sent)) if price != sent => {
+                    vec![format!("ADDED,{},{}", id, price)]
+                }
+                _ => vec![format!("ADDED,{}", id)],
+            };
+            lines.extend(settle_immediate_fills(
+                exchange, &symbol, &book, client_id, fills,
+            ));
+            lines.join("\n")
+        }
+        Command::Cancel { id } => {
+            let owner = exchange.owner(client_id);
+            let cancelled = exchange.books().iter().find_map(|(_, book)| {
+                cancel_order(&mut book.bids.write().unwrap(), id, owner)
+                    .or_else(|| cancel_order(&mut book.asks.write().unwrap(), id, owner))
+            });
+            match cancelled {
+                Some(order) => {
+                    exchange.complete(id, order.client_id(), OrderStatus::Cancelled);
+                    format!("CANCELLED,{}", id)
+                }
+                None => ClientError::UnknownOrder(id).response(),
+            }
+        }
+        Command::Amend { id, price, qty } => {
+            let owner = exchange.owner(client_id);
+            let amended = exchange.books().iter().find_map(|(symbol, book)| {
+                amend_order(exchange, symbol, book, id, owner, &price, qty)
+            });
+            match amended {
+                Some(Ok(amended)) if amended != price => format!("AMENDED,{},{}", id, amended),
+                Some(Ok(_)) => format!("AMENDED,{}", id),
+                Some(Err(e)) => e.response(),
+                None => ClientError::UnknownOrder(id).response(),
+            }
+        }
+        Command::Reduce { id, qty } => {
+            let owner = exchange.owner(client_id);
+            let remaining = exchange.books().iter().find_map(|(_, book)| {
+                reduce_order(&mut book.bids.write().unwrap(), id, owner, qty)
+                    .or_else(|| reduce_order(&mut book.asks.write().unwrap(), id, owner, qty))
+            });
+            match remaining {
+                Some((remaining, order_client)) => {
+                    if remaining.is_zero() {
+                        exchange.complete(id, order_client, OrderStatus::Cancelled);
+                    }
+                    format!("REDUCED,{},{}", id, remaining)
+                }
+                None => ClientError::UnknownOrder(id).response(),
+            }
+        }
+        Command::Sequenced { seq, command } => {
+            acknowledge(seq, &execute_command(exchange, *command, client_id))
+        }
+        Command::Halt { symbol, .. } => {
+            // created if need be, so that orders can be queued before the first batch
+            *exchange.book(&symbol).halt.lock().unwrap() = Some(Halt::UntilResumed);
+            info!("{} halted", symbol);
+            format!("HALTED,{}", symbol)
+        }
+        Command::Resume { symbol, .. } => match exchange.find_book(&symbol) {
+            Some(book) if book.resume() => {
+                info!("{} resumed", symbol);
+                format!("RESUMED,{}", symbol)
+            }
+            _ => ClientError::NotHalted(symbol).response(),
+        },
+        Command::CancelAll => {
+            let mut cancelled = vec![];
+            for (_, book) in exchange.books() {
+                book.with_both_locked(|bids, asks| {
+                    for orders in [bids, asks] {
+                        orders.retain(|order| {
+                            let own = order.client_id() == client_id;
+                            if own {
+                                cancelled.push(order.id());
+                            }
+                            !own
+                        });
+                    }
+                });
+            }
+            let lines: Vec<String> = cancelled
+                .into_iter()
+                .map(|id| {
+                    exchange.complete(id, client_id, OrderStatus::Cancelled);
+                    format!("CANCELLED,{}", id)
+                })
+                .collect();
+            lines.join("\n")
+        }
+        Command::Query(query) => answer_query(exchange, query),
+    }
+}
+
+pub fn answer_query(exchange: &Exchange, query: Query) -> String {
+    match query {
+        Query::Bbo { symbol } => {
+            let (best_bid, best_ask) = best_prices(exchange, &symbol);
+            format!(
+                "BBO,{},{}",
+                price_or_none(best_bid.as_ref()),
+                price_or_none(best_ask.as_ref())
+            )
+        }
+        Query::Spread { symbol } => {
+            // negative if the book crossed since the last batch
+            let spread = match best_prices(exchange, &symbol) {
+                (Some(best_bid), Some(best_ask)) => Some(best_ask - best_bid),
+                _ => None,
+            };
+            format!("SPREAD,{}", price_or_none(spread.as_ref()))
+        }
+        Query::Mid { symbol } => {
+            let indicative = exchange.find_book(&symbol).and_then(|book| {
+                book.with_both_read(|bids, asks| {
+                    let config = exchange.symbol_auction_config(&symbol, &exchange.auction_config);
+                    indicative_price(bids, asks, &config).ok()
+                })
+            });
+            match indicative {
+                None => "MID,NONE".to_string(),
+                Some((price, qty)) => format!("MID,{},{}", price, qty),
+            }
+        }
+        Query::Depth { symbol, levels } => format_depth(exchange, &symbol, levels),
+        Query::Ping => "PONG".to_string(),
+        Query::Clock => format_clock(exchange),
+        Query::Volume { symbol } => {
+            let (bid_volume, ask_volume) = match exchange.find_book(&symbol) {
+                None => (BigDecimal::default(), BigDecimal::default()),
+                // each side is locked only for its own sum
+                Some(book) => (
+                    total_qty(&book.bids.read().unwrap()),
+                    total_qty(&book.asks.read().unwrap()),
+                ),
+            };
+            format!("VOLUME,BID,{},ASK,{}", bid_volume, ask_volume)
+        }
+        Query::Vwap { symbol } => {
+            let price_scale = exchange.symbol_price_scale(&symbol);
+            let vwap = exchange
+                .find_book(&symbol)
+                .and_then(|book| book.vwap.lock().unwrap().value(price_scale));
+            format!("VWAP,{}", price_or_none(vwap.as_ref()))
+        }
+        Query::Trades { symbol, count } => format_trades(exchange, &symbol, count),
+        Query::Order { id } => format_order(exchange, id),
+        Query::Stats { symbol } => {
+            let stats = exchange
+                .find_book(&symbol)
+                .and_then(|book| book.latency.lock().unwrap().stats());
+            format_stats(stats)
+        }
+        Query::ClientOrders { client_id, after } => {
+            format_client_orders(exchange, client_id, after)
+        }
+    }
+}
+
+// best bid and best ask, both read under the same locks
+fn best_prices(exchange: &Exchange, symbol: &str) -> (Option<BigDecimal>, Option<BigDecimal>) {
+    match exchange.find_book(symbol) {
+        None => (None, None),
+        Some(book) => book.with_both_read(|bids, asks| {
+            (
+                // market orders have no price to show
+                bids.iter().filter_map(|o| o.price().cloned()).max(),
+                asks.iter().filter_map(|o| o.price().cloned()).min(),
+            )
+        }),
+    }
+}
+
+// RESTING with the qty left and batches out, FILLED, CANCELLED or UNKNOWN
+fn format_order(exchange: &Exchange, id: OrderId) -> String {
+    let resting = exchange.books().iter().find_map(|(_, book)| {
+        book.with_both_read(|bids, asks| {
+            bids.iter()
+                .chain(asks.iter())
+                .find(|order| order.id() == id)
+                .map(|order| (order.qty(), order.batches_out()))
+        })
+    });
+    match resting {
+        Some((qty, batches_out)) => format!("ORDER,{},RESTING,{},{}", id, qty, batches_out),
+        None => match exchange.completed.lock().unwrap().get(id) {
+            Some(status) => format!("ORDER,{},{}", id, status),
+            // never added, or completed too long ago
+            None => format!("ORDER,{},UNKNOWN", id),
+        },
+    }
+}
+
+// CLOCK,<unix millis>,<millis until the next batch>, NONE if no engine runs the batches
+fn format_clock(exchange: &Exchange) -> String {
+    let now = exchange
+        .clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    let next_batch = *exchange.next_batch.lock().unwrap();
+    // batches before the end of the opening auction don't trade, the uncross is the first after it
+    let next_batch = next_batch.map(|next_batch| match *exchange.phase.lock().unwrap() {
+        MarketPhase::Accumulating { until } => next_batch.max(until),
+        MarketPhase::Uncross | MarketPhase::Continuous => next_batch,
+    });
+    match next_batch {
+        Some(next_batch) => {
+            let left = next_batch.saturating_duration_since(Instant::now());
+            format!("CLOCK,{},{}", now, left.as_millis())
+        }
+        None => format!("CLOCK,{},NONE", now),
+    }
+}
+
+// STATS,<batches>,<min>,<avg>,<p50>,<p99>,<max> in micros, STATS,NONE before the first batch
+fn format_stats(stats: Option<LatencyStats>) -> String {
+    match stats {
+        None => "STATS,NONE".to_string(),
+        Some(stats) => format!(
+            "STATS,{},{},{},{},{},{}",
+            stats.batches,
+            stats.min.as_micros(),
+            stats.avg.as_micros(),
+            stats.p50.as_micros(),
+            stats.p99.as_micros(),
+            stats.max.as_micros()
+        ),
+    }
+}
+
+/**
+MYORDER,<id>,<symbol>,<BUY|SELL>,<price|MKT>,<qty left> per resting order of the client, by id.
+Terminated by END, or by MORE,<last id> if there are more than a page, to be asked for with that id.
+*/
+fn format_client_orders(exchange: &Exchange, client_id: ClientId, after: OrderId) -> String {
+    let mut orders = vec![];
+    for (symbol, book) in exchange.books() {
+        book.with_both_read(|bids, asks| {
+            for (side, orders_of_side) in [("BUY", bids), ("SELL", asks)] {
+                orders.extend(
+                    orders_of_side
+                        .iter()
+                        .filter(|order| order.client_id() == client_id && order.id() > after)
+                        .map(|order| {
+                            let price = order.price().map_or("MKT".to_string(), |p| p.to_string());
+                            let line = format!(
+                                "MYORDER,{},{},{},{},{}",
+                                order.id(),
+                                symbol,
+                                side,
+                                price,
+                                order.qty()
+                            );
+                            (order.id(), line)
+                        }),
+                );
+            }
+        })
+    }
+    orders.sort_unstable_by_key(|(id, _)| *id);
+
+    let more = orders.len() > CLIENT_ORDERS_PAGE;
+    orders.truncate(CLIENT_ORDERS_PAGE);
+    let last = orders.last().map(|(id, _)| *id);
+    let mut lines: Vec<String> = orders.into_iter().map(|(_, line)| line).collect();
+    match last {
+        Some(last) if more => lines.push(format!("MORE,{}", last)),
+        _ => lines.push("END".to_string()),
+    }
+    lines.join("\n")
+}
+
+// a decimal, so that many large orders don't overflow
+// the reserves of icebergs are hidden
+fn total_qty(orders: &[Order]) -> BigDecimal {
+    let total: BigDecimal = orders
+        .iter()
+        .map(|order| order.visible_qty().to_decimal())
+        .sum();
+    // without the trailing zeros of the qty scale
+    total.normalized()
+}
+
+// newline-delimited levels, best price first, terminated by END
+fn format_depth(exchange: &Exchange, symbol: &str, levels: usize) -> String {
+    let mut lines = vec![];
+
+    if let Some(book) = exchange.find_book(symbol) {
+        let bids = price_levels(&book.bids.read().unwrap());
+        let asks = price_levels(&book.asks.read().unwrap());
+
+        for level in bids.iter().rev().take(levels) {
+            lines.push(format!("BID,{},{}", level.price, level.qty));
+        }
+        for level in asks.iter().take(levels) {
+            lines.push(format!("ASK,{},{}", level.price, level.qty));
+        }
+    }
+
+    lines.push("END".to_string());
+    lines.join("\n")
+}
+
+// TRADE,<batch seq>,<price>,<qty> per trade, oldest first
+fn format_trades(exchange: &Exchange, symbol: &str, count: usize) -> String {
+    let mut lines = vec![];
+
+    if let Some(book) = exchange.find_book(symbol) {
+        for trade in book.history.lock().unwrap().last(count) {
+            lines.push(format!(
+                "TRADE,{},{},{}",
+                trade.batch_seq, trade.price, trade.qty
+            ));
+        }
+    }
+
+    lines.push("END".to_string());
+    lines.join("\n")
+}
+
+fn price_or_none(price: Option<&BigDecimal>) -> String {
+    price.map_or_else(|| "NONE".to_string(), |p| p.to_string())
+}
+
+// the resting order with the given id, orders of other clients than `owner` are unknown to the caller
+fn own_order(orders: &[Order], id: OrderId, owner: Option<ClientId>) -> Option<usize> {
+    orders
+        .iter()
+        .position(|o| o.id() == id && owner.is_none_or(|owner| o.client_id() == owner))
+}
+
+// removes the resting order with the given id, if any
+fn cancel_order(orders: &mut Vec<Order>, id: OrderId, owner: Option<ClientId>) -> Option<Order> {
+    own_order(orders, id, owner).map(|idx| orders.remove(idx))
+}
+
+// returns the qty left resting and the client of the order, which is removed once nothing is left
+fn reduce_order(
+    orders: &mut Vec<Order>,
+    id: OrderId,
+    owner: Option<ClientId>,
+    qty: Qty,
+) -> Option<(Qty, ClientId)> {
+    let idx = own_order(orders, id, owner)?;
+    // a smaller qty keeps the time priority, so the order stays in place
+    let remaining = orders[idx].reduce(qty);
+    let client_id = orders[idx].client_id();
+    if remaining.is_zero() {
+        orders.remove(idx);
+    }
+    Some((remaining, client_id))
+}
+
+/**
+None if the order is not in the book or belongs to another client than `owner`, the amended order is checked against the limits.
+Returns the price it rests at, snapped to the scale of the symbol.
+*/
+fn amend_order(
+    exchange: &Exchange,
+    symbol: &str,
+    book: &DoubleSidedBook,
+    id: OrderId,
+    owner: Option<ClientId>,
+    price: &BigDecimal,
+    qty: Qty,
+) -> Option<Result<BigDecimal, ClientError>> {
+    let last_price = book.history.lock().unwrap().last_price();
+    for (orders, side) in [(&book.bids, Side::Bid), (&book.asks, Side::Ask)] {
+        let mut orders = orders.write().unwrap();
+        let idx = match own_order(&orders, id, owner) {
+            Some(idx) => idx,
+            None => continue,
+        };
+        let checked = exchange.snap_price(symbol, price).and_then(|price| {
+            exchange.check_price(symbol, &price)?;
+            exchange.check_limits(symbol, Some(&price), qty, last_price.as_ref())?;
+            Ok(price)
+        });
+        let price = match checked {
+            Ok(price) => price,
+            Err(e) => return Some(Err(e)),
+        };
+        if orders[idx].amend(price.clone(), qty) {
+            // behind the orders that arrived before the amend
+            let order = orders
+                .remove(idx)
+                .with_submitted(exchange.next_submission());
+            insert_order(&mut orders, side, order);
+        }
+        return Some(Ok(price));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auction::{
+        AuctionConfig, BatchReport, BatchResult, CircuitBreaker, NoTradeReason, Order, Qty,
+        TimeInForce,
+    };
+    use crate::{
+        acknowledge, answer_query, apply_command, batch_record, notify_fills, run_batch,
+        run_batches, update_order_book, write_results, Client, ClientError, Command,
+        CommandHandler, CompletedOrders, Exchange, HistoricTrade, MatchMode, OrderLimits,
+        OrderStatus, OrderType, OutputFormat, ParseCommandError, PricePrecision, Query,
+        SymbolConfig, TradeHistory, CLIENT_ORDERS_PAGE,
+    };
+    use bigdecimal::BigDecimal;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::pin::Pin;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, BufReader, ReadBuf};
+
+    fn add(order_type: OrderType, symbol: &str, price: &str, qty: u32) -> Command {
+        Command::Add {
+            order_type,
+            symbol: symbol.to_string(),
+            time_in_force: TimeInForce::GoodTillCancel,
+            min_qty: None,
+            display_qty: None,
+            expires_at: None,
+            post_only: false,
+            price: Some(BigDecimal::from_str(price).unwrap()),
+            qty: Qty::from(qty),
+        }
+    }
+
+    #[test]
+    fn add_assigns_increasing_ids() {
+        let exchange = Exchange::new();
+
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 1), 1),
+            "ADDED,1"
+        );
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.00", 1), 1),
+            "ADDED,2"
+        );
+        assert_eq!(exchange.book("AAPL").bids.read().unwrap()[0].id(), 1);
+        assert_eq!(exchange.book("AAPL").asks.read().unwrap()[0].id(), 2);
+    }
+
+    #[test]
+    fn cancel_removes_order_by_id() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 1), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 1), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.00", 1), 1);
+
+        assert_eq!(
+            apply_command(&exchange, Command::Cancel { id: 2 }, 1),
+            "CANCELLED,2"
+        );
+        assert_eq!(
+            apply_command(&exchange, Command::Cancel { id: 3 }, 1),
+            "CANCELLED,3"
+        );
+
+        let book = exchange.book("AAPL");
+        let bids = book.bids.read().unwrap();
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].id(), 1);
+        assert!(book.asks.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancel_unknown_id_is_an_error() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 1), 1);
+
+        assert_eq!(
+            apply_command(&exchange, Command::Cancel { id: 42 }, 1),
+            "ERR,UNKNOWN_ORDER,unknown order 42"
+        );
+        assert_eq!(exchange.book("AAPL").bids.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn orders_of_other_clients_are_unknown() {
+        use std::collections::HashMap;
+
+        let keys = HashMap::from([("k3y-one".to_string(), 1), ("k3y-two".to_string(), 2)]);
+        let exchange = Exchange::new().with_api_keys(Some(keys));
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+
+        let unknown = "ERR,UNKNOWN_ORDER,unknown order 1";
+        assert_eq!(
+            apply_command(&exchange, Command::Cancel { id: 1 }, 2),
+            unknown
+        );
+        let amend = Command::Amend {
+            id: 1,
+            price: BigDecimal::from_str("10.50").unwrap(),
+            qty: Qty::from(6),
+        };
+        assert_eq!(apply_command(&exchange, amend, 2), unknown);
+        let reduce = Command::Reduce {
+            id: 1,
+            qty: Qty::from(2),
+        };
+        assert_eq!(apply_command(&exchange, reduce, 2), unknown);
+
+        let book = exchange.book("AAPL");
+        {
+            let bids = book.bids.read().unwrap();
+            assert_eq!(bids[0].qty(), Qty::from(5));
+            assert_eq!(
+                bids[0].price(),
+                Some(&BigDecimal::from_str("10.00").unwrap())
+            );
+        }
+
+        // nor can it look the order up, resting or not
+        let order = Query::Order { id: 1 };
+        assert_eq!(
+            exchange.authorize_query(&order, 2),
+            Err(ClientError::Unauthorized)
+        );
+        assert_eq!(exchange.authorize_query(&order, 1), Ok(()));
+        assert_eq!(
+            apply_command(&exchange, Command::Cancel { id: 1 }, 1),
+            "CANCELLED,1"
+        );
+        assert_eq!(
+            exchange.authorize_query(&order, 2),
+            Err(ClientError::Unauthorized)
+        );
+        assert_eq!(exchange.authorize_query(&Query::Order { id: 9 }, 2), Ok(()));
+
+        // without keys a client is a connection, after a reconnect it still reaches its orders
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        assert_eq!(
+            apply_command(&exchange, Command::Cancel { id: 1 }, 2),
+            "CANCELLED,1"
+        );
+        assert_eq!(exchange.authorize_query(&order, 2), Ok(()));
+    }
+
+    #[test]
+    fn parse_add_command() {
+        match CommandHandler::parse_command("ADD,SELL,AAPL,43.521,10\r\n") {
+            Ok(Command::Add {
+                order_type: OrderType::Sell,
+                symbol,
+                time_in_force: TimeInForce::GoodTillCancel,
+                min_qty: None,
+                display_qty: None,
+                expires_at: None,
+                post_only: false,
+                price,
+                qty,
+            }) => {
+                assert_eq!(symbol, "AAPL");
+                // as sent, the order book snaps it
+                assert_eq!(price, Some(BigDecimal::from_str("43.521").unwrap()));
+                assert_eq!(qty, Qty::from(10));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn parse_cancel_command() {
+        assert!(matches!(
+            CommandHandler::parse_command("CANCEL,7\n"),
+            Ok(Command::Cancel { id: 7 })
+        ));
+    }
+
+    #[test]
+    fn parse_missing_field() {
+        assert_eq!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,43.52").err(),
+            Some(ParseCommandError::MissingField("qty"))
+        );
+        assert_eq!(
+            CommandHandler::parse_command("CANCEL").err(),
+            Some(ParseCommandError::MissingField("id"))
+        );
+    }
+
+    #[test]
+    fn parse_unknown_command() {
+        assert_eq!(
+            CommandHandler::parse_command("MODIFY,BUY,AAPL,43.52,10").err(),
+            Some(ParseCommandError::UnknownCommand("MODIFY".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_unknown_side() {
+        assert_eq!(
+            CommandHandler::parse_command("ADD,HOLD,AAPL,43.52,10").err(),
+            Some(ParseCommandError::UnknownSide("HOLD".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_bad_price() {
+        assert_eq!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,43.52.10,5").err(),
+            Some(ParseCommandError::BadPrice("43.52.10".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_bad_qty() {
+        assert_eq!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,43.52,-5").err(),
+            Some(ParseCommandError::BadQty("-5".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_zero_qty() {
+        assert_eq!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,100.00,0").err(),
+            Some(ParseCommandError::ZeroQty)
+        );
+    }
+
+    #[test]
+    fn non_positive_price_is_rejected() {
+        let exchange = Exchange::new();
+        let submit = |msg| match CommandHandler::parse_command(msg) {
+            Ok(command) => apply_command(&exchange, command, 1),
+            Err(e) => ClientError::from(e).response(),
+        };
+
+        assert_eq!(
+            submit("ADD,BUY,AAPL,0,5"),
+            "ERR,BAD_PRICE,price must be positive, got 0"
+        );
+        assert_eq!(
+            submit("ADD,SELL,AAPL,-1.50,5"),
+            "ERR,BAD_PRICE,price must be positive, got -1.50"
+        );
+        // rounds to zero
+        assert_eq!(
+            submit("ADD,SELL,AAPL,0.001,5"),
+            "ERR,BAD_PRICE,price must be positive, got 0.00"
+        );
+        assert_eq!(submit("ADD,SELL,AAPL,1.00,5"), "ADDED,1");
+        assert_eq!(
+            submit("AMEND,1,-1.00,5"),
+            "ERR,BAD_PRICE,price must be positive, got -1.00"
+        );
+    }
+
+    #[test]
+    fn parse_bad_id() {
+        assert_eq!(
+            CommandHandler::parse_command("CANCEL,abc").err(),
+            Some(ParseCommandError::BadId("abc".to_string()))
+        );
+    }
+
+    #[test]
+    fn symbols_clear_independently() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "9.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Buy, "MSFT", "20.00", 3), 1);
+        apply_command(&exchange, add(OrderType::Sell, "MSFT", "21.00", 3), 1);
+
+        let config = AuctionConfig::default();
+        let aapl = run_batch(&exchange.book("AAPL"), &config, 1);
+        let msft = run_batch(&exchange.book("MSFT"), &config, 1);
+
+        assert!(matches!(aapl.report, BatchReport::Trade { qty, .. } if qty == Qty::from(5)));
+        assert!(matches!(
+            msft.report,
+            BatchReport::NoTrade {
+                reason: NoTradeReason::NoCross,
+                ..
+            }
+        ));
+        assert!(exchange.book("AAPL").bids.read().unwrap().is_empty());
+        assert_eq!(exchange.book("MSFT").bids.read().unwrap().len(), 1);
+        assert_eq!(exchange.book("MSFT").asks.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn parse_bbo_query() {
+        match CommandHandler::parse_command("QUERY,BBO,AAPL\n") {
+            Ok(Command::Query(Query::Bbo { symbol })) => assert_eq!(symbol, "AAPL"),
+            _ => panic!(),
+        }
+        assert_eq!(
+            CommandHandler::parse_command("QUERY,LAST,AAPL").err(),
+            Some(ParseCommandError::UnknownQuery("LAST".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_volume_query() {
+        match CommandHandler::parse_command("QUERY,VOLUME,AAPL") {
+            Ok(Command::Query(Query::Volume { symbol })) => assert_eq!(symbol, "AAPL"),
+            _ => panic!(),
+        }
+        assert_eq!(
+            CommandHandler::parse_command("QUERY,VOLUME").err(),
+            Some(ParseCommandError::MissingField("symbol"))
+        );
+    }
+
+    #[test]
+    fn fractional_qty_is_traded() {
+        let exchange = Exchange::new();
+        let submit = |msg: &str| match CommandHandler::parse_command(msg) {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            Ok(command) => apply_command(&exchange, command, 1),
+            Err(e) => ClientError::from(e).response(),
+        };
+        assert_eq!(submit("ADD,BUY,AAPL,10.00,0.5"), "ADDED,1");
+        assert_eq!(submit("ADD,BUY,AAPL,10.00,1.5"), "ADDED,2");
+        assert_eq!(submit("ADD,SELL,AAPL,9.00,1.25"), "ADDED,3");
+        assert_eq!(
+            submit("ADD,SELL,AAPL,9.00,0.000000001"),
+            "ERR,BAD_COMMAND,bad qty 0.000000001"
+        );
+
+        run_batches(&exchange, &AuctionConfig::default());
+        assert_eq!(submit("QUERY,VOLUME,AAPL"), "VOLUME,BID,0.75,ASK,0");
+        assert_eq!(submit("QUERY,ORDER,2"), "ORDER,2,RESTING,0.75,1");
+        assert_eq!(submit("QUERY,VWAP,AAPL"), "VWAP,9.50");
+        assert_eq!(submit("REDUCE,2,0.25"), "REDUCED,2,0.5");
+    }
+
+    #[test]
+    fn circuit_break_halts_the_symbol_until_resumed() {
+        let exchange = Exchange::new();
+        let config = AuctionConfig {
+            circuit_breaker: Some(CircuitBreaker {
+                max_qty: Qty::from(5),
+                cooldown: None,
+            }),
+            ..AuctionConfig::default()
+        };
+        let submit = |msg: &str| match CommandHandler::parse_command(msg) {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            Ok(command) => apply_command(&exchange, command, 1),
+            Err(e) => ClientError::from(e).response(),
+        };
+        submit("ADD,BUY,AAPL,10.00,8");
+        submit("ADD,SELL,AAPL,9.00,8");
+        assert_eq!(submit("RESUME,AAPL"), "ERR,NOT_HALTED,AAPL is not halted");
+
+        let report = |results: Vec<(String, BatchResult)>| {
+            let mut out = vec![];
+            write_results(&mut out, &results, OutputFormat::Text).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            report(run_batches(&exchange, &config)),
+            "AAPL: Batch 1: CIRCUIT_BREAK, price=BigDecimal(\"9.50\"), qty=8, halted\n"
+        );
+        assert_eq!(submit("QUERY,VOLUME,AAPL"), "VOLUME,BID,8,ASK,8");
+        // orders are still accepted while halted
+        assert_eq!(submit("REDUCE,1,4"), "REDUCED,1,4");
+        assert_eq!(
+            report(run_batches(&exchange, &config)),
+            "AAPL: No Trade: Halted\n"
+        );
+
+        assert_eq!(submit("RESUME,AAPL"), "RESUMED,AAPL");
+        assert_eq!(
+            report(run_batches(&exchange, &config)),
+            "AAPL: Batch 3: cleared BID=1, cleared ASK=1, price=BigDecimal(\"9.50\"), qty=4, imbalance BID=0, ASK=4\n"
+        );
+    }
+
+    #[test]
+    fn circuit_break_cooldown() {
+        let exchange = Exchange::new();
+        let config = AuctionConfig {
+            circuit_breaker: Some(CircuitBreaker {
+                max_qty: Qty::from(5),
+                cooldown: Some(2),
+            }),
+            ..AuctionConfig::default()
+        };
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 8), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "9.00", 8), 1);
+
+        let mut reports = vec![];
+        for _ in 0..4 {
+            let results = run_batches(&exchange, &config);
+            reports.push(match &results[0].1.report {
+                BatchReport::CircuitBreak { .. } => "break",
+                BatchReport::NoTrade {
+                    reason: NoTradeReason::Halted,
+                    ..
+                } => "halted",
+                _ => panic!(),
+            });
+        }
+        // the book is the same after the cooldown, so it breaks again
+        assert_eq!(reports, ["break", "halted", "halted", "break"]);
+    }
+
+    #[test]
+    fn orders_accumulate_while_halted() {
+        let exchange = Exchange::new();
+        let config = AuctionConfig::default();
+        let submit = |msg: &str| match CommandHandler::parse_command(msg) {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            Ok(command) => apply_command(&exchange, command, 1),
+            Err(e) => ClientError::from(e).response(),
+        };
+        let report = |results: Vec<(String, BatchResult)>| {
+            let mut out = vec![];
+            write_results(&mut out, &results, OutputFormat::Text).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // before the first order of the symbol
+        assert_eq!(submit("HALT,AAPL,s3cret"), "HALTED,AAPL");
+        submit("ADD,BUY,AAPL,10.00,5");
+        submit("ADD,SELL,AAPL,9.00,3");
+        assert_eq!(
+            report(run_batches(&exchange, &config)),
+            "AAPL: No Trade: Halted\n"
+        );
+        submit("ADD,SELL,AAPL,9.50,2");
+        assert_eq!(
+            report(run_batches(&exchange, &config)),
+            "AAPL: No Trade: Halted\n"
+        );
+        assert_eq!(submit("QUERY,VOLUME,AAPL"), "VOLUME,BID,5,ASK,5");
+
+        assert_eq!(submit("RESUME,AAPL"), "RESUMED,AAPL");
+        assert_eq!(
+            report(run_batches(&exchange, &config)),
+            "AAPL: Batch 3: cleared BID=1, cleared ASK=2, price=BigDecimal(\"9.75\"), qty=5, imbalance BID=0, ASK=0\n"
+        );
+    }
+
+    #[test]
+    fn crossing_order_fills_on_arrival() {
+        for mode in [MatchMode::Batch, MatchMode::Hybrid] {
+            let (tape, mut tape_entries) = tokio::sync::mpsc::unbounded_channel();
+            let exchange = Exchange::new().with_match_mode(mode).with_tape(Some(tape));
+            let submit = |msg: &str, client_id| match CommandHandler::parse_command(msg) {
+                Ok(Command::Query(query)) => answer_query(&exchange, query),
+                Ok(command) => apply_command(&exchange, command, client_id),
+                Err(e) => ClientError::from(e).response(),
+            };
+            submit("ADD,SELL,AAPL,10.00,3", 2);
+            submit("ADD,SELL,AAPL,10.50,2", 2);
+            submit("ADD,SELL,AAPL,11.00,1", 2);
+
+            let added = submit("ADD,BUY,AAPL,10.60,6", 1);
+            if mode == MatchMode::Batch {
+                assert_eq!(added, "ADDED,4");
+                assert_eq!(submit("QUERY,VOLUME,AAPL", 1), "VOLUME,BID,6,ASK,6");
+                assert!(tape_entries.try_recv().is_err());
+                continue;
+            }
+            // at the prices of the resting orders, the rest of the order waits for the batch
+            assert_eq!(
+                added,
+                "ADDED,4\nFILL,4,10.00,3,3,0.00\nFILL,4,10.50,2,1,0.00"
+            );
+            assert_eq!(submit("QUERY,VOLUME,AAPL", 1), "VOLUME,BID,1,ASK,1");
+            assert_eq!(submit("QUERY,ORDER,1", 1), "ORDER,1,FILLED");
+            assert_eq!(submit("QUERY,VWAP,AAPL", 1), "VWAP,10.20");
+            // a trade per resting order, on the tape and in the metrics like those of batches
+            for (price, qty) in [("10.00", 3), ("10.50", 2)] {
+                let entry = tape_entries.try_recv().unwrap();
+                assert_eq!(entry.symbol, "AAPL");
+                assert_eq!(entry.price, BigDecimal::from_str(price).unwrap());
+                assert_eq!(entry.qty, Qty::from(qty));
+            }
+            assert_eq!(
+                exchange
+                    .metrics
+                    .trades
+                    .load(std::sync::atomic::Ordering::Relaxed),
+                2
+            );
+
+            // not while halted
+            submit("HALT,AAPL", 1);
+            assert_eq!(submit("ADD,SELL,AAPL,10.00,1", 2), "ADDED,5");
+            assert_eq!(submit("QUERY,VOLUME,AAPL", 1), "VOLUME,BID,1,ASK,2");
+        }
+    }
+
+    #[test]
+    fn same_batch_orders_clear_in_arrival_order() {
+        let exchange = Exchange::new();
+        let submit = |msg: &str, client_id| match CommandHandler::parse_command(msg) {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            Ok(command) => apply_command(&exchange, command, client_id),
+            Err(e) => ClientError::from(e).response(),
+        };
+        submit("ADD,SELL,AAPL,10.00,1", 2);
+        submit("ADD,SELL,AAPL,10.00,1", 3);
+        submit("ADD,SELL,AAPL,10.00,1", 4);
+        // a larger qty, the order goes behind the two that arrived after it
+        assert_eq!(submit("AMEND,1,10.00,2", 2), "AMENDED,1");
+        submit("ADD,BUY,AAPL,10.00,2", 1);
+
+        run_batches(&exchange, &AuctionConfig::default());
+        assert_eq!(submit("QUERY,ORDER,2", 1), "ORDER,2,FILLED");
+        assert_eq!(submit("QUERY,ORDER,3", 1), "ORDER,3,FILLED");
+        assert_eq!(submit("QUERY,ORDER,1", 1), "ORDER,1,RESTING,2,1");
+    }
+
+    #[test]
+    fn admin_commands_need_the_token() {
+        let halt = |token: Option<&str>| Command::Halt {
+            symbol: "AAPL".to_string(),
+            token: token.map(str::to_string),
+        };
+        let resume = |token: Option<&str>| Command::Resume {
+            symbol: "AAPL".to_string(),
+            token: token.map(str::to_string),
+        };
+
+        let exchange = Exchange::new();
+        assert_eq!(
+            exchange.authorize(&halt(None)),
+            Err(ClientError::AdminDisabled)
+        );
+        assert_eq!(exchange.authorize(&resume(None)), Ok(()));
+
+        let exchange = Exchange::new().with_admin_token(Some("s3cret".to_string()));
+        assert_eq!(exchange.authorize(&halt(Some("s3cret"))), Ok(()));
+        assert_eq!(exchange.authorize(&resume(Some("s3cret"))), Ok(()));
+        assert_eq!(
+            exchange.authorize(&halt(Some("guess"))),
+            Err(ClientError::Unauthorized)
+        );
+        assert_eq!(
+            exchange.authorize(&resume(None)),
+            Err(ClientError::Unauthorized)
+        );
+        assert_eq!(
+            exchange.authorize(&add(OrderType::Buy, "AAPL", "10.00", 1)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn depth_hides_iceberg_reserve() {
+        let exchange = Exchange::new();
+        let submit = |msg: &str| match CommandHandler::parse_command(msg) {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            Ok(command) => apply_command(&exchange, command, 1),
+            Err(e) => ClientError::from(e).response(),
+        };
+        assert_eq!(submit("ADD,BUY,AAPL,DISPLAY=2,10.00,10"), "ADDED,1");
+        assert_eq!(submit("ADD,BUY,AAPL,9.00,1"), "ADDED,2");
+        assert_eq!(
+            submit("ADD,BUY,AAPL,DISPLAY=0,10.00,10"),
+            "ERR,BAD_COMMAND,bad display qty 0"
+        );
+        assert_eq!(submit("QUERY,DEPTH,AAPL"), "BID,10.00,2\nBID,9.00,1\nEND");
+        assert_eq!(submit("QUERY,VOLUME,AAPL"), "VOLUME,BID,3,ASK,0");
+
+        // the reserve is filled, the next tranche is shown
+        assert_eq!(submit("ADD,SELL,AAPL,10.00,6"), "ADDED,3");
+        let results = run_batches(&exchange, &AuctionConfig::default());
+        match &results[0].1.report {
+            BatchReport::Trade { qty, .. } => assert_eq!(*qty, Qty::from(6)),
+            _ => panic!(),
+        }
+        assert_eq!(submit("QUERY,DEPTH,AAPL"), "BID,10.00,2\nBID,9.00,1\nEND");
+        assert_eq!(submit("QUERY,ORDER,1"), "ORDER,1,RESTING,4,1");
+    }
+
+    #[test]
+    fn good_till_time_order_expires_between_batches() {
+        use crate::Clock;
+        use std::sync::Mutex;
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        struct MockClock(Mutex<SystemTime>);
+
+        impl Clock for MockClock {
+            fn now(&self) -> SystemTime {
+                *self.0.lock().unwrap()
+            }
+        }
+
+        let clock = Arc::new(MockClock(Mutex::new(
+            UNIX_EPOCH + Duration::from_secs(1_000),
+        )));
+        let exchange = Exchange::new().with_clock(clock.clone());
+        let submit = |msg: &str| match CommandHandler::parse_command(msg) {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            Ok(command) => apply_command(&exchange, command, 1),
+            Err(e) => ClientError::from(e).response(),
+        };
+        assert_eq!(submit("ADD,BUY,AAPL,GTT=1010,10.00,5"), "ADDED,1");
+        assert_eq!(submit("ADD,SELL,AAPL,11.00,5"), "ADDED,2");
+        assert_eq!(
+            submit("ADD,BUY,AAPL,GTT=1000,10.00,5"),
+            "ERR,EXPIRED,expiry has passed"
+        );
+        assert_eq!(
+            submit("ADD,BUY,AAPL,GTT=soon,10.00,5"),
+            "ERR,BAD_COMMAND,bad expiry soon"
+        );
+
+        let results = run_batches(&exchange, &AuctionConfig::default());
+        assert!(results[0].1.expired.is_empty());
+        assert_eq!(submit("QUERY,ORDER,1"), "ORDER,1,RESTING,5,1");
+
+        *clock.0.lock().unwrap() += Duration::from_secs(10);
+        let results = run_batches(&exchange, &AuctionConfig::default());
+        let expired: Vec<_> = results[0].1.expired.iter().map(|o| o.id()).collect();
+        assert_eq!(expired, [1]);
+        assert_eq!(submit("QUERY,ORDER,1"), "ORDER,1,CANCELLED");
+        assert_eq!(submit("QUERY,DEPTH,AAPL"), "ASK,11.00,5\nEND");
+    }
+
+    #[test]
+    fn full_side_rejects_orders_until_one_is_cancelled() {
+        let exchange = Exchange::new().with_max_orders(Some(2));
+        let buy = || add(OrderType::Buy, "AAPL", "10.00", 5);
+        assert_eq!(apply_command(&exchange, buy(), 1), "ADDED,1");
+        assert_eq!(apply_command(&exchange, buy(), 1), "ADDED,2");
+        assert_eq!(
+            apply_command(&exchange, buy(), 1),
+            "ERR,BOOK_FULL,the side of the book is full"
+        );
+        // the other side and other books have room of their own
+        let sell = add(OrderType::Sell, "AAPL", "11.00", 5);
+        assert_eq!(apply_command(&exchange, sell, 1), "ADDED,3");
+        let msft = add(OrderType::Buy, "MSFT", "10.00", 5);
+        assert_eq!(apply_command(&exchange, msft, 1), "ADDED,4");
+
+        assert_eq!(
+            apply_command(&exchange, Command::Cancel { id: 1 }, 1),
+            "CANCELLED,1"
+        );
+        assert_eq!(apply_command(&exchange, buy(), 1), "ADDED,5");
+        assert_eq!(
+            apply_command(&exchange, buy(), 1),
+            "ERR,BOOK_FULL,the side of the book is full"
+        );
+        assert_eq!(exchange.book("AAPL").bids.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn volume_sums_resting_qty() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.50", 2), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.00", 7), 1);
+
+        let volume = |symbol: &str| Query::Volume {
+            symbol: symbol.to_string(),
+        };
+        assert_eq!(
+            answer_query(&exchange, volume("AAPL")),
+            "VOLUME,BID,7,ASK,7"
+        );
+        assert_eq!(
+            answer_query(&exchange, volume("MSFT")),
+            "VOLUME,BID,0,ASK,0"
+        );
+    }
+
+    #[test]
+    fn bbo_returns_best_prices() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.50", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.25", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.00", 5), 1);
+
+        let bbo = Query::Bbo {
+            symbol: "AAPL".to_string(),
+        };
+        assert_eq!(answer_query(&exchange, bbo), "BBO,10.50,11.00");
+    }
+
+    #[test]
+    fn bbo_reports_empty_sides() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+
+        let bbo = |symbol: &str| Query::Bbo {
+            symbol: symbol.to_string(),
+        };
+        assert_eq!(answer_query(&exchange, bbo("AAPL")), "BBO,10.00,NONE");
+        assert_eq!(answer_query(&exchange, bbo("MSFT")), "BBO,NONE,NONE");
+        assert!(exchange.find_book("MSFT").is_none());
+    }
+
+    #[test]
+    fn spread_of_best_prices() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.90", 5), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.50", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Buy, "MSFT", "20.00", 5), 1);
+
+        let spread = |msg: &str| match CommandHandler::parse_command(msg) {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            _ => panic!(),
+        };
+        assert_eq!(spread("QUERY,SPREAD,AAPL"), "SPREAD,0.10");
+        // no asks
+        assert_eq!(spread("QUERY,SPREAD,MSFT"), "SPREAD,NONE");
+        assert_eq!(spread("QUERY,SPREAD,TSLA"), "SPREAD,NONE");
+
+        // crossed until the next batch
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "10.75", 5), 1);
+        assert_eq!(spread("QUERY,SPREAD,AAPL"), "SPREAD,-0.15");
+    }
+
+    #[test]
+    fn mid_is_the_price_of_the_next_batch() {
+        let config = Arc::new(AuctionConfig {
+            tick_size: Some(BigDecimal::from_str("0.25").unwrap()),
+            ..AuctionConfig::default()
+        });
+        let exchange = Exchange::new().with_auction_config(config.clone());
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.90", 5), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.50", 3), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "10.20", 7), 1);
+        apply_command(&exchange, add(OrderType::Buy, "MSFT", "20.00", 5), 1);
+
+        let mid = |msg: &str| match CommandHandler::parse_command(msg) {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            _ => panic!(),
+        };
+        // midpoint of 10.50 and 10.20 snapped to the tick
+        assert_eq!(mid("QUERY,MID,AAPL"), "MID,10.25,7");
+        // asking doesn't change the book
+        assert_eq!(mid("QUERY,MID,AAPL"), "MID,10.25,7");
+        assert_eq!(mid("QUERY,MID,MSFT"), "MID,NONE");
+        assert_eq!(mid("QUERY,MID,TSLA"), "MID,NONE");
+
+        let results = run_batches(&exchange, &config);
+        let (_, result) = results.iter().find(|(symbol, _)| symbol == "AAPL").unwrap();
+        match &result.report {
+            BatchReport::Trade { price, qty, .. } => {
+                assert_eq!(price.to_string(), "10.25");
+                assert_eq!(*qty, Qty::from(7));
+            }
+            _ => panic!(),
+        }
+        assert_eq!(mid("QUERY,MID,AAPL"), "MID,NONE");
+    }
+
+    #[test]
+    fn parse_depth_query() {
+        match CommandHandler::parse_command("QUERY,DEPTH,AAPL,5") {
+            Ok(Command::Query(Query::Depth { symbol, levels })) => {
+                assert_eq!(symbol, "AAPL");
+                assert_eq!(levels, 5);
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(
+            CommandHandler::parse_command("QUERY,DEPTH,AAPL"),
+            Ok(Command::Query(Query::Depth { levels: 10, .. }))
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("QUERY,DEPTH,AAPL,1000"),
+            Ok(Command::Query(Query::Depth { levels: 100, .. }))
+        ));
+        assert_eq!(
+            CommandHandler::parse_command("QUERY,DEPTH,AAPL,all").err(),
+            Some(ParseCommandError::BadLevels("all".to_string()))
+        );
+    }
+
+    #[test]
+    fn depth_aggregates_top_levels() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.50", 2), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.50", 3), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "9.00", 1), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.00", 4), 1);
+
+        let depth = Query::Depth {
+            symbol: "AAPL".to_string(),
+            levels: 2,
+        };
+        assert_eq!(
+            answer_query(&exchange, depth),
+            "BID,10.50,5\nBID,10.00,5\nASK,11.00,4\nEND"
+        );
+    }
+
+    #[test]
+    fn fills_are_routed_to_submitting_client() {
+        let exchange = Exchange::new();
+        let (buyer_tx, mut buyer_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (seller_tx, mut seller_rx) = tokio::sync::mpsc::unbounded_channel();
+        let buyer = exchange.register_client(buyer_tx);
+        let seller = exchange.register_client(seller_tx);
+
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), buyer);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "10.00", 3), seller);
+
+        let result = run_batch(&exchange.book("AAPL"), &AuctionConfig::default(), 1);
+        assert!(matches!(result.report, BatchReport::Trade { .. }));
+        notify_fills(&exchange, result.report.fills());
+
+        assert_eq!(buyer_rx.try_recv().unwrap(), "FILL,1,10.00,3,2,0.00");
+        assert_eq!(seller_rx.try_recv().unwrap(), "FILL,2,10.00,3,0,0.00");
+        assert!(buyer_rx.try_recv().is_err());
+        assert!(seller_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn parse_add_with_options() {
+        assert!(matches!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,FOK,43.52,10"),
+            Ok(Command::Add {
+                time_in_force: TimeInForce::FillOrKill,
+                qty,
+                ..
+            }) if qty == Qty::from(10)
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("ADD,SELL,AAPL,IOC,43.52,10"),
+            Ok(Command::Add {
+                time_in_force: TimeInForce::ImmediateOrCancel,
+                ..
+            })
+        ));
+        assert_eq!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,XYZ,43.52,10").err(),
+            Some(ParseCommandError::UnknownOption("XYZ".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_pending_commands() {
+        let exchange = Arc::new(Exchange::new());
+        let (tx, rx) = tokio::sync::mpsc::channel(3);
+        let (responder, mut responses) = tokio::sync::mpsc::unbounded_channel();
+        let client = Client {
+            id: 1,
+            responder,
+            authenticated: true,
+        };
+
+        // commands are queued before the order book task even starts.
+        // a disconnect without orders has nothing to answer, not even while shutting down
+        tx.send((Command::CancelAll, client.clone())).await.unwrap();
+        tx.send((add(OrderType::Buy, "AAPL", "10.00", 5), client.clone()))
+            .await
+            .unwrap();
+        tx.send((add(OrderType::Sell, "AAPL", "11.00", 5), client.clone()))
+            .await
+            .unwrap();
+
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        stop.send(()).unwrap();
+        update_order_book(rx, exchange.clone(), stopped).await;
+
+        assert_eq!(responses.recv().await.unwrap(), "ADDED,1");
+        assert_eq!(responses.recv().await.unwrap(), "ADDED,2");
+        assert_eq!(exchange.book("AAPL").bids.read().unwrap().len(), 1);
+        assert_eq!(exchange.book("AAPL").asks.read().unwrap().len(), 1);
+        assert!(responses.try_recv().is_err());
+        // the channel is closed for new commands
+        assert!(tx.send((Command::Cancel { id: 1 }, client)).await.is_err());
+    }
+
+    #[test]
+    fn jittered_batches_stay_in_the_window() {
+        use crate::BatchTiming;
+        use std::time::Duration;
+
+        let interval = Duration::from_millis(100);
+        let jitter = Duration::from_millis(20);
+        let mut timing = BatchTiming::seeded(interval, jitter, 7);
+        let delays: Vec<Duration> = (0..1000).map(|_| timing.next_delay()).collect();
+        assert!(delays
+            .iter()
+            .all(|delay| *delay >= interval - jitter && *delay <= interval + jitter));
+        // spread over the window, not stuck on the interval
+        assert!(delays
+            .iter()
+            .any(|delay| *delay < Duration::from_millis(90)));
+        assert!(delays
+            .iter()
+            .any(|delay| *delay > Duration::from_millis(110)));
+
+        let mut same_seed = BatchTiming::seeded(interval, jitter, 7);
+        assert_eq!(same_seed.next_delay(), delays[0]);
+        // deterministic without jitter
+        let mut fixed = BatchTiming::seeded(interval, Duration::ZERO, 7);
+        assert!((0..10).all(|_| fixed.next_delay() == interval));
+    }
+
+    #[tokio::test]
+    async fn engine_thread_applies_commands() {
+        use crate::{spawn_engine_thread, BatchTiming, OutputFormat};
+        use tokio::time::Duration;
+
+        let exchange = Arc::new(Exchange::new());
+        let (tx, rx) = tokio::sync::mpsc::channel(2);
+        let (responder, mut responses) = tokio::sync::mpsc::unbounded_channel();
+        let client = Client {
+            id: 1,
+            responder,
+            authenticated: true,
+        };
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        let engine = spawn_engine_thread(
+            rx,
+            exchange.clone(),
+            Arc::new(AuctionConfig::default()),
+            OutputFormat::Json,
+            None,
+            // the first batch runs right away, on empty books
+            BatchTiming::new(Duration::from_secs(3600), Duration::ZERO),
+            stopped,
+        )
+        .unwrap();
+
+        tx.send((add(OrderType::Buy, "AAPL", "10.00", 5), client.clone()))
+            .await
+            .unwrap();
+        assert_eq!(responses.recv().await.unwrap(), "ADDED,1");
+
+        // still queued at the shutdown, applied before the engine stops
+        tx.send((add(OrderType::Sell, "AAPL", "11.00", 5), client))
+            .await
+            .unwrap();
+        stop.send(()).unwrap();
+        tokio::task::spawn_blocking(move || engine.join().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(responses.recv().await.unwrap(), "ADDED,2");
+        assert_eq!(exchange.book("AAPL").bids.read().unwrap().len(), 1);
+        assert_eq!(exchange.book("AAPL").asks.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn clock_counts_down_to_the_next_batch() {
+        use crate::{run_engine, BatchTiming, OutputFormat};
+        use std::time::{SystemTime, UNIX_EPOCH};
+        use tokio::time::{sleep, Duration};
+
+        let exchange = Arc::new(Exchange::new());
+        let clock = || {
+            let response = answer_query(&exchange, Query::Clock);
+            let fields: Vec<String> = response.split(',').map(str::to_string).collect();
+            assert_eq!(fields.len(), 3, "{}", response);
+            assert_eq!(fields[0], "CLOCK");
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let server_now = fields[1].parse::<u128>().unwrap();
+            assert!(server_now <= now.as_millis() && server_now + 1000 > now.as_millis());
+            fields[2].clone()
+        };
+        assert!(matches!(
+            CommandHandler::parse_command("QUERY,CLOCK"),
+            Ok(Command::Query(Query::Clock))
+        ));
+        assert_eq!(clock(), "NONE");
+
+        let (interval, jitter) = (Duration::from_millis(200), Duration::from_millis(50));
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        // no books, the batches print nothing
+        let engine = tokio::spawn(run_engine(
+            rx,
+            exchange.clone(),
+            Arc::new(AuctionConfig::default()),
+            OutputFormat::Json,
+            None,
+            BatchTiming::new(interval, jitter),
+            stopped,
+        ));
+        for _ in 0..5 {
+            sleep(Duration::from_millis(70)).await;
+            let left = clock().parse::<u128>().unwrap();
+            assert!(left <= (interval + jitter).as_millis(), "{}", left);
+        }
+
+        stop.send(()).unwrap();
+        engine.await.unwrap();
+        assert_eq!(clock(), "NONE");
+    }
+
+    #[tokio::test]
+    async fn orders_are_cancelled_when_the_client_disconnects() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio::time::{sleep, Duration};
+
+        let exchange = Arc::new(Exchange::new().with_cancel_on_disconnect(true));
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let (_stop, stopped) = tokio::sync::oneshot::channel();
+        tokio::spawn(update_order_book(rx, exchange.clone(), stopped));
+        // an order of another client stays
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "12.00", 5), 99);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(CommandHandler::handle_socket(socket, tx, exchange.clone()));
+
+        stream
+            .write_all(b"ADD,BUY,AAPL,10.00,5\nADD,SELL,MSFT,20.00,3\n")
+            .await
+            .unwrap();
+        let mut lines = tokio::io::BufReader::new(&mut stream).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "ADDED,2");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "ADDED,3");
+        drop(lines);
+        drop(stream);
+
+        let resting = |symbol: &str| {
+            let book = exchange.book(symbol);
+            let bids = book.bids.read().unwrap().len();
+            let asks = book.asks.read().unwrap().len();
+            bids + asks
+        };
+        for _ in 0..100 {
+            if resting("AAPL") == 1 && resting("MSFT") == 0 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(exchange.book("AAPL").asks.read().unwrap()[0].id(), 1);
+        assert_eq!(resting("AAPL"), 1);
+        assert_eq!(resting("MSFT"), 0);
+        assert_eq!(
+            answer_query(&exchange, Query::Order { id: 2 }),
+            "ORDER,2,CANCELLED"
+        );
+    }
+
+    #[test]
+    fn opening_auction_uncrosses_after_warm_up() {
+        use crate::{run_scheduled_batches, MarketPhase};
+        use std::sync::atomic::Ordering;
+        use std::time::{Duration, Instant};
+
+        let start = Instant::now();
+        let exchange = Exchange::new().with_opening_auction(Duration::from_secs(60));
+        let config = AuctionConfig::default();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "9.50", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "9.00", 4), 2);
+
+        // orders keep coming in, nothing is matched
+        assert!(run_scheduled_batches(&exchange, &config, start).is_empty());
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "9.40", 4), 2);
+        let during = start + Duration::from_secs(30);
+        assert!(run_scheduled_batches(&exchange, &config, during).is_empty());
+        assert!(matches!(
+            *exchange.phase.lock().unwrap(),
+            MarketPhase::Accumulating { .. }
+        ));
+        assert_eq!(exchange.batch_seq.load(Ordering::Relaxed), 0);
+        assert_eq!(exchange.book("AAPL").bids.read().unwrap().len(), 2);
+
+        // everything that crosses is cleared by the uncross
+        let after = start + Duration::from_secs(61);
+        let results = run_scheduled_batches(&exchange, &config, after);
+        assert_eq!(*exchange.phase.lock().unwrap(), MarketPhase::Uncross);
+        assert_eq!(results.len(), 1);
+        match &results[0].1.report {
+            BatchReport::Trade { qty, price, .. } => {
+                assert_eq!(*qty, Qty::from(8));
+                assert_eq!(*price, BigDecimal::from_str("9.45").unwrap());
+            }
+            _ => panic!(),
+        }
+        assert_eq!(
+            answer_query(
+                &exchange,
+                Query::Volume {
+                    symbol: "AAPL".to_string()
+                }
+            ),
+            "VOLUME,BID,2,ASK,0"
+        );
+
+        // then batches run every time
+        assert_eq!(run_scheduled_batches(&exchange, &config, after).len(), 1);
+        assert_eq!(*exchange.phase.lock().unwrap(), MarketPhase::Continuous);
+    }
+
+    #[test]
+    fn trade_is_serialized_to_json() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "9.00", 3), 1);
+
+        let result = run_batch(&exchange.book("AAPL"), &AuctionConfig::default(), 7);
+        let record = batch_record("AAPL", &result.report);
+
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"event":"trade","batch_seq":7,"symbol":"AAPL","price":"9.50","qty":"3","cleared_bids":1,"cleared_asks":1,"bid_imbalance":"2","ask_imbalance":"0"}"#
+        );
+    }
+
+    #[test]
+    fn batch_without_trade_is_reported_in_json() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+
+        let results = run_batches(&exchange, &AuctionConfig::default());
+        let mut out = vec![];
+        write_results(&mut out, &results, OutputFormat::Json).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"event\":\"no_trade\",\"batch_seq\":1,\"symbol\":\"AAPL\",\"reason\":\"OneSided\"}\n"
+        );
+    }
+
+    #[test]
+    fn max_qty_limit() {
+        let exchange = Exchange::new().with_limits(OrderLimits {
+            max_qty: Some(Qty::from(100)),
+            ..OrderLimits::default()
+        });
+
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 100), 1),
+            "ADDED,1"
+        );
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 101), 1),
+            "ERR,MAX_QTY,qty 101 exceeds max qty 100"
+        );
+        assert_eq!(exchange.book("AAPL").bids.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn max_notional_limit() {
+        let exchange = Exchange::new().with_limits(OrderLimits {
+            max_notional: Some(BigDecimal::from_str("1000.00").unwrap()),
+            ..OrderLimits::default()
+        });
+
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Sell, "AAPL", "10.00", 100), 1),
+            "ADDED,1"
+        );
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Sell, "AAPL", "10.01", 100), 1),
+            "ERR,MAX_NOTIONAL,notional 1001.00 exceeds max notional 1000.00"
+        );
+        assert_eq!(exchange.book("AAPL").asks.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn over_precise_price_is_snapped() {
+        let exchange = Exchange::new().with_symbol_config(
+            "POWER",
+            SymbolConfig {
+                price_scale: Some(1),
+                ..SymbolConfig::default()
+            },
+        );
+        let submit = |msg: &str| {
+            let cmd = CommandHandler::parse_command(msg).unwrap();
+            apply_command(&exchange, cmd, 1)
+        };
+
+        assert_eq!(submit("ADD,BUY,AAPL,111.456,5"), "ADDED,1,111.46");
+        assert_eq!(
+            submit("ADD,BUY,AAPL,1.00000000000000000000000000000000000000009,5"),
+            "ADDED,2,1.00"
+        );
+        // the same price, only written longer
+        assert_eq!(submit("ADD,BUY,AAPL,111.450,5"), "ADDED,3");
+        assert_eq!(submit("ADD,SELL,POWER,20.25,1"), "ADDED,4,20.3");
+        assert_eq!(submit("AMEND,1,111.454,5"), "AMENDED,1,111.45");
+        assert_eq!(submit("AMEND,1,111.45,4"), "AMENDED,1");
+        assert_eq!(
+            answer_query(
+                &exchange,
+                Query::Depth {
+                    symbol: "AAPL".to_string(),
+                    levels: 1
+                }
+            ),
+            "BID,111.45,9\nEND"
+        );
+        let amend = CommandHandler::parse_command("AMEND,4,20.26,1").unwrap();
+        let sequenced = Command::Sequenced {
+            seq: 9,
+            command: Box::new(amend),
+        };
+        assert_eq!(apply_command(&exchange, sequenced, 1), "ACK,9,4,20.3");
+    }
+
+    #[test]
+    fn over_precise_price_is_rejected() {
+        let exchange = Exchange::new().with_price_precision(PricePrecision::Reject);
+        let submit = |msg: &str| {
+            let cmd = CommandHandler::parse_command(msg).unwrap();
+            apply_command(&exchange, cmd, 1)
+        };
+
+        assert_eq!(
+            submit("ADD,BUY,AAPL,111.456,5"),
+            "ERR,PRICE_PRECISION,price 111.456 has more than 2 decimal digits"
+        );
+        assert_eq!(submit("ADD,BUY,AAPL,111.450,5"), "ADDED,1");
+        assert_eq!(
+            submit("AMEND,1,111.451,5"),
+            "ERR,PRICE_PRECISION,price 111.451 has more than 2 decimal digits"
+        );
+        assert_eq!(
+            submit("AMEND,2,111.451,5"),
+            "ERR,UNKNOWN_ORDER,unknown order 2"
+        );
+        assert_eq!(exchange.book("AAPL").bids.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn parse_min_qty_option() {
+        assert!(matches!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,MINQTY=5,111.45,10"),
+            Ok(Command::Add {
+                min_qty: Some(min_qty),
+                qty,
+                ..
+            }) if min_qty == Qty::from(5) && qty == Qty::from(10)
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,IOC,MINQTY=5,111.45,10"),
+            Ok(Command::Add {
+                time_in_force: TimeInForce::ImmediateOrCancel,
+                min_qty: Some(min_qty),
+                ..
+            }) if min_qty == Qty::from(5)
+        ));
+        assert_eq!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,MINQTY=0,111.45,10").err(),
+            Some(ParseCommandError::BadMinQty("0".to_string()))
+        );
+        assert_eq!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,MINQTY=x,111.45,10").err(),
+            Some(ParseCommandError::BadMinQty("x".to_string()))
+        );
+    }
+
+    #[test]
+    fn every_rejection_has_its_code() {
+        let exchange = Exchange::new()
+            .with_limits(OrderLimits {
+                max_qty: Some(Qty::from(100)),
+                max_notional: Some(BigDecimal::from(5000)),
+                price_band: None,
+            })
+            .with_max_orders(Some(1))
+            .with_price_precision(PricePrecision::Reject);
+        let submit = |msg: &str| match CommandHandler::parse_command(msg) {
+            Ok(cmd) => apply_command(&exchange, cmd, 1),
+            Err(e) => ClientError::from(e).response(),
+        };
+        for (msg, code) in [
+            ("ADD,HOLD,AAPL,10.00,1", "BAD_COMMAND"),
+            ("ADD,BUY,AAPL,0,1", "BAD_PRICE"),
+            ("ADD,BUY,AAPL,10.001,1", "PRICE_PRECISION"),
+            ("ADD,BUY,AAPL,10.00,101", "MAX_QTY"),
+            ("ADD,BUY,AAPL,60.00,100", "MAX_NOTIONAL"),
+            ("ADD,BUY,AAPL,GTT=1,10.00,1", "EXPIRED"),
+            ("CANCEL,42", "UNKNOWN_ORDER"),
+            ("AMEND,42,10.00,1", "UNKNOWN_ORDER"),
+            ("REDUCE,42,1", "UNKNOWN_ORDER"),
+            ("RESUME,AAPL", "NOT_HALTED"),
+        ] {
+            let response = submit(msg);
+            assert!(
+                response.starts_with(&format!("ERR,{},", code)),
+                "{} answered {}",
+                msg,
+                response
+            );
+        }
+        assert_eq!(submit("ADD,BUY,AAPL,10.00,1"), "ADDED,1");
+        assert_eq!(
+            submit("ADD,BUY,AAPL,10.00,1"),
+            "ERR,BOOK_FULL,the side of the book is full"
+        );
+
+        // the sequence number goes before the code
+        assert_eq!(
+            acknowledge(7, &ClientError::UnknownOrder(42).response()),
+            "NACK,7,UNKNOWN_ORDER,unknown order 42"
+        );
+        let halt = CommandHandler::parse_command("HALT,AAPL").unwrap();
+        assert_eq!(
+            exchange.authorize(&halt).map_err(|e| e.code()),
+            Err("ADMIN_DISABLED")
+        );
+    }
+
+    #[test]
+    fn post_only_order_that_would_take_is_rejected() {
+        let exchange = Exchange::new();
+        let (buyer_tx, mut buyer_rx) = tokio::sync::mpsc::unbounded_channel();
+        let buyer = exchange.register_client(buyer_tx);
+        let submit = |msg: &str, client_id| {
+            let cmd = CommandHandler::parse_command(msg).unwrap();
+            apply_command(&exchange, cmd, client_id)
+        };
+        assert_eq!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,POST_ONLY,MKT,10").err(),
+            Some(ParseCommandError::PostOnlyMarket)
+        );
+
+        assert_eq!(submit("ADD,SELL,AAPL,10.00,3", 99), "ADDED,1");
+        assert_eq!(submit("ADD,BUY,AAPL,POST_ONLY,10.50,5", buyer), "ADDED,2");
+        let results = run_batches(&exchange, &AuctionConfig::default());
+        assert!(matches!(
+            results[0].1.report,
+            BatchReport::NoTrade {
+                reason: NoTradeReason::OneSided,
+                ..
+            }
+        ));
+        assert_eq!(buyer_rx.try_recv().unwrap(), "POST_ONLY_REJECT,2");
+        assert!(buyer_rx.try_recv().is_err());
+        assert!(exchange.book("AAPL").bids.read().unwrap().is_empty());
+        assert_eq!(exchange.book("AAPL").asks.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn parse_market_order() {
+        assert!(matches!(
+            CommandHandler::parse_command("ADD,BUY,AAPL,MKT,10"),
+            Ok(Command::Add {
+                price: None,
+                time_in_force: TimeInForce::ImmediateOrCancel,
+                qty,
+                ..
+            }) if qty == Qty::from(10)
+        ));
+        assert!(matches!(
+            CommandHandler::parse_command("ADD,SELL,AAPL,FOK,MKT,10"),
+            Ok(Command::Add {
+                price: None,
+                time_in_force: TimeInForce::FillOrKill,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn bbo_ignores_market_orders() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        let market = CommandHandler::parse_command("ADD,BUY,AAPL,MKT,10").unwrap();
+        assert_eq!(apply_command(&exchange, market, 1), "ADDED,2");
+
+        let bbo = Query::Bbo {
+            symbol: "AAPL".to_string(),
+        };
+        assert_eq!(answer_query(&exchange, bbo), "BBO,10.00,NONE");
+    }
+
+    #[test]
+    fn parse_amend_command() {
+        match CommandHandler::parse_command("AMEND,7,43.521,10\n") {
+            Ok(Command::Amend { id, price, qty }) => {
+                assert_eq!(id, 7);
+                assert_eq!(price, BigDecimal::from_str("43.521").unwrap());
+                assert_eq!(qty, Qty::from(10));
+            }
+            _ => panic!(),
+        }
+        assert_eq!(
+            CommandHandler::parse_command("AMEND,7,MKT,10").err(),
+            Some(ParseCommandError::BadPrice("MKT".to_string()))
+        );
+        assert_eq!(
+            CommandHandler::parse_command("AMEND,7,43.52,0").err(),
+            Some(ParseCommandError::ZeroQty)
+        );
+        assert_eq!(
+            CommandHandler::parse_command("AMEND,7,43.52").err(),
+            Some(ParseCommandError::MissingField("qty"))
+        );
+    }
+
+    #[test]
+    fn amend_updates_resting_order() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "MSFT", "21.00", 3), 1);
+
+        let amend = |id, price: &str, qty| Command::Amend {
+            id,
+            price: BigDecimal::from_str(price).unwrap(),
+            qty: Qty::from(qty),
+        };
+        assert_eq!(
+            apply_command(&exchange, amend(2, "20.50", 4), 1),
+            "AMENDED,2"
+        );
+        assert_eq!(
+            apply_command(&exchange, amend(42, "20.50", 4), 1),
+            "ERR,UNKNOWN_ORDER,unknown order 42"
+        );
+
+        let asks = exchange.book("MSFT").asks.read().unwrap().clone();
+        assert_eq!(asks.len(), 1);
+        assert_eq!(
+            asks[0].price(),
+            Some(&BigDecimal::from_str("20.50").unwrap())
+        );
+        assert_eq!(asks[0].qty(), Qty::from(4));
+        assert_eq!(
+            exchange.book("AAPL").bids.read().unwrap()[0].qty(),
+            Qty::from(5)
+        );
+    }
+
+    #[test]
+    fn parse_vwap_query() {
+        match CommandHandler::parse_command("QUERY,VWAP,AAPL") {
+            Ok(Command::Query(Query::Vwap { symbol })) => assert_eq!(symbol, "AAPL"),
+            _ => panic!(),
+        }
+        assert_eq!(
+            CommandHandler::parse_command("QUERY,VWAP").err(),
+            Some(ParseCommandError::MissingField("symbol"))
+        );
+    }
+
+    #[test]
+    fn vwap_weights_batches_by_qty() {
+        let exchange = Exchange::new();
+        let config = AuctionConfig::default();
+        let vwap = |symbol: &str| Query::Vwap {
+            symbol: symbol.to_string(),
+        };
+        assert_eq!(answer_query(&exchange, vwap("AAPL")), "VWAP,NONE");
+
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 1), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "10.00", 1), 1);
+        run_batch(&exchange.book("AAPL"), &config, 2);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "12.00", 3), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "12.00", 3), 1);
+        run_batch(&exchange.book("AAPL"), &config, 1);
+
+        // (10.00 * 1 + 12.00 * 3) / 4
+        assert_eq!(answer_query(&exchange, vwap("AAPL")), "VWAP,11.50");
+        assert_eq!(answer_query(&exchange, vwap("MSFT")), "VWAP,NONE");
+    }
+
+    #[test]
+    fn vwap_is_rounded_to_the_price_scale_of_the_symbol() {
+        let exchange = Exchange::new().with_symbol_config(
+            "POWER",
+            SymbolConfig {
+                price_scale: Some(1),
+                ..SymbolConfig::default()
+            },
+        );
+        let config = exchange.symbol_auction_config("POWER", &exchange.auction_config);
+        apply_command(&exchange, add(OrderType::Buy, "POWER", "10.1", 1), 1);
+        apply_command(&exchange, add(OrderType::Sell, "POWER", "10.1", 1), 1);
+        run_batch(&exchange.book("POWER"), &config, 1);
+        apply_command(&exchange, add(OrderType::Buy, "POWER", "10.4", 2), 1);
+        apply_command(&exchange, add(OrderType::Sell, "POWER", "10.4", 2), 1);
+        run_batch(&exchange.book("POWER"), &config, 2);
+
+        // (10.1 * 1 + 10.4 * 2) / 3, the same scale as BBO and depth of the symbol
+        let query = Query::Vwap {
+            symbol: "POWER".to_string(),
+        };
+        assert_eq!(answer_query(&exchange, query), "VWAP,10.3");
+    }
+
+    #[test]
+    fn parse_trades_query() {
+        match CommandHandler::parse_command("QUERY,TRADES,AAPL,5") {
+            Ok(Command::Query(Query::Trades { symbol, count })) => {
+                assert_eq!(symbol, "AAPL");
+                assert_eq!(count, 5);
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(
+            CommandHandler::parse_command("QUERY,TRADES,AAPL,1000"),
+            Ok(Command::Query(Query::Trades { count: 100, .. }))
+        ));
+        assert_eq!(
+            CommandHandler::parse_command("QUERY,TRADES,AAPL").err(),
+            Some(ParseCommandError::MissingField("count"))
+        );
+        assert_eq!(
+            CommandHandler::parse_command("QUERY,TRADES,AAPL,all").err(),
+            Some(ParseCommandError::BadCount("all".to_string()))
+        );
+    }
+
+    #[test]
+    fn trade_history_keeps_most_recent() {
+        let mut history = TradeHistory::new(3);
+        for batch_seq in 1..=5 {
+            history.push(HistoricTrade {
+                batch_seq,
+                price: BigDecimal::from(10),
+                qty: Qty::from(1),
+            });
+        }
+
+        let seqs = |count| history.last(count).map(|t| t.batch_seq).collect::<Vec<_>>();
+        assert_eq!(history.trades.len(), 3);
+        assert_eq!(seqs(10), vec![3, 4, 5]);
+        assert_eq!(seqs(2), vec![4, 5]);
+    }
+
+    #[test]
+    fn trades_query_lists_batches() {
+        let exchange = Exchange::new();
+        let config = AuctionConfig::default();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 1), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "10.00", 1), 1);
+        run_batches(&exchange, &config);
+        // no trade, still numbered
+        run_batches(&exchange, &config);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "12.00", 3), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "12.00", 3), 1);
+        run_batches(&exchange, &config);
+
+        let trades = |symbol: &str, count| Query::Trades {
+            symbol: symbol.to_string(),
+            count,
+        };
+        assert_eq!(
+            answer_query(&exchange, trades("AAPL", 10)),
+            "TRADE,1,10.00,1\nTRADE,3,12.00,3\nEND"
+        );
+        assert_eq!(
+            answer_query(&exchange, trades("AAPL", 1)),
+            "TRADE,3,12.00,3\nEND"
+        );
+        assert_eq!(answer_query(&exchange, trades("MSFT", 10)), "END");
+    }
+
+    #[test]
+    fn batches_are_numbered_sequentially() {
+        let exchange = Exchange::new();
+        let config = AuctionConfig::default();
+        let batch_seq = |results: &[(String, BatchResult)]| match &results[0].1.report {
+            BatchReport::Trade { batch_seq, .. } => *batch_seq,
+            _ => panic!(),
+        };
+
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 2), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "10.00", 1), 1);
+        let first = batch_seq(&run_batches(&exchange, &config));
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "10.00", 1), 1);
+        let second = batch_seq(&run_batches(&exchange, &config));
+
+        assert_eq!(first, 1);
+        assert_eq!(second, 2);
+    }
+
+    #[test]
+    fn queries_and_batches_run_concurrently() {
+        let exchange = Arc::new(Exchange::new());
+        let (done, finished) = std::sync::mpsc::channel();
+        let mut workers = 0;
+
+        // order entry and the auction loop
+        for side in 0..2 {
+            let exchange = exchange.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    let (order_type, price) = match side {
+                        0 => (OrderType::Buy, format!("{}.00", 10 + i % 3)),
+                        _ => (OrderType::Sell, format!("{}.00", 11 - i % 3)),
+                    };
+                    apply_command(&exchange, add(order_type, "AAPL", &price, 1), 1);
+                }
+                done.send(()).unwrap();
+            });
+            workers += 1;
+        }
+        {
+            let exchange = exchange.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                for _ in 0..200 {
+                    run_batches(&exchange, &AuctionConfig::default());
+                }
+                done.send(()).unwrap();
+            });
+            workers += 1;
+        }
+        for _ in 0..8 {
+            let exchange = exchange.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let symbol = || "AAPL".to_string();
+                for _ in 0..200 {
+                    answer_query(&exchange, Query::Bbo { symbol: symbol() });
+                    answer_query(
+                        &exchange,
+                        Query::Depth {
+                            symbol: symbol(),
+                            levels: 10,
+                        },
+                    );
+                    answer_query(&exchange, Query::Volume { symbol: symbol() });
+                }
+                done.send(()).unwrap();
+            });
+            workers += 1;
+        }
+
+        // a deadlock leaves some worker hanging forever
+        for _ in 0..workers {
+            finished
+                .recv_timeout(std::time::Duration::from_secs(30))
+                .expect("worker is stuck");
+        }
+    }
+
+    #[test]
+    fn book_is_kept_in_priority_order() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 1), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "11.00", 1), 1);
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 1), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "13.00", 1), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "12.00", 1), 1);
+        // no cross, the orders just get older
+        run_batches(&exchange, &AuctionConfig::default());
+        // a new price moves the order behind the older ones resting at it
+        apply_command(
+            &exchange,
+            Command::Amend {
+                id: 2,
+                price: BigDecimal::from_str("10.00").unwrap(),
+                qty: Qty::from(1),
+            },
+            1,
+        );
+
+        let book = exchange.book("AAPL");
+        let ids = |orders: &[Order]| orders.iter().map(|o| o.id()).collect::<Vec<_>>();
+        assert_eq!(ids(&book.bids.read().unwrap()), vec![1, 3, 2]);
+        assert_eq!(ids(&book.asks.read().unwrap()), vec![5, 4]);
+    }
+
+    #[test]
+    fn price_band_around_last_trade() {
+        let exchange = Exchange::new().with_limits(OrderLimits {
+            price_band: Some(BigDecimal::from(10)),
+            ..OrderLimits::default()
+        });
+
+        // no reference price before the first trade
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Buy, "AAPL", "100.00", 1), 1),
+            "ADDED,1"
+        );
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Sell, "AAPL", "100.00", 1), 1),
+            "ADDED,2"
+        );
+        run_batches(&exchange, &AuctionConfig::default());
+
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Buy, "AAPL", "110.00", 1), 1),
+            "ADDED,3"
+        );
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Sell, "AAPL", "89.99", 1), 1),
+            "ERR,PRICE_BAND,price 89.99 is more than 10% away from the last price 100.00"
+        );
+        assert_eq!(
+            apply_command(
+                &exchange,
+                Command::Amend {
+                    id: 3,
+                    price: BigDecimal::from_str("110.01").unwrap(),
+                    qty: Qty::from(1),
+                },
+                1
+            ),
+            "ERR,PRICE_BAND,price 110.01 is more than 10% away from the last price 100.00"
+        );
+        // other symbols have their own reference
+        assert_eq!(
+            apply_command(&exchange, add(OrderType::Sell, "MSFT", "1.00", 1), 1),
+            "ADDED,4"
+        );
+    }
+
+    #[test]
+    fn parse_reduce_command() {
+        assert!(matches!(
+            CommandHandler::parse_command("REDUCE,7,3\n"),
+            Ok(Command::Reduce { id: 7, qty }) if qty == Qty::from(3)
+        ));
+        assert_eq!(
+            CommandHandler::parse_command("REDUCE,7,0").err(),
+            Some(ParseCommandError::ZeroQty)
+        );
+        assert_eq!(
+            CommandHandler::parse_command("REDUCE,7").err(),
+            Some(ParseCommandError::MissingField("qty"))
+        );
+    }
+
+    #[test]
+    fn reduce_cancels_part_of_the_order() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.00", 4), 1);
+
+        assert_eq!(
+            apply_command(
+                &exchange,
+                Command::Reduce {
+                    id: 1,
+                    qty: Qty::from(2)
+                },
+                1
+            ),
+            "REDUCED,1,3"
+        );
+        assert_eq!(
+            exchange.book("AAPL").bids.read().unwrap()[0].qty(),
+            Qty::from(3)
+        );
+
+        // reducing by the whole qty or more removes the order
+        assert_eq!(
+            apply_command(
+                &exchange,
+                Command::Reduce {
+                    id: 2,
+                    qty: Qty::from(10)
+                },
+                1
+            ),
+            "REDUCED,2,0"
+        );
+        assert!(exchange.book("AAPL").asks.read().unwrap().is_empty());
+        assert_eq!(
+            apply_command(
+                &exchange,
+                Command::Reduce {
+                    id: 2,
+                    qty: Qty::from(1)
+                },
+                1
+            ),
+            "ERR,UNKNOWN_ORDER,unknown order 2"
+        );
+    }
+
+    #[tokio::test]
+    async fn burst_over_rate_limit_is_dropped() {
+        use crate::rate_limit::RateLimiter;
+
+        let exchange = Exchange::new();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let (responder, mut responses) = tokio::sync::mpsc::unbounded_channel();
+        let mut client = CommandHandler::connect(&exchange, responder);
+        let mut limiter = RateLimiter::new(Some(2));
+
+        for msg in [
+            "QUERY,VOLUME,AAPL",
+            "ADD,BUY,AAPL,10.00,5",
+            "ADD,BUY,AAPL,10.00,5",
+            "QUERY,VOLUME,AAPL",
+        ] {
+            assert!(
+                CommandHandler::handle_message(msg, &mut client, &mut limiter, &tx, &exchange,)
+                    .await
+            );
+        }
+
+        assert_eq!(responses.try_recv().unwrap(), "VOLUME,BID,0,ASK,0");
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "ERR,RATE_LIMIT,too many commands"
+        );
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "ERR,RATE_LIMIT,too many commands"
+        );
+        assert!(responses.try_recv().is_err());
+        // only the first ADD made it to the order book
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn sequenced_commands_are_acknowledged() {
+        use crate::rate_limit::RateLimiter;
+
+        let exchange = Exchange::new().with_limits(OrderLimits {
+            max_qty: Some(Qty::from(100)),
+            ..OrderLimits::default()
+        });
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let (responder, mut responses) = tokio::sync::mpsc::unbounded_channel();
+        let mut client = CommandHandler::connect(&exchange, responder);
+        let mut limiter = RateLimiter::new(None);
+
+        for msg in [
+            "12|ADD,BUY,AAPL,111.45,10",
+            "13|ADD,BUY,AAPL,111.45,1000",
+            "14|CANCEL,1",
+            "15|ADD,BUY,AAPL,111.45",
+            "x|CANCEL,1",
+        ] {
+            assert!(
+                CommandHandler::handle_message(msg, &mut client, &mut limiter, &tx, &exchange,)
+                    .await
+            );
+        }
+        // not parsed, answered right away
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "NACK,15,BAD_COMMAND,missing field qty"
+        );
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "ERR,BAD_COMMAND,bad sequence number x"
+        );
+
+        while let Ok((cmd, client)) = rx.try_recv() {
+            let _ = client
+                .responder
+                .send(apply_command(&exchange, cmd, client.id));
+        }
+        assert_eq!(responses.try_recv().unwrap(), "ACK,12,1");
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "NACK,13,MAX_QTY,qty 1000 exceeds max qty 100"
+        );
+        assert_eq!(responses.try_recv().unwrap(), "ACK,14,1");
+        assert!(responses.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn connections_authenticate_with_api_keys() {
+        use crate::rate_limit::RateLimiter;
+        use std::collections::HashMap;
+
+        let keys = HashMap::from([("k3y".to_string(), 7)]);
+        let exchange = Exchange::new().with_api_keys(Some(keys));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let mut limiter = RateLimiter::new(None);
+        let (responder, mut responses) = tokio::sync::mpsc::unbounded_channel();
+        let mut client = CommandHandler::connect(&exchange, responder);
+        // after the ids of the keys
+        assert_eq!(client.id, 8);
+
+        for msg in [
+            "ADD,BUY,AAPL,10.00,5",
+            "AUTH,k3y",
+            "ADD,BUY,AAPL,10.00,5",
+            "AUTH,k3y",
+        ] {
+            assert!(
+                CommandHandler::handle_message(msg, &mut client, &mut limiter, &tx, &exchange,)
+                    .await
+            );
+        }
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "ERR,AUTH_REQUIRED,AUTH required"
+        );
+        assert_eq!(responses.try_recv().unwrap(), "AUTHED,7");
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "ERR,ALREADY_AUTHENTICATED,already authenticated"
+        );
+        // only the ADD after AUTH, on behalf of the client of the key
+        let (cmd, sender) = rx.try_recv().unwrap();
+        assert_eq!(sender.id, 7);
+        assert_eq!(apply_command(&exchange, cmd, sender.id), "ADDED,1");
+        assert_eq!(exchange.book("AAPL").bids.read().unwrap()[0].client_id(), 7);
+        assert!(rx.try_recv().is_err());
+
+        // its own orders only
+        for msg in ["QUERY,MYORDERS,7", "QUERY,MYORDERS,8"] {
+            assert!(
+                CommandHandler::handle_message(msg, &mut client, &mut limiter, &tx, &exchange,)
+                    .await
+            );
+        }
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "MYORDER,1,AAPL,BUY,10.00,5\nEND"
+        );
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "ERR,UNAUTHORIZED,unauthorized"
+        );
+
+        // the same key on a second connection
+        let (responder, mut responses) = tokio::sync::mpsc::unbounded_channel();
+        let mut other = CommandHandler::connect(&exchange, responder);
+        assert!(
+            CommandHandler::handle_message("AUTH,k3y", &mut other, &mut limiter, &tx, &exchange,)
+                .await
+        );
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "ERR,CLIENT_CONNECTED,client 7 is connected already"
+        );
+
+        // a wrong key closes the connection
+        assert!(
+            !CommandHandler::handle_message(
+                "AUTH,guess",
+                &mut other,
+                &mut limiter,
+                &tx,
+                &exchange,
+            )
+            .await
+        );
+        assert_eq!(
+            responses.try_recv().unwrap(),
+            "ERR,UNKNOWN_API_KEY,unknown api key"
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_command_queue_makes_the_connection_wait() {
+        use crate::rate_limit::RateLimiter;
+        use tokio::time::{timeout, Duration};
+
+        let exchange = Exchange::new();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let (responder, _responses) = tokio::sync::mpsc::unbounded_channel();
+        let mut client = CommandHandler::connect(&exchange, responder);
+        let mut limiter = RateLimiter::new(None);
+        let msg = "ADD,BUY,AAPL,10.00,5";
+
+        assert!(
+            CommandHandler::handle_message(msg, &mut client, &mut limiter, &tx, &exchange,).await
+        );
+        // the queue is full, the next command waits for the order book
+        let mut pending = Box::pin(CommandHandler::handle_message(
+            msg,
+            &mut client,
+            &mut limiter,
+            &tx,
+            &exchange,
+        ));
+        assert!(timeout(Duration::from_millis(50), &mut pending)
+            .await
+            .is_err());
+        assert_eq!(tx.capacity(), 0);
+
+        rx.recv().await.unwrap();
+        assert!(pending.await);
+        assert!(rx.try_recv().is_ok());
+
+        // closed order book closes the connection
+        drop(rx);
+        assert!(
+            !CommandHandler::handle_message(msg, &mut client, &mut limiter, &tx, &exchange,).await
+        );
+    }
+
+    #[tokio::test]
+    async fn ping_is_answered_without_the_order_book() {
+        use crate::rate_limit::RateLimiter;
+
+        let exchange = Exchange::new();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let (responder, mut responses) = tokio::sync::mpsc::unbounded_channel();
+        let mut client = CommandHandler::connect(&exchange, responder);
+        let mut limiter = RateLimiter::new(None);
+
+        // even with the command queue full
+        for msg in ["ADD,BUY,AAPL,10.00,5", "PING"] {
+            assert!(
+                CommandHandler::handle_message(msg, &mut client, &mut limiter, &tx, &exchange,)
+                    .await
+            );
+        }
+
+        assert_eq!(responses.try_recv().unwrap(), "PONG");
+        assert!(responses.try_recv().is_err());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+        assert!(exchange.books().is_empty());
+        assert_eq!(
+            CommandHandler::parse_command("PINGS").err(),
+            Some(ParseCommandError::UnknownCommand("PINGS".to_string()))
+        );
+    }
+
+    // returns one chunk per read, like TCP segments arriving one by one
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    fn chunks(chunks: &[&[u8]]) -> BufReader<Chunks> {
+        BufReader::new(Chunks(chunks.iter().map(|chunk| chunk.to_vec()).collect()))
+    }
+
+    impl AsyncRead for Chunks {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(mut chunk) = self.0.pop_front() {
+                // a chunk larger than the buffer is read over several calls
+                let rest = chunk.split_off(chunk.len().min(buf.remaining()));
+                buf.put_slice(&chunk);
+                if !rest.is_empty() {
+                    self.0.push_front(rest);
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_are_framed_on_newline() {
+        let mut reader = chunks(&[
+            b"ADD,BUY,AA",
+            b"PL,10.00,5\r\nPING\nQUERY,VOL",
+            b"UME,AAPL\n",
+            b"CANCEL,1",
+        ]);
+        let mut buf = vec![];
+
+        let mut messages = vec![];
+        while let Some(msg) = CommandHandler::read_message(&mut reader, &mut buf, 100)
+            .await
+            .unwrap()
+        {
+            messages.push(msg);
+        }
+        assert_eq!(
+            messages,
+            vec![
+                "ADD,BUY,AAPL,10.00,5",
+                "PING",
+                "QUERY,VOLUME,AAPL",
+                // closed without a newline
+                "CANCEL,1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn line_over_max_length_is_rejected() {
+        let mut reader = chunks(&[&vec![b'A'; 1 << 20]]);
+        let mut buf = vec![];
+
+        let err = CommandHandler::read_message(&mut reader, &mut buf, 4096)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "line longer than 4096 bytes");
+        // nothing past the limit is buffered
+        assert!(buf.len() <= 4098);
+
+        // exactly at the limit is fine, line ending not included
+        let mut reader = chunks(&[b"AAAAAAAAAA\r\n"]);
+        let msg = CommandHandler::read_message(&mut reader, &mut buf, 10).await;
+        assert_eq!(msg.unwrap().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn order_status_through_its_life() {
+        let exchange = Exchange::new();
+        let config = AuctionConfig::default();
+        let status = |id| {
+            let query = CommandHandler::parse_command(&format!("QUERY,ORDER,{}", id));
+            match query {
+                Ok(Command::Query(query)) => answer_query(&exchange, query),
+                _ => panic!("expected a query"),
+            }
+        };
+
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.00", 3), 1);
+        assert_eq!(status(1), "ORDER,1,RESTING,5,0");
+        run_batches(&exchange, &config);
+        assert_eq!(status(1), "ORDER,1,RESTING,5,1");
+
+        apply_command(&exchange, Command::Cancel { id: 2 }, 1);
+        assert_eq!(status(2), "ORDER,2,CANCELLED");
+
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "9.00", 8), 1);
+        run_batches(&exchange, &config);
+        assert_eq!(status(1), "ORDER,1,FILLED");
+        assert_eq!(status(3), "ORDER,3,RESTING,3,1");
+
+        // partially filled, then cancelled by its time in force
+        let ioc = Command::Add {
+            order_type: OrderType::Buy,
+            symbol: "AAPL".to_string(),
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            min_qty: None,
+            display_qty: None,
+            expires_at: None,
+            post_only: false,
+            price: Some(BigDecimal::from(9)),
+            qty: Qty::from(4),
+        };
+        apply_command(&exchange, ioc, 1);
+        run_batches(&exchange, &config);
+        assert_eq!(status(3), "ORDER,3,FILLED");
+        assert_eq!(status(4), "ORDER,4,CANCELLED");
+
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "8.00", 2), 1);
+        apply_command(
+            &exchange,
+            Command::Reduce {
+                id: 5,
+                qty: Qty::from(2),
+            },
+            1,
+        );
+        assert_eq!(status(5), "ORDER,5,CANCELLED");
+
+        assert_eq!(status(6), "ORDER,6,UNKNOWN");
+        assert_eq!(
+            CommandHandler::parse_command("QUERY,ORDER,abc").err(),
+            Some(ParseCommandError::BadId("abc".to_string()))
+        );
+    }
+
+    #[test]
+    fn batch_latency_over_the_window() {
+        use crate::metrics::BatchLatency;
+        use std::time::Duration;
+
+        let exchange = Exchange::new();
+        let config = AuctionConfig::default();
+        let stats = || match CommandHandler::parse_command("QUERY,STATS,AAPL") {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            _ => panic!("expected a query"),
+        };
+        assert_eq!(stats(), "STATS,NONE");
+
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "9.00", 3), 1);
+        for batches in 1..=3 {
+            run_batches(&exchange, &config);
+            let line = stats();
+            let fields: Vec<&str> = line.split(',').collect();
+            assert_eq!(fields[0], "STATS");
+            assert_eq!(fields[1], batches.to_string());
+            let micros: Vec<u128> = fields[2..].iter().map(|f| f.parse().unwrap()).collect();
+            // min, avg, p50, p99, max
+            assert!(micros[0] <= micros[1] && micros[1] <= micros[4]);
+            assert!(micros[0] <= micros[2] && micros[2] <= micros[3] && micros[3] <= micros[4]);
+        }
+
+        let mut latency = BatchLatency::new(100);
+        for micros in 1..=200 {
+            latency.record(Duration::from_micros(micros));
+        }
+        let stats = latency.stats().unwrap();
+        // only the last 100 batches
+        assert_eq!(stats.batches, 100);
+        assert_eq!(stats.min, Duration::from_micros(101));
+        assert_eq!(stats.p50, Duration::from_micros(150));
+        assert_eq!(stats.p99, Duration::from_micros(199));
+        assert_eq!(stats.max, Duration::from_micros(200));
+        assert_eq!(stats.avg, Duration::from_nanos(150_500));
+    }
+
+    #[test]
+    fn orders_of_a_client_on_both_sides() {
+        let exchange = Exchange::new();
+        let query = |msg: &str| match CommandHandler::parse_command(msg) {
+            Ok(Command::Query(query)) => answer_query(&exchange, query),
+            _ => panic!("expected a query"),
+        };
+
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "MSFT", "20.00", 3), 1);
+        // another client
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "11.00", 2), 2);
+        apply_command(&exchange, add(OrderType::Sell, "AAPL", "12.00", 4), 1);
+        apply_command(&exchange, Command::Cancel { id: 2 }, 1);
+
+        assert_eq!(
+            query("QUERY,MYORDERS,1"),
+            "MYORDER,1,AAPL,BUY,10.00,5\n\
+             MYORDER,4,AAPL,SELL,12.00,4\n\
+             END"
+        );
+        assert_eq!(
+            query("QUERY,MYORDERS,1,1"),
+            "MYORDER,4,AAPL,SELL,12.00,4\nEND"
+        );
+        assert_eq!(query("QUERY,MYORDERS,3"), "END");
+        assert_eq!(
+            CommandHandler::parse_command("QUERY,MYORDERS,me").err(),
+            Some(ParseCommandError::BadId("me".to_string()))
+        );
+
+        // a page at a time, each from the last id of the one before
+        for _ in 0..CLIENT_ORDERS_PAGE {
+            apply_command(&exchange, add(OrderType::Buy, "IBM", "1.00", 1), 1);
+        }
+        let first = query("QUERY,MYORDERS,1");
+        let lines: Vec<&str> = first.lines().collect();
+        assert_eq!(lines.len(), CLIENT_ORDERS_PAGE + 1);
+        let last_id = lines[CLIENT_ORDERS_PAGE - 1].split(',').nth(1).unwrap();
+        assert_eq!(lines[CLIENT_ORDERS_PAGE], format!("MORE,{}", last_id));
+        let second = query(&format!("QUERY,MYORDERS,1,{}", last_id));
+        assert_eq!(second.lines().count(), 3);
+        assert!(second.ends_with("END"));
+    }
+
+    #[test]
+    fn completed_orders_are_bounded() {
+        let mut completed = CompletedOrders::new(2);
+        completed.insert(1, 1, OrderStatus::Filled);
+        completed.insert(2, 1, OrderStatus::Filled);
+        // updated in place, without taking another slot
+        completed.insert(2, 1, OrderStatus::Cancelled);
+        completed.insert(3, 2, OrderStatus::Cancelled);
+
+        assert_eq!(completed.get(1), None);
+        assert_eq!(completed.get(2), Some(OrderStatus::Cancelled));
+        assert_eq!(completed.get(3), Some(OrderStatus::Cancelled));
+        assert_eq!(completed.client_id(3), Some(2));
+        assert_eq!(completed.ids.len(), 2);
+    }
+
+    #[test]
+    fn negative_prices_cross_for_configured_symbols() {
+        let exchange = Exchange::new().with_symbol_config(
+            "POWER",
+            SymbolConfig {
+                allow_negative_price: true,
+                ..SymbolConfig::default()
+            },
+        );
+        let submit = |msg| match CommandHandler::parse_command(msg) {
+            Ok(command) => apply_command(&exchange, command, 1),
+            Err(e) => ClientError::from(e).response(),
+        };
+
+        assert_eq!(submit("ADD,BUY,POWER,-2.00,5"), "ADDED,1");
+        assert_eq!(submit("ADD,SELL,POWER,-3.00,3"), "ADDED,2");
+        assert_eq!(submit("ADD,SELL,POWER,-1.00,3"), "ADDED,3");
+        assert_eq!(submit("QUERY,BBO,POWER"), "BBO,-2.00,-3.00");
+
+        let results = run_batches(&exchange, &AuctionConfig::default());
+        match &results[0].1.report {
+            BatchReport::Trade { price, qty, .. } => {
+                // halfway between -2.00 and -3.00
+                assert_eq!(*price, BigDecimal::from_str("-2.50").unwrap());
+                assert_eq!(*qty, Qty::from(3));
+            }
+            _ => panic!("expected a trade"),
+        }
+        assert_eq!(submit("QUERY,BBO,POWER"), "BBO,-2.00,-1.00");
+    }
+
+    #[test]
+    fn limits_measure_negative_prices_by_absolute_value() {
+        let limits = OrderLimits {
+            max_notional: Some(BigDecimal::from(100)),
+            price_band: Some(BigDecimal::from(10)),
+            ..OrderLimits::default()
+        };
+        let price = |price: &str| BigDecimal::from_str(price).unwrap();
+
+        assert!(limits
+            .check(
+                &limits,
+                Some(&price("-2.1")),
+                Qty::from(1),
+                Some(&price("-2"))
+            )
+            .is_ok());
+        assert!(limits
+            .check(
+                &limits,
+                Some(&price("-3")),
+                Qty::from(1),
+                Some(&price("-2"))
+            )
+            .is_err());
+        assert!(limits
+            .check(&limits, Some(&price("-20")), Qty::from(10), None)
+            .is_err());
+        // no band around a trade at 0, it would reject every other price
+        assert!(limits
+            .check(&limits, Some(&price("-1")), Qty::from(1), Some(&price("0")))
+            .is_ok());
+        assert!(limits
+            .check(
+                &limits,
+                Some(&price("0.5")),
+                Qty::from(1),
+                Some(&price("0.00"))
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn seeded_book_clears_in_the_next_batch() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "AAPL", "9.00", 1), 1);
+
+        // 5000 orders a side on 100 levels from 9.50 to 10.49, in no particular order
+        let price = |id: u64| BigDecimal::new((950 + id * 37 % 100).into(), 2);
+        let book = exchange.book("AAPL");
+        book.extend_bids((1001..=6000).map(|id| Order::new(id, price(id), Qty::from(1))));
+        book.extend_asks((6001..=11000).map(|id| Order::new(id, price(id), Qty::from(1))));
+
+        book.with_both_read(|bids, asks| {
+            assert_eq!((bids.len(), asks.len()), (5001, 5000));
+            assert!(bids.windows(2).all(|w| w[0].price() >= w[1].price()));
+            assert!(asks.windows(2).all(|w| w[0].price() <= w[1].price()));
+            // behind the seeded bids, the lowest price
+            assert_eq!(bids[5000].id(), 1);
+        });
+
+        // the bids from 10.00 up meet the asks up to 9.99
+        let results = run_batches(&exchange, &AuctionConfig::default());
+        match &results[0].1.report {
+            BatchReport::Trade { qty, .. } => assert_eq!(*qty, Qty::from(2500)),
+            _ => panic!("expected a trade"),
+        }
+        book.with_both_read(|bids, asks| {
+            assert_eq!((bids.len(), asks.len()), (2501, 2500));
+            assert_eq!(
+                bids[0].price(),
+                Some(&BigDecimal::from_str("9.99").unwrap())
+            );
+            assert_eq!(
+                asks[0].price(),
+                Some(&BigDecimal::from_str("10.00").unwrap())
+            );
+        });
+    }
+}
@@ -1,193 +1,1208 @@
-#![feature(test)]
-
-use crate::auction::{calculate_batch, BatchReport, Order};
-use bigdecimal::{BigDecimal, FromPrimitive};
-use rand::Rng;
-use std::cell::RefCell;
-use std::collections::hash_map::RandomState;
-use std::collections::{BTreeSet, HashMap, VecDeque};
+use bigdecimal::BigDecimal;
+use fba::auction::{
+    AuctionConfig, CircuitBreaker, ClearingPriceRule, FeeSchedule, Qty, RoundingMode,
+};
+use fba::config;
+use fba::event_log::{self, EventLog};
+use fba::tape::write_tape;
+use fba::{
+    print_results, record_trades, replay, run_batches, run_engine, snapshot, spawn_engine_thread,
+    BatchTiming, CommandHandler, Exchange, MatchMode, OrderLimits, OutputFormat, PricePrecision,
+    SymbolConfig, DEFAULT_MAX_LINE,
+};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
-use tokio::io;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
 use tokio::time::Duration;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-pub mod auction;
-
+const LOG_ENV: &str = "RUST_LOG";
+const DEFAULT_LOG_FILTER: &str = "info";
 const INTERVAL_MILLIS: u64 = 100;
+const INTERVAL_ENV: &str = "FBA_INTERVAL_MS";
+const INTERVAL_JITTER_ENV: &str = "FBA_INTERVAL_JITTER_MS";
+const DEFAULT_BIND: &str = "0.0.0.0:7777";
+const BIND_ENV: &str = "FBA_BIND";
+const BIND_FLAG: &str = "--bind";
+const OUTPUT_ENV: &str = "FBA_OUTPUT";
+const OUTPUT_FLAG: &str = "--output";
+const TRADE_LOG_ENV: &str = "FBA_TRADE_LOG";
+const TRADE_LOG_FLAG: &str = "--trade-log";
+const SNAPSHOT_ENV: &str = "FBA_SNAPSHOT";
+const SNAPSHOT_FLAG: &str = "--snapshot";
+const EVENT_LOG_ENV: &str = "FBA_EVENT_LOG";
+const EVENT_LOG_FLAG: &str = "--event-log";
+const API_KEYS_ENV: &str = "FBA_API_KEYS";
+const API_KEYS_FLAG: &str = "--api-keys";
+const CONFIG_ENV: &str = "FBA_CONFIG";
+const CONFIG_FLAG: &str = "--config";
+// replay commands from a file, `-` for stdin
+const REPLAY_FLAG: &str = "--replay";
+const MAX_QTY_ENV: &str = "FBA_MAX_QTY";
+const MAX_NOTIONAL_ENV: &str = "FBA_MAX_NOTIONAL";
+const PRICE_BAND_ENV: &str = "FBA_PRICE_BAND_PCT";
+const NEGATIVE_PRICE_ENV: &str = "FBA_NEGATIVE_PRICE_SYMBOLS";
+const RATE_LIMIT_ENV: &str = "FBA_MAX_COMMANDS_PER_SEC";
+const ADMIN_TOKEN_ENV: &str = "FBA_ADMIN_TOKEN";
+const MATCH_MODE_ENV: &str = "FBA_MATCH_MODE";
+const PRICE_PRECISION_ENV: &str = "FBA_PRICE_PRECISION";
+const MAX_ORDERS_ENV: &str = "FBA_MAX_ORDERS_PER_SIDE";
+const COMMAND_QUEUE_ENV: &str = "FBA_COMMAND_QUEUE";
+const MAX_LINE_ENV: &str = "FBA_MAX_LINE";
+const CANCEL_ON_DISCONNECT_ENV: &str = "FBA_CANCEL_ON_DISCONNECT";
+const ENGINE_THREAD_ENV: &str = "FBA_ENGINE_THREAD";
+const OPENING_AUCTION_ENV: &str = "FBA_OPENING_AUCTION_MS";
+const CIRCUIT_BREAKER_QTY_ENV: &str = "FBA_CIRCUIT_BREAKER_QTY";
+const CIRCUIT_BREAKER_COOLDOWN_ENV: &str = "FBA_CIRCUIT_BREAKER_BATCHES";
+// commands waiting for the order book, connections wait once it is full
+const DEFAULT_COMMAND_QUEUE: usize = 1024;
+const PRICE_SCALE_ENV: &str = "FBA_PRICE_SCALE";
+const TICK_SIZE_ENV: &str = "FBA_TICK_SIZE";
+const MAX_CROSS_ENV: &str = "FBA_MAX_CROSS";
+const ROUNDING_MODE_ENV: &str = "FBA_ROUNDING_MODE";
+const CLEARING_PRICE_ENV: &str = "FBA_CLEARING_PRICE";
+const MAKER_FEE_ENV: &str = "FBA_MAKER_FEE_BPS";
+const TAKER_FEE_ENV: &str = "FBA_TAKER_FEE_BPS";
+#[cfg(feature = "websocket")]
+const WS_BIND_ENV: &str = "FBA_WS_BIND";
+#[cfg(feature = "websocket")]
+const WS_BIND_FLAG: &str = "--ws-bind";
+#[cfg(feature = "metrics")]
+const METRICS_BIND_ENV: &str = "FBA_METRICS_BIND";
+#[cfg(feature = "metrics")]
+const METRICS_BIND_FLAG: &str = "--metrics-bind";
+const MAX_PRICE_SCALE: i64 = 18;
 
-struct DoubleSidedBook {
-    bids: Mutex<Vec<Order>>,
-    asks: Mutex<Vec<Order>>,
+// `RUST_LOG` directives, e.g. `warn,fba=debug`, info if unset
+fn parse_log_filter(value: Option<&str>) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(value.unwrap_or(DEFAULT_LOG_FILTER))
+        .map_err(|e| format!("{} is invalid: {}", LOG_ENV, e))
 }
 
-impl DoubleSidedBook {
-    fn new() -> DoubleSidedBook {
-        DoubleSidedBook {
-            bids: Mutex::new(vec![]),
-            asks: Mutex::new(vec![]),
+fn parse_interval(value: Option<&str>) -> u64 {
+    match value.map(|v| v.trim().parse::<u64>()) {
+        None => INTERVAL_MILLIS,
+        Some(Ok(millis)) if millis > 0 => millis,
+        Some(_) => {
+            warn!(
+                "{} must be a positive number of millis, using {}",
+                INTERVAL_ENV, INTERVAL_MILLIS
+            );
+            INTERVAL_MILLIS
         }
     }
 }
 
-enum CommandType {
-    Add,
-    Cancel,
+// Some(None) if the flag is passed without a value
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<Option<&'a str>> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .map(|idx| args.get(idx + 1).map(String::as_str))
 }
 
-enum OrderType {
-    Buy,
-    Sell,
+// listen address: --bind flag, then FBA_BIND, then the default
+fn parse_bind(args: &[String], env: Option<&str>) -> Result<SocketAddr, String> {
+    let addr = match flag_value(args, BIND_FLAG) {
+        Some(None) => return Err(format!("{} requires an address", BIND_FLAG)),
+        Some(Some(addr)) => addr,
+        None => env.unwrap_or(DEFAULT_BIND),
+    };
+
+    addr.parse::<SocketAddr>()
+        .map_err(|e| format!("invalid bind address {}: {}", addr, e))
 }
 
-struct Command {
-    command_type: CommandType,
-    order_type: OrderType,
-    price: BigDecimal,
-    qty: u32,
+// address of an optional endpoint: the flag, then the env variable, disabled if neither is set
+#[cfg(any(feature = "websocket", feature = "metrics"))]
+fn parse_optional_bind(
+    args: &[String],
+    flag: &str,
+    env: Option<&str>,
+) -> Result<Option<SocketAddr>, String> {
+    let addr = match flag_value(args, flag) {
+        Some(None) => return Err(format!("{} requires an address", flag)),
+        Some(Some(addr)) => addr,
+        None => match env {
+            Some(addr) => addr,
+            None => return Ok(None),
+        },
+    };
+
+    addr.parse::<SocketAddr>()
+        .map(Some)
+        .map_err(|e| format!("invalid bind address {}: {}", addr, e))
 }
 
-struct CommandHandler;
-impl CommandHandler {
-    // assume it's always valid
-    fn parse_command(msg: String) -> Command {
-        let mut split = msg.split(',');
+// report format: --output flag, then FBA_OUTPUT, then text
+fn parse_output(args: &[String], env: Option<&str>) -> Result<OutputFormat, String> {
+    let output = match flag_value(args, OUTPUT_FLAG) {
+        Some(None) => return Err(format!("{} requires a format", OUTPUT_FLAG)),
+        Some(Some(output)) => output,
+        None => env.unwrap_or("text"),
+    };
 
-        let command_type = match split.next().unwrap() {
-            "ADD" => CommandType::Add,
-            "CANCEL" => CommandType::Cancel,
-            _ => panic!("unknown command"),
-        };
+    match output {
+        "text" => Ok(OutputFormat::Text),
+        "json" => Ok(OutputFormat::Json),
+        output => Err(format!("unknown output format {}", output)),
+    }
+}
 
-        let order_type = match split.next().unwrap() {
-            "BUY" => OrderType::Buy,
-            "SELL" => OrderType::Sell,
-            _ => panic!("unknown order type"),
-        };
+// comma-separated symbols that accept zero and negative prices
+fn parse_negative_price_symbols(value: Option<&str>) -> Vec<(String, SymbolConfig)> {
+    value
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|symbol| !symbol.is_empty())
+        .map(|symbol| {
+            let config = SymbolConfig {
+                allow_negative_price: true,
+                ..SymbolConfig::default()
+            };
+            (symbol.to_string(), config)
+        })
+        .collect()
+}
 
-        // assume precision is 2 digits
-        let price = BigDecimal::from_str(split.next().unwrap())
-            .unwrap()
-            .round(2);
+// the symbols of the config file, plus the negative price ones of the env
+fn load_symbols(
+    path: Option<&Path>,
+    negative_price: Option<&str>,
+    price_scale: i64,
+) -> Result<HashMap<String, SymbolConfig>, String> {
+    let mut symbols = match path {
+        Some(path) => config::load_symbols(path, price_scale)?,
+        None => HashMap::new(),
+    };
+    for (symbol, config) in parse_negative_price_symbols(negative_price) {
+        symbols.entry(symbol).or_insert(config).allow_negative_price = true;
+    }
+    Ok(symbols)
+}
 
-        let qty = split.next().unwrap().trim().parse::<u32>().unwrap();
+fn new_exchange(
+    limits: OrderLimits,
+    symbols: HashMap<String, SymbolConfig>,
+    config: &Arc<AuctionConfig>,
+) -> Exchange {
+    symbols.into_iter().fold(
+        Exchange::new()
+            .with_limits(limits)
+            .with_auction_config(config.clone()),
+        |exchange, (symbol, config)| exchange.with_symbol_config(&symbol, config),
+    )
+}
 
-        Command {
-            command_type,
-            order_type,
-            price,
-            qty,
-        }
+// optional file path: the flag, then the env variable, disabled if neither is set
+fn parse_path(args: &[String], flag: &str, env: Option<&str>) -> Result<Option<PathBuf>, String> {
+    match flag_value(args, flag) {
+        Some(None) => Err(format!("{} requires a path", flag)),
+        Some(Some(path)) => Ok(Some(PathBuf::from(path))),
+        None => Ok(env.map(PathBuf::from)),
     }
+}
 
-    async fn handle_socket(tcp_stream: TcpStream, tx: UnboundedSender<Command>) {
-        let mut buff_reader = BufReader::new(tcp_stream);
-        loop {
-            let mut msg = String::new();
-            let read = buff_reader.read_line(&mut msg).await.unwrap(); // assume everything is OK
+// both limits are optional, a set but invalid one is an error
+fn parse_limits(
+    max_qty: Option<&str>,
+    max_notional: Option<&str>,
+    price_band: Option<&str>,
+) -> Result<OrderLimits, String> {
+    let max_qty = max_qty
+        .map(|qty| {
+            qty.trim()
+                .parse::<Qty>()
+                .map_err(|_| format!("{} must be a quantity, got {}", MAX_QTY_ENV, qty))
+        })
+        .transpose()?;
+    let max_notional = max_notional
+        .map(|notional| {
+            BigDecimal::from_str(notional.trim())
+                .map_err(|_| format!("{} must be a decimal, got {}", MAX_NOTIONAL_ENV, notional))
+        })
+        .transpose()?;
+    let price_band = price_band
+        .map(|band| {
+            BigDecimal::from_str(band.trim())
+                .ok()
+                .filter(|band| *band > BigDecimal::from(0))
+                .ok_or_else(|| {
+                    format!(
+                        "{} must be a positive percentage, got {}",
+                        PRICE_BAND_ENV, band
+                    )
+                })
+        })
+        .transpose()?;
 
-            // socket closed by client
-            if read == 0 {
-                break;
-            }
+    Ok(OrderLimits {
+        max_qty,
+        max_notional,
+        price_band,
+    })
+}
 
-            let command = CommandHandler::parse_command(msg);
-            tx.send(command);
-        }
+// decimal digits of prices, the auction default if unset
+fn parse_price_scale(value: Option<&str>) -> Result<i64, String> {
+    let value = match value {
+        None => return Ok(AuctionConfig::default().price_scale),
+        Some(value) => value,
+    };
+    match value.trim().parse::<i64>() {
+        Ok(scale) if (0..=MAX_PRICE_SCALE).contains(&scale) => Ok(scale),
+        _ => Err(format!(
+            "{} must be between 0 and {}, got {}",
+            PRICE_SCALE_ENV, MAX_PRICE_SCALE, value
+        )),
     }
 }
 
-async fn run_auction(book: Arc<DoubleSidedBook>) {
-    let mut bid_lock = book.bids.lock().unwrap();
-    let mut ask_lock = book.asks.lock().unwrap();
-
-    let report = calculate_batch(&mut bid_lock, &mut ask_lock);
-
-    drop(bid_lock);
-    drop(ask_lock);
-
-    // run separate task, that will print to stdio
-    tokio::spawn(async move {
-        match report {
-            BatchReport::NoTrade => println!("No Trade"),
-            BatchReport::Trade {
-                price,
-                qty,
-                cleared_bids,
-                cleared_asks,
-            } => {
-                println!(
-                    "Batch: cleared BID={}, cleared ASK={}, price={:?}, qty={}",
-                    cleared_bids.len(),
-                    cleared_asks.len(),
-                    price,
-                    qty
-                );
-            }
-        }
-    });
+// capacity of the command queue, the default if unset
+fn parse_command_queue(value: Option<&str>) -> Result<usize, String> {
+    let value = match value {
+        None => return Ok(DEFAULT_COMMAND_QUEUE),
+        Some(value) => value,
+    };
+    match value.trim().parse::<usize>() {
+        Ok(capacity) if capacity > 0 => Ok(capacity),
+        _ => Err(format!(
+            "{} must be a positive number, got {}",
+            COMMAND_QUEUE_ENV, value
+        )),
+    }
 }
 
-async fn update_order_book(mut rx: UnboundedReceiver<Command>, book: Arc<DoubleSidedBook>) {
-    loop {
-        let cmd = rx.recv().await.unwrap();
-
-        let order = Order::new(cmd.price.clone(), cmd.qty);
-
-        /*
-        Come up with better non-blocking solution.
-        This implementation will lock current thread for the time auction is running
-         */
-        match cmd.order_type {
-            OrderType::Buy => match cmd.command_type {
-                CommandType::Add => book.bids.lock().unwrap().push(order),
-                CommandType::Cancel => book
-                    .bids
-                    .lock()
-                    .unwrap()
-                    .retain(|o| !(o.qty == order.qty && o.price == order.price)),
-            },
-            OrderType::Sell => match cmd.command_type {
-                CommandType::Add => book.asks.lock().unwrap().push(order),
-                CommandType::Cancel => book
-                    .asks
-                    .lock()
-                    .unwrap()
-                    .retain(|o| !(o.qty == order.qty && o.price == order.price)),
-            },
+// bytes of a single message, the default if unset
+fn parse_max_line(value: Option<&str>) -> Result<usize, String> {
+    let value = match value {
+        None => return Ok(DEFAULT_MAX_LINE),
+        Some(value) => value,
+    };
+    match value.trim().parse::<usize>() {
+        Ok(max_line) if max_line > 0 => Ok(max_line),
+        _ => Err(format!(
+            "{} must be a positive number, got {}",
+            MAX_LINE_ENV, value
+        )),
+    }
+}
+
+// off if unset
+fn parse_bool(name: &str, value: Option<&str>) -> Result<bool, String> {
+    match value.map(str::trim) {
+        None => Ok(false),
+        Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(value) => Err(format!("{} must be true or false, got {}", name, value)),
+    }
+}
+
+// warm-up of the opening auction, None if unset
+fn parse_opening_auction(value: Option<&str>) -> Result<Option<Duration>, String> {
+    let value = match value {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    match value.trim().parse::<u64>() {
+        Ok(millis) if millis > 0 => Ok(Some(Duration::from_millis(millis))),
+        _ => Err(format!(
+            "{} must be a positive number of millis, got {}",
+            OPENING_AUCTION_ENV, value
+        )),
+    }
+}
+
+// disabled without a max qty, the cooldown alone is an error
+fn parse_circuit_breaker(
+    max_qty: Option<&str>,
+    cooldown: Option<&str>,
+) -> Result<Option<CircuitBreaker>, String> {
+    let cooldown = cooldown
+        .map(|batches| {
+            batches.trim().parse::<u16>().map_err(|_| {
+                format!(
+                    "{} must be a number of batches, got {}",
+                    CIRCUIT_BREAKER_COOLDOWN_ENV, batches
+                )
+            })
+        })
+        .transpose()?;
+    let max_qty = match max_qty {
+        None if cooldown.is_some() => {
+            return Err(format!(
+                "{} requires {}",
+                CIRCUIT_BREAKER_COOLDOWN_ENV, CIRCUIT_BREAKER_QTY_ENV
+            ))
         }
+        None => return Ok(None),
+        Some(max_qty) => max_qty,
+    };
+    match max_qty.trim().parse::<Qty>() {
+        Ok(max_qty) if !max_qty.is_zero() => Ok(Some(CircuitBreaker { max_qty, cooldown })),
+        _ => Err(format!(
+            "{} must be a positive quantity, got {}",
+            CIRCUIT_BREAKER_QTY_ENV, max_qty
+        )),
+    }
+}
+
+// commands per second of a connection, None if unset
+fn parse_rate_limit(value: Option<&str>) -> Result<Option<u32>, String> {
+    let value = match value {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    match value.trim().parse::<u32>() {
+        Ok(limit) if limit > 0 => Ok(Some(limit)),
+        _ => Err(format!(
+            "{} must be a positive number, got {}",
+            RATE_LIMIT_ENV, value
+        )),
+    }
+}
+
+// millis either side of the interval, 0 if unset. batches can't come closer than back to back
+fn parse_jitter(value: Option<&str>, interval: u64) -> Result<u64, String> {
+    let value = match value {
+        None => return Ok(0),
+        Some(value) => value,
+    };
+    match value.trim().parse::<u64>() {
+        Ok(jitter) if jitter <= interval => Ok(jitter),
+        _ => Err(format!(
+            "{} must be a number of millis up to the interval {}, got {}",
+            INTERVAL_JITTER_ENV, interval, value
+        )),
+    }
+}
+
+// the last field of HALT and RESUME, so it can't have a comma. None if unset
+fn parse_admin_token(value: Option<String>) -> Result<Option<String>, String> {
+    match value {
+        Some(token) if token.is_empty() || token.contains(',') => Err(format!(
+            "{} must be non-empty and without commas",
+            ADMIN_TOKEN_ENV
+        )),
+        token => Ok(token),
+    }
+}
+
+// orders on a side of a book, None if unset
+fn parse_max_orders(value: Option<&str>) -> Result<Option<usize>, String> {
+    let value = match value {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    match value.trim().parse::<usize>() {
+        Ok(max) if max > 0 => Ok(Some(max)),
+        _ => Err(format!(
+            "{} must be a positive number, got {}",
+            MAX_ORDERS_ENV, value
+        )),
     }
 }
 
+// clearing price tick, None if unset
+fn parse_tick_size(value: Option<&str>) -> Result<Option<BigDecimal>, String> {
+    let value = match value {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    match BigDecimal::from_str(value.trim()) {
+        Ok(tick) if tick > BigDecimal::from(0) => Ok(Some(tick)),
+        _ => Err(format!(
+            "{} must be a positive decimal, got {}",
+            TICK_SIZE_ENV, value
+        )),
+    }
+}
+
+// how far a bid may be above an ask before the older one is cancelled, None if unset
+fn parse_max_cross(value: Option<&str>) -> Result<Option<BigDecimal>, String> {
+    let value = match value {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    match BigDecimal::from_str(value.trim()) {
+        Ok(max_cross) if max_cross >= BigDecimal::from(0) => Ok(Some(max_cross)),
+        _ => Err(format!(
+            "{} must be a non-negative decimal, got {}",
+            MAX_CROSS_ENV, value
+        )),
+    }
+}
+
+// where between the marginal bid and ask the batch clears, the midpoint if unset
+fn parse_clearing_price_rule(value: Option<&str>) -> Result<ClearingPriceRule, String> {
+    match value.map(str::trim) {
+        None | Some("midpoint") => Ok(ClearingPriceRule::Midpoint),
+        Some("bid") => Ok(ClearingPriceRule::BidSide),
+        Some("ask") => Ok(ClearingPriceRule::AskSide),
+        Some("weighted_mid") => Ok(ClearingPriceRule::WeightedMid),
+        Some("on_grid") => Ok(ClearingPriceRule::OnGrid),
+        Some(value) => Err(format!(
+            "{} must be midpoint, bid, ask, weighted_mid or on_grid, got {}",
+            CLEARING_PRICE_ENV, value
+        )),
+    }
+}
+
+// half up if unset
+fn parse_rounding_mode(value: Option<&str>) -> Result<RoundingMode, String> {
+    match value.map(str::trim) {
+        None | Some("half_up") => Ok(RoundingMode::HalfUp),
+        Some("half_even") => Ok(RoundingMode::HalfEven),
+        Some("floor") => Ok(RoundingMode::Floor),
+        Some("ceil") => Ok(RoundingMode::Ceil),
+        Some(value) => Err(format!(
+            "{} must be half_up, half_even, floor or ceil, got {}",
+            ROUNDING_MODE_ENV, value
+        )),
+    }
+}
+
+// snapped if unset
+fn parse_price_precision(value: Option<&str>) -> Result<PricePrecision, String> {
+    match value.map(str::trim) {
+        None | Some("snap") => Ok(PricePrecision::Snap),
+        Some("reject") => Ok(PricePrecision::Reject),
+        Some(value) => Err(format!(
+            "{} must be snap or reject, got {}",
+            PRICE_PRECISION_ENV, value
+        )),
+    }
+}
+
+// batches only if unset
+fn parse_match_mode(value: Option<&str>) -> Result<MatchMode, String> {
+    match value.map(str::trim) {
+        None | Some("batch") => Ok(MatchMode::Batch),
+        Some("hybrid") => Ok(MatchMode::Hybrid),
+        Some(value) => Err(format!(
+            "{} must be batch or hybrid, got {}",
+            MATCH_MODE_ENV, value
+        )),
+    }
+}
+
+// basis points, zero if unset
+fn parse_fees(maker: Option<&str>, taker: Option<&str>) -> Result<FeeSchedule, String> {
+    let bps = |name, value: Option<&str>| match value {
+        None => Ok(BigDecimal::from(0)),
+        Some(value) => BigDecimal::from_str(value.trim())
+            .map_err(|_| format!("{} must be a decimal, got {}", name, value)),
+    };
+    Ok(FeeSchedule {
+        maker_bps: bps(MAKER_FEE_ENV, maker)?,
+        taker_bps: bps(TAKER_FEE_ENV, taker)?,
+    })
+}
+
+// invalid configuration is fatal
+fn or_exit<T>(config: Result<T, String>) -> T {
+    config.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let env = |name| std::env::var(name).ok();
+    // diagnostics only, batch reports are printed to stdout whatever the level
+    let log_filter = or_exit(parse_log_filter(env(LOG_ENV).as_deref()));
+    tracing_subscriber::fmt()
+        .with_env_filter(log_filter)
+        .with_writer(io::stderr)
+        // no escape codes in redirected logs
+        .with_ansi(io::IsTerminal::is_terminal(&io::stderr()))
+        .init();
+    let bind = or_exit(parse_bind(&args, env(BIND_ENV).as_deref()));
+    let output = or_exit(parse_output(&args, env(OUTPUT_ENV).as_deref()));
+    let trade_log = or_exit(parse_path(
+        &args,
+        TRADE_LOG_FLAG,
+        env(TRADE_LOG_ENV).as_deref(),
+    ));
+    let snapshot_path = or_exit(parse_path(
+        &args,
+        SNAPSHOT_FLAG,
+        env(SNAPSHOT_ENV).as_deref(),
+    ));
+    let event_log_path = or_exit(parse_path(
+        &args,
+        EVENT_LOG_FLAG,
+        env(EVENT_LOG_ENV).as_deref(),
+    ));
+    // both would restore the books
+    if snapshot_path.is_some() && event_log_path.is_some() {
+        or_exit(Err(format!(
+            "{} and {} can't be used together",
+            SNAPSHOT_ENV, EVENT_LOG_ENV
+        )))
+    }
+    let limits = or_exit(parse_limits(
+        env(MAX_QTY_ENV).as_deref(),
+        env(MAX_NOTIONAL_ENV).as_deref(),
+        env(PRICE_BAND_ENV).as_deref(),
+    ));
+    let price_scale = or_exit(parse_price_scale(env(PRICE_SCALE_ENV).as_deref()));
+    let config_path = or_exit(parse_path(&args, CONFIG_FLAG, env(CONFIG_ENV).as_deref()));
+    let symbols = or_exit(load_symbols(
+        config_path.as_deref(),
+        env(NEGATIVE_PRICE_ENV).as_deref(),
+        price_scale,
+    ));
+    let tick_size = or_exit(parse_tick_size(env(TICK_SIZE_ENV).as_deref()));
+    let rounding_mode = or_exit(parse_rounding_mode(env(ROUNDING_MODE_ENV).as_deref()));
+    let clearing_price_rule = or_exit(parse_clearing_price_rule(
+        env(CLEARING_PRICE_ENV).as_deref(),
+    ));
+    let max_cross = or_exit(parse_max_cross(env(MAX_CROSS_ENV).as_deref()));
+    let circuit_breaker = or_exit(parse_circuit_breaker(
+        env(CIRCUIT_BREAKER_QTY_ENV).as_deref(),
+        env(CIRCUIT_BREAKER_COOLDOWN_ENV).as_deref(),
+    ));
+    let fees = or_exit(parse_fees(
+        env(MAKER_FEE_ENV).as_deref(),
+        env(TAKER_FEE_ENV).as_deref(),
+    ));
+    let replay_path = or_exit(parse_path(&args, REPLAY_FLAG, None));
+    let rate_limit = or_exit(parse_rate_limit(env(RATE_LIMIT_ENV).as_deref()));
+    let max_orders = or_exit(parse_max_orders(env(MAX_ORDERS_ENV).as_deref()));
+    let match_mode = or_exit(parse_match_mode(env(MATCH_MODE_ENV).as_deref()));
+    let price_precision = or_exit(parse_price_precision(env(PRICE_PRECISION_ENV).as_deref()));
+    let admin_token = or_exit(parse_admin_token(env(ADMIN_TOKEN_ENV)));
+    let api_keys = or_exit(
+        parse_path(&args, API_KEYS_FLAG, env(API_KEYS_ENV).as_deref())
+            .and_then(|path| path.map(|path| config::load_api_keys(&path)).transpose()),
+    );
+    let command_queue = or_exit(parse_command_queue(env(COMMAND_QUEUE_ENV).as_deref()));
+    let max_line = or_exit(parse_max_line(env(MAX_LINE_ENV).as_deref()));
+    let cancel_on_disconnect = or_exit(parse_bool(
+        CANCEL_ON_DISCONNECT_ENV,
+        env(CANCEL_ON_DISCONNECT_ENV).as_deref(),
+    ));
+    let engine_thread = or_exit(parse_bool(
+        ENGINE_THREAD_ENV,
+        env(ENGINE_THREAD_ENV).as_deref(),
+    ));
+    let opening_auction = or_exit(parse_opening_auction(env(OPENING_AUCTION_ENV).as_deref()));
+    #[cfg(feature = "websocket")]
+    let ws_bind = or_exit(parse_optional_bind(
+        &args,
+        WS_BIND_FLAG,
+        env(WS_BIND_ENV).as_deref(),
+    ));
+    #[cfg(feature = "metrics")]
+    let metrics_bind = or_exit(parse_optional_bind(
+        &args,
+        METRICS_BIND_FLAG,
+        env(METRICS_BIND_ENV).as_deref(),
+    ));
+
+    let config = Arc::new(AuctionConfig {
+        price_scale,
+        tick_size,
+        rounding_mode,
+        clearing_price_rule,
+        fees,
+        circuit_breaker,
+        max_cross,
+        ..AuctionConfig::default()
+    });
+
+    // no networking, snapshot or tape: the output depends on the replayed commands only
+    if let Some(path) = replay_path {
+        let exchange = new_exchange(limits, symbols, &config)
+            .with_max_orders(max_orders)
+            .with_match_mode(match_mode)
+            .with_price_precision(price_precision);
+        let out = &mut io::stdout().lock();
+        let replayed = if path.as_os_str() == "-" {
+            replay::replay(io::stdin().lock(), out, &exchange, &config, output)
+        } else {
+            std::fs::File::open(&path).and_then(|file| {
+                replay::replay(io::BufReader::new(file), out, &exchange, &config, output)
+            })
+        };
+        if let Err(e) = replayed {
+            error!("failed to replay {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // optional trade tape: batches and hybrid trades -> channel -> file
+    let (tape, tape_writer) = match trade_log {
+        Some(path) => {
+            let (tape_tx, tape_rx) = tokio::sync::mpsc::unbounded_channel();
+            let writer = tokio::spawn(async move {
+                if let Err(e) = write_tape(&path, tape_rx).await {
+                    error!("trade log {} failed: {}", path.display(), e);
+                }
+            });
+            (Some(tape_tx), Some(writer))
+        }
+        None => (None, None),
+    };
+
     // init order books
-    let book = Arc::new(DoubleSidedBook::new());
+    let exchange = new_exchange(limits, symbols, &config)
+        .with_max_orders(max_orders)
+        .with_match_mode(match_mode)
+        .with_price_precision(price_precision)
+        .with_rate_limit(rate_limit)
+        .with_max_line(max_line)
+        .with_cancel_on_disconnect(cancel_on_disconnect)
+        .with_admin_token(admin_token)
+        .with_api_keys(api_keys)
+        .with_tape(tape.clone());
+    // the books of the logged session, then new events are appended
+    let exchange = match &event_log_path {
+        Some(path) => {
+            let exchange = if path.exists() {
+                event_log::replay_file(path, exchange, &config).unwrap_or_else(|e| {
+                    error!("failed to replay event log {}: {}", path.display(), e);
+                    std::process::exit(1);
+                })
+            } else {
+                exchange
+            };
+            match EventLog::open(path) {
+                Ok(event_log) => exchange.with_event_log(event_log),
+                Err(e) => {
+                    error!("failed to open event log {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => exchange,
+    };
+    let exchange = Arc::new(match opening_auction {
+        Some(warm_up) => {
+            info!("opening auction accumulates orders for {:?}", warm_up);
+            exchange.with_opening_auction(warm_up)
+        }
+        None => exchange,
+    });
+    if let Some(path) = snapshot_path.as_deref().filter(|path| path.exists()) {
+        match snapshot::restore(&exchange, path) {
+            Ok(count) => info!("restored {} orders from {}", count, path.display()),
+            Err(e) => {
+                error!("failed to restore snapshot {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
 
     // init channel
     // pipeline: socket -> channel -> order book
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-
-    //schedule periodic auction execution
-    let _book = book.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_millis(INTERVAL_MILLIS));
-        loop {
-            interval.tick().await;
-            run_auction(_book.clone()).await;
-        }
-    });
+    let (tx, rx) = tokio::sync::mpsc::channel(command_queue);
 
-    //init task for order book updates
+    // order book updates and periodic auction execution
     // channel -> order book
-    tokio::spawn(update_order_book(rx, book.clone()));
+    let interval = parse_interval(env(INTERVAL_ENV).as_deref());
+    let jitter = or_exit(parse_jitter(env(INTERVAL_JITTER_ENV).as_deref(), interval));
+    let timing = BatchTiming::new(
+        Duration::from_millis(interval),
+        Duration::from_millis(jitter),
+    );
+    let (stop_engine, engine_stopped) = oneshot::channel::<()>();
+    let engine = if engine_thread {
+        let thread = spawn_engine_thread(
+            rx,
+            exchange.clone(),
+            config.clone(),
+            output,
+            tape.clone(),
+            timing,
+            engine_stopped,
+        );
+        let thread = or_exit(thread.map_err(|e| format!("failed to start the engine: {}", e)));
+        info!("matching engine runs on a thread of its own");
+        tokio::task::spawn_blocking(move || {
+            let _ = thread.join();
+        })
+    } else {
+        tokio::spawn(run_engine(
+            rx,
+            exchange.clone(),
+            config.clone(),
+            output,
+            tape.clone(),
+            timing,
+            engine_stopped,
+        ))
+    };
+
+    let tcp_listener = match TcpListener::bind(bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("failed to bind {}: {}", bind, e);
+            std::process::exit(1);
+        }
+    };
+    info!("listening on {}", bind);
+
+    #[cfg(feature = "websocket")]
+    let ws_server = match ws_bind {
+        None => None,
+        Some(ws_bind) => match TcpListener::bind(ws_bind).await {
+            Ok(listener) => Some(tokio::spawn(fba::websocket::serve(
+                listener,
+                tx.clone(),
+                exchange.clone(),
+            ))),
+            Err(e) => {
+                error!("failed to bind {}: {}", ws_bind, e);
+                std::process::exit(1);
+            }
+        },
+    };
 
-    let mut tcp_listener = TcpListener::bind("0.0.0.0:7777").await.unwrap();
+    #[cfg(feature = "metrics")]
+    let metrics_server = match metrics_bind {
+        None => None,
+        Some(metrics_bind) => match TcpListener::bind(metrics_bind).await {
+            Ok(listener) => Some(tokio::spawn(fba::metrics::serve(
+                listener,
+                exchange.clone(),
+            ))),
+            Err(e) => {
+                error!("failed to bind {}: {}", metrics_bind, e);
+                std::process::exit(1);
+            }
+        },
+    };
 
     loop {
-        let (socket, _) = tcp_listener.accept().await.unwrap(); // assume everything is OK
-        tokio::spawn(CommandHandler::handle_socket(socket, tx.clone()));
+        tokio::select! {
+            accepted = tcp_listener.accept() => {
+                let socket = match accepted {
+                    Ok((socket, _)) => socket,
+                    // e.g. out of file descriptors, the listener itself is fine
+                    Err(e) => {
+                        warn!("failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                tokio::spawn(CommandHandler::handle_socket(
+                    socket,
+                    tx.clone(),
+                    exchange.clone(),
+                ));
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    // shutdown: no new connections, apply pending commands and run the last batch
+    drop(tcp_listener);
+    #[cfg(feature = "websocket")]
+    if let Some(ws_server) = ws_server {
+        ws_server.abort();
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
+    let _ = stop_engine.send(());
+    let _ = engine.await;
+
+    info!("shutting down, final batch");
+    let results = run_batches(&exchange, &config);
+    record_trades(&tape, &results);
+    print_results(results, output);
+
+    if let Some(path) = snapshot_path {
+        if let Err(e) = snapshot::save(&exchange, &path) {
+            error!("failed to save snapshot {}: {}", path.display(), e);
+        }
+    }
+
+    // closing the channel flushes the tape
+    exchange.close_tape();
+    drop(tape);
+    if let Some(writer) = tape_writer {
+        let _ = writer.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        parse_bind, parse_circuit_breaker, parse_clearing_price_rule, parse_interval, parse_jitter,
+        parse_limits, parse_log_filter, parse_match_mode, parse_max_cross, parse_output,
+        parse_path, parse_price_precision, parse_price_scale, parse_rounding_mode, parse_tick_size,
+    };
+    use bigdecimal::BigDecimal;
+    use fba::auction::{CircuitBreaker, ClearingPriceRule, Qty, RoundingMode};
+    use fba::{MatchMode, OrderLimits, OutputFormat, PricePrecision, DEFAULT_MAX_LINE};
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    #[test]
+    fn log_filter_from_rust_log() {
+        assert_eq!(parse_log_filter(None).unwrap().to_string(), "info");
+        let filter = parse_log_filter(Some("warn,fba=debug,fba::websocket=off")).unwrap();
+        assert_eq!(
+            filter.max_level_hint(),
+            Some(tracing_subscriber::filter::LevelFilter::DEBUG)
+        );
+        assert!(parse_log_filter(Some("fba=loud")).is_err());
+    }
+
+    #[test]
+    fn interval_falls_back_to_default() {
+        assert_eq!(parse_interval(None), 100);
+        assert_eq!(parse_interval(Some("250")), 250);
+        assert_eq!(parse_interval(Some("0")), 100);
+        assert_eq!(parse_interval(Some("-5")), 100);
+        assert_eq!(parse_interval(Some("fast")), 100);
+    }
+
+    #[test]
+    fn jitter_from_env() {
+        assert_eq!(parse_jitter(None, 100), Ok(0));
+        assert_eq!(parse_jitter(Some("20"), 100), Ok(20));
+        assert_eq!(parse_jitter(Some("100"), 100), Ok(100));
+        assert!(parse_jitter(Some("101"), 100).is_err());
+        assert!(parse_jitter(Some("-1"), 100).is_err());
+    }
+
+    #[test]
+    fn bind_address_from_flag_env_or_default() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            parse_bind(&args(&[]), None).unwrap().to_string(),
+            "0.0.0.0:7777"
+        );
+        assert_eq!(
+            parse_bind(&args(&[]), Some("127.0.0.1:9000"))
+                .unwrap()
+                .to_string(),
+            "127.0.0.1:9000"
+        );
+        assert_eq!(
+            parse_bind(&args(&["--bind", "127.0.0.1:8000"]), Some("127.0.0.1:9000"))
+                .unwrap()
+                .to_string(),
+            "127.0.0.1:8000"
+        );
+        assert!(parse_bind(&args(&["--bind"]), None).is_err());
+        assert!(parse_bind(&args(&["--bind", "localhost"]), None).is_err());
+    }
+
+    #[test]
+    fn output_format_from_flag_or_env() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(parse_output(&args(&[]), None), Ok(OutputFormat::Text));
+        assert_eq!(
+            parse_output(&args(&[]), Some("json")),
+            Ok(OutputFormat::Json)
+        );
+        assert_eq!(
+            parse_output(&args(&["--output", "text"]), Some("json")),
+            Ok(OutputFormat::Text)
+        );
+        assert!(parse_output(&args(&["--output", "xml"]), None).is_err());
+    }
+
+    #[test]
+    fn path_from_flag_or_env() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(parse_path(&args(&[]), "--trade-log", None), Ok(None));
+        assert_eq!(
+            parse_path(&args(&[]), "--trade-log", Some("trades.csv")),
+            Ok(Some(PathBuf::from("trades.csv")))
+        );
+        assert_eq!(
+            parse_path(
+                &args(&["--trade-log", "tape.csv"]),
+                "--trade-log",
+                Some("trades.csv")
+            ),
+            Ok(Some(PathBuf::from("tape.csv")))
+        );
+        assert!(parse_path(&args(&["--snapshot"]), "--snapshot", None).is_err());
+    }
+
+    #[test]
+    fn limits_from_env() {
+        assert_eq!(parse_limits(None, None, None), Ok(OrderLimits::default()));
+        assert_eq!(
+            parse_limits(Some("100"), Some("5000.50"), Some("2.5")),
+            Ok(OrderLimits {
+                max_qty: Some(Qty::from(100)),
+                max_notional: Some(BigDecimal::from_str("5000.50").unwrap()),
+                price_band: Some(BigDecimal::from_str("2.5").unwrap()),
+            })
+        );
+        assert!(parse_limits(Some("lots"), None, None).is_err());
+        assert!(parse_limits(None, Some("1.2.3"), None).is_err());
+        assert!(parse_limits(None, None, Some("0")).is_err());
+    }
+
+    #[test]
+    fn price_scale_from_env() {
+        assert_eq!(parse_price_scale(None), Ok(2));
+        assert_eq!(parse_price_scale(Some("4")), Ok(4));
+        assert!(parse_price_scale(Some("-1")).is_err());
+        assert!(parse_price_scale(Some("19")).is_err());
+    }
+
+    #[test]
+    fn tick_size_from_env() {
+        assert_eq!(parse_tick_size(None), Ok(None));
+        assert_eq!(
+            parse_tick_size(Some("0.05")),
+            Ok(Some(BigDecimal::from_str("0.05").unwrap()))
+        );
+        assert!(parse_tick_size(Some("0")).is_err());
+        assert!(parse_tick_size(Some("tick")).is_err());
+    }
+
+    #[test]
+    fn circuit_breaker_from_env() {
+        assert_eq!(parse_circuit_breaker(None, None), Ok(None));
+        assert_eq!(
+            parse_circuit_breaker(Some("1000"), None),
+            Ok(Some(CircuitBreaker {
+                max_qty: Qty::from(1000),
+                cooldown: None,
+            }))
+        );
+        assert_eq!(
+            parse_circuit_breaker(Some("1000"), Some("5")),
+            Ok(Some(CircuitBreaker {
+                max_qty: Qty::from(1000),
+                cooldown: Some(5),
+            }))
+        );
+        assert!(parse_circuit_breaker(Some("0"), None).is_err());
+        assert!(parse_circuit_breaker(Some("1000"), Some("-1")).is_err());
+        assert!(parse_circuit_breaker(None, Some("5")).is_err());
+    }
+
+    #[test]
+    fn rounding_mode_from_env() {
+        assert_eq!(parse_rounding_mode(None), Ok(RoundingMode::HalfUp));
+        assert_eq!(
+            parse_rounding_mode(Some("half_even")),
+            Ok(RoundingMode::HalfEven)
+        );
+        assert_eq!(parse_rounding_mode(Some("floor")), Ok(RoundingMode::Floor));
+        assert_eq!(parse_rounding_mode(Some("ceil")), Ok(RoundingMode::Ceil));
+        assert!(parse_rounding_mode(Some("up")).is_err());
+    }
+
+    #[test]
+    fn clearing_price_rule_from_env() {
+        assert_eq!(
+            parse_clearing_price_rule(None),
+            Ok(ClearingPriceRule::Midpoint)
+        );
+        assert_eq!(
+            parse_clearing_price_rule(Some("bid")),
+            Ok(ClearingPriceRule::BidSide)
+        );
+        assert_eq!(
+            parse_clearing_price_rule(Some("weighted_mid")),
+            Ok(ClearingPriceRule::WeightedMid)
+        );
+        assert_eq!(
+            parse_clearing_price_rule(Some("on_grid")),
+            Ok(ClearingPriceRule::OnGrid)
+        );
+        assert!(parse_clearing_price_rule(Some("last")).is_err());
+    }
+
+    #[test]
+    fn max_cross_from_env() {
+        assert_eq!(parse_max_cross(None), Ok(None));
+        assert_eq!(
+            parse_max_cross(Some("2.5")),
+            Ok(Some(BigDecimal::from_str("2.5").unwrap()))
+        );
+        assert_eq!(parse_max_cross(Some("0")), Ok(Some(BigDecimal::from(0))));
+        assert!(parse_max_cross(Some("-1")).is_err());
+    }
+
+    #[test]
+    fn match_mode_from_env() {
+        assert_eq!(parse_match_mode(None), Ok(MatchMode::Batch));
+        assert_eq!(parse_match_mode(Some(" hybrid")), Ok(MatchMode::Hybrid));
+        assert!(parse_match_mode(Some("continuous")).is_err());
+    }
+
+    #[test]
+    fn price_precision_from_env() {
+        assert_eq!(parse_price_precision(None), Ok(PricePrecision::Snap));
+        assert_eq!(
+            parse_price_precision(Some("reject")),
+            Ok(PricePrecision::Reject)
+        );
+        assert!(parse_price_precision(Some("round")).is_err());
+    }
+
+    #[cfg(any(feature = "websocket", feature = "metrics"))]
+    #[test]
+    fn optional_bind_from_flag_or_env() {
+        use crate::parse_optional_bind;
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let flag = "--ws-bind";
+
+        assert_eq!(parse_optional_bind(&args(&[]), flag, None), Ok(None));
+        assert_eq!(
+            parse_optional_bind(&args(&[]), flag, Some("127.0.0.1:7778")),
+            Ok(Some("127.0.0.1:7778".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_optional_bind(
+                &args(&["--ws-bind", "0.0.0.0:9000"]),
+                flag,
+                Some("127.0.0.1:7778")
+            ),
+            Ok(Some("0.0.0.0:9000".parse().unwrap()))
+        );
+        assert!(parse_optional_bind(&args(&["--ws-bind", "nowhere"]), flag, None).is_err());
+        assert!(parse_optional_bind(&args(&["--ws-bind"]), flag, None).is_err());
+    }
+
+    #[test]
+    fn fees_from_env() {
+        use crate::{parse_fees, FeeSchedule};
+
+        assert_eq!(parse_fees(None, None), Ok(FeeSchedule::default()));
+        assert_eq!(
+            parse_fees(Some("-0.5"), Some("2")),
+            Ok(FeeSchedule {
+                maker_bps: BigDecimal::from_str("-0.5").unwrap(),
+                taker_bps: BigDecimal::from(2),
+            })
+        );
+        assert!(parse_fees(Some("free"), None).is_err());
+    }
+
+    #[test]
+    fn rate_limit_from_env() {
+        use crate::parse_rate_limit;
+
+        assert_eq!(parse_rate_limit(None), Ok(None));
+        assert_eq!(parse_rate_limit(Some("50")), Ok(Some(50)));
+        assert!(parse_rate_limit(Some("0")).is_err());
+        assert!(parse_rate_limit(Some("fast")).is_err());
+    }
+
+    #[test]
+    fn admin_token_from_env() {
+        use crate::parse_admin_token;
+
+        assert_eq!(parse_admin_token(None), Ok(None));
+        assert_eq!(
+            parse_admin_token(Some("s3cret".to_string())),
+            Ok(Some("s3cret".to_string()))
+        );
+        assert!(parse_admin_token(Some(String::new())).is_err());
+        assert!(parse_admin_token(Some("a,b".to_string())).is_err());
+    }
+
+    #[test]
+    fn max_orders_from_env() {
+        use crate::parse_max_orders;
+
+        assert_eq!(parse_max_orders(None), Ok(None));
+        assert_eq!(parse_max_orders(Some("100000")), Ok(Some(100_000)));
+        assert!(parse_max_orders(Some("0")).is_err());
+        assert!(parse_max_orders(Some("all")).is_err());
+    }
+
+    #[test]
+    fn negative_price_symbols_from_env() {
+        use crate::parse_negative_price_symbols;
+
+        assert!(parse_negative_price_symbols(None).is_empty());
+        let symbols = parse_negative_price_symbols(Some("POWER, SPREAD,"));
+        assert_eq!(
+            symbols.iter().map(|(symbol, _)| symbol).collect::<Vec<_>>(),
+            vec!["POWER", "SPREAD"]
+        );
+        assert!(symbols
+            .iter()
+            .all(|(_, config)| config.allow_negative_price));
+    }
+
+    #[test]
+    fn symbols_from_config_file_and_env() {
+        use crate::load_symbols;
+
+        let path = std::env::temp_dir().join(format!("fba-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "[POWER]\ntick_size = 0.5\n").unwrap();
+        let symbols = load_symbols(Some(&path), Some("POWER,SPREAD"), 2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(symbols.len(), 2);
+        assert!(symbols["POWER"].allow_negative_price);
+        assert!(symbols["POWER"].tick_size.is_some());
+        assert!(symbols["SPREAD"].allow_negative_price);
+
+        assert!(load_symbols(Some(&path), None, 2).is_err());
+        assert!(load_symbols(None, None, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn max_line_from_env() {
+        use crate::parse_max_line;
+
+        assert_eq!(parse_max_line(None), Ok(DEFAULT_MAX_LINE));
+        assert_eq!(parse_max_line(Some("256")), Ok(256));
+        assert!(parse_max_line(Some("0")).is_err());
+        assert!(parse_max_line(Some("long")).is_err());
+    }
+
+    #[test]
+    fn opening_auction_from_env() {
+        use crate::parse_opening_auction;
+        use std::time::Duration;
+
+        assert_eq!(parse_opening_auction(None), Ok(None));
+        assert_eq!(
+            parse_opening_auction(Some("30000")),
+            Ok(Some(Duration::from_secs(30)))
+        );
+        assert!(parse_opening_auction(Some("0")).is_err());
+        assert!(parse_opening_auction(Some("soon")).is_err());
+    }
+
+    #[test]
+    fn cancel_on_disconnect_from_env() {
+        use crate::{parse_bool, CANCEL_ON_DISCONNECT_ENV};
+
+        let parse = |value| parse_bool(CANCEL_ON_DISCONNECT_ENV, value);
+        assert_eq!(parse(None), Ok(false));
+        assert_eq!(parse(Some("true")), Ok(true));
+        assert_eq!(parse(Some("false")), Ok(false));
+        assert!(parse(Some("yes")).is_err());
+    }
+
+    #[test]
+    fn command_queue_from_env() {
+        use crate::parse_command_queue;
+
+        assert_eq!(parse_command_queue(None), Ok(1024));
+        assert_eq!(parse_command_queue(Some("16")), Ok(16));
+        assert!(parse_command_queue(Some("0")).is_err());
+        assert!(parse_command_queue(Some("many")).is_err());
     }
 }
@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+#[cfg(feature = "metrics")]
+use {
+    crate::auction::Qty,
+    crate::Exchange,
+    std::fmt::Write,
+    std::sync::atomic::Ordering,
+    std::sync::Arc,
+    tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    tokio::net::{TcpListener, TcpStream},
+};
+
+// counters of the exchange activity since the start
+#[derive(Default)]
+pub struct Metrics {
+    pub orders_received: AtomicU64,
+    // refused by the order limits
+    pub orders_rejected: AtomicU64,
+    pub batches: AtomicU64,
+    pub trades: AtomicU64,
+    // in units of the qty, see `Qty::units`
+    pub traded_volume: AtomicU64,
+}
+
+// wall time of the last batches of a book that ran the auction, halted ones don't count
+pub(crate) struct BatchLatency {
+    window: VecDeque<Duration>,
+    capacity: usize,
+    // since the start, not only the window
+    count: u64,
+    total: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LatencyStats {
+    // batches in the window
+    pub batches: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl BatchLatency {
+    pub(crate) fn new(capacity: usize) -> BatchLatency {
+        BatchLatency {
+            window: VecDeque::with_capacity(capacity),
+            capacity,
+            count: 0,
+            total: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn record(&mut self, elapsed: Duration) {
+        if self.window.len() == self.capacity {
+            self.window.pop_front();
+        }
+        self.window.push_back(elapsed);
+        self.count += 1;
+        self.total += elapsed;
+    }
+
+    // None before the first batch
+    pub(crate) fn stats(&self) -> Option<LatencyStats> {
+        let mut sorted: Vec<Duration> = self.window.iter().copied().collect();
+        sorted.sort_unstable();
+        // nearest rank
+        let percentile = |p: f64| sorted[((p * sorted.len() as f64).ceil() as usize).max(1) - 1];
+        Some(LatencyStats {
+            batches: sorted.len(),
+            min: *sorted.first()?,
+            avg: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            p50: percentile(0.5),
+            p99: percentile(0.99),
+            max: *sorted.last()?,
+        })
+    }
+
+    #[cfg(feature = "metrics")]
+    fn totals(&self) -> (u64, Duration) {
+        (self.count, self.total)
+    }
+}
+
+/**
+Renders the counters and the resting order gauges in the Prometheus text exposition format.
+*/
+#[cfg(feature = "metrics")]
+fn render(exchange: &Exchange) -> String {
+    let metrics = &exchange.metrics;
+    let counters = [
+        (
+            "fba_orders_received_total",
+            "Orders received by the order book.",
+            &metrics.orders_received,
+        ),
+        (
+            "fba_orders_rejected_total",
+            "Orders rejected by the order limits.",
+            &metrics.orders_rejected,
+        ),
+        ("fba_batches_total", "Batches run.", &metrics.batches),
+        (
+            "fba_trades_total",
+            "Batches that ended with a trade.",
+            &metrics.trades,
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, help, counter) in counters {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+    }
+    // fractional lots are shown as decimals
+    let traded_volume = Qty::from_units(metrics.traded_volume.load(Ordering::Relaxed));
+    out.push_str("# HELP fba_traded_volume_total Cumulative traded qty.\n");
+    out.push_str("# TYPE fba_traded_volume_total counter\n");
+    let _ = writeln!(out, "fba_traded_volume_total {}", traded_volume);
+
+    let (mut bids, mut asks) = (0, 0);
+    for (_, book) in exchange.books() {
+        bids += book.bids.read().unwrap().len();
+        asks += book.asks.read().unwrap().len();
+    }
+    out.push_str("# HELP fba_resting_orders Orders resting in the books.\n");
+    out.push_str("# TYPE fba_resting_orders gauge\n");
+    let _ = writeln!(out, "fba_resting_orders{{side=\"bid\"}} {}", bids);
+    let _ = writeln!(out, "fba_resting_orders{{side=\"ask\"}} {}", asks);
+
+    out.push_str("# HELP fba_batch_duration_seconds Wall time of the auction of a batch, quantiles of the last batches.\n");
+    out.push_str("# TYPE fba_batch_duration_seconds summary\n");
+    for (symbol, book) in exchange.books() {
+        let latency = book.latency.lock().unwrap();
+        let stats = match latency.stats() {
+            Some(stats) => stats,
+            None => continue,
+        };
+        for (quantile, value) in [("0.5", stats.p50), ("0.99", stats.p99)] {
+            let _ = writeln!(
+                out,
+                "fba_batch_duration_seconds{{symbol=\"{}\",quantile=\"{}\"}} {}",
+                symbol,
+                quantile,
+                value.as_secs_f64()
+            );
+        }
+        let (count, total) = latency.totals();
+        let _ = writeln!(
+            out,
+            "fba_batch_duration_seconds_sum{{symbol=\"{}\"}} {}",
+            symbol,
+            total.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "fba_batch_duration_seconds_count{{symbol=\"{}\"}} {}",
+            symbol, count
+        );
+    }
+    out
+}
+
+// accepts HTTP connections until the task is aborted
+#[cfg(feature = "metrics")]
+pub async fn serve(listener: TcpListener, exchange: Arc<Exchange>) {
+    loop {
+        let (socket, _) = listener.accept().await.unwrap(); // assume everything is OK
+        tokio::spawn(handle_http(socket, exchange.clone()));
+    }
+}
+
+// minimal HTTP/1.1, one request per connection
+#[cfg(feature = "metrics")]
+async fn handle_http(tcp_stream: TcpStream, exchange: Arc<Exchange>) {
+    let (read_half, mut write_half) = tcp_stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await.is_err() {
+        return;
+    }
+    // headers are not used, but have to be read before responding
+    loop {
+        let mut header = String::new();
+        match reader.read_line(&mut header).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) if header.trim().is_empty() => break,
+            Ok(_) => {}
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", render(&exchange))
+        }
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    let _ = write_half.write_all(response.as_bytes()).await;
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use crate::auction::{AuctionConfig, Qty, TimeInForce};
+    use crate::metrics::{render, serve};
+    use crate::{apply_command, run_batches, Command, Exchange, OrderType};
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn add(order_type: OrderType, price: &str, qty: u32) -> Command {
+        Command::Add {
+            order_type,
+            symbol: "AAPL".to_string(),
+            time_in_force: TimeInForce::GoodTillCancel,
+            min_qty: None,
+            display_qty: None,
+            expires_at: None,
+            post_only: false,
+            price: Some(BigDecimal::from_str(price).unwrap()),
+            qty: Qty::from(qty),
+        }
+    }
+
+    #[test]
+    fn render_counters_and_gauges() {
+        let exchange = Exchange::new();
+        apply_command(&exchange, add(OrderType::Buy, "10.00", 5), 1);
+        apply_command(&exchange, add(OrderType::Sell, "9.00", 3), 1);
+        apply_command(&exchange, add(OrderType::Sell, "12.00", 1), 1);
+        run_batches(&exchange, &AuctionConfig::default());
+
+        let text = render(&exchange);
+        assert!(text.contains("# TYPE fba_orders_received_total counter\n"));
+        assert!(text.contains("fba_orders_received_total 3\n"));
+        assert!(text.contains("fba_orders_rejected_total 0\n"));
+        assert!(text.contains("fba_batches_total 1\n"));
+        assert!(text.contains("fba_trades_total 1\n"));
+        assert!(text.contains("fba_traded_volume_total 3\n"));
+        assert!(text.contains("fba_resting_orders{side=\"bid\"} 1\n"));
+        assert!(text.contains("fba_resting_orders{side=\"ask\"} 1\n"));
+        assert!(text.contains("# TYPE fba_batch_duration_seconds summary\n"));
+        assert!(text.contains("fba_batch_duration_seconds{symbol=\"AAPL\",quantile=\"0.99\"} "));
+        assert!(text.contains("fba_batch_duration_seconds_count{symbol=\"AAPL\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn metrics_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(Exchange::new())));
+
+        let get = |path: &'static str| async move {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            socket.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            socket.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("fba_resting_orders{side=\"ask\"} 0\n"));
+        // no batches yet
+        assert!(response.ends_with("# TYPE fba_batch_duration_seconds summary\n"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::auction::round_price;
+use bigdecimal::{BigDecimal, Signed, ToPrimitive};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/**
+Price of an order, compared and cloned on the hot path of every batch.
+Only the clearing price and the fees are calculated in decimals, once per batch.
+Prices own their data: the orders holding them rest in the book for many batches, and books and
+workspaces are kept by exchanges that outlive any borrow.
+*/
+pub trait Price: Clone + Ord + 'static {
+    fn to_decimal(&self) -> BigDecimal;
+
+    // rounded half up if the price doesn't fit the type
+    fn from_decimal(decimal: &BigDecimal) -> Self;
+}
+
+// the default, exact for any number of digits
+impl Price for BigDecimal {
+    fn to_decimal(&self) -> BigDecimal {
+        self.clone()
+    }
+
+    fn from_decimal(decimal: &BigDecimal) -> BigDecimal {
+        decimal.clone()
+    }
+}
+
+/**
+Fixed-point price, `units * 10^-SCALE`. Cheaper to sort and clone than `BigDecimal`,
+but limited to `SCALE` digits and the `i64` range.
+Converted from and to decimals at the protocol boundary.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct FixedPrice<const SCALE: u32>(pub i64);
+
+impl<const SCALE: u32> Price for FixedPrice<SCALE> {
+    fn to_decimal(&self) -> BigDecimal {
+        BigDecimal::new(self.0.into(), i64::from(SCALE))
+    }
+
+    // saturates outside of the i64 range
+    fn from_decimal(decimal: &BigDecimal) -> FixedPrice<SCALE> {
+        let scale = i64::from(SCALE);
+        let (units, _) = round_price(decimal, scale)
+            .with_scale(scale)
+            .as_bigint_and_exponent();
+        FixedPrice(units.to_i64().unwrap_or(if units.is_negative() {
+            i64::MIN
+        } else {
+            i64::MAX
+        }))
+    }
+}
+
+impl<const SCALE: u32> fmt::Display for FixedPrice<SCALE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_decimal())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::price::{FixedPrice, Price};
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+
+    fn decimal(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn fixed_price_round_trip() {
+        let price = FixedPrice::<3>::from_decimal(&decimal("143.25"));
+        assert_eq!(price, FixedPrice(143250));
+        assert_eq!(price.to_decimal(), decimal("143.250"));
+        assert_eq!(price.to_string(), "143.250");
+    }
+
+    #[test]
+    fn fixed_price_is_rounded_half_up() {
+        assert_eq!(
+            FixedPrice::<2>::from_decimal(&decimal("1.005")),
+            FixedPrice(101)
+        );
+        assert_eq!(
+            FixedPrice::<2>::from_decimal(&decimal("1.004")),
+            FixedPrice(100)
+        );
+        assert_eq!(
+            FixedPrice::<0>::from_decimal(&decimal("-2.5")),
+            FixedPrice(-3)
+        );
+        assert_eq!(
+            FixedPrice::<2>::from_decimal(&decimal("1e30")),
+            FixedPrice(i64::MAX)
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use bigdecimal::{BigDecimal, Signed, ToPrimitive};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::str::FromStr;
+
+// decimal digits of quantities, the smallest qty is 10^-QTY_SCALE of a lot
+pub const QTY_SCALE: u32 = 8;
+const UNITS_PER_LOT: u64 = 10u64.pow(QTY_SCALE);
+
+/**
+Quantity of an order, fixed-point with `QTY_SCALE` decimal digits, so fractional sizes
+trade as exactly as whole lots. Integer arithmetic on the hot path of every batch.
+Printed without trailing zeros, whole lots look the same as plain integers.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Qty(u64);
+
+impl Qty {
+    pub const ZERO: Qty = Qty(0);
+    pub const LOT: Qty = Qty(UNITS_PER_LOT);
+
+    pub const fn from_units(units: u64) -> Qty {
+        Qty(units)
+    }
+
+    // in 10^-QTY_SCALE of a lot
+    pub const fn units(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Qty) -> Option<Qty> {
+        self.0.checked_add(other.0).map(Qty)
+    }
+
+    pub fn saturating_add(self, other: Qty) -> Qty {
+        Qty(self.0.saturating_add(other.0))
+    }
+
+    // without trailing zeros after the point, so the scale of a product stays that of the price
+    pub fn to_decimal(self) -> BigDecimal {
+        let (mut units, mut scale) = (self.0, QTY_SCALE);
+        while scale > 0 && units % 10 == 0 {
+            units /= 10;
+            scale -= 1;
+        }
+        BigDecimal::new(units.into(), i64::from(scale))
+    }
+
+    // None if negative, finer than QTY_SCALE digits or too large
+    pub fn from_decimal(decimal: &BigDecimal) -> Option<Qty> {
+        let scaled = decimal.with_scale(i64::from(QTY_SCALE));
+        if decimal.is_negative() || scaled != *decimal {
+            return None;
+        }
+        let (units, _) = scaled.as_bigint_and_exponent();
+        units.to_u64().map(Qty)
+    }
+}
+
+// whole lots
+impl From<u32> for Qty {
+    fn from(lots: u32) -> Qty {
+        Qty(u64::from(lots) * UNITS_PER_LOT)
+    }
+}
+
+impl Add for Qty {
+    type Output = Qty;
+
+    fn add(self, other: Qty) -> Qty {
+        Qty(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Qty {
+    fn add_assign(&mut self, other: Qty) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Qty {
+    type Output = Qty;
+
+    fn sub(self, other: Qty) -> Qty {
+        Qty(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Qty {
+    fn sub_assign(&mut self, other: Qty) {
+        self.0 -= other.0;
+    }
+}
+
+impl Sum for Qty {
+    fn sum<I: Iterator<Item = Qty>>(iter: I) -> Qty {
+        iter.fold(Qty::ZERO, Add::add)
+    }
+}
+
+impl fmt::Display for Qty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (lots, fraction) = (self.0 / UNITS_PER_LOT, self.0 % UNITS_PER_LOT);
+        if fraction == 0 {
+            return write!(f, "{}", lots);
+        }
+        let digits = format!("{:0width$}", fraction, width = QTY_SCALE as usize);
+        write!(f, "{}.{}", lots, digits.trim_end_matches('0'))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseQtyError;
+
+// a decimal, negative or finer than QTY_SCALE digits is an error
+impl FromStr for Qty {
+    type Err = ParseQtyError;
+
+    fn from_str(s: &str) -> Result<Qty, ParseQtyError> {
+        BigDecimal::from_str(s)
+            .ok()
+            .and_then(|decimal| Qty::from_decimal(&decimal))
+            .ok_or(ParseQtyError)
+    }
+}
+
+// a string like prices, so fractions stay exact in JSON
+impl Serialize for Qty {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// also whole lots as a number, the format before fractional quantities
+impl<'de> Deserialize<'de> for Qty {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Qty, D::Error> {
+        struct QtyVisitor;
+
+        impl Visitor<'_> for QtyVisitor {
+            type Value = Qty;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a non-negative quantity")
+            }
+
+            fn visit_u64<E: de::Error>(self, lots: u64) -> Result<Qty, E> {
+                lots.checked_mul(UNITS_PER_LOT)
+                    .map(Qty)
+                    .ok_or_else(|| E::custom(format!("qty {} is too large", lots)))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Qty, E> {
+                value
+                    .parse()
+                    .map_err(|_| E::custom(format!("bad qty {}", value)))
+            }
+        }
+
+        deserializer.deserialize_any(QtyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::qty::Qty;
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+
+    fn decimal(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn qty_round_trip() {
+        let half = Qty::from_decimal(&decimal("0.5")).unwrap();
+        assert_eq!(half.units(), 50_000_000);
+        assert_eq!(half.to_string(), "0.5");
+        assert_eq!(half + half, Qty::from(1));
+        assert_eq!(Qty::from(3).to_string(), "3");
+        assert_eq!(
+            Qty::from_decimal(&decimal("2.00000001"))
+                .unwrap()
+                .to_string(),
+            "2.00000001"
+        );
+        assert_eq!(Qty::from(3).to_decimal().to_string(), "3");
+        assert_eq!(half.to_decimal().to_string(), "0.5");
+
+        // finer than the scale, negative or out of range
+        assert_eq!(Qty::from_decimal(&decimal("0.000000001")), None);
+        assert_eq!(Qty::from_decimal(&decimal("-1")), None);
+        assert_eq!(Qty::from_decimal(&decimal("1e12")), None);
+    }
+
+    #[test]
+    fn qty_serde() {
+        let half = Qty::from_units(50_000_000);
+        assert_eq!(serde_json::to_string(&half).unwrap(), r#""0.5""#);
+        assert_eq!(serde_json::from_str::<Qty>(r#""0.5""#).unwrap(), half);
+        assert_eq!(serde_json::from_str::<Qty>("3").unwrap(), Qty::from(3));
+        assert!(serde_json::from_str::<Qty>(r#""-1""#).is_err());
+        assert_eq!("0.25".parse::<Qty>().unwrap().units(), 25_000_000);
+        assert!("lot".parse::<Qty>().is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+// max commands per second of a single connection, counted in fixed one second windows
+pub struct RateLimiter {
+    // None - unlimited
+    max_per_sec: Option<u32>,
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub fn new(max_per_sec: Option<u32>) -> RateLimiter {
+        RateLimiter {
+            max_per_sec,
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    // false if the command has to be dropped
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let max_per_sec = match self.max_per_sec {
+            Some(max_per_sec) => max_per_sec,
+            None => return true,
+        };
+        if now.duration_since(self.window_start) >= WINDOW {
+            self.window_start = now;
+            self.count = 0;
+        }
+        if self.count >= max_per_sec {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn burst_is_limited_until_next_window() {
+        let mut limiter = RateLimiter::new(Some(3));
+        let start = limiter.window_start;
+
+        let allowed = (0..5).filter(|_| limiter.allow(start)).count();
+        assert_eq!(allowed, 3);
+        assert!(!limiter.allow(start + Duration::from_millis(999)));
+        assert!(limiter.allow(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn no_limit_by_default() {
+        let mut limiter = RateLimiter::new(None);
+        let now = Instant::now();
+
+        assert!((0..10_000).all(|_| limiter.allow(now)));
+    }
+}
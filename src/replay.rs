@@ -0,0 +1,100 @@
+use crate::auction::AuctionConfig;
+use crate::{
+    answer_query, apply_command, run_batches, write_results, ClientError, Command, CommandHandler,
+    Exchange, OutputFormat,
+};
+use std::io::{self, BufRead, Write};
+
+// line that runs a batch instead of a command
+const BATCH: &str = "BATCH";
+
+/**
+Applies newline-delimited commands to the exchange without any networking.
+Batches run only on `BATCH` lines, so the same input always gives the same output.
+Responses, notifications and batch reports go to `out` in the order they happen.
+*/
+pub fn replay(
+    input: impl BufRead,
+    out: &mut impl Write,
+    exchange: &Exchange,
+    config: &AuctionConfig,
+    output: OutputFormat,
+) -> io::Result<()> {
+    let (responder, mut notifications) = tokio::sync::mpsc::unbounded_channel();
+    let client = CommandHandler::connect(exchange, responder);
+
+    for line in input.lines() {
+        let line = line?;
+        match line.trim() {
+            "" => continue,
+            BATCH => {
+                let results = run_batches(exchange, config);
+                write_results(out, &results, output)?;
+            }
+            msg => {
+                let response = match CommandHandler::parse_command(msg) {
+                    Ok(Command::Query(query)) => answer_query(exchange, query),
+                    Ok(command) => apply_command(exchange, command, client.id),
+                    Err(e) => ClientError::from(e).response(),
+                };
+                writeln!(out, "{}", response)?;
+            }
+        }
+
+        while let Ok(notification) = notifications.try_recv() {
+            writeln!(out, "{}", notification)?;
+        }
+    }
+
+    exchange.unregister_client(client.id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auction::AuctionConfig;
+    use crate::replay::replay;
+    use crate::{Exchange, OutputFormat};
+
+    fn run(script: &str, output: OutputFormat) -> String {
+        let mut out = vec![];
+        replay(
+            script.as_bytes(),
+            &mut out,
+            &Exchange::new(),
+            &AuctionConfig::default(),
+            output,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn replay_is_reproducible() {
+        let script = "ADD,BUY,AAPL,10.00,5\n\
+                      ADD,SELL,AAPL,9.00,3\n\
+                      ADD,SELL,MSFT,20.00,1\n\
+                      ADD,HOLD,AAPL,9.00,3\n\
+                      BATCH\n\
+                      QUERY,BBO,AAPL\n\
+                      \n\
+                      BATCH\n";
+
+        let out = run(script, OutputFormat::Json);
+        assert_eq!(
+            out,
+            "ADDED,1\n\
+             ADDED,2\n\
+             ADDED,3\n\
+             ERR,BAD_COMMAND,unknown side HOLD\n\
+             {\"event\":\"trade\",\"batch_seq\":1,\"symbol\":\"AAPL\",\"price\":\"9.50\",\"qty\":\"3\",\"cleared_bids\":1,\"cleared_asks\":1,\"bid_imbalance\":\"2\",\"ask_imbalance\":\"0\"}\n\
+             {\"event\":\"no_trade\",\"batch_seq\":1,\"symbol\":\"MSFT\",\"reason\":\"OneSided\"}\n\
+             FILL,1,9.50,3,2,0.00\n\
+             FILL,2,9.50,3,0,0.00\n\
+             BBO,10.00,NONE\n\
+             {\"event\":\"no_trade\",\"batch_seq\":2,\"symbol\":\"AAPL\",\"reason\":\"OneSided\"}\n\
+             {\"event\":\"no_trade\",\"batch_seq\":2,\"symbol\":\"MSFT\",\"reason\":\"OneSided\"}\n"
+        );
+        assert_eq!(run(script, OutputFormat::Json), out);
+    }
+}
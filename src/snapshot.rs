@@ -0,0 +1,192 @@
+use crate::auction::Order;
+use crate::Exchange;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic;
+
+// bumped on incompatible changes of the format
+const VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    // numbering of batches continues after a restart
+    #[serde(default)]
+    batch_seq: u64,
+    books: BTreeMap<String, BookSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BookSnapshot {
+    bids: Vec<Order>,
+    asks: Vec<Order>,
+}
+
+/**
+Writes the resting orders of all books to `path`.
+The file is replaced atomically, so a crash while saving keeps the previous snapshot.
+*/
+pub fn save(exchange: &Exchange, path: &Path) -> io::Result<()> {
+    let books = exchange
+        .books()
+        .into_iter()
+        .map(|(symbol, book)| {
+            let bids = book.bids.read().unwrap().clone();
+            let asks = book.asks.read().unwrap().clone();
+            (symbol, BookSnapshot { bids, asks })
+        })
+        .collect();
+    let snapshot = Snapshot {
+        version: VERSION,
+        batch_seq: exchange.batch_seq.load(atomic::Ordering::Relaxed),
+        books,
+    };
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(&snapshot)?)?;
+    fs::rename(&tmp, path)
+}
+
+/**
+Loads the books saved by `save` into the exchange and returns the number of restored orders.
+New order and client ids, arrival numbers and batch numbers continue after the highest restored ones,
+so the owners of restored orders are not confused with new clients.
+*/
+pub fn restore(exchange: &Exchange, path: &Path) -> io::Result<usize> {
+    let snapshot: Snapshot = serde_json::from_slice(&fs::read(path)?)?;
+    if snapshot.version > VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported snapshot version {}", snapshot.version),
+        ));
+    }
+
+    let mut restored = 0;
+    let mut max_id = 0;
+    let mut max_client_id = 0;
+    let mut max_submitted = 0;
+    for (symbol, BookSnapshot { bids, asks }) in snapshot.books {
+        restored += bids.len() + asks.len();
+        max_id = bids
+            .iter()
+            .chain(&asks)
+            .map(|o| o.id())
+            .fold(max_id, u64::max);
+        max_client_id = bids
+            .iter()
+            .chain(&asks)
+            .map(|o| o.client_id())
+            .fold(max_client_id, u64::max);
+        max_submitted = bids
+            .iter()
+            .chain(&asks)
+            .map(|o| o.submitted())
+            .fold(max_submitted, u64::max);
+
+        let book = exchange.book(&symbol);
+        book.bids.write().unwrap().extend(bids);
+        book.asks.write().unwrap().extend(asks);
+    }
+    exchange
+        .next_order_id
+        .fetch_max(max_id + 1, atomic::Ordering::Relaxed);
+    exchange
+        .next_client_id
+        .fetch_max(max_client_id + 1, atomic::Ordering::Relaxed);
+    exchange
+        .next_submission
+        .fetch_max(max_submitted + 1, atomic::Ordering::Relaxed);
+    exchange
+        .batch_seq
+        .fetch_max(snapshot.batch_seq, atomic::Ordering::Relaxed);
+
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auction::{AuctionConfig, Order, Qty, TimeInForce};
+    use crate::snapshot::{restore, save};
+    use crate::{run_batches, Exchange};
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+    use std::sync::atomic;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("fba-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let exchange = Exchange::new();
+        let aapl = exchange.book("AAPL");
+        aapl.bids.write().unwrap().push(
+            Order::new(1, BigDecimal::from_str("9.00").unwrap(), Qty::from(5)).with_client(3),
+        );
+        aapl.asks.write().unwrap().push(Order::new(
+            2,
+            BigDecimal::from_str("11.00").unwrap(),
+            Qty::from(4),
+        ));
+        exchange.book("MSFT").bids.write().unwrap().push(
+            Order::new(7, BigDecimal::from_str("20.50").unwrap(), Qty::from(1))
+                .with_time_in_force(TimeInForce::GoodTillCancel),
+        );
+        // age the orders, so that batches_out is not zero
+        run_batches(&exchange, &AuctionConfig::default());
+
+        let path = temp_path("snapshot");
+        save(&exchange, &path).unwrap();
+        let restored = Exchange::new();
+        let count = restore(&restored, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(count, 3);
+        for (symbol, book) in exchange.books() {
+            let restored_book = restored.find_book(&symbol).unwrap();
+            assert_eq!(
+                *restored_book.bids.read().unwrap(),
+                *book.bids.read().unwrap()
+            );
+            assert_eq!(
+                *restored_book.asks.read().unwrap(),
+                *book.asks.read().unwrap()
+            );
+        }
+        assert_eq!(restored.next_order_id.load(atomic::Ordering::Relaxed), 8);
+        assert_eq!(restored.next_client_id.load(atomic::Ordering::Relaxed), 4);
+        assert_eq!(restored.batch_seq.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let path = temp_path("snapshot-v1");
+        std::fs::write(
+            &path,
+            r#"{"version":1,"books":{"AAPL":{"bids":[{"id":1,"qty":5,"price":"9.00"}],"asks":[]}}}"#,
+        )
+        .unwrap();
+        let exchange = Exchange::new();
+        let count = restore(&exchange, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(
+            exchange.book("AAPL").bids.read().unwrap()[0],
+            Order::new(1, BigDecimal::from_str("9.00").unwrap(), Qty::from(5))
+        );
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let path = temp_path("snapshot-v99");
+        std::fs::write(&path, r#"{"version":99,"books":{}}"#).unwrap();
+        let result = restore(&Exchange::new(), &path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
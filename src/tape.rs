@@ -0,0 +1,112 @@
+use crate::auction::Qty;
+use bigdecimal::BigDecimal;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::Duration;
+
+const HEADER: &str = "timestamp,symbol,price,qty";
+const FLUSH_MILLIS: u64 = 1000;
+
+// single executed trade on the tape
+#[derive(Debug, PartialEq)]
+pub struct TapeEntry {
+    // millis since the unix epoch
+    pub timestamp: u128,
+    pub symbol: String,
+    pub price: BigDecimal,
+    pub qty: Qty,
+}
+
+impl TapeEntry {
+    pub fn new(symbol: String, price: BigDecimal, qty: Qty) -> TapeEntry {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        TapeEntry {
+            timestamp,
+            symbol,
+            price,
+            qty,
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{}\n",
+            self.timestamp, self.symbol, self.price, self.qty
+        )
+    }
+}
+
+/**
+Appends every received entry to the CSV file at `path`.
+The header is written only if the file is empty.
+Buffered entries are flushed periodically and once the channel is closed.
+*/
+pub async fn write_tape(path: &Path, mut rx: UnboundedReceiver<TapeEntry>) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let is_empty = file.metadata().await?.len() == 0;
+    let mut writer = BufWriter::new(file);
+    if is_empty {
+        writer.write_all(format!("{}\n", HEADER).as_bytes()).await?;
+    }
+
+    let mut flush = tokio::time::interval(Duration::from_millis(FLUSH_MILLIS));
+    loop {
+        tokio::select! {
+            entry = rx.recv() => match entry {
+                Some(entry) => writer.write_all(entry.to_csv().as_bytes()).await?,
+                None => break,
+            },
+            _ = flush.tick() => writer.flush().await?,
+        }
+    }
+
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auction::Qty;
+    use crate::tape::{write_tape, TapeEntry};
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+
+    fn entry(timestamp: u128, symbol: &str, price: &str, qty: u32) -> TapeEntry {
+        TapeEntry {
+            timestamp,
+            symbol: symbol.to_string(),
+            price: BigDecimal::from_str(price).unwrap(),
+            qty: Qty::from(qty),
+        }
+    }
+
+    #[tokio::test]
+    async fn tape_appends_with_single_header() {
+        let path = std::env::temp_dir().join(format!("fba-tape-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        for e in [entry(1, "AAPL", "9.50", 3), entry(2, "MSFT", "20.00", 1)] {
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            tx.send(e).unwrap();
+            drop(tx);
+            write_tape(&path, rx).await.unwrap();
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            content,
+            "timestamp,symbol,price,qty\n1,AAPL,9.50,3\n2,MSFT,20.00,1\n"
+        );
+    }
+}
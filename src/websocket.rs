@@ -0,0 +1,124 @@
+use crate::rate_limit::RateLimiter;
+use crate::{Client, Command, CommandHandler, Exchange};
+use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+// accepts websocket connections until the task is aborted
+pub async fn serve(listener: TcpListener, tx: Sender<(Command, Client)>, exchange: Arc<Exchange>) {
+    loop {
+        let (socket, _) = listener.accept().await.unwrap(); // assume everything is OK
+        tokio::spawn(handle_websocket(socket, tx.clone(), exchange.clone()));
+    }
+}
+
+/**
+Same protocol as the TCP socket: every text frame is a single command,
+every response and notification is sent back as a text frame.
+*/
+async fn handle_websocket(
+    tcp_stream: TcpStream,
+    tx: Sender<(Command, Client)>,
+    exchange: Arc<Exchange>,
+) {
+    // a frame is a single command, so it has the same limit as a TCP line
+    let config = WebSocketConfig::default().max_message_size(Some(exchange.max_line()));
+    let ws_stream =
+        match tokio_tungstenite::accept_async_with_config(tcp_stream, Some(config)).await {
+            Ok(ws_stream) => ws_stream,
+            Err(e) => {
+                warn!("websocket handshake failed: {}", e);
+                return;
+            }
+        };
+    let (mut sink, mut stream) = ws_stream.split();
+
+    // separate task writes responses back to the client
+    let (responder, mut responses) = tokio::sync::mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(line) = responses.recv().await {
+            if sink.send(Message::Text(line.into())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut client = CommandHandler::connect(&exchange, responder);
+    let mut limiter = RateLimiter::new(exchange.rate_limit);
+
+    while let Some(frame) = stream.next().await {
+        match frame {
+            Ok(Message::Text(msg)) => {
+                if !CommandHandler::handle_message(&msg, &mut client, &mut limiter, &tx, &exchange)
+                    .await
+                {
+                    break;
+                }
+            }
+            Ok(Message::Close(_)) | Err(_) => break,
+            // pings are answered by tungstenite, binary frames are not part of the protocol
+            Ok(_) => {}
+        }
+    }
+
+    CommandHandler::disconnect(client, &tx, &exchange).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::websocket::serve;
+    use crate::{update_order_book, Exchange};
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    async fn next_text<S>(ws: &mut S) -> String
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        ws.next()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_text()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn commands_over_websocket() {
+        let exchange = Arc::new(Exchange::new());
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let (_stop, stopped) = tokio::sync::oneshot::channel();
+        tokio::spawn(update_order_book(rx, exchange.clone(), stopped));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, tx, exchange.clone()));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        // queries are answered right away, so wait for the order to be added first
+        ws.send(Message::Text("ADD,BUY,AAPL,10.00,5".into()))
+            .await
+            .unwrap();
+        assert_eq!(next_text(&mut ws).await, "ADDED,1");
+        ws.send(Message::Text("QUERY,VOLUME,AAPL".into()))
+            .await
+            .unwrap();
+        assert_eq!(next_text(&mut ws).await, "VOLUME,BID,5,ASK,0");
+        ws.send(Message::Text("ADD,HOLD,AAPL,10.00,5".into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_text(&mut ws).await,
+            "ERR,BAD_COMMAND,unknown side HOLD"
+        );
+    }
+}
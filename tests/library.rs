@@ -0,0 +1,45 @@
+use bigdecimal::BigDecimal;
+use fba::auction::{AuctionConfig, BatchReport, Qty};
+use fba::{
+    answer_query, apply_command, run_batches, ClientError, Command, CommandHandler, Exchange,
+};
+use std::str::FromStr;
+
+// the engine embedded without the server: commands in, batch results out
+#[test]
+fn embedded_auction() {
+    let exchange = Exchange::new();
+    let config = AuctionConfig::default();
+    let submit = |msg: &str| match CommandHandler::parse_command(msg) {
+        Ok(Command::Query(query)) => answer_query(&exchange, query),
+        Ok(command) => apply_command(&exchange, command, 1),
+        Err(e) => ClientError::from(e).response(),
+    };
+
+    assert_eq!(submit("ADD,BUY,AAPL,10.00,5"), "ADDED,1");
+    assert_eq!(submit("ADD,SELL,AAPL,9.00,3"), "ADDED,2");
+    assert_eq!(
+        submit("ADD,HOLD,AAPL,9.00,3"),
+        "ERR,BAD_COMMAND,unknown side HOLD"
+    );
+
+    let results = run_batches(&exchange, &config);
+    assert_eq!(results.len(), 1);
+    let (symbol, result) = &results[0];
+    assert_eq!(symbol, "AAPL");
+    match &result.report {
+        BatchReport::Trade {
+            batch_seq,
+            price,
+            qty,
+            ..
+        } => {
+            assert_eq!(*batch_seq, 1);
+            assert_eq!(*price, BigDecimal::from_str("9.50").unwrap());
+            assert_eq!(*qty, Qty::from(3));
+        }
+        _ => panic!("expected a trade"),
+    }
+
+    assert_eq!(submit("QUERY,VOLUME,AAPL"), "VOLUME,BID,2,ASK,0");
+}
@@ -0,0 +1,57 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// runs the binary in replay mode with the script on stdin
+fn replay(script: &str, args: &[&str]) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fba"))
+        .args(["--replay", "-"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn scripted_session() {
+    let script = "\
+ADD,SELL,AAPL,43.52,10
+ADD,BUY,AAPL,43.55,5
+ADD,BUY,MSFT,20.00,1
+BATCH
+CANCEL,1
+ADD,BUY,AAPL,IOC,43.60,2
+BATCH
+QUERY,TRADES,AAPL,10
+";
+
+    let expected = "\
+ADDED,1
+ADDED,2
+ADDED,3
+AAPL: Batch 1: cleared BID=1, cleared ASK=1, price=BigDecimal(\"43.54\"), qty=5, imbalance BID=0, ASK=5
+MSFT: No Trade: OneSided
+FILL,2,43.54,5,0,0.00
+FILL,1,43.54,5,5,0.00
+CANCELLED,1
+ADDED,4
+AAPL: Cancelled 1 orders
+AAPL: No Trade: OneSided
+MSFT: No Trade: OneSided
+CANCELLED,4
+TRADE,1,43.54,5
+END
+";
+    assert_eq!(replay(script, &[]), expected);
+    // same input, same output
+    assert_eq!(replay(script, &[]), expected);
+}